@@ -1,10 +1,10 @@
-use oreno_quic::connection::Connection;
+use oreno_quic::connection::{Connection, ConnectionError};
 use oreno_quic::frame::Frame;
 use oreno_quic::tls::TlsConfig;
-use oreno_quic::packet::PacketHeader;
-use bytes::Bytes;
+use oreno_quic::packet::{self, PacketHeader};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::net::UdpSocket;
 
 #[tokio::main]
@@ -32,49 +32,82 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     socket.send_to(&handshake_packet, server_addr).await?;
     println!("Sent TLS ClientHello");
     
-    // Wait for TLS response
+    // Wait for TLS response, restarting if the server asks for another version or a Retry
     let mut buf = vec![0u8; 1500];
-    let (len, peer_addr) = socket.recv_from(&mut buf).await?;
-    println!("Received {} bytes from {}", len, peer_addr);
-    
-    // Process the server's response
-    let mut packet_data = Bytes::copy_from_slice(&buf[..len]);
-    match PacketHeader::decode(&mut packet_data) {
-        Ok(header) => {
-            println!("Decoded packet header: {:?}", header);
+    'handshake: loop {
+        // Lost handshake packets are sent again when the loss detection timer fires
+        let received = match connection.poll_timeout() {
+            Some(timeout) => tokio::time::timeout_at(timeout.into(), socket.recv_from(&mut buf)).await,
+            None => Ok(socket.recv_from(&mut buf).await),
+        };
+        let Ok(received) = received else {
+            connection.handle_timeout(Instant::now());
+            if connection.is_closed() {
+                println!("Server did not answer before the idle timeout");
+                return Ok(());
+            }
+            let packets = connection.poll_packets()?;
+            let datagrams = connection.coalesce_packets(packets);
+            println!("Retransmitting {} datagrams after a loss detection timeout", datagrams.len());
+            connection.pace(datagrams);
+            flush(&mut connection, &socket, server_addr).await?;
+            continue;
+        };
+        let (len, peer_addr) = received?;
+        println!("Received {} bytes from {}", len, peer_addr);
+        
+        // Process the server's response, one coalesced packet at a time
+        for packet in packet::split_datagram(&buf[..len]) {
+            let packet = match packet {
+                Ok(packet) => match connection.receive_packet(packet) {
+                    Ok(received) => received,
+                    Err(ConnectionError::NoCommonVersion) => {
+                        println!("Server supports none of our QUIC versions");
+                        return Err(ConnectionError::NoCommonVersion.into());
+                    }
+                    Err(e) => {
+                        println!("Dropping packet: {}", e);
+                        continue;
+                    }
+                },
+                Err(e) => {
+                    println!("Packet decode error: {}", e);
+                    break;
+                }
+            };
+            println!("Decrypted packet header: {:?}", packet.header);
+            
+            // Both ask for the first flight again, under a new version or with a token
+            if let PacketHeader::VersionNegotiation(_) | PacketHeader::Retry(_) = packet.header {
+                println!("Restarting handshake with version {:#x}", connection.version);
+                let handshake_packet = connection.start_tls_handshake()?;
+                socket.send_to(&handshake_packet, server_addr).await?;
+                continue 'handshake;
+            }
+            let level = packet.header.encryption_level();
             
-            while !packet_data.is_empty() {
-                match Frame::decode(&mut packet_data) {
-                    Ok(frame) => {
-                        println!("Decoded frame: {:?}", frame);
-                        
-                        if let Frame::Crypto { .. } = frame {
-                            match connection.process_crypto_frame(&frame) {
-                                Ok(Some(response_packet)) => {
-                                    println!("Sending TLS handshake response");
-                                    socket.send_to(&response_packet, server_addr).await?;
-                                }
-                                Ok(None) => {
-                                    if connection.is_tls_handshake_complete() {
-                                        println!("TLS handshake completed successfully!");
-                                    }
-                                }
-                                Err(e) => {
-                                    println!("TLS handshake error: {}", e);
-                                }
+            for frame in packet.frames {
+                println!("Decoded frame: {:?}", frame);
+                
+                if let Frame::Crypto { .. } = frame {
+                    match connection.process_crypto_frame(level, &frame) {
+                        Ok(response_packets) => {
+                            let datagrams = connection.coalesce_packets(response_packets);
+                            println!("Sending {} TLS handshake response datagrams", datagrams.len());
+                            connection.pace(datagrams);
+                            flush(&mut connection, &socket, server_addr).await?;
+                            if connection.is_tls_handshake_complete() {
+                                println!("TLS handshake completed successfully!");
                             }
                         }
-                    }
-                    Err(e) => {
-                        println!("Frame decode error: {}", e);
-                        break;
+                        Err(e) => {
+                            println!("TLS handshake error: {}", e);
+                        }
                     }
                 }
             }
         }
-        Err(e) => {
-            println!("Packet decode error: {}", e);
-        }
+        break;
     }
     
     // Send a PING after TLS handshake
@@ -87,10 +120,55 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let (len, peer_addr) = socket.recv_from(&mut buf).await?;
     println!("Received {} bytes PING response from {}", len, peer_addr);
     
+    // Send a message on a bidirectional stream and wait for the server to echo it back
+    // 1-RTT packets can only be sent once the handshake has completed
+    if connection.is_tls_handshake_complete() {
+        let stream_id = connection.open_bi()?;
+        connection.write(stream_id, b"Hello over a QUIC stream")?;
+        connection.finish(stream_id)?;
+        let packets = connection.poll_packets()?;
+        let datagrams = connection.coalesce_packets(packets);
+        connection.pace(datagrams);
+        flush(&mut connection, &socket, server_addr).await?;
+        println!("Sent message on stream {}", stream_id);
+        
+        let mut echo = Vec::new();
+        let mut chunk = [0u8; 1024];
+        'echo: loop {
+            let (len, _) = socket.recv_from(&mut buf).await?;
+            for packet in packet::split_datagram(&buf[..len]).flatten() {
+                if let Err(e) = connection.receive_packet(packet) {
+                    println!("Dropping packet: {}", e);
+                }
+            }
+            loop {
+                match connection.read(stream_id, &mut chunk) {
+                    Ok(0) => break 'echo,
+                    Ok(read) => echo.extend_from_slice(&chunk[..read]),
+                    Err(_) => break,
+                }
+            }
+        }
+        println!("Stream {} echoed: {}", stream_id, String::from_utf8_lossy(&echo));
+    } else {
+        println!("Skipping stream echo: TLS handshake did not complete");
+    }
+    
     // Send connection close
     let close_packet = connection.close("Client disconnecting".to_string())?;
     socket.send_to(&close_packet, server_addr).await?;
     println!("Sent CONNECTION_CLOSE to server");
     
+    Ok(())
+}
+
+/// Sends the connection's queued datagrams as its pacer releases them, rather than in one line-rate burst
+async fn flush(connection: &mut Connection, socket: &UdpSocket, server_addr: SocketAddr) -> std::io::Result<()> {
+    while let Some(next) = connection.next_send_time(Instant::now()) {
+        tokio::time::sleep_until(next.into()).await;
+        while let Some(datagram) = connection.poll_transmit(Instant::now()) {
+            socket.send_to(&datagram, server_addr).await?;
+        }
+    }
     Ok(())
 }
\ No newline at end of file
@@ -1,10 +1,12 @@
-use oreno_quic::connection::{Connection, ConnectionManager, ConnectionState};
+use oreno_quic::connection::{ConnectionManager, IncomingAction};
+use oreno_quic::token::AddressValidator;
 use oreno_quic::frame::Frame;
-use oreno_quic::packet::PacketHeader;
+use oreno_quic::packet::{self, ProtectedHeader};
 use oreno_quic::tls::TlsConfig;
-use bytes::Bytes;
 use tokio::net::UdpSocket;
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Instant;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -20,28 +22,91 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("Waiting for clients...");
     
     let mut connection_manager = ConnectionManager::new();
+    
+    // With --retry, clients must prove their address before the server keeps any state
+    if std::env::args().any(|arg| arg == "--retry") {
+        connection_manager.require_address_validation(AddressValidator::new());
+        println!("Address validation enabled: first Initials are answered with a Retry");
+    }
     let mut buf = vec![0u8; 1500];
     
+    // Short headers carry the server's connection ID rather than the client's one connections are keyed by
+    let mut local_conn_ids: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+    
     loop {
-        let (len, peer_addr) = socket.recv_from(&mut buf).await?;
+        // Release whatever the connections' pacers allow out now
+        for (peer_addr, datagram) in connection_manager.poll_transmit(Instant::now()) {
+            socket.send_to(&datagram, peer_addr).await?;
+        }
+        
+        // Wait for the next datagram, a connection timer (loss detection, idle or keep-alive), or a pacer with more to send
+        let deadline = connection_manager.poll_timeout().into_iter()
+            .chain(connection_manager.next_send_time(Instant::now()))
+            .min();
+        let sleep = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+        let (len, peer_addr) = tokio::select! {
+            received = socket.recv_from(&mut buf) => received?,
+            _ = sleep => {
+                let timeouts = connection_manager.handle_timeouts(Instant::now());
+                for (conn_id, error) in timeouts.errors {
+                    println!("Connection {:02x?} failed after a timer fired: {}", conn_id, error);
+                }
+                for (peer_addr, datagram) in timeouts.datagrams {
+                    println!("[{}] Sending a retransmission or keep-alive after a timer fired", peer_addr);
+                    socket.send_to(&datagram, peer_addr).await?;
+                }
+                // Connections that idled out or finished closing or draining are dropped
+                for conn_id in connection_manager.reap_closed() {
+                    println!("Connection {:02x?} closed and was removed", conn_id);
+                    local_conn_ids.retain(|_, key| *key != conn_id);
+                }
+                continue;
+            }
+        };
         println!("\n[{}] Received {} bytes", peer_addr, len);
         
-        let mut packet_data = Bytes::copy_from_slice(&buf[..len]);
+        // Packets of a version we do not speak get a Version Negotiation reply instead of a connection
+        if let Some(version) = packet::long_header_version(&buf[..len]) {
+            if !connection_manager.supported_versions().contains(&version) {
+                if let Some(response) = connection_manager.version_negotiation(&buf[..len]) {
+                    println!("[{}] Unsupported version {:#x}, sending Version Negotiation", peer_addr, version);
+                    socket.send_to(&response, peer_addr).await?;
+                }
+                continue;
+            }
+        }
         
-        match PacketHeader::decode(&mut packet_data) {
+        match ProtectedHeader::parse(&buf[..len]) {
             Ok(header) => {
-                println!("[{}] Decoded packet header: {:?}", peer_addr, header);
+                println!("[{}] Parsed packet header: {:?}", peer_addr, header);
                 
-                let conn_id = match &header {
-                    PacketHeader::Long(h) => &h.src_conn_id,
-                    PacketHeader::Short(h) => &h.dest_conn_id,
+                let conn_id = match &header.src_conn_id {
+                    Some(src_conn_id) => src_conn_id.data.clone(),
+                    None => local_conn_ids.get(&header.dest_conn_id.data).cloned()
+                        .unwrap_or_else(|| header.dest_conn_id.data.clone()),
                 };
                 
-                let connection_exists = connection_manager.get_connection(&conn_id.data).is_some();
+                let connection_exists = connection_manager.get_connection(&conn_id).is_some();
                 
                 if !connection_exists {
+                    let mut new_connection = match connection_manager.handle_incoming(&buf[..len], peer_addr) {
+                        IncomingAction::Accept(connection) => *connection,
+                        IncomingAction::Retry(retry) => {
+                            println!("[{}] Sending Retry to validate the client address", peer_addr);
+                            socket.send_to(&retry, peer_addr).await?;
+                            continue;
+                        }
+                        IncomingAction::Drop => {
+                            println!("[{}] Dropping packet: no connection and no valid token", peer_addr);
+                            continue;
+                        }
+                    };
                     println!("[{}] Creating new connection with TLS", peer_addr);
-                    let mut new_connection = Connection::new_server(peer_addr, conn_id.clone());
                     
                     // Setup TLS for the new connection
                     if let Err(e) = new_connection.setup_tls(tls_config.clone()) {
@@ -49,63 +114,117 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         continue;
                     }
                     
-                    connection_manager.add_connection(conn_id.data.clone(), new_connection);
+                    local_conn_ids.insert(new_connection.local_conn_id.data.clone(), conn_id.clone());
+                    connection_manager.add_connection(conn_id.clone(), new_connection);
                 }
                 
-                let connection = connection_manager.get_connection(&conn_id.data).unwrap();
+                let connection = connection_manager.get_connection(&conn_id).unwrap();
                 
-                while !packet_data.is_empty() {
-                    match Frame::decode(&mut packet_data) {
-                        Ok(frame) => {
-                            println!("[{}] Decoded frame: {:?}", peer_addr, frame);
-                            
-                            match frame {
-                                Frame::Ping => {
-                                    println!("[{}] Received PING, sending PONG", peer_addr);
-                                    let pong_packet = connection.create_initial_packet(vec![Frame::Ping])?;
-                                    socket.send_to(&pong_packet, peer_addr).await?;
-                                    println!("[{}] Sent PONG response", peer_addr);
-                                }
-                                Frame::ConnectionClose { error_code, reason } => {
-                                    println!("[{}] Connection close received: code={}, reason=\"{}\"", 
-                                        peer_addr, error_code, reason);
-                                    connection.handle_state_transition(ConnectionState::Closed);
-                                    connection_manager.remove_connection(&conn_id.data);
-                                    println!("[{}] Connection closed and removed", peer_addr);
-                                    break;
-                                }
-                                Frame::Padding { length } => {
-                                    println!("[{}] Received {} bytes of padding", peer_addr, length);
+                // A datagram may carry several coalesced packets for the same connection
+                'packets: for packet in packet::split_datagram(&buf[..len]) {
+                    let packet = match packet {
+                        Ok(packet) => match connection.receive_packet(packet) {
+                            Ok(received) => received,
+                            Err(e) => {
+                                println!("[{}] Dropping packet: {}", peer_addr, e);
+                                continue;
+                            }
+                        },
+                        Err(e) => {
+                            println!("[{}] Packet decode error: {}", peer_addr, e);
+                            break;
+                        }
+                    };
+                    let level = packet.header.encryption_level();
+                    
+                    for frame in packet.frames {
+                        println!("[{}] Decoded frame: {:?}", peer_addr, frame);
+                        
+                        match frame {
+                            Frame::Ping => {
+                                println!("[{}] Received PING, sending PONG", peer_addr);
+                                let pong_packet = connection.create_initial_packet(vec![Frame::Ping])?;
+                                connection.pace(vec![pong_packet]);
+                                println!("[{}] Queued PONG response", peer_addr);
+                            }
+                            Frame::ConnectionClose { error_code, reason } | Frame::ApplicationClose { error_code, reason } => {
+                                println!("[{}] Connection close received: code={}, reason=\"{}\"", 
+                                    peer_addr, error_code, reason);
+                                // The connection drains for three PTOs and is reaped once its timer fires
+                                println!("[{}] Connection draining", peer_addr);
+                                break 'packets;
+                            }
+                            Frame::Padding { length } => {
+                                println!("[{}] Received {} bytes of padding", peer_addr, length);
+                            }
+                            Frame::Ack { largest, ref ranges, .. } => {
+                                println!("[{}] Received ACK: largest={}, ranges={}", peer_addr, largest, ranges.len());
+                            }
+                            Frame::MaxData { .. }
+                            | Frame::MaxStreamData { .. }
+                            | Frame::DataBlocked { .. }
+                            | Frame::StreamDataBlocked { .. }
+                            | Frame::MaxStreams { .. }
+                            | Frame::StreamsBlocked { .. } => {
+                                println!("[{}] Received flow control frame: {:?}", peer_addr, frame);
+                            }
+                            Frame::HandshakeDone => {
+                                println!("[{}] Ignoring HANDSHAKE_DONE: only servers send it", peer_addr);
+                            }
+                            Frame::ResetStream { stream_id, error_code, .. } => {
+                                println!("[{}] Client reset stream {} with error code {}", peer_addr, stream_id, error_code);
+                            }
+                            Frame::StopSending { stream_id, error_code } => {
+                                println!("[{}] Client stopped stream {} with error code {}", peer_addr, stream_id, error_code);
+                                let packets = connection.poll_packets()?;
+                                let datagrams = connection.coalesce_packets(packets);
+                                connection.pace(datagrams);
+                            }
+                            Frame::Stream { stream_id, ref data, fin, .. } => {
+                                println!("[{}] Received STREAM frame: stream={}, data_len={}, fin={}", peer_addr, stream_id, data.len(), fin);
+                                while let Some(opened) = connection.accept_stream() {
+                                    println!("[{}] Client opened stream {}", peer_addr, opened);
                                 }
-                                Frame::Crypto { offset, ref data } => {
-                                    println!("[{}] Received CRYPTO frame: offset={}, data_len={}", peer_addr, offset, data.len());
-                                    
-                                    // Process TLS handshake data
-                                    match connection.process_crypto_frame(&frame) {
-                                        Ok(Some(response_packet)) => {
-                                            println!("[{}] Sending TLS handshake response", peer_addr);
-                                            socket.send_to(&response_packet, peer_addr).await?;
+                                
+                                // Echo whatever is readable back on the same stream
+                                let mut echo = [0u8; 1024];
+                                loop {
+                                    match connection.read(stream_id, &mut echo) {
+                                        Ok(0) => {
+                                            let _ = connection.finish(stream_id);
+                                            break;
                                         }
-                                        Ok(None) => {
-                                            if connection.is_tls_handshake_complete() {
-                                                println!("[{}] TLS handshake completed successfully!", peer_addr);
-                                            }
+                                        Ok(len) => {
+                                            let _ = connection.write(stream_id, &echo[..len]);
                                         }
-                                        Err(e) => {
-                                            println!("[{}] TLS handshake error: {}", peer_addr, e);
+                                        Err(_) => break,
+                                    }
+                                }
+                                let packets = connection.poll_packets()?;
+                                let datagrams = connection.coalesce_packets(packets);
+                                connection.pace(datagrams);
+                            }
+                            Frame::Crypto { offset, ref data } => {
+                                println!("[{}] Received CRYPTO frame: offset={}, data_len={}", peer_addr, offset, data.len());
+                                
+                                // Process TLS handshake data
+                                match connection.process_crypto_frame(level, &frame) {
+                                    Ok(response_packets) => {
+                                        let datagrams = connection.coalesce_packets(response_packets);
+                                        println!("[{}] Queued {} TLS handshake response datagrams", peer_addr, datagrams.len());
+                                        connection.pace(datagrams);
+                                        if connection.is_tls_handshake_complete() {
+                                            println!("[{}] TLS handshake completed successfully!", peer_addr);
                                         }
                                     }
+                                    Err(e) => {
+                                        println!("[{}] TLS handshake error: {}", peer_addr, e);
+                                    }
                                 }
                             }
                         }
-                        Err(e) => {
-                            println!("[{}] Frame decode error: {}", peer_addr, e);
-                            break;
-                        }
                     }
                 }
-                
-                // Connection is already in the manager, no need to re-add
             }
             Err(e) => {
                 println!("[{}] Packet decode error: {}", peer_addr, e);
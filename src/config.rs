@@ -0,0 +1,69 @@
+use crate::congestion::CongestionControl;
+use crate::flow_control::{DEFAULT_CONNECTION_WINDOW, DEFAULT_STREAM_WINDOW, MAX_CONNECTION_WINDOW, MAX_STREAM_WINDOW};
+use crate::pacer::DEFAULT_PACING_BURST;
+use crate::packet::SUPPORTED_VERSIONS;
+use std::time::Duration;
+
+/// Streams of each direction a peer may have open at once unless configured otherwise
+pub const DEFAULT_MAX_CONCURRENT_STREAMS: u64 = 100;
+/// How long a connection may sit without activity before closing unless configured otherwise
+pub const DEFAULT_MAX_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Limits a connection imposes on its peer, and how it controls congestion
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransportConfig {
+    /// Bidirectional streams the peer may have open at once
+    pub max_concurrent_bidi_streams: u64,
+    /// Unidirectional streams the peer may have open at once
+    pub max_concurrent_uni_streams: u64,
+    /// Receive window each stream starts with
+    pub stream_receive_window: u64,
+    /// Largest receive window auto-tuning grows a stream to
+    pub max_stream_receive_window: u64,
+    /// Receive window for the connection as a whole
+    pub receive_window: u64,
+    /// Largest receive window auto-tuning grows the connection to
+    pub max_receive_window: u64,
+    /// Congestion controller the connection starts with
+    pub congestion_control: CongestionControl,
+    /// Datagrams the pacer lets out back to back before spacing them at the pacing rate
+    pub pacing_burst: usize,
+    /// Idle timeout advertised to the peer; the connection closes after the smaller of both sides' values. `None` disables it on this side
+    pub max_idle_timeout: Option<Duration>,
+    /// Interval of PINGs that keep a quiet connection from idling out; off by default
+    pub keep_alive_interval: Option<Duration>,
+    /// QUIC versions a client offers or a server accepts, in order of preference. Versions this
+    /// implementation does not speak are skipped, and a list left empty falls back to `SUPPORTED_VERSIONS`
+    pub supported_versions: Vec<u32>,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent_bidi_streams: DEFAULT_MAX_CONCURRENT_STREAMS,
+            max_concurrent_uni_streams: DEFAULT_MAX_CONCURRENT_STREAMS,
+            stream_receive_window: DEFAULT_STREAM_WINDOW,
+            max_stream_receive_window: MAX_STREAM_WINDOW,
+            receive_window: DEFAULT_CONNECTION_WINDOW,
+            max_receive_window: MAX_CONNECTION_WINDOW,
+            congestion_control: CongestionControl::default(),
+            pacing_burst: DEFAULT_PACING_BURST,
+            max_idle_timeout: Some(DEFAULT_MAX_IDLE_TIMEOUT),
+            keep_alive_interval: None,
+            supported_versions: SUPPORTED_VERSIONS.to_vec(),
+        }
+    }
+}
+
+impl TransportConfig {
+    /// `supported_versions` without those this implementation has no Initial secrets for
+    pub fn negotiable_versions(&self) -> Vec<u32> {
+        let versions: Vec<u32> = self.supported_versions.iter().copied()
+            .filter(|version| SUPPORTED_VERSIONS.contains(version))
+            .collect();
+        if versions.is_empty() {
+            return SUPPORTED_VERSIONS.to_vec();
+        }
+        versions
+    }
+}
@@ -0,0 +1,882 @@
+use crate::packet::DEFAULT_MAX_DATAGRAM_SIZE;
+use crate::rtt::RttEstimator;
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Decides how many bytes a connection may have in flight (RFC 9002 Section 7).
+/// Connections use [`NewReno`] unless given another controller.
+pub trait CongestionController: fmt::Debug + Send {
+    /// An ack-eliciting packet of `bytes` was sent
+    fn on_packet_sent(&mut self, _now: Instant, _bytes: usize) {}
+    
+    /// A packet of `bytes` sent at `sent` was acknowledged
+    fn on_ack(&mut self, now: Instant, sent: Instant, bytes: usize, rtt: &RttEstimator);
+    
+    /// Packets were lost, the newest of them sent at `sent`. With `persistent_congestion`
+    /// every packet over several PTOs was lost and the window should collapse (RFC 9002 Section 7.6).
+    fn on_congestion_event(&mut self, now: Instant, sent: Instant, persistent_congestion: bool);
+    
+    /// Congestion window in bytes
+    fn window(&self) -> usize;
+    
+    /// Rate to spread sends at, in bytes per second: 1.25 windows per smoothed RTT (RFC 9002 Section 7.7)
+    fn pacing_rate(&self, rtt: &RttEstimator) -> u64 {
+        let srtt = rtt.smoothed_rtt().max(Duration::from_micros(1));
+        (self.window() as u128 * 5 / 4 * 1_000_000 / srtt.as_micros()) as u64
+    }
+}
+
+/// Window a connection starts with (RFC 9002 Section 7.2)
+pub fn initial_window(max_datagram_size: usize) -> usize {
+    (10 * max_datagram_size).min((2 * max_datagram_size).max(14720))
+}
+
+/// Smallest window, which persistent congestion collapses to
+pub fn minimum_window(max_datagram_size: usize) -> usize {
+    2 * max_datagram_size
+}
+
+/// Congestion controller selectable through the transport config
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CongestionControl {
+    #[default]
+    NewReno,
+    Cubic,
+    Bbr,
+}
+
+impl CongestionControl {
+    pub fn build(self, max_datagram_size: usize) -> Box<dyn CongestionController> {
+        match self {
+            CongestionControl::NewReno => Box::new(NewReno::new(max_datagram_size)),
+            CongestionControl::Cubic => Box::new(Cubic::new(max_datagram_size)),
+            CongestionControl::Bbr => Box::new(Bbr::new(max_datagram_size)),
+        }
+    }
+}
+
+/// The RFC 9002 NewReno controller: slow start, then congestion avoidance, halving the window once per recovery period
+#[derive(Debug, Clone)]
+pub struct NewReno {
+    max_datagram_size: usize,
+    window: usize,
+    /// Window at which slow start ends, unbounded until the first congestion event
+    ssthresh: usize,
+    /// Losses of packets sent before this do not shrink the window again
+    recovery_start: Option<Instant>,
+    /// Bytes acknowledged in congestion avoidance since the window last grew
+    bytes_acked: usize,
+}
+
+impl Default for NewReno {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_DATAGRAM_SIZE)
+    }
+}
+
+impl NewReno {
+    pub fn new(max_datagram_size: usize) -> Self {
+        Self {
+            max_datagram_size,
+            window: initial_window(max_datagram_size),
+            ssthresh: usize::MAX,
+            recovery_start: None,
+            bytes_acked: 0,
+        }
+    }
+    
+    pub fn ssthresh(&self) -> usize {
+        self.ssthresh
+    }
+    
+    pub fn in_slow_start(&self) -> bool {
+        self.window < self.ssthresh
+    }
+    
+    fn in_recovery(&self, sent: Instant) -> bool {
+        self.recovery_start.is_some_and(|start| sent <= start)
+    }
+}
+
+impl CongestionController for NewReno {
+    fn on_ack(&mut self, _now: Instant, sent: Instant, bytes: usize, _rtt: &RttEstimator) {
+        // Packets sent before the recovery period started do not grow the window
+        if self.in_recovery(sent) {
+            return;
+        }
+        if self.in_slow_start() {
+            self.window += bytes;
+            return;
+        }
+        // Congestion avoidance grows the window by one datagram per window acknowledged
+        self.bytes_acked += bytes;
+        if self.bytes_acked >= self.window {
+            self.bytes_acked -= self.window;
+            self.window += self.max_datagram_size;
+        }
+    }
+    
+    fn on_congestion_event(&mut self, now: Instant, sent: Instant, persistent_congestion: bool) {
+        if !self.in_recovery(sent) {
+            self.recovery_start = Some(now);
+            self.ssthresh = (self.window / 2).max(minimum_window(self.max_datagram_size));
+            self.window = self.ssthresh;
+            self.bytes_acked = 0;
+        }
+        if persistent_congestion {
+            self.window = minimum_window(self.max_datagram_size);
+            self.recovery_start = None;
+        }
+    }
+    
+    fn window(&self) -> usize {
+        self.window
+    }
+}
+
+/// Scaling constant of the cubic window function, in segments per second cubed (RFC 9438 Section 4.1)
+const CUBIC_C: f64 = 0.4;
+/// Multiplicative decrease factor on congestion (RFC 9438 Section 4.6)
+const CUBIC_BETA: f64 = 0.7;
+/// Additive increase of the Reno-friendly estimate, chosen so it matches Reno's average rate (RFC 9438 Section 4.3)
+const CUBIC_ALPHA: f64 = 3.0 * (1.0 - CUBIC_BETA) / (1.0 + CUBIC_BETA);
+
+/// The RFC 9438 CUBIC controller: after a loss the window grows along a cubic curve that flattens out
+/// around the window the loss happened at, never growing slower than Reno would
+#[derive(Debug, Clone)]
+pub struct Cubic {
+    max_datagram_size: usize,
+    /// Congestion window in bytes, kept fractional so small increments add up
+    window: f64,
+    ssthresh: usize,
+    recovery_start: Option<Instant>,
+    /// When the current congestion avoidance epoch began
+    epoch_start: Option<Instant>,
+    /// Window before the last reduction, which the cubic curve plateaus at
+    w_max: f64,
+    /// Seconds from the start of the epoch until the curve reaches `w_max`
+    k: f64,
+    /// Window Reno would have reached in the same epoch
+    w_est: f64,
+    /// Window just before the last reduction
+    cwnd_prior: f64,
+}
+
+impl Default for Cubic {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_DATAGRAM_SIZE)
+    }
+}
+
+impl Cubic {
+    pub fn new(max_datagram_size: usize) -> Self {
+        Self {
+            max_datagram_size,
+            window: initial_window(max_datagram_size) as f64,
+            ssthresh: usize::MAX,
+            recovery_start: None,
+            epoch_start: None,
+            w_max: 0.0,
+            k: 0.0,
+            w_est: 0.0,
+            cwnd_prior: 0.0,
+        }
+    }
+    
+    pub fn ssthresh(&self) -> usize {
+        self.ssthresh
+    }
+    
+    pub fn in_slow_start(&self) -> bool {
+        (self.window as usize) < self.ssthresh
+    }
+    
+    /// Window the cubic curve was heading back to when the last reduction happened
+    pub fn w_max(&self) -> usize {
+        self.w_max as usize
+    }
+    
+    fn in_recovery(&self, sent: Instant) -> bool {
+        self.recovery_start.is_some_and(|start| sent <= start)
+    }
+    
+    /// Cubic window function W_cubic(t) in bytes, `t` seconds into the epoch (RFC 9438 Section 4.2)
+    fn w_cubic(&self, t: f64) -> f64 {
+        CUBIC_C * (t - self.k).powi(3) * self.max_datagram_size as f64 + self.w_max
+    }
+    
+    fn start_epoch(&mut self, now: Instant) {
+        self.epoch_start = Some(now);
+        let segment = self.max_datagram_size as f64;
+        if self.window < self.w_max {
+            self.k = ((self.w_max - self.window) / segment / CUBIC_C).cbrt();
+        } else {
+            self.k = 0.0;
+            self.w_max = self.window;
+        }
+        self.w_est = self.window;
+    }
+}
+
+impl CongestionController for Cubic {
+    fn on_ack(&mut self, now: Instant, sent: Instant, bytes: usize, rtt: &RttEstimator) {
+        if self.in_recovery(sent) {
+            return;
+        }
+        if self.in_slow_start() {
+            self.window += bytes as f64;
+            return;
+        }
+        if self.epoch_start.is_none() {
+            self.start_epoch(now);
+        }
+        let t = self.epoch_start.map_or(0.0, |start| now.saturating_duration_since(start).as_secs_f64());
+        let segment = self.max_datagram_size as f64;
+        let bytes = bytes as f64;
+        
+        // Reno-friendly region: never grow slower than Reno would (RFC 9438 Section 4.3)
+        let alpha = if self.w_est >= self.cwnd_prior { 1.0 } else { CUBIC_ALPHA };
+        self.w_est += alpha * bytes * segment / self.window;
+        if self.w_cubic(t) < self.w_est {
+            self.window = self.window.max(self.w_est);
+            return;
+        }
+        
+        // Concave and convex regions: close a fraction of the gap to where the curve is one RTT from now
+        let target = self.w_cubic(t + rtt.smoothed_rtt().as_secs_f64()).clamp(self.window, 1.5 * self.window);
+        self.window += (target - self.window) * bytes / self.window;
+    }
+    
+    fn on_congestion_event(&mut self, now: Instant, sent: Instant, persistent_congestion: bool) {
+        if !self.in_recovery(sent) {
+            self.recovery_start = Some(now);
+            self.epoch_start = None;
+            // Fast convergence: a flow losing below its previous maximum releases bandwidth to newer flows
+            self.w_max = if self.window < self.w_max {
+                self.window * (1.0 + CUBIC_BETA) / 2.0
+            } else {
+                self.window
+            };
+            self.cwnd_prior = self.window;
+            let minimum = minimum_window(self.max_datagram_size) as f64;
+            self.window = (self.window * CUBIC_BETA).max(minimum);
+            self.ssthresh = self.window as usize;
+        }
+        if persistent_congestion {
+            self.window = minimum_window(self.max_datagram_size) as f64;
+            self.recovery_start = None;
+            self.epoch_start = None;
+        }
+    }
+    
+    fn window(&self) -> usize {
+        self.window as usize
+    }
+}
+
+/// Pacing gain that doubles the delivery rate every round in Startup, 2/ln(2)
+const BBR_STARTUP_GAIN: f64 = 2.885;
+/// Window headroom over the bandwidth-delay product, absorbing delayed and aggregated ACKs
+const BBR_CWND_GAIN: f64 = 2.0;
+/// ProbeBW pacing gains, one phase per min RTT: probe for more bandwidth, drain the queue that built, then cruise
+const BBR_PROBE_BW_GAINS: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+/// Rounds a delivery rate sample stays in the bottleneck bandwidth filter
+const BBR_BW_FILTER_ROUNDS: u64 = 10;
+/// How long a min RTT sample is trusted before ProbeRTT measures it again
+const BBR_MIN_RTT_WINDOW: Duration = Duration::from_secs(10);
+/// How long ProbeRTT holds the window at its minimum
+const BBR_PROBE_RTT_DURATION: Duration = Duration::from_millis(200);
+/// Smallest window in datagrams, which ProbeRTT drains the flight to
+const BBR_MIN_PACKETS: usize = 4;
+/// The pipe counts as full once the bandwidth estimate grows by less than this over several rounds
+const BBR_FULL_BW_GROWTH: f64 = 1.25;
+const BBR_FULL_BW_ROUNDS: u32 = 3;
+/// Fraction of the window kept as the in-flight ceiling after a loss
+const BBR_LOSS_BETA: f64 = 0.7;
+
+/// Phases of the BBR state machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BbrState {
+    /// Doubling the sending rate every round until the bandwidth estimate stops growing
+    Startup,
+    /// Sending below the estimate to drain the queue Startup built
+    Drain,
+    /// Sending at the estimate, periodically probing above and below it
+    ProbeBw,
+    /// Shrinking the flight to a few packets so the queue empties and the min RTT can be remeasured
+    ProbeRtt,
+}
+
+/// Delivery state captured when a packet was sent, for the rate sample its acknowledgement gives
+#[derive(Debug, Clone, Copy)]
+struct BbrPacket {
+    sent: Instant,
+    bytes: usize,
+    /// Bytes delivered when the packet was sent
+    delivered: u64,
+    /// When the last of those bytes were acknowledged
+    delivered_time: Instant,
+}
+
+/// A BBR-style model-based controller: rather than reacting to loss it estimates the bottleneck bandwidth
+/// and the min RTT, paces at the former and keeps about one bandwidth-delay product in flight.
+/// Loss still caps the flight, as in BBRv2.
+#[derive(Debug, Clone)]
+pub struct Bbr {
+    max_datagram_size: usize,
+    state: BbrState,
+    window: usize,
+    pacing_gain: f64,
+    /// Delivery rate samples as (round, bytes per second), kept decreasing so the front is the windowed maximum
+    bw_samples: VecDeque<(u64, f64)>,
+    min_rtt: Option<Duration>,
+    min_rtt_stamp: Option<Instant>,
+    /// Packets in flight, oldest first
+    in_flight: VecDeque<BbrPacket>,
+    bytes_in_flight: usize,
+    delivered: u64,
+    delivered_time: Option<Instant>,
+    /// Round trips counted as acknowledgements of packets sent after the previous round ended
+    round_count: u64,
+    next_round_delivered: u64,
+    /// Bandwidth estimate Startup last saw grow, and the rounds since
+    full_bw: f64,
+    full_bw_rounds: u32,
+    filled_pipe: bool,
+    cycle_index: usize,
+    cycle_start: Option<Instant>,
+    /// When ProbeRTT may end, set once the flight has drained
+    probe_rtt_done: Option<Instant>,
+    probe_rtt_round_done: bool,
+    /// Window to restore after ProbeRTT
+    prior_window: usize,
+    /// Most the flight may hold since the last loss
+    inflight_hi: usize,
+    recovery_start: Option<Instant>,
+}
+
+impl Default for Bbr {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_DATAGRAM_SIZE)
+    }
+}
+
+impl Bbr {
+    pub fn new(max_datagram_size: usize) -> Self {
+        Self {
+            max_datagram_size,
+            state: BbrState::Startup,
+            window: initial_window(max_datagram_size),
+            pacing_gain: BBR_STARTUP_GAIN,
+            bw_samples: VecDeque::new(),
+            min_rtt: None,
+            min_rtt_stamp: None,
+            in_flight: VecDeque::new(),
+            bytes_in_flight: 0,
+            delivered: 0,
+            delivered_time: None,
+            round_count: 0,
+            next_round_delivered: 0,
+            full_bw: 0.0,
+            full_bw_rounds: 0,
+            filled_pipe: false,
+            cycle_index: 0,
+            cycle_start: None,
+            probe_rtt_done: None,
+            probe_rtt_round_done: false,
+            prior_window: 0,
+            inflight_hi: usize::MAX,
+            recovery_start: None,
+        }
+    }
+    
+    pub fn state(&self) -> BbrState {
+        self.state
+    }
+    
+    /// Estimated bottleneck bandwidth in bytes per second, the largest delivery rate over the last ten rounds
+    pub fn bottleneck_bandwidth(&self) -> u64 {
+        self.max_bw() as u64
+    }
+    
+    /// Smallest RTT seen within the last ten seconds
+    pub fn min_rtt(&self) -> Option<Duration> {
+        self.min_rtt
+    }
+    
+    /// Multiple of the bandwidth estimate currently paced at
+    pub fn pacing_gain(&self) -> f64 {
+        self.pacing_gain
+    }
+    
+    pub fn round_count(&self) -> u64 {
+        self.round_count
+    }
+    
+    fn max_bw(&self) -> f64 {
+        self.bw_samples.front().map_or(0.0, |&(_, bw)| bw)
+    }
+    
+    fn minimum_window(&self) -> usize {
+        BBR_MIN_PACKETS * self.max_datagram_size
+    }
+    
+    /// `gain` bandwidth-delay products in bytes, once both estimates exist
+    fn bdp(&self, gain: f64) -> Option<usize> {
+        let min_rtt = self.min_rtt?;
+        let bw = self.max_bw();
+        (bw > 0.0).then_some((gain * bw * min_rtt.as_secs_f64()) as usize)
+    }
+    
+    fn in_recovery(&self, sent: Instant) -> bool {
+        self.recovery_start.is_some_and(|start| sent <= start)
+    }
+    
+    fn update_bandwidth(&mut self, bw: f64) {
+        while self.bw_samples.front().is_some_and(|&(round, _)| round + BBR_BW_FILTER_ROUNDS <= self.round_count) {
+            self.bw_samples.pop_front();
+        }
+        while self.bw_samples.back().is_some_and(|&(_, sample)| sample <= bw) {
+            self.bw_samples.pop_back();
+        }
+        self.bw_samples.push_back((self.round_count, bw));
+    }
+    
+    /// Takes a new min RTT when the sample is lower or the current one has expired, then enters ProbeRTT on expiry
+    fn update_min_rtt(&mut self, now: Instant, rtt: Duration) {
+        let expired = self.min_rtt_stamp.is_some_and(|stamp| now > stamp + BBR_MIN_RTT_WINDOW);
+        if expired || self.min_rtt.is_none_or(|min_rtt| rtt < min_rtt) {
+            self.min_rtt = Some(rtt);
+            self.min_rtt_stamp = Some(now);
+        }
+        if expired && self.state != BbrState::ProbeRtt {
+            self.state = BbrState::ProbeRtt;
+            self.pacing_gain = 1.0;
+            self.prior_window = self.window;
+            self.probe_rtt_done = None;
+        }
+    }
+    
+    /// Startup ends once the bandwidth estimate stalls for three rounds
+    fn check_full_pipe(&mut self) {
+        let bw = self.max_bw();
+        if bw >= self.full_bw * BBR_FULL_BW_GROWTH {
+            self.full_bw = bw;
+            self.full_bw_rounds = 0;
+            return;
+        }
+        self.full_bw_rounds += 1;
+        if self.full_bw_rounds >= BBR_FULL_BW_ROUNDS {
+            self.filled_pipe = true;
+        }
+    }
+    
+    fn enter_drain(&mut self) {
+        self.filled_pipe = true;
+        self.state = BbrState::Drain;
+        self.pacing_gain = 1.0 / BBR_STARTUP_GAIN;
+    }
+    
+    /// Starts the gain cycle at a random phase other than the draining one, so competing flows do not probe in lockstep
+    fn enter_probe_bw(&mut self, now: Instant) {
+        use rand::Rng;
+        self.state = BbrState::ProbeBw;
+        self.cycle_index = match rand::thread_rng().gen_range(0..BBR_PROBE_BW_GAINS.len() - 1) {
+            0 => 0,
+            index => index + 1,
+        };
+        self.pacing_gain = BBR_PROBE_BW_GAINS[self.cycle_index];
+        self.cycle_start = Some(now);
+    }
+    
+    /// Moves to the next gain once a phase has lasted a min RTT; the draining phase ends early once the queue is gone
+    fn update_gain_cycle(&mut self, now: Instant) {
+        let min_rtt = self.min_rtt.unwrap_or_default();
+        let elapsed = self.cycle_start.map_or(Duration::MAX, |start| now.saturating_duration_since(start));
+        let drained = self.pacing_gain < 1.0 && self.bdp(1.0).is_some_and(|bdp| self.bytes_in_flight <= bdp);
+        if elapsed > min_rtt || drained {
+            self.cycle_index = (self.cycle_index + 1) % BBR_PROBE_BW_GAINS.len();
+            self.pacing_gain = BBR_PROBE_BW_GAINS[self.cycle_index];
+            self.cycle_start = Some(now);
+        }
+    }
+    
+    /// Waits for the flight to drain, then holds it there for 200ms and a round before resuming
+    fn update_probe_rtt(&mut self, now: Instant, round_start: bool) {
+        let Some(done) = self.probe_rtt_done else {
+            if self.bytes_in_flight <= self.minimum_window() {
+                self.probe_rtt_done = Some(now + BBR_PROBE_RTT_DURATION);
+                self.probe_rtt_round_done = false;
+                self.next_round_delivered = self.delivered;
+            }
+            return;
+        };
+        self.probe_rtt_round_done |= round_start;
+        if self.probe_rtt_round_done && now >= done {
+            self.min_rtt_stamp = Some(now);
+            self.window = self.window.max(self.prior_window);
+            if self.filled_pipe {
+                self.enter_probe_bw(now);
+            } else {
+                self.state = BbrState::Startup;
+                self.pacing_gain = BBR_STARTUP_GAIN;
+            }
+        }
+    }
+    
+    /// Grows the window towards two bandwidth-delay products, capped by the in-flight ceiling from the last loss
+    fn update_window(&mut self, bytes: usize) {
+        let initial = initial_window(self.max_datagram_size);
+        let target = self
+            .bdp(BBR_CWND_GAIN)
+            .map_or(initial, |bdp| bdp + 3 * self.max_datagram_size)
+            .min(self.inflight_hi);
+        if self.filled_pipe {
+            self.window = (self.window + bytes).min(target);
+        } else if self.window < target || self.delivered < initial as u64 {
+            self.window += bytes;
+        }
+        self.window = self.window.max(self.minimum_window());
+        if self.state == BbrState::ProbeRtt {
+            self.window = self.window.min(self.minimum_window());
+        }
+    }
+}
+
+impl CongestionController for Bbr {
+    fn on_packet_sent(&mut self, now: Instant, bytes: usize) {
+        // A flight starting from idle measures delivery from now, not from the last acknowledgement
+        if self.in_flight.is_empty() {
+            self.delivered_time = Some(now);
+        }
+        let delivered_time = *self.delivered_time.get_or_insert(now);
+        self.in_flight.push_back(BbrPacket { sent: now, bytes, delivered: self.delivered, delivered_time });
+        self.bytes_in_flight += bytes;
+    }
+    
+    fn on_ack(&mut self, now: Instant, sent: Instant, bytes: usize, _rtt: &RttEstimator) {
+        self.delivered += bytes as u64;
+        self.delivered_time = Some(now);
+        
+        // Anything older still tracked was lost or acknowledged out of order, and gives no sample
+        let mut packet = None;
+        while let Some(front) = self.in_flight.front().copied().filter(|front| front.sent <= sent) {
+            self.in_flight.pop_front();
+            self.bytes_in_flight -= front.bytes;
+            if front.sent == sent {
+                packet = Some(front);
+                break;
+            }
+        }
+        
+        let mut round_start = false;
+        if let Some(packet) = packet {
+            if packet.delivered >= self.next_round_delivered {
+                self.next_round_delivered = self.delivered;
+                self.round_count += 1;
+                round_start = true;
+            }
+            // Delivery rate: bytes acknowledged since this packet was sent, over the time they took
+            let interval = now.saturating_duration_since(packet.delivered_time).as_secs_f64();
+            if interval > 0.0 {
+                self.update_bandwidth((self.delivered - packet.delivered) as f64 / interval);
+            }
+            self.update_min_rtt(now, now.saturating_duration_since(packet.sent));
+        }
+        
+        // After a loss the ceiling only rises again while probing for more bandwidth
+        if self.inflight_hi != usize::MAX && self.pacing_gain > 1.0 && !self.in_recovery(sent) {
+            self.inflight_hi += bytes;
+        }
+        
+        match self.state {
+            BbrState::Startup => {
+                if round_start {
+                    self.check_full_pipe();
+                }
+                if self.filled_pipe {
+                    self.enter_drain();
+                }
+            }
+            BbrState::Drain => {
+                if self.bdp(1.0).is_none_or(|bdp| self.bytes_in_flight <= bdp) {
+                    self.enter_probe_bw(now);
+                }
+            }
+            BbrState::ProbeBw => self.update_gain_cycle(now),
+            BbrState::ProbeRtt => self.update_probe_rtt(now, round_start),
+        }
+        self.update_window(bytes);
+    }
+    
+    fn on_congestion_event(&mut self, now: Instant, sent: Instant, persistent_congestion: bool) {
+        // The lost packets, and anything sent before them, will not give rate samples
+        while self.in_flight.front().is_some_and(|front| front.sent <= sent) {
+            if let Some(packet) = self.in_flight.pop_front() {
+                self.bytes_in_flight -= packet.bytes;
+            }
+        }
+        if !self.in_recovery(sent) {
+            self.recovery_start = Some(now);
+            self.inflight_hi = ((self.window as f64 * BBR_LOSS_BETA) as usize).max(self.minimum_window());
+            self.window = self.window.min(self.inflight_hi);
+            // Loss in Startup means the pipe is already full
+            if self.state == BbrState::Startup {
+                self.enter_drain();
+            }
+        }
+        if persistent_congestion {
+            self.window = self.minimum_window();
+            self.recovery_start = None;
+        }
+    }
+    
+    fn window(&self) -> usize {
+        self.window
+    }
+    
+    /// Pacing gain times the bandwidth estimate, or the initial window per RTT at the Startup gain before any sample
+    fn pacing_rate(&self, rtt: &RttEstimator) -> u64 {
+        let bw = self.max_bw();
+        if bw > 0.0 {
+            return (self.pacing_gain * bw) as u64;
+        }
+        let srtt = rtt.smoothed_rtt().max(Duration::from_micros(1));
+        (BBR_STARTUP_GAIN * self.window as f64 / srtt.as_secs_f64()) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MDS: usize = DEFAULT_MAX_DATAGRAM_SIZE;
+
+    #[test]
+    fn test_initial_window() {
+        assert_eq!(initial_window(1200), 12000);
+        assert_eq!(initial_window(1500), 14720);
+        assert_eq!(initial_window(9000), 18000);
+        assert_eq!(NewReno::default().window(), 12000);
+    }
+
+    #[test]
+    fn test_slow_start_grows_by_bytes_acked() {
+        let mut reno = NewReno::default();
+        let rtt = RttEstimator::new();
+        let now = Instant::now();
+        reno.on_ack(now, now, 3000, &rtt);
+        assert_eq!(reno.window(), 15000);
+        assert!(reno.in_slow_start());
+    }
+
+    #[test]
+    fn test_congestion_event_halves_once_per_recovery() {
+        let mut reno = NewReno::default();
+        let start = Instant::now();
+        let later = start + Duration::from_millis(100);
+        reno.on_congestion_event(later, start, false);
+        assert_eq!(reno.window(), 6000);
+        assert_eq!(reno.ssthresh(), 6000);
+
+        // Another loss from before the recovery period is part of the same event
+        reno.on_congestion_event(later + Duration::from_millis(10), start + Duration::from_millis(50), false);
+        assert_eq!(reno.window(), 6000);
+        // Nor do acknowledgements of those packets grow the window
+        reno.on_ack(later, start, MDS, &RttEstimator::new());
+        assert_eq!(reno.window(), 6000);
+
+        reno.on_congestion_event(later + Duration::from_millis(200), later + Duration::from_millis(1), false);
+        assert_eq!(reno.window(), 3000);
+    }
+
+    #[test]
+    fn test_congestion_avoidance_grows_one_datagram_per_window() {
+        let mut reno = NewReno::default();
+        let rtt = RttEstimator::new();
+        let start = Instant::now();
+        reno.on_congestion_event(start, start, false);
+        assert!(!reno.in_slow_start());
+
+        let sent = start + Duration::from_millis(1);
+        for _ in 0..4 {
+            reno.on_ack(sent, sent, MDS, &rtt);
+        }
+        assert_eq!(reno.window(), 6000);
+        reno.on_ack(sent, sent, MDS, &rtt);
+        assert_eq!(reno.window(), 6000 + MDS);
+    }
+
+    #[test]
+    fn test_persistent_congestion_collapses_window() {
+        let mut reno = NewReno::default();
+        let now = Instant::now();
+        reno.on_congestion_event(now, now, true);
+        assert_eq!(reno.window(), minimum_window(MDS));
+    }
+
+    #[test]
+    fn test_pacing_rate() {
+        let reno = NewReno::default();
+        let rtt = RttEstimator::new();
+        let expected = 12000 * 5 / 4 * 1_000_000 / rtt.smoothed_rtt().as_micros() as u64;
+        assert_eq!(reno.pacing_rate(&rtt), expected);
+    }
+
+    /// Cubic controller that has left slow start with a loss at `window` bytes, at `start`
+    fn cubic_after_loss(window: usize, start: Instant) -> Cubic {
+        let mut cubic = Cubic::default();
+        let rtt = RttEstimator::new();
+        cubic.on_ack(start, start, window - cubic.window(), &rtt);
+        assert_eq!(cubic.window(), window);
+        cubic.on_congestion_event(start, start, false);
+        cubic
+    }
+
+    /// Acknowledges one window in datagram-sized ACKs every `rtt` for `rounds` rounds,
+    /// returning the window after each round
+    fn drive_rounds(cubic: &mut Cubic, start: Instant, rtt: Duration, rounds: u32) -> Vec<usize> {
+        let mut estimator = RttEstimator::new();
+        estimator.update(rtt, Duration::ZERO, None);
+        (1..=rounds)
+            .map(|round| {
+                let now = start + rtt * round;
+                let sent = now - rtt / 2;
+                for _ in 0..cubic.window() / MDS {
+                    cubic.on_ack(now, sent, MDS, &estimator);
+                }
+                cubic.window()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_cubic_multiplicative_decrease() {
+        let start = Instant::now();
+        let cubic = cubic_after_loss(12000, start);
+        assert_eq!(cubic.window(), 8400);
+        assert_eq!(cubic.ssthresh(), 8400);
+        assert_eq!(cubic.w_max(), 12000);
+        assert!(!cubic.in_slow_start());
+    }
+
+    #[test]
+    fn test_cubic_fast_convergence() {
+        let start = Instant::now();
+        let mut cubic = cubic_after_loss(12000, start);
+        let later = start + Duration::from_millis(100);
+        
+        // Losing again below the previous maximum lowers the plateau further
+        cubic.on_congestion_event(later, later, false);
+        assert_eq!(cubic.w_max(), 8400 * 17 / 20);
+        assert_eq!(cubic.window(), 5880);
+    }
+
+    #[test]
+    fn test_cubic_window_trajectory() {
+        let start = Instant::now();
+        let w_max = 100 * MDS;
+        let mut cubic = cubic_after_loss(w_max, start);
+        assert_eq!(cubic.window(), 70 * MDS);
+        
+        // K = cbrt(30 segments / C) is a little over 4.2 seconds, 42 rounds of 100ms
+        let trajectory = drive_rounds(&mut cubic, start, Duration::from_millis(100), 80);
+        assert!(trajectory.windows(2).all(|pair| pair[0] <= pair[1]));
+        let growth = |round: usize| trajectory[round] - trajectory[round - 1];
+        // Concave on the way up to w_max, flat around it, convex beyond
+        assert!(growth(5) > growth(20));
+        assert!(growth(20) > growth(42));
+        assert!(growth(70) > growth(42));
+        let plateau = trajectory[41];
+        assert!(plateau > w_max * 97 / 100 && plateau < w_max * 103 / 100);
+        // Four seconds past K the curve is 0.4 * 3.8^3, about 22 segments, above w_max
+        assert!(trajectory[79] > w_max * 115 / 100);
+    }
+
+    #[test]
+    fn test_cubic_reno_friendly_region() {
+        let start = Instant::now();
+        let mut cubic = cubic_after_loss(12000, start);
+        
+        // With a short RTT, Reno's additive increase passes w_max long before the cubic curve's K of ~2s
+        let trajectory = drive_rounds(&mut cubic, start, Duration::from_millis(10), 20);
+        assert!(trajectory[19] > 12000);
+        assert!((cubic.w_cubic(0.2) as usize) < 12000);
+    }
+
+    #[test]
+    fn test_cubic_persistent_congestion() {
+        let start = Instant::now();
+        let mut cubic = cubic_after_loss(12000, start);
+        let later = start + Duration::from_secs(1);
+        cubic.on_congestion_event(later, later, true);
+        assert_eq!(cubic.window(), minimum_window(MDS));
+        
+        // Slow start resumes until the threshold from the last loss
+        let rtt = RttEstimator::new();
+        cubic.on_ack(later, later + Duration::from_millis(1), MDS, &rtt);
+        assert_eq!(cubic.window(), minimum_window(MDS) + MDS);
+        assert!(cubic.in_slow_start());
+    }
+
+    #[test]
+    fn test_bbr_bandwidth_filter_forgets_old_rounds() {
+        let mut bbr = Bbr::default();
+        bbr.update_bandwidth(1000.0);
+        bbr.round_count = 5;
+        bbr.update_bandwidth(600.0);
+        bbr.update_bandwidth(800.0);
+        assert_eq!(bbr.bottleneck_bandwidth(), 1000);
+        
+        // Ten rounds after the largest sample the next largest takes over
+        bbr.round_count = 10;
+        bbr.update_bandwidth(100.0);
+        assert_eq!(bbr.bottleneck_bandwidth(), 800);
+        bbr.round_count = 15;
+        bbr.update_bandwidth(100.0);
+        assert_eq!(bbr.bottleneck_bandwidth(), 100);
+    }
+
+    #[test]
+    fn test_bbr_full_pipe_after_three_flat_rounds() {
+        let mut bbr = Bbr::default();
+        for (round, bw) in [1000.0, 2000.0, 2200.0, 2300.0, 2400.0].into_iter().enumerate() {
+            bbr.round_count = round as u64;
+            bbr.update_bandwidth(bw);
+            bbr.check_full_pipe();
+        }
+        assert_eq!(bbr.full_bw, 2000.0);
+        assert!(bbr.filled_pipe);
+    }
+
+    #[test]
+    fn test_bbr_min_rtt_expires() {
+        let mut bbr = Bbr::default();
+        let start = Instant::now();
+        bbr.update_min_rtt(start, Duration::from_millis(40));
+        bbr.update_min_rtt(start + Duration::from_secs(5), Duration::from_millis(60));
+        assert_eq!(bbr.min_rtt(), Some(Duration::from_millis(40)));
+        assert_eq!(bbr.state(), BbrState::Startup);
+        
+        bbr.update_min_rtt(start + Duration::from_secs(11), Duration::from_millis(60));
+        assert_eq!(bbr.min_rtt(), Some(Duration::from_millis(60)));
+        assert_eq!(bbr.state(), BbrState::ProbeRtt);
+        assert_eq!(bbr.pacing_gain(), 1.0);
+    }
+
+    #[test]
+    fn test_bbr_pacing_before_first_sample() {
+        let bbr = Bbr::default();
+        let mut rtt = RttEstimator::new();
+        rtt.update(Duration::from_millis(100), Duration::ZERO, None);
+        assert_eq!(bbr.pacing_rate(&rtt), (BBR_STARTUP_GAIN * 12000.0 / 0.1) as u64);
+    }
+
+    #[test]
+    fn test_congestion_control_selection() {
+        assert_eq!(CongestionControl::default(), CongestionControl::NewReno);
+        assert_eq!(CongestionControl::Cubic.build(MDS).window(), initial_window(MDS));
+        assert_eq!(CongestionControl::Bbr.build(MDS).window(), initial_window(MDS));
+    }
+}
@@ -1,10 +1,19 @@
-use crate::packet::{ConnectionId, PacketHeader, LongHeader, PacketType};
-use crate::frame::Frame;
-use crate::tls::{TlsConfig, QuicClientTls, QuicServerTls};
-use crate::crypto::QuicCrypto;
-use std::collections::HashMap;
+use crate::packet::{self, ConnectionId, DatagramBuilder, PacketHeader, LongHeader, ShortHeader, PacketType, ProtectedHeader, RetryHeader, VersionNegotiationHeader};
+use crate::frame::{self, Frame};
+use crate::tls::{TlsConfig, QuicClientTls, QuicServerTls, HandshakeOutput};
+use crate::crypto::{self, QuicCrypto, EncryptionLevel, CryptoError, HEADER_PROTECTION_SAMPLE_LEN, RETRY_INTEGRITY_TAG_LEN};
+use crate::transport_parameters::TransportParameters;
+use crate::token::AddressValidator;
+use crate::config::{TransportConfig, DEFAULT_MAX_IDLE_TIMEOUT};
+use crate::stream::{Dir, RecvState, SendState, Side, StreamError, StreamId, Streams};
+use crate::congestion::CongestionController;
+use crate::recovery::{Recovery, SentPacket, Timeout};
+use crate::pacer::{Pacer, DEFAULT_PACING_BURST};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::net::SocketAddr;
+use std::ops::RangeInclusive;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use bytes::{BytesMut, Bytes};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -13,6 +22,8 @@ pub enum ConnectionState {
     Handshake,
     Established,
     Closing,
+    /// The peer closed the connection; nothing is sent until the draining period ends
+    Draining,
     Closed,
 }
 
@@ -20,332 +31,1703 @@ pub enum ConnectionState {
 pub struct Connection {
     pub local_conn_id: ConnectionId,
     pub remote_conn_id: Option<ConnectionId>,
+    /// Destination Connection ID of the client's first Initial, which seeds the Initial keys unless a Retry follows
+    pub original_dest_conn_id: ConnectionId,
+    /// Source Connection ID of the Retry that validated the client's address, if one was sent
+    pub retry_src_conn_id: Option<ConnectionId>,
     pub state: ConnectionState,
     pub remote_addr: SocketAddr,
     pub packet_number: u64,
     pub version: u32,
+    /// Versions this endpoint accepts, in order of preference, all of which it has Initial secrets for
+    supported_versions: Vec<u32>,
+    /// Largest UDP payload this connection sends on its path
+    pub max_datagram_size: usize,
     pub is_client: bool,
     pub crypto: QuicCrypto,
     pub tls_config: Option<Arc<TlsConfig>>,
     pub client_tls: Option<QuicClientTls>,
     pub server_tls: Option<QuicServerTls>,
+    pub peer_transport_parameters: Option<TransportParameters>,
+    crypto_streams: HashMap<EncryptionLevel, CryptoStream>,
+    spaces: HashMap<EncryptionLevel, PacketNumberSpace>,
+    /// Whether any packet from the peer has been processed, after which Version Negotiation is ignored
+    received_valid_packet: bool,
+    /// Token from a Retry, echoed in every later Initial
+    retry_token: Bytes,
+    streams: Streams,
+    recovery: Recovery,
+    /// Datagrams waiting for the pacing rate to let them out
+    pacer: Pacer,
+    /// Idle timeout this endpoint advertises, `None` if it never idles out on its own account
+    max_idle_timeout: Option<Duration>,
+    keep_alive_interval: Option<Duration>,
+    /// When the idle timer last restarted: on receiving a packet, or on the first ack-eliciting send after one (RFC 9000 Section 10.1)
+    idle_timer_start: Instant,
+    /// Whether an ack-eliciting packet has gone out since the last packet was received
+    ack_eliciting_sent: bool,
+    /// Last time a packet was received or an ack-eliciting packet sent, which keep-alives count from
+    last_activity: Instant,
+    /// Datagram carrying this endpoint's CONNECTION_CLOSE, repeated in reply to packets arriving while closing
+    close_datagram: Option<Vec<u8>>,
+    /// When the closing or draining period ends and the connection becomes Closed
+    close_deadline: Option<Instant>,
+    /// Packets received while closing, and how many it takes before the next reply
+    closing_received: u64,
+    closing_next_reply: u64,
+}
+
+/// A packet whose header protection and AEAD have been removed
+#[derive(Debug)]
+pub struct ReceivedPacket {
+    pub header: PacketHeader,
+    pub frames: Vec<Frame>,
+}
+
+/// Snapshot of a connection's path measurements
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// Most recent RTT sample, if any packet has been acknowledged yet
+    pub latest_rtt: Option<Duration>,
+    pub min_rtt: Option<Duration>,
+    /// Smoothed RTT, which is the initial RTT until the first sample
+    pub smoothed_rtt: Duration,
+    pub rttvar: Duration,
+    /// Bytes sent in ack-eliciting packets not yet acknowledged or declared lost
+    pub bytes_in_flight: usize,
+    /// Bytes the congestion controller allows in flight
+    pub congestion_window: usize,
+}
+
+/// AEAD tag appended to every packet payload
+const AEAD_TAG_LEN: usize = 16;
+
+/// Largest amount of handshake data carried in a single CRYPTO frame
+const MAX_CRYPTO_FRAME_DATA: usize = 1000;
+
+/// Exponent the ACK Delay field is scaled by, the default from RFC 9000 Section 18.2 that also applies to the peer
+/// unless its transport parameters say otherwise
+const ACK_DELAY_EXPONENT: u32 = 3;
+
+/// Ranges an ACK frame reports at most; older ones are forgotten. Even with 8-byte varints the frame
+/// stays near 530 bytes, so it always fits a packet of the smallest datagram size
+const MAX_ACK_RANGES: usize = 32;
+
+/// Packet numbers seen in one packet number space, used to truncate and reconstruct them and to build ACK frames
+#[derive(Debug, Default)]
+struct PacketNumberSpace {
+    largest_received: Option<u64>,
+    largest_acked: Option<u64>,
+    /// Largest packet number sent at this level, beyond which the peer cannot acknowledge anything
+    largest_sent: Option<u64>,
+    received: ReceivedRanges,
+    /// When the largest packet number was received, from which the ACK delay is measured
+    largest_received_time: Option<Instant>,
+    /// Whether an ack-eliciting packet has arrived since the last ACK frame was built
+    ack_pending: bool,
+    /// Frames for the next packet at this level: lost frames, probes and one-off control frames
+    pending_frames: VecDeque<Frame>,
+    /// Whether this level's keys are no longer used to send, after which nothing is sent or tracked
+    discarded: bool,
+}
+
+/// Received packet numbers as disjoint inclusive ranges, keyed by their first packet number.
+/// Only the `MAX_ACK_RANGES` highest ranges are kept; anything below them counts as already received.
+#[derive(Debug, Default)]
+struct ReceivedRanges {
+    ranges: BTreeMap<u64, u64>,
+    /// Packet numbers below this were forgotten and are treated as duplicates (RFC 9000 Section 12.3)
+    floor: u64,
+}
+
+impl ReceivedRanges {
+    fn insert(&mut self, packet_number: u64) {
+        let mut start = packet_number;
+        let mut end = packet_number;
+        if let Some((&below_start, &below_end)) = self.ranges.range(..=packet_number).next_back() {
+            if below_end >= packet_number {
+                return;
+            }
+            if below_end + 1 == packet_number {
+                start = below_start;
+            }
+        }
+        if let Some(above_end) = self.ranges.remove(&(packet_number + 1)) {
+            end = above_end;
+        }
+        self.ranges.insert(start, end);
+        while self.ranges.len() > MAX_ACK_RANGES {
+            if let Some((_, dropped_end)) = self.ranges.pop_first() {
+                self.floor = dropped_end + 1;
+            }
+        }
+    }
+    
+    /// Whether `packet_number` was received before, or is too old to tell
+    fn contains(&self, packet_number: u64) -> bool {
+        packet_number < self.floor
+            || self.ranges.range(..=packet_number).next_back().is_some_and(|(_, &end)| end >= packet_number)
+    }
+    
+    /// Forgets every packet number below `packet_number`
+    fn remove_below(&mut self, packet_number: u64) {
+        let mut kept = self.ranges.split_off(&packet_number);
+        if let Some((_, &end)) = self.ranges.last_key_value().filter(|(_, &end)| end >= packet_number) {
+            kept.insert(packet_number, end);
+        }
+        self.ranges = kept;
+        self.floor = self.floor.max(packet_number);
+    }
+    
+    /// Ranges in the descending order an ACK frame carries them
+    fn ack_ranges(&self) -> Vec<RangeInclusive<u64>> {
+        self.ranges.iter().rev().map(|(&start, &end)| start..=end).collect()
+    }
+}
+
+/// Offsets of the CRYPTO stream at one encryption level
+#[derive(Debug, Default)]
+struct CryptoStream {
+    send_offset: u64,
+    recv_offset: u64,
+    pending: BTreeMap<u64, Bytes>,
+}
+
+impl CryptoStream {
+    /// Buffers `data` at `offset` and returns the bytes now contiguous with the read offset
+    fn receive(&mut self, offset: u64, data: Bytes) -> Vec<u8> {
+        let end = offset + data.len() as u64;
+        if end <= self.recv_offset {
+            return Vec::new();
+        }
+        
+        // Keep the longer chunk if the peer retransmitted from the same offset
+        let longer = self.pending.get(&offset).is_none_or(|existing| existing.len() < data.len());
+        if longer {
+            self.pending.insert(offset, data);
+        }
+        
+        let mut ready = Vec::new();
+        while let Some(entry) = self.pending.first_entry() {
+            if *entry.key() > self.recv_offset {
+                break;
+            }
+            let (chunk_offset, chunk) = entry.remove_entry();
+            let chunk_end = chunk_offset + chunk.len() as u64;
+            if chunk_end > self.recv_offset {
+                let skip = (self.recv_offset - chunk_offset) as usize;
+                ready.extend_from_slice(&chunk[skip..]);
+                self.recv_offset = chunk_end;
+            }
+        }
+        
+        ready
+    }
 }
 
 impl Connection {
     pub fn new_client(remote_addr: SocketAddr) -> Self {
         let mut crypto = QuicCrypto::new();
-        let local_conn_id = ConnectionId::random(8);
+        let local_conn_id = ConnectionId::random(packet::DEFAULT_CONN_ID_LEN);
+        let original_dest_conn_id = ConnectionId::random(packet::DEFAULT_CONN_ID_LEN);
         
         // Setup initial encryption keys
-        let _ = crypto.setup_initial_keys(&local_conn_id.data, true);
+        let _ = crypto.setup_initial_keys_for_version(packet::SUPPORTED_VERSIONS[0], &original_dest_conn_id.data, true);
         
         Self {
             local_conn_id,
             remote_conn_id: None,
+            original_dest_conn_id,
+            retry_src_conn_id: None,
             state: ConnectionState::Initial,
             remote_addr,
             packet_number: 0,
-            version: 1,
+            version: packet::SUPPORTED_VERSIONS[0],
+            supported_versions: packet::SUPPORTED_VERSIONS.to_vec(),
+            max_datagram_size: packet::DEFAULT_MAX_DATAGRAM_SIZE,
             is_client: true,
             crypto,
             tls_config: None,
             client_tls: None,
             server_tls: None,
+            peer_transport_parameters: None,
+            crypto_streams: HashMap::new(),
+            spaces: HashMap::new(),
+            received_valid_packet: false,
+            retry_token: Bytes::new(),
+            streams: Streams::new(Side::Client),
+            recovery: Recovery::new(),
+            pacer: Pacer::new(DEFAULT_PACING_BURST * packet::DEFAULT_MAX_DATAGRAM_SIZE, u64::MAX),
+            max_idle_timeout: Some(DEFAULT_MAX_IDLE_TIMEOUT),
+            keep_alive_interval: None,
+            idle_timer_start: Instant::now(),
+            ack_eliciting_sent: false,
+            last_activity: Instant::now(),
+            close_datagram: None,
+            close_deadline: None,
+            closing_received: 0,
+            closing_next_reply: 1,
         }
     }
     
     pub fn new_server(remote_addr: SocketAddr, remote_conn_id: ConnectionId) -> Self {
+        Self::accept(remote_addr, remote_conn_id.clone(), remote_conn_id)
+    }
+    
+    /// Creates the server side of a connection from the connection IDs in the client's first Initial
+    pub fn accept(remote_addr: SocketAddr, remote_conn_id: ConnectionId, original_dest_conn_id: ConnectionId) -> Self {
         let mut crypto = QuicCrypto::new();
-        let local_conn_id = ConnectionId::random(8);
+        let local_conn_id = ConnectionId::random(packet::DEFAULT_CONN_ID_LEN);
         
         // Setup initial encryption keys
-        let _ = crypto.setup_initial_keys(&remote_conn_id.data, false);
+        let _ = crypto.setup_initial_keys_for_version(packet::SUPPORTED_VERSIONS[0], &original_dest_conn_id.data, false);
         
         Self {
             local_conn_id,
             remote_conn_id: Some(remote_conn_id),
+            original_dest_conn_id,
+            retry_src_conn_id: None,
             state: ConnectionState::Initial,
             remote_addr,
             packet_number: 0,
-            version: 1,
+            version: packet::SUPPORTED_VERSIONS[0],
+            supported_versions: packet::SUPPORTED_VERSIONS.to_vec(),
+            max_datagram_size: packet::DEFAULT_MAX_DATAGRAM_SIZE,
             is_client: false,
             crypto,
             tls_config: None,
             client_tls: None,
             server_tls: None,
+            peer_transport_parameters: None,
+            crypto_streams: HashMap::new(),
+            spaces: HashMap::new(),
+            received_valid_packet: false,
+            retry_token: Bytes::new(),
+            streams: Streams::new(Side::Server),
+            recovery: Recovery::new(),
+            pacer: Pacer::new(DEFAULT_PACING_BURST * packet::DEFAULT_MAX_DATAGRAM_SIZE, u64::MAX),
+            max_idle_timeout: Some(DEFAULT_MAX_IDLE_TIMEOUT),
+            keep_alive_interval: None,
+            idle_timer_start: Instant::now(),
+            ack_eliciting_sent: false,
+            last_activity: Instant::now(),
+            close_datagram: None,
+            close_deadline: None,
+            closing_received: 0,
+            closing_next_reply: 1,
         }
     }
     
+    /// Creates the server side of a connection whose client echoed a valid Retry token
+    ///
+    /// The client's Initial keys now follow `retry_src_conn_id`, while the transport parameters
+    /// still report the `original_dest_conn_id` recovered from the token.
+    pub fn accept_retried(remote_addr: SocketAddr, remote_conn_id: ConnectionId, original_dest_conn_id: ConnectionId, retry_src_conn_id: ConnectionId) -> Self {
+        let mut connection = Self::accept(remote_addr, remote_conn_id, original_dest_conn_id);
+        let _ = connection.crypto.setup_initial_keys_for_version(connection.version, &retry_src_conn_id.data, false);
+        connection.retry_src_conn_id = Some(retry_src_conn_id);
+        connection
+    }
+    
     pub fn next_packet_number(&mut self) -> u64 {
         let pn = self.packet_number;
         self.packet_number += 1;
         pn
     }
     
+    /// Allocates the next packet number and the number of bytes it is sent with at `level`
+    fn allocate_packet_number(&mut self, level: EncryptionLevel) -> (u64, usize) {
+        let packet_number = self.next_packet_number();
+        let space = self.spaces.entry(level).or_default();
+        space.largest_sent = Some(packet_number);
+        let (_, packet_number_len) = packet::encode_packet_number(packet_number, space.largest_acked);
+        (packet_number, packet_number_len)
+    }
+    
+    /// Largest packet number successfully received at `level`
+    pub fn largest_received_packet_number(&self, level: EncryptionLevel) -> Option<u64> {
+        self.spaces.get(&level).and_then(|space| space.largest_received)
+    }
+    
+    /// Largest packet number the peer has acknowledged at `level`
+    pub fn largest_acked_packet_number(&self, level: EncryptionLevel) -> Option<u64> {
+        self.spaces.get(&level).and_then(|space| space.largest_acked)
+    }
+    
+    /// Builds an ACK frame for `level` if an ack-eliciting packet has arrived since the last one
+    pub fn ack_frame(&mut self, level: EncryptionLevel) -> Option<Frame> {
+        let space = self.spaces.get_mut(&level)?;
+        if !space.ack_pending {
+            return None;
+        }
+        space.ack_pending = false;
+        
+        let largest = space.largest_received?;
+        let delay = space.largest_received_time
+            .map_or(0, |received| received.elapsed().as_micros() as u64 >> ACK_DELAY_EXPONENT);
+        Some(Frame::Ack {
+            largest,
+            delay,
+            ranges: space.received.ack_ranges(),
+            ecn: None,
+        })
+    }
+    
+    /// Peer's connection ID, or until the server's is known the one from its Retry or the client's original choice
+    fn dest_conn_id(&self) -> ConnectionId {
+        self.remote_conn_id.clone()
+            .or_else(|| self.retry_src_conn_id.clone())
+            .unwrap_or_else(|| self.original_dest_conn_id.clone())
+    }
+    
     pub fn create_initial_packet(&mut self, frames: Vec<Frame>) -> Result<Vec<u8>, ConnectionError> {
+        let (packet_number, packet_number_len) = self.allocate_packet_number(EncryptionLevel::Initial);
         let header = PacketHeader::Long(LongHeader {
             packet_type: PacketType::Initial,
             version: self.version,
-            dest_conn_id: self.remote_conn_id.clone().unwrap_or_else(|| ConnectionId::new(vec![])),
+            dest_conn_id: self.dest_conn_id(),
             src_conn_id: self.local_conn_id.clone(),
-            packet_number: self.next_packet_number(),
+            token: self.retry_token.clone(),
+            length: 0,
+            packet_number,
+            packet_number_len,
         });
         
-        self.encode_packet(header, frames)
+        self.send_packet(header, frames)
     }
     
     pub fn create_handshake_packet(&mut self, frames: Vec<Frame>) -> Result<Vec<u8>, ConnectionError> {
+        let (packet_number, packet_number_len) = self.allocate_packet_number(EncryptionLevel::Handshake);
         let header = PacketHeader::Long(LongHeader {
             packet_type: PacketType::Handshake,
             version: self.version,
-            dest_conn_id: self.remote_conn_id.clone().unwrap_or_else(|| ConnectionId::new(vec![])),
+            dest_conn_id: self.dest_conn_id(),
             src_conn_id: self.local_conn_id.clone(),
-            packet_number: self.next_packet_number(),
+            token: Bytes::new(),
+            length: 0,
+            packet_number,
+            packet_number_len,
         });
         
-        self.encode_packet(header, frames)
+        self.send_packet(header, frames)
     }
     
-    fn encode_packet(&self, header: PacketHeader, frames: Vec<Frame>) -> Result<Vec<u8>, ConnectionError> {
-        let mut buf = BytesMut::new();
-        
-        header.encode(&mut buf).map_err(|_| ConnectionError::PacketEncoding)?;
-        
-        for frame in frames {
-            frame.encode(&mut buf).map_err(|_| ConnectionError::FrameEncoding)?;
-        }
+    pub fn create_short_packet(&mut self, frames: Vec<Frame>) -> Result<Vec<u8>, ConnectionError> {
+        let (packet_number, packet_number_len) = self.allocate_packet_number(EncryptionLevel::Application);
+        let header = PacketHeader::Short(ShortHeader {
+            dest_conn_id: self.dest_conn_id(),
+            packet_number,
+            packet_number_len,
+        });
         
-        Ok(buf.to_vec())
+        self.send_packet(header, frames)
     }
     
-    pub fn handle_state_transition(&mut self, new_state: ConnectionState) {
-        println!("Connection state: {:?} -> {:?}", self.state, new_state);
-        self.state = new_state;
+    fn create_packet(&mut self, level: EncryptionLevel, frames: Vec<Frame>) -> Result<Vec<u8>, ConnectionError> {
+        match level {
+            EncryptionLevel::Initial => self.create_initial_packet(frames),
+            EncryptionLevel::Handshake => self.create_handshake_packet(frames),
+            EncryptionLevel::Application => self.create_short_packet(frames),
+        }
     }
     
-    pub fn is_closed(&self) -> bool {
-        matches!(self.state, ConnectionState::Closed)
+    /// Encodes a packet and records it for loss detection if it needs to be acknowledged
+    fn send_packet(&mut self, header: PacketHeader, frames: Vec<Frame>) -> Result<Vec<u8>, ConnectionError> {
+        let level = header.encryption_level();
+        let packet_number = header.packet_number();
+        let packet = self.encode_packet(header, &frames)?;
+        
+        if self.is_discarded(level) {
+            return Ok(packet);
+        }
+        let now = Instant::now();
+        let sent = SentPacket::new(packet_number, now, packet.len(), &frames);
+        if sent.ack_eliciting {
+            self.recovery.on_packet_sent(level, sent);
+            if !self.ack_eliciting_sent {
+                self.idle_timer_start = now;
+                self.ack_eliciting_sent = true;
+            }
+            self.last_activity = now;
+        }
+        // A client stops using Initial keys once it sends its first Handshake packet (RFC 9001 Section 4.9.1)
+        if self.is_client && level == EncryptionLevel::Handshake {
+            self.discard_space(EncryptionLevel::Initial);
+        }
+        Ok(packet)
     }
     
-    pub fn setup_tls(&mut self, tls_config: Arc<TlsConfig>) -> Result<(), ConnectionError> {
-        self.tls_config = Some(tls_config.clone());
+    /// Seals `frames` with the keys for the header's level and applies header protection
+    fn encode_packet(&self, mut header: PacketHeader, frames: &[Frame]) -> Result<Vec<u8>, ConnectionError> {
+        let level = header.encryption_level();
+        let pn_len = header.packet_number_len();
         
-        if self.is_client {
-            let client_tls = QuicClientTls::new(tls_config.client_config.clone(), "localhost")
-                .map_err(|_| ConnectionError::TlsSetupFailed)?;
-            self.client_tls = Some(client_tls);
-        } else {
-            let server_tls = QuicServerTls::new(tls_config.server_config.clone())
-                .map_err(|_| ConnectionError::TlsSetupFailed)?;
-            self.server_tls = Some(server_tls);
+        let mut payload = BytesMut::new();
+        for frame in frames {
+            frame.encode(&mut payload).map_err(|_| ConnectionError::FrameEncoding)?;
         }
         
-        Ok(())
+        // The header protection sample starts 4 bytes past the packet number, so short payloads are padded
+        let mut min_payload = (4 + HEADER_PROTECTION_SAMPLE_LEN).saturating_sub(pn_len + AEAD_TAG_LEN);
+        // A client's Initial fills a datagram on its own, so every datagram carrying one is large enough
+        // for the server to answer without exceeding its amplification limit (RFC 9000 Section 14.1)
+        if self.is_client && level == EncryptionLevel::Initial {
+            let header_len = Self::long_header_len(&mut header, packet::DEFAULT_MAX_DATAGRAM_SIZE)?;
+            min_payload = min_payload.max(packet::DEFAULT_MAX_DATAGRAM_SIZE.saturating_sub(header_len + AEAD_TAG_LEN));
+        }
+        if payload.len() < min_payload {
+            Frame::Padding { length: min_payload - payload.len() }
+                .encode(&mut payload)
+                .map_err(|_| ConnectionError::FrameEncoding)?;
+        }
+        
+        if let PacketHeader::Long(long) = &mut header {
+            long.length = (pn_len + payload.len() + AEAD_TAG_LEN) as u64;
+        }
+        
+        let mut header_buf = BytesMut::new();
+        header.encode(&mut header_buf).map_err(|_| ConnectionError::PacketEncoding)?;
+        let pn_offset = header_buf.len() - pn_len;
+        
+        let ciphertext = self.crypto.encrypt_packet(level, header.packet_number(), &header_buf, &payload)
+            .map_err(encryption_error)?;
+        
+        let mut packet = header_buf.to_vec();
+        packet.extend_from_slice(&ciphertext);
+        self.crypto.protect_header(level, &mut packet, pn_offset).map_err(encryption_error)?;
+        
+        Ok(packet)
     }
     
-    pub fn start_tls_handshake(&mut self) -> Result<Vec<u8>, ConnectionError> {
-        if self.is_client {
-            if let Some(ref mut client_tls) = self.client_tls {
-                let handshake_data = client_tls.get_handshake_data()
-                    .map_err(|_| ConnectionError::TlsHandshakeFailed)?;
-                
-                if !handshake_data.is_empty() {
-                    let crypto_frame = Frame::Crypto {
-                        offset: 0,
-                        data: Bytes::from(handshake_data),
-                    };
-                    
-                    return self.create_initial_packet(vec![crypto_frame]);
-                }
+    /// Length of a long header whose packet, once sealed, is `packet_len` bytes long
+    fn long_header_len(header: &mut PacketHeader, packet_len: usize) -> Result<usize, ConnectionError> {
+        if let PacketHeader::Long(long) = header {
+            long.length = packet_len as u64;
+        }
+        let mut header_buf = BytesMut::new();
+        header.encode(&mut header_buf).map_err(|_| ConnectionError::PacketEncoding)?;
+        Ok(header_buf.len())
+    }
+    
+    /// Coalesces packets, in order, into as few datagrams as the path allows
+    pub fn coalesce_packets(&self, packets: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        let mut datagrams = Vec::new();
+        let mut builder = DatagramBuilder::new(self.max_datagram_size);
+        
+        for packet in packets {
+            if builder.push(&packet) {
+                continue;
             }
-        } else {
-            if let Some(ref mut server_tls) = self.server_tls {
-                let handshake_data = server_tls.get_handshake_data()
-                    .map_err(|_| ConnectionError::TlsHandshakeFailed)?;
-                
-                if !handshake_data.is_empty() {
-                    let crypto_frame = Frame::Crypto {
-                        offset: 0,
-                        data: Bytes::from(handshake_data),
-                    };
-                    
-                    return self.create_initial_packet(vec![crypto_frame]);
-                }
+            if !builder.is_empty() {
+                let full = std::mem::replace(&mut builder, DatagramBuilder::new(self.max_datagram_size));
+                datagrams.push(full.finish());
+            }
+            // A packet that is too large on its own is still sent by itself
+            if !builder.push(&packet) {
+                datagrams.push(packet);
             }
         }
         
-        Err(ConnectionError::TlsNotSetup)
+        if !builder.is_empty() {
+            datagrams.push(builder.finish());
+        }
+        datagrams
     }
     
-    pub fn process_crypto_frame(&mut self, crypto_frame: &Frame) -> Result<Option<Vec<u8>>, ConnectionError> {
-        if let Frame::Crypto { offset: _, data } = crypto_frame {
-            if self.is_client {
-                if let Some(ref mut client_tls) = self.client_tls {
-                    client_tls.process_handshake_data(data)
-                        .map_err(|_| ConnectionError::TlsHandshakeFailed)?;
-                    
-                    if client_tls.is_handshake_complete() {
-                        self.handle_state_transition(ConnectionState::Established);
-                        return Ok(None);
-                    }
-                    
-                    let response_data = client_tls.get_handshake_data()
-                        .map_err(|_| ConnectionError::TlsHandshakeFailed)?;
-                    
-                    if !response_data.is_empty() {
-                        let crypto_frame = Frame::Crypto {
-                            offset: 0,
-                            data: Bytes::from(response_data),
-                        };
-                        
-                        let packet = self.create_handshake_packet(vec![crypto_frame])?;
-                        return Ok(Some(packet));
-                    }
-                }
+    /// Removes header protection, decrypts `data` and decodes its frames
+    ///
+    /// A packet that breaks the protocol closes the connection with the matching transport error;
+    /// the CONNECTION_CLOSE is queued for `poll_transmit` and the error still returned.
+    pub fn receive_packet(&mut self, data: &[u8]) -> Result<ReceivedPacket, ConnectionError> {
+        let result = self.process_packet(data);
+        self.close_on_error(result)
+    }
+    
+    fn process_packet(&mut self, data: &[u8]) -> Result<ReceivedPacket, ConnectionError> {
+        // A draining endpoint neither processes nor answers anything the peer still sends
+        if matches!(self.state, ConnectionState::Draining | ConnectionState::Closed) {
+            return Err(ConnectionError::InvalidState);
+        }
+        if packet::is_version_negotiation(data) {
+            return self.receive_version_negotiation(data);
+        }
+        
+        // Short headers carry one of our own connection IDs, whose length only we know
+        let conn_id_len = self.local_conn_id.len();
+        let protected = ProtectedHeader::parse_with_conn_id_len(data, conn_id_len)
+            .map_err(|_| ConnectionError::PacketDecoding)?;
+        if protected.packet_type == PacketType::Retry {
+            return self.receive_retry(data);
+        }
+        let level = protected.packet_type.encryption_level();
+        if !self.crypto.has_keys(&level) {
+            return Err(ConnectionError::KeysUnavailable);
+        }
+        
+        // Anything past the Length field belongs to a coalesced packet
+        let mut packet = data[..protected.packet_len].to_vec();
+        let pn_len = self.crypto.unprotect_header(level, &mut packet, protected.pn_offset)
+            .map_err(|_| ConnectionError::DecryptionFailed)?;
+        let header_len = protected.pn_offset + pn_len;
+        
+        let mut header_bytes = Bytes::copy_from_slice(&packet[..header_len]);
+        let mut header = PacketHeader::decode_with_conn_id_len(&mut header_bytes, conn_id_len)
+            .map_err(|_| ConnectionError::DecryptionFailed)?;
+        let packet_number = packet::decode_packet_number(
+            header.packet_number(),
+            pn_len,
+            self.largest_received_packet_number(level),
+        );
+        header.set_packet_number(packet_number);
+        
+        let (header_buf, ciphertext) = packet.split_at_mut(header_len);
+        let payload_len = self.crypto.decrypt_packet(level, packet_number, header_buf, ciphertext)
+            .map_err(|_| ConnectionError::DecryptionFailed)?;
+        // A replayed or duplicated packet must not be processed twice (RFC 9000 Section 12.3)
+        if self.spaces.get(&level).is_some_and(|space| space.received.contains(packet_number)) {
+            return Err(ConnectionError::DuplicatePacket);
+        }
+        
+        let mut payload = Bytes::copy_from_slice(&ciphertext[..payload_len]);
+        let mut frames = Vec::new();
+        while !payload.is_empty() {
+            frames.push(Frame::decode(&mut payload).map_err(|_| ConnectionError::FrameDecoding)?);
+        }
+        if !frames.iter().all(|frame| frame.is_allowed_at(level)) {
+            return Err(ConnectionError::ProtocolViolation);
+        }
+        
+        let peer_closed = frames.iter().any(Frame::is_close);
+        if self.state == ConnectionState::Closing {
+            if peer_closed {
+                self.handle_state_transition(ConnectionState::Draining);
             } else {
-                if let Some(ref mut server_tls) = self.server_tls {
-                    server_tls.process_handshake_data(data)
-                        .map_err(|_| ConnectionError::TlsHandshakeFailed)?;
-                    
-                    if server_tls.is_handshake_complete() {
-                        self.handle_state_transition(ConnectionState::Established);
-                        return Ok(None);
-                    }
-                    
-                    let response_data = server_tls.get_handshake_data()
-                        .map_err(|_| ConnectionError::TlsHandshakeFailed)?;
-                    
-                    if !response_data.is_empty() {
-                        let crypto_frame = Frame::Crypto {
-                            offset: 0,
-                            data: Bytes::from(response_data),
-                        };
-                        
-                        let packet = self.create_handshake_packet(vec![crypto_frame])?;
-                        return Ok(Some(packet));
-                    }
+                self.reply_while_closing();
+            }
+            return Ok(ReceivedPacket { header, frames });
+        }
+        if peer_closed {
+            // The peer has gone: wait out its in-flight packets without sending anything (RFC 9000 Section 10.2.2)
+            self.start_close_period(ConnectionState::Draining);
+            return Ok(ReceivedPacket { header, frames });
+        }
+        
+        // Only authenticated packets may move the reconstruction window, or keep the connection from idling out
+        let now = Instant::now();
+        self.idle_timer_start = now;
+        self.ack_eliciting_sent = false;
+        self.last_activity = now;
+        let space = self.spaces.entry(level).or_default();
+        // An ACK of a packet never sent is a forgery or a broken peer (RFC 9000 Section 13.1)
+        let ack = frames.iter().find_map(|frame| match frame {
+            Frame::Ack { largest, delay, ranges, .. } => Some((*largest, ranges.clone(), *delay)),
+            _ => None,
+        });
+        if ack.as_ref().is_some_and(|(largest, _, _)| space.largest_sent < Some(*largest)) {
+            return Err(ConnectionError::ProtocolViolation);
+        }
+        if space.largest_received < Some(packet_number) {
+            space.largest_received = Some(packet_number);
+            space.largest_received_time = Some(now);
+        }
+        space.received.insert(packet_number);
+        space.ack_pending |= frames.iter().any(Frame::is_ack_eliciting);
+        for frame in &frames {
+            match frame {
+                // Only a server can confirm the handshake (RFC 9000 Section 19.20)
+                Frame::HandshakeDone if !self.is_client => return Err(ConnectionError::ProtocolViolation),
+                Frame::HandshakeDone => self.confirm_handshake(),
+                Frame::Stream { stream_id, offset, data, fin } => {
+                    self.streams.receive(*stream_id, *offset, data.clone(), *fin).map_err(stream_error)?;
+                }
+                Frame::MaxStreams { dir, max_streams } => self.streams.receive_max_streams(*dir, *max_streams),
+                Frame::MaxData { max_data } => self.streams.receive_max_data(*max_data),
+                Frame::MaxStreamData { stream_id, max_data } => {
+                    self.streams.receive_max_stream_data(*stream_id, *max_data).map_err(stream_error)?;
+                }
+                Frame::StreamDataBlocked { stream_id, .. } => {
+                    self.streams.receive_stream_data_blocked(*stream_id).map_err(stream_error)?;
                 }
+                Frame::ResetStream { stream_id, error_code, final_size } => {
+                    self.streams.receive_reset_stream(*stream_id, *error_code, *final_size).map_err(stream_error)?;
+                }
+                Frame::StopSending { stream_id, error_code } => {
+                    self.streams.receive_stop_sending(*stream_id, *error_code).map_err(stream_error)?;
+                }
+                _ => {}
+            }
+        }
+        if let Some((_, ranges, delay)) = ack {
+            self.on_ack_received(level, &ranges, delay);
+        }
+        // A server stops using Initial keys once it receives a Handshake packet (RFC 9001 Section 4.9.1)
+        if !self.is_client && level == EncryptionLevel::Handshake {
+            self.discard_space(EncryptionLevel::Initial);
+        }
+        self.received_valid_packet = true;
+        
+        // The client switches to the connection ID the server chose once it has authenticated a packet from it
+        if let PacketHeader::Long(long) = &header {
+            if self.is_client && self.remote_conn_id.is_none() {
+                self.remote_conn_id = Some(long.src_conn_id.clone());
             }
         }
         
-        Ok(None)
+        Ok(ReceivedPacket { header, frames })
     }
     
-    pub fn is_tls_handshake_complete(&self) -> bool {
-        if self.is_client {
-            self.client_tls.as_ref().map_or(false, |tls| tls.is_handshake_complete())
-        } else {
-            self.server_tls.as_ref().map_or(false, |tls| tls.is_handshake_complete())
+    /// Applies the client rules for Version Negotiation (RFC 9000 Section 6.2)
+    ///
+    /// On success the handshake is reset for the newly selected version and the caller must
+    /// send `start_tls_handshake` again.
+    fn receive_version_negotiation(&mut self, data: &[u8]) -> Result<ReceivedPacket, ConnectionError> {
+        // Once the server has answered with a real packet, the version is settled
+        if !self.is_client || self.received_valid_packet {
+            return Err(ConnectionError::VersionNegotiationIgnored);
+        }
+        
+        let header = PacketHeader::decode(&mut Bytes::copy_from_slice(data))
+            .map_err(|_| ConnectionError::PacketDecoding)?;
+        let PacketHeader::VersionNegotiation(VersionNegotiationHeader { dest_conn_id, src_conn_id, supported_versions }) = &header else {
+            return Err(ConnectionError::PacketDecoding);
+        };
+        
+        // The connection IDs must echo our Initial, which an off-path attacker cannot see
+        if *dest_conn_id != self.local_conn_id || *src_conn_id != self.original_dest_conn_id {
+            return Err(ConnectionError::VersionNegotiationIgnored);
         }
+        // A server that lists the version we offered would have accepted it, so this is a downgrade attempt
+        if supported_versions.contains(&self.version) {
+            return Err(ConnectionError::VersionNegotiationIgnored);
+        }
+        
+        let Some(version) = self.supported_versions.iter().copied().find(|version| supported_versions.contains(version)) else {
+            self.handle_state_transition(ConnectionState::Closed);
+            return Err(ConnectionError::NoCommonVersion);
+        };
+        
+        self.received_valid_packet = true;
+        self.set_version(version)?;
+        self.restart_handshake()?;
+        
+        Ok(ReceivedPacket { header, frames: Vec::new() })
     }
     
-    pub fn close(&mut self, reason: String) -> Result<Vec<u8>, ConnectionError> {
-        let frame = Frame::ConnectionClose {
-            error_code: 0,
-            reason,
+    /// Applies a Retry (RFC 9000 Section 17.2.5.2): adopt its connection ID and token and resend the first flight
+    ///
+    /// On success the caller must send `start_tls_handshake` again.
+    fn receive_retry(&mut self, data: &[u8]) -> Result<ReceivedPacket, ConnectionError> {
+        // A client acts on at most one Retry, and only before anything else from the server
+        if !self.is_client || self.received_valid_packet {
+            return Err(ConnectionError::RetryIgnored);
+        }
+        
+        // Only a server that saw our Initial can produce a tag over its Destination Connection ID
+        if !crypto::verify_retry_integrity_for_version(self.version, &self.original_dest_conn_id.data, data) {
+            return Err(ConnectionError::DecryptionFailed);
+        }
+        
+        let header = PacketHeader::decode(&mut Bytes::copy_from_slice(data))
+            .map_err(|_| ConnectionError::PacketDecoding)?;
+        let PacketHeader::Retry(RetryHeader { dest_conn_id, src_conn_id, token, .. }) = &header else {
+            return Err(ConnectionError::PacketDecoding);
         };
+        if token.is_empty() || *dest_conn_id != self.local_conn_id {
+            return Err(ConnectionError::RetryIgnored);
+        }
         
-        self.handle_state_transition(ConnectionState::Closing);
+        self.received_valid_packet = true;
+        self.retry_src_conn_id = Some(src_conn_id.clone());
+        self.retry_token = token.clone();
         
-        match self.state {
-            ConnectionState::Initial | ConnectionState::Handshake => {
-                self.create_initial_packet(vec![frame])
+        // Initial keys follow the new Destination Connection ID (RFC 9001 Section 5.2)
+        self.crypto.setup_initial_keys_for_version(self.version, &src_conn_id.data, true).map_err(encryption_error)?;
+        self.restart_handshake()?;
+        
+        Ok(ReceivedPacket { header, frames: Vec::new() })
+    }
+    
+    /// Applies an ACK frame: acknowledged frames advance stream states and lost ones are queued again
+    fn on_ack_received(&mut self, level: EncryptionLevel, ranges: &[RangeInclusive<u64>], delay: u64) {
+        let exponent = self.peer_transport_parameters.as_ref()
+            .and_then(|params| params.ack_delay_exponent)
+            .unwrap_or(ACK_DELAY_EXPONENT as u64);
+        let ack_delay = Duration::from_micros(delay.saturating_mul(1 << exponent));
+        let outcome = self.recovery.on_ack_received(level, ranges, ack_delay, Instant::now());
+        // Only packets actually sent and now acknowledged move the window packet numbers are truncated against
+        if let Some(largest) = outcome.acked.iter().map(|packet| packet.packet_number).max() {
+            let space = self.spaces.entry(level).or_default();
+            space.largest_acked = space.largest_acked.max(Some(largest));
+        }
+        // The peer has the ACK frames in these packets, so what they reported need not be repeated (RFC 9000 Section 13.2.4)
+        if let Some(ack_largest) = outcome.acked.iter().filter_map(|packet| packet.ack_largest).max() {
+            self.spaces.entry(level).or_default().received.remove_below(ack_largest);
+        }
+        for frame in outcome.acked.iter().flat_map(|packet| &packet.frames) {
+            match frame {
+                Frame::Stream { stream_id, offset, data, fin } => {
+                    self.streams.stream_frame_acked(*stream_id, *offset, data.len() as u64, *fin);
+                }
+                Frame::ResetStream { stream_id, .. } => self.streams.reset_stream_acked(*stream_id),
+                _ => {}
             }
-            _ => {
-                let header = PacketHeader::Long(LongHeader {
-                    packet_type: PacketType::Initial,
-                    version: self.version,
-                    dest_conn_id: self.remote_conn_id.clone().unwrap_or_else(|| ConnectionId::new(vec![])),
-                    src_conn_id: self.local_conn_id.clone(),
-                    packet_number: self.next_packet_number(),
-                });
-                self.encode_packet(header, vec![frame])
+        }
+        self.requeue(level, outcome.lost.into_iter().flat_map(|packet| packet.frames));
+    }
+    
+    /// Queues frames to be sent again at `level`, returning how many were queued
+    fn requeue(&mut self, level: EncryptionLevel, frames: impl IntoIterator<Item = Frame>) -> usize {
+        if self.is_discarded(level) {
+            return 0;
+        }
+        let space = self.spaces.entry(level).or_default();
+        let before = space.pending_frames.len();
+        for frame in frames {
+            // A reset stream's data is never sent again (RFC 9000 Section 3.1)
+            if let Frame::Stream { stream_id, .. } = &frame {
+                if self.streams.is_reset(*stream_id) {
+                    continue;
+                }
             }
+            space.pending_frames.push_back(frame);
         }
+        space.pending_frames.len() - before
     }
-}
-
-#[derive(Debug)]
-pub struct ConnectionManager {
-    connections: HashMap<Vec<u8>, Connection>,
-}
-
-impl ConnectionManager {
-    pub fn new() -> Self {
-        Self {
-            connections: HashMap::new(),
+    
+    fn is_discarded(&self, level: EncryptionLevel) -> bool {
+        self.spaces.get(&level).is_some_and(|space| space.discarded)
+    }
+    
+    /// Stops sending and tracking packets at `level` (RFC 9002 Section 6.4)
+    fn discard_space(&mut self, level: EncryptionLevel) {
+        if self.is_discarded(level) {
+            return;
         }
+        self.recovery.discard_space(level);
+        let space = self.spaces.entry(level).or_default();
+        space.discarded = true;
+        space.ack_pending = false;
+        space.pending_frames.clear();
     }
     
-    pub fn get_connection(&mut self, conn_id: &[u8]) -> Option<&mut Connection> {
-        self.connections.get_mut(conn_id)
+    /// Handshake packets need no more retransmission once the handshake is confirmed (RFC 9001 Section 4.1.2)
+    fn confirm_handshake(&mut self) {
+        if self.recovery.is_handshake_confirmed() {
+            return;
+        }
+        self.recovery.confirm_handshake();
+        self.discard_space(EncryptionLevel::Handshake);
     }
     
-    pub fn add_connection(&mut self, conn_id: Vec<u8>, connection: Connection) {
-        self.connections.insert(conn_id, connection);
+    pub fn stats(&self) -> ConnectionStats {
+        let rtt = self.recovery.rtt();
+        ConnectionStats {
+            latest_rtt: rtt.latest_rtt(),
+            min_rtt: rtt.min_rtt(),
+            smoothed_rtt: rtt.smoothed_rtt(),
+            rttvar: rtt.rttvar(),
+            bytes_in_flight: self.recovery.bytes_in_flight(),
+            congestion_window: self.recovery.congestion_controller().window(),
+        }
     }
     
-    pub fn remove_connection(&mut self, conn_id: &[u8]) {
-        self.connections.remove(conn_id);
+    /// Replaces the congestion controller, which is NewReno unless set otherwise
+    pub fn set_congestion_controller(&mut self, controller: Box<dyn CongestionController>) {
+        self.recovery.set_congestion_controller(controller);
     }
-}
-
-#[derive(Debug)]
-pub enum ConnectionError {
-    PacketEncoding,
-    FrameEncoding,
-    InvalidState,
-    TlsSetupFailed,
-    TlsHandshakeFailed,
-    TlsNotSetup,
-}
-
-impl std::fmt::Display for ConnectionError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            ConnectionError::PacketEncoding => write!(f, "Packet encoding error"),
-            ConnectionError::FrameEncoding => write!(f, "Frame encoding error"),
-            ConnectionError::InvalidState => write!(f, "Invalid connection state"),
-            ConnectionError::TlsSetupFailed => write!(f, "TLS setup failed"),
-            ConnectionError::TlsHandshakeFailed => write!(f, "TLS handshake failed"),
-            ConnectionError::TlsNotSetup => write!(f, "TLS not setup"),
+    
+    /// Queues datagrams, typically from `coalesce_packets`, to be released by `poll_transmit` at the pacing rate
+    pub fn pace(&mut self, datagrams: Vec<Vec<u8>>) {
+        self.pacer.set_rate(self.pacing_rate());
+        for datagram in datagrams {
+            self.pacer.push(datagram);
         }
     }
-}
-
-impl std::error::Error for ConnectionError {}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::frame::Frame;
-    use std::net::SocketAddr;
-
-    fn get_test_addr() -> SocketAddr {
-        "127.0.0.1:8080".parse().unwrap()
+    
+    /// Next queued datagram the pacer lets out at `now`, spreading them over the RTT according to the congestion window
+    pub fn poll_transmit(&mut self, now: Instant) -> Option<Vec<u8>> {
+        self.pacer.set_rate(self.pacing_rate());
+        self.pacer.poll_transmit(now)
     }
-
-    #[test]
-    fn test_connection_new_client() {
-        let addr = get_test_addr();
-        let conn = Connection::new_client(addr);
-        
-        assert_eq!(conn.state, ConnectionState::Initial);
-        assert_eq!(conn.remote_addr, addr);
-        assert_eq!(conn.local_conn_id.len(), 8);
-        assert!(conn.remote_conn_id.is_none());
-        assert_eq!(conn.packet_number, 0);
-        assert_eq!(conn.version, 1);
+    
+    /// When `poll_transmit` next releases a datagram, or `None` with nothing queued
+    pub fn next_send_time(&self, now: Instant) -> Option<Instant> {
+        self.pacer.next_send_time(now)
     }
-
-    #[test]
-    fn test_connection_new_server() {
-        let addr = get_test_addr();
-        let remote_conn_id = ConnectionId::new(vec![1, 2, 3, 4]);
-        let conn = Connection::new_server(addr, remote_conn_id.clone());
-        
-        assert_eq!(conn.state, ConnectionState::Initial);
-        assert_eq!(conn.remote_addr, addr);
+    
+    /// Bytes per second the congestion controller wants sends spread at
+    pub fn pacing_rate(&self) -> u64 {
+        self.recovery.congestion_controller().pacing_rate(self.recovery.rtt())
+    }
+    
+    /// Negotiated idle timeout: the smaller of both endpoints' values, but never under three PTOs (RFC 9000 Section 10.1)
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        let peer = self.peer_transport_parameters.as_ref()
+            .and_then(|params| params.max_idle_timeout)
+            .filter(|&millis| millis > 0)
+            .map(Duration::from_millis);
+        let timeout = match (self.max_idle_timeout, peer) {
+            (Some(local), Some(peer)) => local.min(peer),
+            (local, peer) => local.or(peer)?,
+        };
+        Some(timeout.max(3 * self.recovery.probe_timeout()))
+    }
+    
+    /// When a keep-alive PING is due, once 1-RTT packets can carry it
+    fn keep_alive_deadline(&self) -> Option<Instant> {
+        let interval = self.keep_alive_interval?;
+        self.crypto.has_keys(&EncryptionLevel::Application).then_some(self.last_activity + interval)
+    }
+    
+    /// When `handle_timeout` should next be called, if any timer is armed
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        if self.is_closed() {
+            return None;
+        }
+        if self.is_closing() {
+            return self.close_deadline;
+        }
+        let idle = self.idle_timeout().map(|timeout| self.idle_timer_start + timeout);
+        [self.recovery.timeout(), idle, self.keep_alive_deadline()].into_iter().flatten().min()
+    }
+    
+    /// Runs the idle, keep-alive and loss detection timers. An expired idle timer closes the connection
+    /// silently; anything else queued is sent by the next `poll_packets`.
+    pub fn handle_timeout(&mut self, now: Instant) {
+        if self.is_closed() {
+            return;
+        }
+        if self.is_closing() {
+            if self.close_deadline.is_some_and(|deadline| deadline <= now) {
+                self.handle_state_transition(ConnectionState::Closed);
+            }
+            return;
+        }
+        if self.idle_timeout().is_some_and(|timeout| self.idle_timer_start + timeout <= now) {
+            self.handle_state_transition(ConnectionState::Closed);
+            self.recovery.reset();
+            return;
+        }
+        if self.keep_alive_deadline().is_some_and(|deadline| deadline <= now) {
+            self.requeue(EncryptionLevel::Application, [Frame::Ping]);
+            // Counted from now even if the PING waits on the congestion window
+            self.last_activity = now;
+        }
+        match self.recovery.on_timeout(now) {
+            Some(Timeout::Lost(level, packets)) => {
+                self.requeue(level, packets.into_iter().flat_map(|packet| packet.frames));
+            }
+            Some(Timeout::Probe(level, frames)) => {
+                let queued = self.requeue(level, frames);
+                // With nothing to resend, a PING still elicits the acknowledgement the timer is waiting for
+                if queued == 0 {
+                    self.requeue(level, [Frame::Ping]);
+                }
+            }
+            None => {}
+        }
+    }
+    
+    /// Discards handshake progress so the first flight can be sent again
+    fn restart_handshake(&mut self) -> Result<(), ConnectionError> {
+        self.crypto_streams.clear();
+        self.spaces.clear();
+        self.recovery.reset();
+        self.client_tls = None;
+        self.server_tls = None;
+        self.state = ConnectionState::Initial;
+        
+        if let Some(tls_config) = self.tls_config.clone() {
+            self.setup_tls(tls_config)?;
+        }
+        Ok(())
+    }
+    
+    /// Switches to `version`, deriving the Initial keys again with its salt (RFC 9001 Section 5.2)
+    fn set_version(&mut self, version: u32) -> Result<(), ConnectionError> {
+        let initial_conn_id = self.retry_src_conn_id.as_ref().unwrap_or(&self.original_dest_conn_id);
+        self.crypto.setup_initial_keys_for_version(version, &initial_conn_id.data, self.is_client)
+            .map_err(encryption_error)?;
+        self.version = version;
+        Ok(())
+    }
+    
+    pub fn handle_state_transition(&mut self, new_state: ConnectionState) {
+        println!("Connection state: {:?} -> {:?}", self.state, new_state);
+        self.state = new_state;
+    }
+    
+    pub fn is_closed(&self) -> bool {
+        matches!(self.state, ConnectionState::Closed)
+    }
+    
+    /// Whether the connection is in its closing or draining period, still holding state but sending no data
+    pub fn is_closing(&self) -> bool {
+        matches!(self.state, ConnectionState::Closing | ConnectionState::Draining)
+    }
+    
+    /// Enters `Closing` or `Draining` for three PTOs, long enough for the peer's in-flight packets to arrive
+    /// (RFC 9000 Section 10.2), dropping everything that was waiting to be sent or retransmitted
+    fn start_close_period(&mut self, state: ConnectionState) {
+        self.close_deadline = Some(Instant::now() + 3 * self.recovery.probe_timeout());
+        self.handle_state_transition(state);
+        self.recovery.reset();
+        for space in self.spaces.values_mut() {
+            space.pending_frames.clear();
+        }
+    }
+    
+    /// Answers a packet received while closing with the close datagram again, each reply waiting for
+    /// twice as many packets as the last so a peer that keeps sending cannot make us flood it (RFC 9000 Section 10.2.1)
+    fn reply_while_closing(&mut self) {
+        self.closing_received += 1;
+        if self.closing_received < self.closing_next_reply {
+            return;
+        }
+        self.closing_next_reply *= 2;
+        if let Some(datagram) = self.close_datagram.clone() {
+            self.pace(vec![datagram]);
+        }
+    }
+    
+    pub fn setup_tls(&mut self, tls_config: Arc<TlsConfig>) -> Result<(), ConnectionError> {
+        self.tls_config = Some(tls_config.clone());
+        
+        let mut transport_parameters = TransportParameters {
+            original_destination_connection_id: (!self.is_client).then(|| self.original_dest_conn_id.clone()),
+            initial_source_connection_id: Some(self.local_conn_id.clone()),
+            retry_source_connection_id: if self.is_client { None } else { self.retry_src_conn_id.clone() },
+            max_idle_timeout: self.max_idle_timeout.map(|timeout| timeout.as_millis() as u64),
+            ..TransportParameters::default()
+        };
+        self.streams.local_transport_parameters(&mut transport_parameters);
+        let transport_parameters = transport_parameters.to_bytes();
+        
+        if self.is_client {
+            let client_tls = QuicClientTls::for_version(tls_config.client_config.clone(), "localhost", self.version, transport_parameters)
+                .map_err(|_| ConnectionError::TlsSetupFailed)?;
+            self.client_tls = Some(client_tls);
+        } else {
+            let server_tls = QuicServerTls::for_version(tls_config.server_config.clone(), self.version, transport_parameters)
+                .map_err(|_| ConnectionError::TlsSetupFailed)?;
+            self.server_tls = Some(server_tls);
+        }
+        
+        Ok(())
+    }
+    
+    /// Produces the client's first flight as a single datagram
+    pub fn start_tls_handshake(&mut self) -> Result<Vec<u8>, ConnectionError> {
+        let packets = self.flush_handshake()?;
+        let mut datagrams = self.coalesce_packets(packets);
+        match datagrams.len() {
+            0 => Err(ConnectionError::TlsNotSetup),
+            1 => Ok(datagrams.remove(0)),
+            // The server must see the whole ClientHello before it can derive anything
+            _ => Err(ConnectionError::PacketEncoding),
+        }
+    }
+    
+    /// Feeds a CRYPTO frame received at `level` to TLS and returns the packets to send in response
+    ///
+    /// A failed handshake closes the connection like a protocol error in `receive_packet`.
+    pub fn process_crypto_frame(&mut self, level: EncryptionLevel, crypto_frame: &Frame) -> Result<Vec<Vec<u8>>, ConnectionError> {
+        let result = self.process_crypto_data(level, crypto_frame);
+        self.close_on_error(result)
+    }
+    
+    fn process_crypto_data(&mut self, level: EncryptionLevel, crypto_frame: &Frame) -> Result<Vec<Vec<u8>>, ConnectionError> {
+        let Frame::Crypto { offset, data } = crypto_frame else {
+            return Ok(Vec::new());
+        };
+        
+        let ready = self.crypto_streams.entry(level).or_default().receive(*offset, data.clone());
+        if ready.is_empty() {
+            return Ok(Vec::new());
+        }
+        
+        let result = if self.is_client {
+            let client_tls = self.client_tls.as_mut().ok_or(ConnectionError::TlsNotSetup)?;
+            client_tls.process_handshake_data(&ready)
+        } else {
+            let server_tls = self.server_tls.as_mut().ok_or(ConnectionError::TlsNotSetup)?;
+            server_tls.process_handshake_data(&ready)
+        };
+        result.map_err(|_| ConnectionError::TlsHandshakeFailed)?;
+        
+        self.read_peer_transport_parameters()?;
+        let mut packets = self.flush_handshake()?;
+        
+        if self.is_tls_handshake_complete() && self.state != ConnectionState::Established {
+            self.handle_state_transition(ConnectionState::Established);
+            // The server confirms the handshake as soon as it completes, and tells the client
+            if !self.is_client {
+                self.confirm_handshake();
+                self.requeue(EncryptionLevel::Application, [Frame::HandshakeDone]);
+            }
+        }
+        
+        packets.extend(self.poll_packets()?);
+        Ok(packets)
+    }
+    
+    /// Installs any new keys from TLS and packs pending handshake messages into packets
+    fn flush_handshake(&mut self) -> Result<Vec<Vec<u8>>, ConnectionError> {
+        let HandshakeOutput { messages, keys } = if self.is_client {
+            self.client_tls.as_mut().ok_or(ConnectionError::TlsNotSetup)?.get_handshake_data()
+        } else {
+            self.server_tls.as_mut().ok_or(ConnectionError::TlsNotSetup)?.get_handshake_data()
+        };
+        
+        for (level, level_keys) in keys {
+            self.crypto.install_tls_keys(level, level_keys);
+            if level == EncryptionLevel::Handshake && self.state == ConnectionState::Initial {
+                self.handle_state_transition(ConnectionState::Handshake);
+            }
+        }
+        
+        let mut packets = Vec::new();
+        for (level, data) in messages {
+            for chunk in data.chunks(MAX_CRYPTO_FRAME_DATA) {
+                let stream = self.crypto_streams.entry(level).or_default();
+                let crypto_frame = Frame::Crypto {
+                    offset: stream.send_offset,
+                    data: Bytes::copy_from_slice(chunk),
+                };
+                stream.send_offset += chunk.len() as u64;
+                
+                // Acknowledge what the peer sent at this level in the same packet
+                let mut frames: Vec<Frame> = self.ack_frame(level).into_iter().collect();
+                frames.push(crypto_frame);
+                packets.push(self.create_packet(level, frames)?);
+            }
+        }
+        
+        Ok(packets)
+    }
+    
+    fn read_peer_transport_parameters(&mut self) -> Result<(), ConnectionError> {
+        if self.peer_transport_parameters.is_some() {
+            return Ok(());
+        }
+        
+        let encoded = if self.is_client {
+            self.client_tls.as_ref().and_then(|tls| tls.peer_transport_parameters())
+        } else {
+            self.server_tls.as_ref().and_then(|tls| tls.peer_transport_parameters())
+        };
+        
+        if let Some(encoded) = encoded {
+            let params = TransportParameters::decode(&mut Bytes::copy_from_slice(encoded))
+                .map_err(|_| ConnectionError::TransportParameters)?;
+            // The server echoes the DCID it saw, so a tampered first Initial is detected
+            if self.is_client && params.original_destination_connection_id.as_ref() != Some(&self.original_dest_conn_id) {
+                return Err(ConnectionError::TransportParameters);
+            }
+            // Likewise for the Retry, which must be reported exactly when one was received
+            if self.is_client && params.retry_source_connection_id != self.retry_src_conn_id {
+                return Err(ConnectionError::TransportParameters);
+            }
+            self.streams.set_peer_transport_parameters(&params);
+            if let Some(max_ack_delay) = params.max_ack_delay {
+                self.recovery.set_max_ack_delay(Duration::from_millis(max_ack_delay));
+            }
+            self.peer_transport_parameters = Some(params);
+        }
+        
+        Ok(())
+    }
+    
+    pub fn is_tls_handshake_complete(&self) -> bool {
+        if self.is_client {
+            self.client_tls.as_ref().is_some_and(|tls| tls.is_handshake_complete())
+        } else {
+            self.server_tls.as_ref().is_some_and(|tls| tls.is_handshake_complete())
+        }
+    }
+    
+    /// Derives keying material from the TLS exporter, e.g. for channel binding
+    pub fn export_keying_material(&self, out: &mut [u8], label: &[u8], context: Option<&[u8]>) -> Result<(), ConnectionError> {
+        let result = if self.is_client {
+            self.client_tls.as_ref().ok_or(ConnectionError::TlsNotSetup)?
+                .export_keying_material(out, label, context)
+        } else {
+            self.server_tls.as_ref().ok_or(ConnectionError::TlsNotSetup)?
+                .export_keying_material(out, label, context)
+        };
+        result.map_err(|error| match error {
+            rustls::Error::HandshakeNotComplete => ConnectionError::HandshakeNotComplete,
+            _ => ConnectionError::TlsHandshakeFailed,
+        })
+    }
+    
+    /// Replaces the limits imposed on the peer and the congestion controller; must be called before `setup_tls`
+    /// and before any stream is used
+    pub fn set_transport_config(&mut self, config: TransportConfig) {
+        self.recovery.set_congestion_controller(config.congestion_control.build(self.max_datagram_size));
+        self.pacer = Pacer::new(config.pacing_burst * self.max_datagram_size, u64::MAX);
+        self.max_idle_timeout = config.max_idle_timeout;
+        self.keep_alive_interval = config.keep_alive_interval;
+        self.supported_versions = config.negotiable_versions();
+        // A client that has sent nothing yet offers its most preferred version
+        if self.is_client && self.packet_number == 0 && self.supported_versions[0] != self.version {
+            let _ = self.set_version(self.supported_versions[0]);
+        }
+        let side = if self.is_client { Side::Client } else { Side::Server };
+        self.streams = Streams::with_config(side, config);
+    }
+    
+    /// Opens a bidirectional stream, or fails with `Blocked` while the peer's stream limit is reached
+    pub fn open_bi(&mut self) -> Result<StreamId, StreamError> {
+        self.streams.open(Dir::Bi)
+    }
+    
+    /// Opens a unidirectional stream, or fails with `Blocked` while the peer's stream limit is reached
+    pub fn open_uni(&mut self) -> Result<StreamId, StreamError> {
+        self.streams.open(Dir::Uni)
+    }
+    
+    /// Next stream the peer has opened
+    pub fn accept_stream(&mut self) -> Option<StreamId> {
+        self.streams.accept()
+    }
+    
+    /// Queues as much of `data` as the peer's credit allows; it goes out with the next `poll_packets`
+    pub fn write(&mut self, stream_id: StreamId, data: &[u8]) -> Result<usize, StreamError> {
+        self.streams.write(stream_id, data)
+    }
+    
+    pub fn finish(&mut self, stream_id: StreamId) -> Result<(), StreamError> {
+        self.streams.finish(stream_id)
+    }
+    
+    /// Abandons sending on a stream; the peer's reads fail with `StreamError::Reset(error_code)`
+    pub fn reset_stream(&mut self, stream_id: StreamId, error_code: u64) -> Result<(), StreamError> {
+        self.streams.reset(stream_id, error_code)
+    }
+    
+    /// Stops reading a stream; the peer's writes fail with `StreamError::Stopped(error_code)`
+    pub fn stop_sending(&mut self, stream_id: StreamId, error_code: u64) -> Result<(), StreamError> {
+        self.streams.stop_sending(stream_id, error_code)
+    }
+    
+    /// State of the sending half of a stream, or `None` once it is closed and forgotten
+    pub fn send_state(&self, stream_id: StreamId) -> Option<SendState> {
+        self.streams.send_state(stream_id)
+    }
+    
+    /// State of the receiving half of a stream, or `None` once it is closed and forgotten
+    pub fn recv_state(&self, stream_id: StreamId) -> Option<RecvState> {
+        self.streams.recv_state(stream_id)
+    }
+    
+    /// Reads received stream data in order, returning 0 once the peer has finished the stream
+    ///
+    /// Reading frees receive window, which `poll_packets` then advertises to the peer.
+    pub fn read(&mut self, stream_id: StreamId, buf: &mut [u8]) -> Result<usize, StreamError> {
+        self.streams.read(stream_id, buf)
+    }
+    
+    /// Packs pending frames into packets at every level with keys: ACKs, lost frames and probes,
+    /// then flow control and STREAM frames at 1-RTT
+    pub fn poll_packets(&mut self) -> Result<Vec<Vec<u8>>, ConnectionError> {
+        let mut packets = Vec::new();
+        if self.is_closing() || self.is_closed() {
+            return Ok(packets);
+        }
+        for level in [EncryptionLevel::Initial, EncryptionLevel::Handshake, EncryptionLevel::Application] {
+            // Stream data stays buffered until 1-RTT keys are available
+            if self.crypto.has_keys(&level) && !self.is_discarded(level) {
+                packets.extend(self.poll_level(level)?);
+            }
+        }
+        Ok(packets)
+    }
+    
+    fn poll_level(&mut self, level: EncryptionLevel) -> Result<Vec<Vec<u8>>, ConnectionError> {
+        let max_payload = self.max_datagram_size.saturating_sub(self.packet_overhead(level));
+        
+        let mut control: VecDeque<Frame> = self.ack_frame(level).into_iter().collect();
+        if let Some(space) = self.spaces.get_mut(&level) {
+            control.extend(space.pending_frames.drain(..));
+        }
+        let with_streams = level == EncryptionLevel::Application;
+        if with_streams {
+            control.extend(self.streams.poll_control_frames(Instant::now(), self.recovery.rtt().smoothed_rtt()));
+        }
+        
+        let mut packets = Vec::new();
+        loop {
+            // With the congestion window full only an ACK goes out; everything else waits for acknowledgements
+            if !self.recovery.can_send() {
+                if matches!(control.front(), Some(Frame::Ack { .. })) {
+                    packets.push(self.create_packet(level, control.pop_front().into_iter().collect())?);
+                }
+                self.spaces.entry(level).or_default().pending_frames.extend(control);
+                break;
+            }
+            
+            let mut frames = Vec::new();
+            let mut remaining = max_payload;
+            while let Some(frame) = control.front() {
+                let len = frame_len(frame)?;
+                if len > remaining && !frames.is_empty() {
+                    break;
+                }
+                remaining = remaining.saturating_sub(len);
+                frames.extend(control.pop_front());
+            }
+            while let Some(frame) = with_streams.then(|| self.streams.poll_frame(remaining)).flatten() {
+                remaining -= frame_len(&frame)?;
+                frames.push(frame);
+            }
+            
+            if frames.is_empty() {
+                break;
+            }
+            packets.push(self.create_packet(level, frames)?);
+        }
+        
+        Ok(packets)
+    }
+    
+    /// Largest header plus AEAD tag a packet at `level` is sent with
+    fn packet_overhead(&self, level: EncryptionLevel) -> usize {
+        let dest_conn_id_len = self.dest_conn_id().len();
+        match level {
+            // Flags, connection ID and the longest packet number
+            EncryptionLevel::Application => 1 + dest_conn_id_len + 4 + AEAD_TAG_LEN,
+            // Flags, version, both connection IDs with their lengths, a 2-byte Length and the longest packet number
+            EncryptionLevel::Handshake | EncryptionLevel::Initial => {
+                let token_len = if level == EncryptionLevel::Initial {
+                    frame::varint_len(self.retry_token.len() as u64) + self.retry_token.len()
+                } else {
+                    0
+                };
+                1 + 4 + 1 + dest_conn_id_len + 1 + self.local_conn_id.len() + token_len + 2 + 4 + AEAD_TAG_LEN
+            }
+        }
+    }
+    
+    /// Closes the connection immediately (RFC 9000 Section 10.2), returning a datagram with CONNECTION_CLOSE at
+    /// the highest encryption level available. The connection then stays in `Closing` for three PTOs, answering
+    /// packets from the peer with the same datagram through `poll_transmit`, before becoming `Closed`.
+    pub fn close(&mut self, reason: String) -> Result<Vec<u8>, ConnectionError> {
+        self.close_with_error(NO_ERROR, reason)
+    }
+    
+    /// Like `close`, reporting `error_code` from RFC 9000 Section 20.1 to the peer
+    pub fn close_with_error(&mut self, error_code: u64, reason: String) -> Result<Vec<u8>, ConnectionError> {
+        match self.state {
+            ConnectionState::Closing => return self.close_datagram.clone().ok_or(ConnectionError::InvalidState),
+            ConnectionState::Draining | ConnectionState::Closed => return Err(ConnectionError::InvalidState),
+            _ => {}
+        }
+        let frame = Frame::ConnectionClose {
+            error_code,
+            reason,
+        };
+        
+        let levels: Vec<EncryptionLevel> = [EncryptionLevel::Initial, EncryptionLevel::Handshake, EncryptionLevel::Application]
+            .into_iter()
+            .filter(|level| self.crypto.has_keys(level) && !self.is_discarded(*level))
+            .collect();
+        let highest = *levels.last().ok_or(ConnectionError::KeysUnavailable)?;
+        // Until the handshake is confirmed the peer may not have the highest keys yet, so each lower level
+        // still in use carries a copy too (RFC 9000 Section 10.2.3)
+        let confirmed = self.recovery.is_handshake_confirmed();
+        let mut packets = Vec::new();
+        for level in levels {
+            if level == highest || !confirmed {
+                packets.push(self.create_packet(level, vec![frame.clone()])?);
+            }
+        }
+        // The last datagram holds the highest level's packet, along with whichever lower copies fit before it
+        let datagram = self.coalesce_packets(packets).pop().ok_or(ConnectionError::PacketEncoding)?;
+        
+        self.start_close_period(ConnectionState::Closing);
+        self.close_datagram = Some(datagram.clone());
+        self.closing_received = 0;
+        self.closing_next_reply = 1;
+        Ok(datagram)
+    }
+    
+    /// Closes the connection with the transport error behind a failed `result`, if the peer caused it
+    fn close_on_error<T>(&mut self, result: Result<T, ConnectionError>) -> Result<T, ConnectionError> {
+        if let Err(error) = &result {
+            if let Some(error_code) = error.transport_error_code() {
+                if let Ok(datagram) = self.close_with_error(error_code, error.to_string()) {
+                    self.pace(vec![datagram]);
+                }
+            }
+        }
+        result
+    }
+}
+
+/// What a server does with a client Initial that matches no connection
+#[derive(Debug)]
+pub enum IncomingAction {
+    /// Start a connection; the caller still configures TLS and registers it
+    Accept(Box<Connection>),
+    /// Send this Retry and keep no state for the client
+    Retry(Vec<u8>),
+    /// Discard the datagram
+    Drop,
+}
+
+#[derive(Debug, Default)]
+pub struct ConnectionManager {
+    connections: HashMap<Vec<u8>, Connection>,
+    address_validator: Option<AddressValidator>,
+    transport_config: TransportConfig,
+}
+
+impl ConnectionManager {
+    pub fn new() -> Self {
+        Self {
+            connections: HashMap::new(),
+            address_validator: None,
+            transport_config: TransportConfig::default(),
+        }
+    }
+    
+    /// Limits every connection accepted from now on imposes on its client
+    pub fn set_transport_config(&mut self, config: TransportConfig) {
+        self.transport_config = config;
+    }
+    
+    /// Versions new connections are accepted with, from the transport config, in order of preference
+    pub fn supported_versions(&self) -> Vec<u32> {
+        self.transport_config.negotiable_versions()
+    }
+    
+    /// Version Negotiation packet answering a datagram whose version is not among `supported_versions`
+    pub fn version_negotiation(&self, datagram: &[u8]) -> Option<Vec<u8>> {
+        packet::version_negotiation_response(datagram, &self.supported_versions())
+    }
+    
+    /// Makes clients prove their address with a Retry token before any connection is created
+    pub fn require_address_validation(&mut self, validator: AddressValidator) {
+        self.address_validator = Some(validator);
+    }
+    
+    /// Decides how to answer a datagram from `remote_addr` that starts with a new client Initial
+    pub fn handle_incoming(&self, datagram: &[u8], remote_addr: SocketAddr) -> IncomingAction {
+        match self.handle_initial(datagram, remote_addr) {
+            IncomingAction::Accept(mut connection) => {
+                connection.set_transport_config(self.transport_config.clone());
+                IncomingAction::Accept(connection)
+            }
+            action => action,
+        }
+    }
+    
+    fn handle_initial(&self, datagram: &[u8], remote_addr: SocketAddr) -> IncomingAction {
+        // Anything smaller than a padded client Initial would let a spoofed source amplify our reply (RFC 9000 Section 14.1)
+        if datagram.len() < packet::DEFAULT_MAX_DATAGRAM_SIZE {
+            return IncomingAction::Drop;
+        }
+        // Versions outside the configured list are for `version_negotiation` to answer
+        let Some(version) = packet::long_header_version(datagram).filter(|version| self.supported_versions().contains(version)) else {
+            return IncomingAction::Drop;
+        };
+        let Ok(header) = ProtectedHeader::parse(datagram) else {
+            return IncomingAction::Drop;
+        };
+        let (PacketType::Initial, Some(src_conn_id)) = (header.packet_type, header.src_conn_id) else {
+            return IncomingAction::Drop;
+        };
+        
+        let Some(validator) = &self.address_validator else {
+            return accept_version(Connection::accept(remote_addr, src_conn_id, header.dest_conn_id), version);
+        };
+        
+        if header.token.is_empty() {
+            let retry_src_conn_id = ConnectionId::random(packet::DEFAULT_CONN_ID_LEN);
+            let token = validator.issue(remote_addr, &header.dest_conn_id, &retry_src_conn_id);
+            return match retry_packet(version, src_conn_id, retry_src_conn_id, token, &header.dest_conn_id) {
+                Ok(retry) => IncomingAction::Retry(retry),
+                Err(_) => IncomingAction::Drop,
+            };
+        }
+        
+        // The token must come from this client address and name the connection ID it is now using
+        match validator.validate(&header.token, remote_addr) {
+            Ok(validated) if validated.retry_src_conn_id == header.dest_conn_id => accept_version(
+                Connection::accept_retried(remote_addr, src_conn_id, validated.original_dest_conn_id, validated.retry_src_conn_id),
+                version,
+            ),
+            _ => IncomingAction::Drop,
+        }
+    }
+    
+    pub fn get_connection(&mut self, conn_id: &[u8]) -> Option<&mut Connection> {
+        self.connections.get_mut(conn_id)
+    }
+    
+    pub fn add_connection(&mut self, conn_id: Vec<u8>, connection: Connection) {
+        self.connections.insert(conn_id, connection);
+    }
+    
+    pub fn remove_connection(&mut self, conn_id: &[u8]) {
+        self.connections.remove(conn_id);
+    }
+    
+    /// Earliest timer across all connections
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        self.connections.values().filter_map(Connection::poll_timeout).min()
+    }
+    
+    /// Earliest time any connection's pacer releases a queued datagram
+    pub fn next_send_time(&self, now: Instant) -> Option<Instant> {
+        self.connections.values().filter_map(|connection| connection.next_send_time(now)).min()
+    }
+    
+    /// Every datagram the connections' pacers let out at `now`, with their destinations
+    pub fn poll_transmit(&mut self, now: Instant) -> Vec<(SocketAddr, Vec<u8>)> {
+        let mut datagrams = Vec::new();
+        for connection in self.connections.values_mut() {
+            while let Some(datagram) = connection.poll_transmit(now) {
+                datagrams.push((connection.remote_addr, datagram));
+            }
+        }
+        datagrams
+    }
+    
+    /// Removes connections that have closed, such as those whose idle timer expired, returning their IDs
+    pub fn reap_closed(&mut self) -> Vec<Vec<u8>> {
+        let closed: Vec<Vec<u8>> = self.connections.iter()
+            .filter(|(_, connection)| connection.is_closed())
+            .map(|(conn_id, _)| conn_id.clone())
+            .collect();
+        for conn_id in &closed {
+            self.connections.remove(conn_id);
+        }
+        closed
+    }
+    
+    /// Runs every expired connection timer. Connections that idle out are left closed for `reap_closed`
+    /// to remove; one that fails while sending is closed with INTERNAL_ERROR and reported back.
+    pub fn handle_timeouts(&mut self, now: Instant) -> TimeoutOutput {
+        let mut output = TimeoutOutput::default();
+        for (conn_id, connection) in self.connections.iter_mut() {
+            if connection.poll_timeout().is_some_and(|timeout| timeout <= now) {
+                connection.handle_timeout(now);
+                let remote_addr = connection.remote_addr;
+                // One broken connection must not stop the timers of the others
+                let datagrams = match connection.poll_packets() {
+                    Ok(packets) => connection.coalesce_packets(packets),
+                    Err(error) => {
+                        let close = connection.close_with_error(INTERNAL_ERROR, error.to_string());
+                        output.errors.push((conn_id.clone(), error));
+                        match close {
+                            Ok(datagram) => vec![datagram],
+                            // Without a packet to tell the peer, the connection is simply dropped
+                            Err(_) => {
+                                connection.handle_state_transition(ConnectionState::Closed);
+                                continue;
+                            }
+                        }
+                    }
+                };
+                output.datagrams.extend(datagrams.into_iter().map(|datagram| (remote_addr, datagram)));
+            }
+        }
+        output
+    }
+}
+
+/// What `ConnectionManager::handle_timeouts` produced
+#[derive(Debug, Default)]
+pub struct TimeoutOutput {
+    /// Datagrams to send, with their destinations
+    pub datagrams: Vec<(SocketAddr, Vec<u8>)>,
+    /// Connections that failed while running their timers, now closing
+    pub errors: Vec<(Vec<u8>, ConnectionError)>,
+}
+
+/// Server side of a connection speaking the `version` of the client's Initial
+fn accept_version(mut connection: Connection, version: u32) -> IncomingAction {
+    match connection.set_version(version) {
+        Ok(()) => IncomingAction::Accept(Box::new(connection)),
+        Err(_) => IncomingAction::Drop,
+    }
+}
+
+/// Encodes a Retry and seals it with the integrity tag over the client's original DCID
+fn retry_packet(version: u32, dest_conn_id: ConnectionId, src_conn_id: ConnectionId, token: Bytes, original_dest_conn_id: &ConnectionId) -> Result<Vec<u8>, ConnectionError> {
+    let header = PacketHeader::Retry(RetryHeader {
+        version,
+        dest_conn_id,
+        src_conn_id,
+        token,
+        integrity_tag: [0; RETRY_INTEGRITY_TAG_LEN],
+    });
+    
+    let mut packet = BytesMut::new();
+    header.encode(&mut packet).map_err(|_| ConnectionError::PacketEncoding)?;
+    let tag_offset = packet.len() - RETRY_INTEGRITY_TAG_LEN;
+    let tag = crypto::retry_integrity_tag_for_version(version, &original_dest_conn_id.data, &packet[..tag_offset])
+        .map_err(encryption_error)?;
+    packet[tag_offset..].copy_from_slice(&tag);
+    
+    Ok(packet.to_vec())
+}
+
+/// Maps a stream error caused by a peer's frame to the matching transport error
+fn stream_error(error: StreamError) -> ConnectionError {
+    match error {
+        StreamError::FinalSize => ConnectionError::FinalSize,
+        StreamError::FlowControl => ConnectionError::FlowControl,
+        StreamError::StreamLimit => ConnectionError::StreamLimit,
+        _ => ConnectionError::StreamState,
+    }
+}
+
+fn frame_len(frame: &Frame) -> Result<usize, ConnectionError> {
+    let mut buf = BytesMut::new();
+    frame.encode(&mut buf).map_err(|_| ConnectionError::FrameEncoding)?;
+    Ok(buf.len())
+}
+
+fn encryption_error(error: CryptoError) -> ConnectionError {
+    match error {
+        CryptoError::NoKeys => ConnectionError::KeysUnavailable,
+        _ => ConnectionError::PacketEncoding,
+    }
+}
+
+#[derive(Debug)]
+pub enum ConnectionError {
+    PacketEncoding,
+    PacketDecoding,
+    FrameEncoding,
+    FrameDecoding,
+    InvalidState,
+    TlsSetupFailed,
+    TlsHandshakeFailed,
+    TlsNotSetup,
+    /// The TLS handshake has not finished, so its secrets are not available yet
+    HandshakeNotComplete,
+    TransportParameters,
+    KeysUnavailable,
+    DecryptionFailed,
+    /// A packet with this packet number was already received
+    DuplicatePacket,
+    /// A Version Negotiation packet arrived that the client must not act on
+    VersionNegotiationIgnored,
+    /// The server supports none of the versions this endpoint offers
+    NoCommonVersion,
+    /// A Retry arrived that the client must not act on
+    RetryIgnored,
+    /// The peer sent on a stream it may not send on
+    StreamState,
+    /// The peer changed the final size of a stream
+    FinalSize,
+    /// The peer sent more data than the credit it was given
+    FlowControl,
+    /// The peer opened more streams than it was allowed
+    StreamLimit,
+    /// The peer sent a frame it is not allowed to send
+    ProtocolViolation,
+}
+
+/// Transport error codes sent in CONNECTION_CLOSE (RFC 9000 Section 20.1)
+pub const NO_ERROR: u64 = 0x00;
+pub const INTERNAL_ERROR: u64 = 0x01;
+pub const FLOW_CONTROL_ERROR: u64 = 0x03;
+pub const STREAM_LIMIT_ERROR: u64 = 0x04;
+pub const STREAM_STATE_ERROR: u64 = 0x05;
+pub const FINAL_SIZE_ERROR: u64 = 0x06;
+pub const FRAME_ENCODING_ERROR: u64 = 0x07;
+pub const TRANSPORT_PARAMETER_ERROR: u64 = 0x08;
+pub const PROTOCOL_VIOLATION: u64 = 0x0a;
+/// CRYPTO_ERROR carrying the TLS handshake_failure alert (RFC 9001 Section 4.8)
+pub const CRYPTO_HANDSHAKE_FAILURE: u64 = 0x0128;
+
+impl ConnectionError {
+    /// Transport error code to close the connection with, or `None` for local failures and
+    /// packets that are only dropped
+    pub fn transport_error_code(&self) -> Option<u64> {
+        match self {
+            ConnectionError::FlowControl => Some(FLOW_CONTROL_ERROR),
+            ConnectionError::StreamLimit => Some(STREAM_LIMIT_ERROR),
+            ConnectionError::StreamState => Some(STREAM_STATE_ERROR),
+            ConnectionError::FinalSize => Some(FINAL_SIZE_ERROR),
+            ConnectionError::FrameDecoding => Some(FRAME_ENCODING_ERROR),
+            ConnectionError::TransportParameters => Some(TRANSPORT_PARAMETER_ERROR),
+            ConnectionError::ProtocolViolation => Some(PROTOCOL_VIOLATION),
+            ConnectionError::TlsHandshakeFailed => Some(CRYPTO_HANDSHAKE_FAILURE),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionError::PacketEncoding => write!(f, "Packet encoding error"),
+            ConnectionError::PacketDecoding => write!(f, "Packet decoding error"),
+            ConnectionError::FrameEncoding => write!(f, "Frame encoding error"),
+            ConnectionError::FrameDecoding => write!(f, "Frame decoding error"),
+            ConnectionError::InvalidState => write!(f, "Invalid connection state"),
+            ConnectionError::TlsSetupFailed => write!(f, "TLS setup failed"),
+            ConnectionError::TlsHandshakeFailed => write!(f, "TLS handshake failed"),
+            ConnectionError::TlsNotSetup => write!(f, "TLS not setup"),
+            ConnectionError::HandshakeNotComplete => write!(f, "TLS handshake not complete"),
+            ConnectionError::TransportParameters => write!(f, "Invalid transport parameters"),
+            ConnectionError::KeysUnavailable => write!(f, "No keys for encryption level"),
+            ConnectionError::DecryptionFailed => write!(f, "Packet decryption failed"),
+            ConnectionError::DuplicatePacket => write!(f, "Duplicate packet"),
+            ConnectionError::VersionNegotiationIgnored => write!(f, "Version Negotiation packet ignored"),
+            ConnectionError::NoCommonVersion => write!(f, "No mutually supported QUIC version"),
+            ConnectionError::RetryIgnored => write!(f, "Retry packet ignored"),
+            ConnectionError::StreamState => write!(f, "Stream state error"),
+            ConnectionError::FinalSize => write!(f, "Stream final size error"),
+            ConnectionError::FlowControl => write!(f, "Flow control error"),
+            ConnectionError::StreamLimit => write!(f, "Stream limit error"),
+            ConnectionError::ProtocolViolation => write!(f, "Protocol violation"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectionError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::congestion::{initial_window, CongestionControl};
+    use crate::flow_control::{DEFAULT_CONNECTION_WINDOW, DEFAULT_STREAM_WINDOW, INITIAL_RTT};
+    use crate::rtt::RttEstimator;
+    use crate::frame::Frame;
+    use std::net::SocketAddr;
+
+    fn get_test_addr() -> SocketAddr {
+        "127.0.0.1:8080".parse().unwrap()
+    }
+
+    #[test]
+    fn test_connection_new_client() {
+        let addr = get_test_addr();
+        let conn = Connection::new_client(addr);
+        
+        assert_eq!(conn.state, ConnectionState::Initial);
+        assert_eq!(conn.remote_addr, addr);
+        assert_eq!(conn.local_conn_id.len(), 8);
+        assert!(conn.remote_conn_id.is_none());
+        assert_eq!(conn.packet_number, 0);
+        assert_eq!(conn.version, 1);
+    }
+
+    #[test]
+    fn test_connection_new_server() {
+        let addr = get_test_addr();
+        let remote_conn_id = ConnectionId::new(vec![1, 2, 3, 4]);
+        let conn = Connection::new_server(addr, remote_conn_id.clone());
+        
+        assert_eq!(conn.state, ConnectionState::Initial);
+        assert_eq!(conn.remote_addr, addr);
         assert_eq!(conn.local_conn_id.len(), 8);
         assert_eq!(conn.remote_conn_id.unwrap().data, remote_conn_id.data);
         assert_eq!(conn.packet_number, 0);
@@ -353,72 +1735,1559 @@ mod tests {
     }
 
     #[test]
-    fn test_packet_number_increment() {
-        let mut conn = Connection::new_client(get_test_addr());
+    fn test_packet_number_increment() {
+        let mut conn = Connection::new_client(get_test_addr());
+        
+        assert_eq!(conn.next_packet_number(), 0);
+        assert_eq!(conn.next_packet_number(), 1);
+        assert_eq!(conn.next_packet_number(), 2);
+        assert_eq!(conn.packet_number, 3);
+    }
+
+    #[test]
+    fn test_connection_state_transitions() {
+        let mut conn = Connection::new_client(get_test_addr());
+        
+        assert_eq!(conn.state, ConnectionState::Initial);
+        assert!(!conn.is_closed());
+        
+        conn.handle_state_transition(ConnectionState::Handshake);
+        assert_eq!(conn.state, ConnectionState::Handshake);
+        assert!(!conn.is_closed());
+        
+        conn.handle_state_transition(ConnectionState::Established);
+        assert_eq!(conn.state, ConnectionState::Established);
+        assert!(!conn.is_closed());
+        
+        conn.handle_state_transition(ConnectionState::Closing);
+        assert_eq!(conn.state, ConnectionState::Closing);
+        assert!(!conn.is_closed());
+        
+        conn.handle_state_transition(ConnectionState::Closed);
+        assert_eq!(conn.state, ConnectionState::Closed);
+        assert!(conn.is_closed());
+    }
+
+    #[test]
+    fn test_create_initial_packet() {
+        let mut conn = Connection::new_client(get_test_addr());
+        conn.remote_conn_id = Some(ConnectionId::new(vec![5, 6, 7, 8]));
+        
+        let frames = vec![Frame::Ping];
+        let packet = conn.create_initial_packet(frames).unwrap();
+        
+        assert!(!packet.is_empty());
+        assert_eq!(conn.packet_number, 1); // Should increment
+    }
+
+    #[test]
+    fn test_create_handshake_packet() {
+        let mut conn = Connection::new_client(get_test_addr());
+        conn.remote_conn_id = Some(ConnectionId::new(vec![5, 6, 7, 8]));
+        conn.crypto.setup_handshake_keys(&[0x42; 32], &[0x24; 32], true).unwrap();
+        
+        let frames = vec![Frame::Ping];
+        let packet = conn.create_handshake_packet(frames).unwrap();
+        
+        assert!(!packet.is_empty());
+        assert_eq!(conn.packet_number, 1); // Should increment
+    }
+
+    #[test]
+    fn test_close_connection() {
+        let mut conn = Connection::new_client(get_test_addr());
+        conn.remote_conn_id = Some(ConnectionId::new(vec![1, 2, 3, 4]));
+        
+        let close_packet = conn.close("Test close".to_string()).unwrap();
+        
+        assert!(!close_packet.is_empty());
+        assert_eq!(conn.state, ConnectionState::Closing);
+    }
+
+    #[test]
+    fn test_handshake_packet_without_keys() {
+        let mut conn = Connection::new_client(get_test_addr());
+        
+        assert!(matches!(
+            conn.create_handshake_packet(vec![Frame::Ping]),
+            Err(ConnectionError::KeysUnavailable)
+        ));
+    }
+
+    #[test]
+    fn test_initial_packet_roundtrip() {
+        let mut client = Connection::new_client(get_test_addr());
+        let packet = client.create_initial_packet(vec![Frame::Ping]).unwrap();
+        
+        let protected = ProtectedHeader::parse(&packet).unwrap();
+        assert_eq!(protected.dest_conn_id, client.original_dest_conn_id);
+        assert_eq!(protected.packet_len, packet.len());
+        
+        let mut server = Connection::accept(get_test_addr(), protected.src_conn_id.unwrap(), protected.dest_conn_id);
+        let received = server.receive_packet(&packet).unwrap();
+        
+        assert_eq!(received.header.packet_number(), 0);
+        assert!(matches!(received.frames[0], Frame::Ping));
+        
+        // The reply lets the client learn the server's connection ID
+        let reply = server.create_initial_packet(vec![Frame::Ping]).unwrap();
+        client.receive_packet(&reply).unwrap();
+        assert_eq!(client.remote_conn_id, Some(server.local_conn_id.clone()));
+    }
+
+    #[test]
+    fn test_stream_frame_in_initial_rejected() {
+        let mut client = Connection::new_client(get_test_addr());
+        let stream = Frame::Stream { stream_id: StreamId(0), offset: 0, data: Bytes::from_static(b"early"), fin: false };
+        let packet = client.create_initial_packet(vec![stream]).unwrap();
+        
+        let protected = ProtectedHeader::parse(&packet).unwrap();
+        let mut server = Connection::accept(get_test_addr(), protected.src_conn_id.unwrap(), protected.dest_conn_id);
+        assert!(matches!(server.receive_packet(&packet), Err(ConnectionError::ProtocolViolation)));
+        assert_eq!(close_error_code(&mut server, &mut client), Some(PROTOCOL_VIOLATION));
+    }
+
+    #[test]
+    fn test_length_field_bounds_coalesced_packet() {
+        let mut client = Connection::new_client(get_test_addr());
+        let first = client.create_initial_packet(vec![Frame::Ping]).unwrap();
+        let second = client.create_initial_packet(vec![Frame::Padding { length: 30 }]).unwrap();
+        let datagram = [first.clone(), second].concat();
+        
+        let mut server = Connection::accept(get_test_addr(), client.local_conn_id.clone(), client.original_dest_conn_id.clone());
+        let received = server.receive_packet(&datagram).unwrap();
+        
+        assert_eq!(received.header.packet_number(), 0);
+        assert!(matches!(received.frames[0], Frame::Ping));
+    }
+
+    #[test]
+    fn test_largest_received_packet_number_per_space() {
+        let mut client = Connection::new_client(get_test_addr());
+        let mut server = Connection::accept(get_test_addr(), client.local_conn_id.clone(), client.original_dest_conn_id.clone());
+        assert_eq!(server.largest_received_packet_number(EncryptionLevel::Initial), None);
+        
+        let packets: Vec<_> = (0..300)
+            .map(|_| client.create_initial_packet(vec![Frame::Ping]).unwrap())
+            .collect();
+        
+        // Deliver with some reordering; every packet number is reconstructed from its truncated form
+        for (chunk_index, chunk) in packets.chunks(10).enumerate() {
+            for (offset, packet) in chunk.iter().enumerate().rev() {
+                let received = server.receive_packet(packet).unwrap();
+                assert_eq!(received.header.packet_number(), (chunk_index * 10 + offset) as u64);
+            }
+        }
+        
+        assert_eq!(server.largest_received_packet_number(EncryptionLevel::Initial), Some(299));
+        assert_eq!(server.largest_received_packet_number(EncryptionLevel::Handshake), None);
+    }
+
+    #[test]
+    fn test_short_packet_with_long_conn_id() {
+        let mut client = Connection::new_client(get_test_addr());
+        let mut server = Connection::accept(get_test_addr(), client.local_conn_id.clone(), client.original_dest_conn_id.clone());
+        server.local_conn_id = ConnectionId::random(16);
+        client.remote_conn_id = Some(server.local_conn_id.clone());
+        client.crypto.setup_application_keys(&[0x11; 32], &[0x22; 32], true).unwrap();
+        server.crypto.setup_application_keys(&[0x11; 32], &[0x22; 32], false).unwrap();
+        
+        let packet = client.create_short_packet(vec![Frame::Ping]).unwrap();
+        let received = server.receive_packet(&packet).unwrap();
+        
+        match received.header {
+            PacketHeader::Short(header) => assert_eq!(header.dest_conn_id, server.local_conn_id),
+            _ => panic!("Expected Short header"),
+        }
+        assert!(matches!(received.frames[0], Frame::Ping));
+    }
+
+    #[test]
+    fn test_coalesce_packets_up_to_mtu() {
+        // The server's Initials are not padded, unlike the client's
+        let mut client = Connection::new_client(get_test_addr());
+        let mut server = Connection::accept(get_test_addr(), client.local_conn_id.clone(), client.original_dest_conn_id.clone());
+        server.max_datagram_size = 200;
+        let packets: Vec<_> = (0..5)
+            .map(|_| server.create_initial_packet(vec![Frame::Padding { length: 60 }]).unwrap())
+            .collect();
+        let packet_len = packets[0].len();
+        
+        let datagrams = server.coalesce_packets(packets);
+        
+        let per_datagram = 200 / packet_len;
+        assert_eq!(datagrams.len(), 5usize.div_ceil(per_datagram));
+        assert!(datagrams.iter().all(|datagram| datagram.len() <= 200));
+        
+        let received: Vec<_> = datagrams.iter()
+            .flat_map(|datagram| packet::split_datagram(datagram))
+            .map(|packet| client.receive_packet(packet.unwrap()).unwrap().header.packet_number())
+            .collect();
+        assert_eq!(received, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_packet_payload_is_encrypted() {
+        let mut conn = Connection::new_client(get_test_addr());
+        let reason = "plaintext should not leak";
+        let packet = conn.close(reason.to_string()).unwrap();
+        
+        assert!(!packet.windows(reason.len()).any(|window| window == reason.as_bytes()));
+    }
+
+    #[test]
+    fn test_corrupted_packet_rejected() {
+        let mut client = Connection::new_client(get_test_addr());
+        let mut packet = client.create_initial_packet(vec![Frame::Ping]).unwrap();
+        let mut server = Connection::accept(get_test_addr(), client.local_conn_id.clone(), client.original_dest_conn_id.clone());
+        
+        let last = packet.len() - 1;
+        packet[last] ^= 0x01;
+        
+        assert!(matches!(server.receive_packet(&packet), Err(ConnectionError::DecryptionFailed)));
+    }
+
+    #[test]
+    fn test_wrong_initial_keys_rejected() {
+        let mut client = Connection::new_client(get_test_addr());
+        let packet = client.create_initial_packet(vec![Frame::Ping]).unwrap();
+        // Keys derived from the wrong connection ID cannot open the packet
+        let mut server = Connection::new_server(get_test_addr(), client.local_conn_id.clone());
+        
+        assert!(matches!(server.receive_packet(&packet), Err(ConnectionError::DecryptionFailed)));
+    }
+
+    #[test]
+    fn test_receive_without_keys() {
+        let mut client = Connection::new_client(get_test_addr());
+        client.crypto.setup_handshake_keys(&[0x42; 32], &[0x24; 32], true).unwrap();
+        let packet = client.create_handshake_packet(vec![Frame::Ping]).unwrap();
+        let mut server = Connection::accept(get_test_addr(), client.local_conn_id.clone(), client.original_dest_conn_id.clone());
+        
+        assert!(matches!(server.receive_packet(&packet), Err(ConnectionError::KeysUnavailable)));
+    }
+
+    /// Decrypts datagrams for `conn` and returns its responses coalesced into datagrams
+    fn deliver_handshake(conn: &mut Connection, datagrams: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        let mut responses = Vec::new();
+        for datagram in datagrams {
+            assert!(datagram.len() <= conn.max_datagram_size);
+            // Each packet's CRYPTO data may unlock the keys for the next one
+            for packet in packet::split_datagram(&datagram) {
+                let received = conn.receive_packet(packet.unwrap()).unwrap();
+                let level = received.header.encryption_level();
+                for frame in &received.frames {
+                    responses.extend(conn.process_crypto_frame(level, frame).unwrap());
+                }
+            }
+        }
+        conn.coalesce_packets(responses)
+    }
+
+    /// Version Negotiation packet answering `client`'s first Initial
+    fn version_negotiation_for(client: &Connection, supported_versions: Vec<u32>) -> Vec<u8> {
+        let header = PacketHeader::VersionNegotiation(VersionNegotiationHeader {
+            dest_conn_id: client.local_conn_id.clone(),
+            src_conn_id: client.original_dest_conn_id.clone(),
+            supported_versions,
+        });
+        let mut buf = BytesMut::new();
+        header.encode(&mut buf).unwrap();
+        buf.to_vec()
+    }
+
+    #[test]
+    fn test_version_negotiation_restarts_handshake() {
+        let mut client = Connection::new_client(get_test_addr());
+        client.setup_tls(Arc::new(TlsConfig::new().unwrap())).unwrap();
+        client.start_tls_handshake().unwrap();
+
+        let received = client.receive_packet(&version_negotiation_for(&client, vec![packet::QUIC_VERSION_DRAFT_29])).unwrap();
+        assert!(matches!(received.header, PacketHeader::VersionNegotiation(_)));
+        assert_eq!(client.version, packet::QUIC_VERSION_DRAFT_29);
+
+        // The ClientHello is sent again under the new version
+        let datagram = client.start_tls_handshake().unwrap();
+        assert_eq!(packet::long_header_version(&datagram), Some(packet::QUIC_VERSION_DRAFT_29));
+
+        // Only the first Version Negotiation packet counts
+        assert!(matches!(
+            client.receive_packet(&version_negotiation_for(&client, vec![packet::QUIC_VERSION_1])),
+            Err(ConnectionError::VersionNegotiationIgnored)
+        ));
+        assert_eq!(client.version, packet::QUIC_VERSION_DRAFT_29);
+    }
+
+    #[test]
+    fn test_client_initial_padded_for_version_negotiation() {
+        let mut client = Connection::new_client(get_test_addr());
+        client.setup_tls(Arc::new(TlsConfig::new().unwrap())).unwrap();
+        let datagram = client.start_tls_handshake().unwrap();
+        assert!(datagram.len() >= packet::DEFAULT_MAX_DATAGRAM_SIZE);
+
+        // A server without version 1 answers the real first flight, and the client follows it
+        let vn = packet::version_negotiation_response(&datagram, &[packet::QUIC_VERSION_DRAFT_29]).unwrap();
+        client.receive_packet(&vn).unwrap();
+        assert_eq!(client.version, packet::QUIC_VERSION_DRAFT_29);
+        let datagram = client.start_tls_handshake().unwrap();
+        assert!(datagram.len() >= packet::DEFAULT_MAX_DATAGRAM_SIZE);
+    }
+
+    #[test]
+    fn test_handshake_completes_after_version_negotiation() {
+        let tls_config = Arc::new(TlsConfig::new().unwrap());
+        let mut manager = ConnectionManager::new();
+        manager.set_transport_config(TransportConfig {
+            supported_versions: vec![packet::QUIC_VERSION_DRAFT_29],
+            ..TransportConfig::default()
+        });
+
+        let mut client = Connection::new_client(get_test_addr());
+        client.setup_tls(tls_config.clone()).unwrap();
+        let client_hello = client.start_tls_handshake().unwrap();
+        assert!(matches!(manager.handle_incoming(&client_hello, get_test_addr()), IncomingAction::Drop));
+        let vn = manager.version_negotiation(&client_hello).unwrap();
+        client.receive_packet(&vn).unwrap();
+
+        // Both sides derive draft 29 Initial keys and run its TLS extension from here on
+        let client_hello = client.start_tls_handshake().unwrap();
+        let IncomingAction::Accept(mut server) = manager.handle_incoming(&client_hello, get_test_addr()) else {
+            panic!("Expected the connection to be accepted");
+        };
+        assert_eq!(server.version, packet::QUIC_VERSION_DRAFT_29);
+        server.setup_tls(tls_config).unwrap();
+
+        let server_flight = deliver_handshake(&mut server, vec![client_hello]);
+        let client_finished = deliver_handshake(&mut client, server_flight);
+        deliver_handshake(&mut server, client_finished);
+        assert_eq!(client.state, ConnectionState::Established);
+        assert_eq!(server.state, ConnectionState::Established);
+    }
+
+    #[test]
+    fn test_configured_versions_choose_offered_version() {
+        let mut client = Connection::new_client(get_test_addr());
+        client.set_transport_config(TransportConfig {
+            // Versions without Initial secrets are never offered
+            supported_versions: vec![0x1a2a_3a4a, packet::QUIC_VERSION_DRAFT_29],
+            ..TransportConfig::default()
+        });
+        assert_eq!(client.supported_versions, vec![packet::QUIC_VERSION_DRAFT_29]);
+        client.setup_tls(Arc::new(TlsConfig::new().unwrap())).unwrap();
+        let datagram = client.start_tls_handshake().unwrap();
+        assert_eq!(packet::long_header_version(&datagram), Some(packet::QUIC_VERSION_DRAFT_29));
+
+        let IncomingAction::Accept(server) = ConnectionManager::new().handle_incoming(&datagram, get_test_addr()) else {
+            panic!("Expected the connection to be accepted");
+        };
+        assert_eq!(server.version, packet::QUIC_VERSION_DRAFT_29);
+    }
+
+    #[test]
+    fn test_version_negotiation_listing_current_version_ignored() {
+        let mut client = Connection::new_client(get_test_addr());
+
+        // Steering the client to another version while claiming to support its choice is a downgrade
+        let vn = version_negotiation_for(&client, vec![packet::QUIC_VERSION_DRAFT_29, packet::QUIC_VERSION_1]);
+        assert!(matches!(client.receive_packet(&vn), Err(ConnectionError::VersionNegotiationIgnored)));
+        assert_eq!(client.version, packet::QUIC_VERSION_1);
+    }
+
+    #[test]
+    fn test_version_negotiation_after_server_packet_ignored() {
+        let mut client = Connection::new_client(get_test_addr());
+        let vn = version_negotiation_for(&client, vec![packet::QUIC_VERSION_DRAFT_29]);
+
+        let mut server = Connection::accept(get_test_addr(), client.local_conn_id.clone(), client.original_dest_conn_id.clone());
+        let reply = server.create_initial_packet(vec![Frame::Ping]).unwrap();
+        client.receive_packet(&reply).unwrap();
+
+        assert!(matches!(client.receive_packet(&vn), Err(ConnectionError::VersionNegotiationIgnored)));
+        assert_eq!(client.version, packet::QUIC_VERSION_1);
+    }
+
+    #[test]
+    fn test_version_negotiation_with_wrong_conn_ids_ignored() {
+        let mut client = Connection::new_client(get_test_addr());
+
+        let header = PacketHeader::VersionNegotiation(VersionNegotiationHeader {
+            dest_conn_id: client.local_conn_id.clone(),
+            src_conn_id: ConnectionId::random(8),
+            supported_versions: vec![packet::QUIC_VERSION_DRAFT_29],
+        });
+        let mut vn = BytesMut::new();
+        header.encode(&mut vn).unwrap();
+
+        assert!(matches!(client.receive_packet(&vn), Err(ConnectionError::VersionNegotiationIgnored)));
+    }
+
+    #[test]
+    fn test_version_negotiation_without_common_version_aborts() {
+        let mut client = Connection::new_client(get_test_addr());
+        let vn = version_negotiation_for(&client, vec![0x1a2a_3a4a]);
+
+        assert!(matches!(client.receive_packet(&vn), Err(ConnectionError::NoCommonVersion)));
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn test_server_ignores_version_negotiation() {
+        let client = Connection::new_client(get_test_addr());
+        let mut server = Connection::accept(get_test_addr(), client.local_conn_id.clone(), client.original_dest_conn_id.clone());
+
+        let vn = version_negotiation_for(&client, vec![packet::QUIC_VERSION_DRAFT_29]);
+        assert!(matches!(server.receive_packet(&vn), Err(ConnectionError::VersionNegotiationIgnored)));
+    }
+
+    #[test]
+    fn test_tls_handshake_installs_keys() {
+        let tls_config = Arc::new(TlsConfig::new().unwrap());
+        let mut client = Connection::new_client(get_test_addr());
+        client.setup_tls(tls_config.clone()).unwrap();
+        
+        let client_hello = client.start_tls_handshake().unwrap();
+        let mut server = Connection::accept(get_test_addr(), client.local_conn_id.clone(), client.original_dest_conn_id.clone());
+        server.setup_tls(tls_config).unwrap();
+        
+        let mut early_binding = [0u8; 32];
+        assert!(matches!(
+            client.export_keying_material(&mut early_binding, b"EXPORTER-binding", None),
+            Err(ConnectionError::HandshakeNotComplete)
+        ));
+        
+        let server_flight = deliver_handshake(&mut server, vec![client_hello]);
+        // The server's Initial and Handshake packets share datagrams
+        assert!(server_flight.iter().any(|datagram| packet::split_datagram(datagram).count() > 1));
+        assert_eq!(server.state, ConnectionState::Handshake);
+        assert!(server.crypto.has_keys(&EncryptionLevel::Handshake));
+        assert!(server.crypto.has_keys(&EncryptionLevel::Application));
+        
+        let client_finished = deliver_handshake(&mut client, server_flight);
+        assert_eq!(client.state, ConnectionState::Established);
+        assert!(client.crypto.has_keys(&EncryptionLevel::Application));
+        assert_eq!(client.remote_conn_id, Some(server.local_conn_id.clone()));
+        assert_eq!(
+            client.peer_transport_parameters.as_ref().unwrap().initial_source_connection_id,
+            Some(server.local_conn_id.clone())
+        );
+        
+        deliver_handshake(&mut server, client_finished);
+        assert_eq!(server.state, ConnectionState::Established);
+        
+        let mut client_binding = [0u8; 32];
+        let mut server_binding = [0u8; 32];
+        client.export_keying_material(&mut client_binding, b"EXPORTER-binding", None).unwrap();
+        server.export_keying_material(&mut server_binding, b"EXPORTER-binding", None).unwrap();
+        assert_eq!(client_binding, server_binding);
+        assert_eq!(
+            server.peer_transport_parameters.as_ref().unwrap().initial_source_connection_id,
+            Some(client.local_conn_id.clone())
+        );
+    }
+
+    /// Client and server connections that have completed the handshake with each other
+    fn established_pair() -> (Connection, Connection) {
+        established_pair_with_server_config(TransportConfig::default())
+    }
+
+    fn established_pair_with_server_config(config: TransportConfig) -> (Connection, Connection) {
+        established_pair_with_configs(TransportConfig::default(), config)
+    }
+
+    fn established_pair_with_configs(client_config: TransportConfig, server_config: TransportConfig) -> (Connection, Connection) {
+        let tls_config = Arc::new(TlsConfig::new().unwrap());
+        let mut client = Connection::new_client(get_test_addr());
+        client.set_transport_config(client_config);
+        client.setup_tls(tls_config.clone()).unwrap();
+        
+        let client_hello = client.start_tls_handshake().unwrap();
+        let mut server = Connection::accept(get_test_addr(), client.local_conn_id.clone(), client.original_dest_conn_id.clone());
+        server.set_transport_config(server_config);
+        server.setup_tls(tls_config).unwrap();
+        
+        let server_flight = deliver_handshake(&mut server, vec![client_hello]);
+        let client_finished = deliver_handshake(&mut client, server_flight);
+        let handshake_done = deliver_handshake(&mut server, client_finished);
+        deliver_handshake(&mut client, handshake_done);
+        assert_eq!(server.state, ConnectionState::Established);
+        (client, server)
+    }
+
+    /// Delivers every packet `from` has pending to `to`, returning the frames they carried
+    fn transfer(from: &mut Connection, to: &mut Connection) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        let packets = from.poll_packets().unwrap();
+        for datagram in from.coalesce_packets(packets) {
+            assert!(datagram.len() <= from.max_datagram_size);
+            for packet in packet::split_datagram(&datagram) {
+                frames.extend(to.receive_packet(packet.unwrap()).unwrap().frames);
+            }
+        }
+        frames
+    }
+
+    /// Error code of the CONNECTION_CLOSE `from` queued after a protocol error, as `to` receives it
+    fn close_error_code(from: &mut Connection, to: &mut Connection) -> Option<u64> {
+        assert_eq!(from.state, ConnectionState::Closing);
+        let datagram = from.poll_transmit(Instant::now())?;
+        to.receive_packet(&datagram).unwrap().frames.into_iter().find_map(|frame| match frame {
+            Frame::ConnectionClose { error_code, .. } => Some(error_code),
+            _ => None,
+        })
+    }
+
+    fn read_available(conn: &mut Connection, stream_id: StreamId) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut buf = [0u8; 4096];
+        while let Ok(len @ 1..) = conn.read(stream_id, &mut buf) {
+            data.extend_from_slice(&buf[..len]);
+        }
+        data
+    }
+
+    fn read_to_end(conn: &mut Connection, stream_id: StreamId) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut buf = [0u8; 512];
+        loop {
+            match conn.read(stream_id, &mut buf).unwrap() {
+                0 => return data,
+                len => data.extend_from_slice(&buf[..len]),
+            }
+        }
+    }
+
+    #[test]
+    fn test_bidirectional_stream_echo() {
+        let (mut client, mut server) = established_pair();
+        
+        let stream_id = client.open_bi().unwrap();
+        client.write(stream_id, b"ping over a stream").unwrap();
+        client.finish(stream_id).unwrap();
+        transfer(&mut client, &mut server);
+        
+        assert_eq!(server.accept_stream(), Some(stream_id));
+        assert_eq!(server.accept_stream(), None);
+        let request = read_to_end(&mut server, stream_id);
+        assert_eq!(request, b"ping over a stream");
+        
+        server.write(stream_id, &request).unwrap();
+        server.finish(stream_id).unwrap();
+        transfer(&mut server, &mut client);
+        
+        assert_eq!(read_to_end(&mut client, stream_id), b"ping over a stream");
+        assert_eq!(client.largest_acked_packet_number(EncryptionLevel::Application), server.largest_received_packet_number(EncryptionLevel::Application));
+    }
+
+    #[test]
+    fn test_stream_data_reassembled_across_packets() {
+        let (mut client, mut server) = established_pair();
+        let data: Vec<u8> = (0..6000u32).map(|i| i as u8).collect();
+        
+        let stream_id = client.open_uni().unwrap();
+        client.write(stream_id, &data).unwrap();
+        client.finish(stream_id).unwrap();
+        let packets = client.poll_packets().unwrap();
+        assert!(packets.len() > 4);
+        
+        // Deliver in reverse so every packet but the last arrives ahead of its predecessors
+        for packet in packets.iter().rev() {
+            server.receive_packet(packet).unwrap();
+        }
+        assert_eq!(server.accept_stream(), Some(stream_id));
+        assert_eq!(read_to_end(&mut server, stream_id), data);
+        assert_eq!(server.write(stream_id, b"reply"), Err(StreamError::UnknownStream));
+    }
+
+    #[test]
+    fn test_stream_open_waits_for_peer_credit() {
+        let mut client = Connection::new_client(get_test_addr());
+        
+        // Without the server's transport parameters there is no credit to open a stream against
+        assert_eq!(client.open_bi(), Err(StreamError::Blocked));
+        assert!(client.poll_packets().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_configured_stream_limit_enforced() {
+        let config = TransportConfig { max_concurrent_bidi_streams: 1, ..TransportConfig::default() };
+        let (mut client, mut server) = established_pair_with_server_config(config);
+        assert_eq!(client.open_bi(), Ok(StreamId(0)));
+        assert_eq!(client.open_bi(), Err(StreamError::Blocked));
+        
+        // A client ignoring the limit is a connection error
+        let packet = client.create_short_packet(vec![Frame::Stream {
+            stream_id: StreamId(4),
+            offset: 0,
+            data: Bytes::from_static(b"too many"),
+            fin: false,
+        }]).unwrap();
+        assert!(matches!(server.receive_packet(&packet), Err(ConnectionError::StreamLimit)));
+        assert_eq!(close_error_code(&mut server, &mut client), Some(STREAM_LIMIT_ERROR));
+    }
+
+    #[test]
+    fn test_stream_credit_reissued_after_close() {
+        let config = TransportConfig { max_concurrent_uni_streams: 1, ..TransportConfig::default() };
+        let (mut client, mut server) = established_pair_with_server_config(config);
+        let stream_id = client.open_uni().unwrap();
+        assert_eq!(client.open_uni(), Err(StreamError::Blocked));
+        
+        client.write(stream_id, b"done").unwrap();
+        client.finish(stream_id).unwrap();
+        let frames = transfer(&mut client, &mut server);
+        assert!(frames.iter().any(|frame| matches!(frame, Frame::StreamsBlocked { dir: Dir::Uni, limit: 1 })));
+        
+        assert_eq!(read_to_end(&mut server, stream_id), b"done");
+        let frames = transfer(&mut server, &mut client);
+        assert!(frames.iter().any(|frame| matches!(frame, Frame::MaxStreams { dir: Dir::Uni, max_streams: 2 })));
+        assert_eq!(client.open_uni(), Ok(StreamId(6)));
+    }
+
+    #[test]
+    fn test_credit_replenished_as_data_is_read() {
+        let (mut client, mut server) = established_pair();
+        let data: Vec<u8> = (0..4 * DEFAULT_STREAM_WINDOW as usize).map(|i| (i % 251) as u8).collect();
+        let stream_id = client.open_uni().unwrap();
+        
+        let mut written = 0;
+        let mut received = Vec::new();
+        let mut grants = Vec::new();
+        let mut blocked_reported = false;
+        for _ in 0..32 {
+            if let Ok(len) = client.write(stream_id, &data[written..]) {
+                written += len;
+            }
+            for frame in transfer(&mut client, &mut server) {
+                blocked_reported |= matches!(frame, Frame::StreamDataBlocked { .. });
+            }
+            
+            received.extend(read_available(&mut server, stream_id));
+            for frame in transfer(&mut server, &mut client) {
+                if let Frame::MaxStreamData { max_data, .. } = frame {
+                    grants.push(max_data);
+                }
+            }
+            if received.len() == data.len() {
+                break;
+            }
+        }
+        
+        assert_eq!(received, data);
+        assert!(blocked_reported);
+        // Windows were used up well within two round trips, so auto-tuning grew them
+        assert!(grants.windows(2).any(|pair| pair[1] - pair[0] > DEFAULT_STREAM_WINDOW));
+    }
+
+    #[test]
+    fn test_stream_data_beyond_stream_limit_rejected() {
+        let (mut client, mut server) = established_pair();
+        let stream_id = server.open_uni().unwrap();
+        
+        let packet = server.create_short_packet(vec![Frame::Stream {
+            stream_id,
+            offset: DEFAULT_STREAM_WINDOW,
+            data: Bytes::from_static(b"x"),
+            fin: false,
+        }]).unwrap();
+        assert!(matches!(client.receive_packet(&packet), Err(ConnectionError::FlowControl)));
+        assert_eq!(close_error_code(&mut client, &mut server), Some(FLOW_CONTROL_ERROR));
+    }
+
+    #[test]
+    fn test_stream_data_beyond_connection_limit_rejected() {
+        let (mut client, mut server) = established_pair();
+        
+        // Each stream stays within its own window, but together they exceed the connection's
+        let streams = DEFAULT_CONNECTION_WINDOW / DEFAULT_STREAM_WINDOW + 1;
+        let frames = (0..streams).map(|_| Frame::Stream {
+            stream_id: server.open_uni().unwrap(),
+            offset: DEFAULT_STREAM_WINDOW - 1,
+            data: Bytes::from_static(b"x"),
+            fin: false,
+        }).collect();
+        let packet = server.create_short_packet(frames).unwrap();
+        assert!(matches!(client.receive_packet(&packet), Err(ConnectionError::FlowControl)));
+    }
+
+    #[test]
+    fn test_stream_frame_on_own_unidirectional_stream_rejected() {
+        let (mut client, mut server) = established_pair();
+        let stream_id = client.open_uni().unwrap();
+        
+        let packet = server.create_short_packet(vec![Frame::Stream {
+            stream_id,
+            offset: 0,
+            data: Bytes::from_static(b"not allowed"),
+            fin: false,
+        }]).unwrap();
+        assert!(matches!(client.receive_packet(&packet), Err(ConnectionError::StreamState)));
+    }
+
+    #[test]
+    fn test_stream_aborted_in_both_directions() {
+        let (mut client, mut server) = established_pair();
+        let stream_id = client.open_bi().unwrap();
+        client.write(stream_id, b"request").unwrap();
+        transfer(&mut client, &mut server);
+        assert_eq!(server.accept_stream(), Some(stream_id));
+        
+        // The server stops reading and resets its own half with a different code
+        server.stop_sending(stream_id, 0x10).unwrap();
+        server.reset_stream(stream_id, 0x20).unwrap();
+        let frames = transfer(&mut server, &mut client);
+        assert!(frames.iter().any(|frame| matches!(frame, Frame::StopSending { error_code: 0x10, .. })));
+        assert!(frames.iter().any(|frame| matches!(frame, Frame::ResetStream { error_code: 0x20, final_size: 0, .. })));
+        
+        assert_eq!(client.read(stream_id, &mut [0u8; 16]), Err(StreamError::Reset(0x20)));
+        assert_eq!(client.write(stream_id, b"more"), Err(StreamError::Stopped(0x10)));
+        
+        // The client's answering RESET_STREAM closes the stream on both sides
+        let frames = transfer(&mut client, &mut server);
+        assert!(frames.iter().any(|frame| matches!(frame, Frame::ResetStream { error_code: 0x10, final_size: 7, .. })));
+        assert_eq!(client.send_state(stream_id), None);
+        assert_eq!(client.recv_state(stream_id), None);
+        assert_eq!(server.recv_state(stream_id), None);
+    }
+
+    #[test]
+    fn test_reset_beyond_stream_limit_rejected() {
+        let (mut client, mut server) = established_pair();
+        let stream_id = server.open_uni().unwrap();
         
-        assert_eq!(conn.next_packet_number(), 0);
-        assert_eq!(conn.next_packet_number(), 1);
-        assert_eq!(conn.next_packet_number(), 2);
-        assert_eq!(conn.packet_number, 3);
+        let packet = server.create_short_packet(vec![Frame::ResetStream {
+            stream_id,
+            error_code: 0,
+            final_size: DEFAULT_STREAM_WINDOW + 1,
+        }]).unwrap();
+        assert!(matches!(client.receive_packet(&packet), Err(ConnectionError::FlowControl)));
     }
 
     #[test]
-    fn test_connection_state_transitions() {
-        let mut conn = Connection::new_client(get_test_addr());
+    fn test_handshake_packets_acknowledged() {
+        let tls_config = Arc::new(TlsConfig::new().unwrap());
+        let mut client = Connection::new_client(get_test_addr());
+        client.setup_tls(tls_config.clone()).unwrap();
         
-        assert_eq!(conn.state, ConnectionState::Initial);
-        assert!(!conn.is_closed());
+        let client_hello = client.start_tls_handshake().unwrap();
+        let mut server = Connection::accept(get_test_addr(), client.local_conn_id.clone(), client.original_dest_conn_id.clone());
+        server.setup_tls(tls_config).unwrap();
         
-        conn.handle_state_transition(ConnectionState::Handshake);
-        assert_eq!(conn.state, ConnectionState::Handshake);
-        assert!(!conn.is_closed());
+        // The server's Initial acknowledges the ClientHello
+        let server_flight = deliver_handshake(&mut server, vec![client_hello]);
+        deliver_handshake(&mut client, server_flight);
+        assert_eq!(client.largest_acked_packet_number(EncryptionLevel::Initial), Some(0));
+        assert_eq!(server.largest_acked_packet_number(EncryptionLevel::Initial), None);
+    }
+
+    #[test]
+    fn test_ack_frame_only_after_ack_eliciting_packet() {
+        let mut client = Connection::new_client(get_test_addr());
+        let mut server = Connection::accept(get_test_addr(), client.local_conn_id.clone(), client.original_dest_conn_id.clone());
         
-        conn.handle_state_transition(ConnectionState::Established);
-        assert_eq!(conn.state, ConnectionState::Established);
-        assert!(!conn.is_closed());
+        let padding = client.create_initial_packet(vec![Frame::Padding { length: 1 }]).unwrap();
+        server.receive_packet(&padding).unwrap();
+        assert!(server.ack_frame(EncryptionLevel::Initial).is_none());
         
-        conn.handle_state_transition(ConnectionState::Closing);
-        assert_eq!(conn.state, ConnectionState::Closing);
-        assert!(!conn.is_closed());
+        let ping = client.create_initial_packet(vec![Frame::Ping]).unwrap();
+        server.receive_packet(&ping).unwrap();
+        match server.ack_frame(EncryptionLevel::Initial) {
+            Some(Frame::Ack { largest, ranges, .. }) => {
+                assert_eq!(largest, 1);
+                assert_eq!(ranges, vec![0..=1]);
+            }
+            other => panic!("Expected Ack frame, got {:?}", other),
+        }
+        assert!(server.ack_frame(EncryptionLevel::Initial).is_none());
+        assert!(server.ack_frame(EncryptionLevel::Handshake).is_none());
+    }
+
+    #[test]
+    fn test_received_ranges_merge_and_prune() {
+        let mut received = ReceivedRanges::default();
+        for packet_number in [5, 3, 4, 9, 0, 4] {
+            received.insert(packet_number);
+        }
+        assert_eq!(received.ack_ranges(), vec![9..=9, 3..=5, 0..=0]);
+
+        assert!(received.contains(4));
+        assert!(!received.contains(7));
+
+        received.remove_below(4);
+        assert_eq!(received.ack_ranges(), vec![9..=9, 4..=5]);
+        // Whatever was forgotten counts as already received
+        assert!(received.contains(1));
+        assert!(!received.contains(6));
+    }
+
+    #[test]
+    fn test_duplicate_packet_rejected() {
+        let (mut client, mut server) = established_pair();
+        let packet = client.create_short_packet(vec![Frame::Ping]).unwrap();
+        assert!(matches!(server.receive_packet(&packet).unwrap().frames[0], Frame::Ping));
+        assert!(matches!(server.receive_packet(&packet), Err(ConnectionError::DuplicatePacket)));
+        // A replay is dropped without closing the connection
+        assert_eq!(server.state, ConnectionState::Established);
+    }
+
+    #[test]
+    fn test_packets_below_kept_ranges_rejected() {
+        let (mut client, mut server) = established_pair();
+        let packets: Vec<Vec<u8>> = (0..2 * MAX_ACK_RANGES + 3)
+            .map(|_| client.create_short_packet(vec![Frame::Ping]).unwrap())
+            .collect();
+        // Every other packet arrives, so the earliest ranges are dropped
+        for packet in packets.iter().skip(1).step_by(2) {
+            server.receive_packet(packet).unwrap();
+        }
+        assert!(matches!(server.receive_packet(&packets[0]), Err(ConnectionError::DuplicatePacket)));
+        // Packets that never arrived above the floor are still accepted
+        server.receive_packet(packets.last().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_ack_frame_keeps_highest_ranges() {
+        let (mut client, mut server) = established_pair();
+        // Every other packet is lost, leaving a gap before each one that arrives
+        for i in 0..2 * MAX_ACK_RANGES + 10 {
+            let packet = client.create_short_packet(vec![Frame::Ping]).unwrap();
+            if i % 2 == 1 {
+                server.receive_packet(&packet).unwrap();
+            }
+        }
+        let largest = server.largest_received_packet_number(EncryptionLevel::Application).unwrap();
+        match server.ack_frame(EncryptionLevel::Application) {
+            Some(Frame::Ack { ranges, .. }) => {
+                assert_eq!(ranges.len(), MAX_ACK_RANGES);
+                assert_eq!(ranges[0], largest..=largest);
+            }
+            other => panic!("Expected Ack frame, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_acknowledged_ack_stops_reporting_ranges() {
+        let (mut client, mut server) = established_pair();
+        for _ in 0..3 {
+            let packet = client.create_short_packet(vec![Frame::Ping]).unwrap();
+            server.receive_packet(&packet).unwrap();
+        }
+        let ack = server.ack_frame(EncryptionLevel::Application).unwrap();
+        let Frame::Ack { largest, .. } = ack else { unreachable!() };
+
+        // Once the packet carrying the ACK is itself acknowledged, the ranges below it are dropped
+        let packet = server.create_short_packet(vec![ack, Frame::Ping]).unwrap();
+        client.receive_packet(&packet).unwrap();
+        transfer(&mut client, &mut server);
+        let packet = client.create_short_packet(vec![Frame::Ping]).unwrap();
+        server.receive_packet(&packet).unwrap();
+        match server.ack_frame(EncryptionLevel::Application) {
+            Some(Frame::Ack { ranges, .. }) => assert!(ranges.iter().all(|range| *range.start() >= largest)),
+            other => panic!("Expected Ack frame, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_lost_server_flight_recovered_by_pto() {
+        let tls_config = Arc::new(TlsConfig::new().unwrap());
+        let mut client = Connection::new_client(get_test_addr());
+        client.setup_tls(tls_config.clone()).unwrap();
         
-        conn.handle_state_transition(ConnectionState::Closed);
-        assert_eq!(conn.state, ConnectionState::Closed);
-        assert!(conn.is_closed());
+        let client_hello = client.start_tls_handshake().unwrap();
+        let mut server = Connection::accept(get_test_addr(), client.local_conn_id.clone(), client.original_dest_conn_id.clone());
+        server.setup_tls(tls_config).unwrap();
+        
+        // The whole server flight is dropped, so only probes can get the handshake going again
+        deliver_handshake(&mut server, vec![client_hello]);
+        for _ in 0..8 {
+            if client.state == ConnectionState::Established {
+                break;
+            }
+            let timeout = server.poll_timeout().expect("PTO armed for the server flight");
+            server.handle_timeout(timeout);
+            let packets = server.poll_packets().unwrap();
+            assert!(!packets.is_empty());
+            let responses = deliver_handshake(&mut client, server.coalesce_packets(packets));
+            deliver_handshake(&mut server, responses);
+        }
+        assert_eq!(client.state, ConnectionState::Established);
+        assert_eq!(server.state, ConnectionState::Established);
     }
 
     #[test]
-    fn test_create_initial_packet() {
-        let mut conn = Connection::new_client(get_test_addr());
-        conn.remote_conn_id = Some(ConnectionId::new(vec![5, 6, 7, 8]));
+    fn test_lost_client_hello_recovered_by_pto() {
+        let tls_config = Arc::new(TlsConfig::new().unwrap());
+        let mut client = Connection::new_client(get_test_addr());
+        client.setup_tls(tls_config.clone()).unwrap();
+        client.start_tls_handshake().unwrap();
         
-        let frames = vec![Frame::Ping];
-        let packet = conn.create_initial_packet(frames).unwrap();
+        let timeout = client.poll_timeout().expect("PTO armed for the ClientHello");
+        client.handle_timeout(timeout);
+        assert_eq!(client.recovery.pto_count(), 1);
+        let packets = client.poll_packets().unwrap();
+        let retransmitted = client.coalesce_packets(packets);
         
-        assert!(!packet.is_empty());
-        assert_eq!(conn.packet_number, 1); // Should increment
+        let mut server = Connection::accept(get_test_addr(), client.local_conn_id.clone(), client.original_dest_conn_id.clone());
+        server.setup_tls(tls_config).unwrap();
+        let server_flight = deliver_handshake(&mut server, retransmitted);
+        let client_finished = deliver_handshake(&mut client, server_flight);
+        deliver_handshake(&mut server, client_finished);
+        assert_eq!(server.state, ConnectionState::Established);
     }
 
     #[test]
-    fn test_create_handshake_packet() {
+    fn test_handshake_done_confirms_client() {
+        let tls_config = Arc::new(TlsConfig::new().unwrap());
+        let mut client = Connection::new_client(get_test_addr());
+        client.setup_tls(tls_config.clone()).unwrap();
+        
+        let client_hello = client.start_tls_handshake().unwrap();
+        let mut server = Connection::accept(get_test_addr(), client.local_conn_id.clone(), client.original_dest_conn_id.clone());
+        server.setup_tls(tls_config).unwrap();
+        let server_flight = deliver_handshake(&mut server, vec![client_hello]);
+        let client_finished = deliver_handshake(&mut client, server_flight);
+        
+        // The server confirms as soon as it completes, the client only once it hears about it
+        let handshake_done = deliver_handshake(&mut server, client_finished);
+        assert!(server.recovery.is_handshake_confirmed());
+        assert!(!client.recovery.is_handshake_confirmed());
+        deliver_handshake(&mut client, handshake_done);
+        assert!(client.recovery.is_handshake_confirmed());
+        assert!(client.is_discarded(EncryptionLevel::Initial));
+        assert!(client.is_discarded(EncryptionLevel::Handshake));
+        assert!(server.is_discarded(EncryptionLevel::Handshake));
+    }
+
+    #[test]
+    fn test_handshake_done_from_client_rejected() {
+        let (mut client, mut server) = established_pair();
+        let packet = client.create_short_packet(vec![Frame::HandshakeDone]).unwrap();
+        assert!(matches!(server.receive_packet(&packet), Err(ConnectionError::ProtocolViolation)));
+        assert_eq!(close_error_code(&mut server, &mut client), Some(PROTOCOL_VIOLATION));
+    }
+
+    #[test]
+    fn test_ack_of_unsent_packet_rejected() {
+        let (mut client, mut server) = established_pair();
+        let largest_acked = client.largest_acked_packet_number(EncryptionLevel::Application);
+        let forged = Frame::Ack { largest: 1_000_000, delay: 0, ranges: vec![0..=1_000_000], ecn: None };
+        let packet = server.create_short_packet(vec![forged]).unwrap();
+        assert!(matches!(client.receive_packet(&packet), Err(ConnectionError::ProtocolViolation)));
+        assert_eq!(client.largest_acked_packet_number(EncryptionLevel::Application), largest_acked);
+
+        // Packet numbers are still encoded against what was really acknowledged
+        assert!(client.create_short_packet(vec![Frame::Ping]).is_ok());
+    }
+
+    #[test]
+    fn test_lost_stream_data_retransmitted_after_ack() {
+        let (mut client, mut server) = established_pair();
+        let stream_id = client.open_uni().unwrap();
+        
+        // The first packet is lost and the three after it arrive, which is enough to declare it lost
+        let mut packets = Vec::new();
+        for chunk in [b"one ", b"two ", b"six ", b"ten "] {
+            client.write(stream_id, chunk).unwrap();
+            packets.extend(client.poll_packets().unwrap());
+        }
+        assert_eq!(packets.len(), 4);
+        for packet in &packets[1..] {
+            server.receive_packet(packet).unwrap();
+        }
+        transfer(&mut server, &mut client);
+        
+        client.finish(stream_id).unwrap();
+        transfer(&mut client, &mut server);
+        assert_eq!(read_to_end(&mut server, stream_id), b"one two six ten ");
+        
+        transfer(&mut server, &mut client);
+        assert_eq!(client.send_state(stream_id), None);
+        // Nothing is left to recover, so only the idle timer is armed
+        assert_eq!(client.recovery.timeout(), None);
+        assert_eq!(client.poll_timeout(), Some(client.idle_timer_start + client.idle_timeout().unwrap()));
+    }
+
+    #[test]
+    fn test_lost_stream_data_retransmitted_after_pto() {
+        let (mut client, mut server) = established_pair();
+        
+        let stream_id = client.open_bi().unwrap();
+        client.write(stream_id, b"lost once").unwrap();
+        client.finish(stream_id).unwrap();
+        assert!(!client.poll_packets().unwrap().is_empty());
+        
+        let timeout = client.poll_timeout().expect("PTO armed for the stream data");
+        client.handle_timeout(timeout);
+        transfer(&mut client, &mut server);
+        assert_eq!(read_to_end(&mut server, stream_id), b"lost once");
+    }
+
+    #[test]
+    fn test_rtt_measured_from_acks() {
+        let (mut client, mut server) = established_pair();
+        let stats = client.stats();
+        assert!(stats.latest_rtt.is_some());
+        assert!(stats.min_rtt <= stats.latest_rtt);
+        assert!(stats.smoothed_rtt < INITIAL_RTT);
+        
+        // Data in flight is counted until the peer acknowledges it
+        let stream_id = client.open_uni().unwrap();
+        client.write(stream_id, b"measure me").unwrap();
+        transfer(&mut client, &mut server);
+        assert!(client.stats().bytes_in_flight > 0);
+        transfer(&mut server, &mut client);
+        assert_eq!(client.stats().bytes_in_flight, 0);
+    }
+
+    #[test]
+    fn test_peer_ack_delay_exponent_applied() {
+        let (mut client, _server) = established_pair();
+        client.peer_transport_parameters.as_mut().unwrap().ack_delay_exponent = Some(10);
+        client.recovery = Recovery::new();
+        let ms = Duration::from_millis(1);
+        
+        let now = Instant::now();
+        client.recovery.on_packet_sent(EncryptionLevel::Application, SentPacket::new(1000, now - 100 * ms, 50, &[Frame::Ping]));
+        client.on_ack_received(EncryptionLevel::Application, &[1000..=1000], 0);
+        
+        // 48 << 10 microseconds of ack delay take the 150ms sample back to about 100ms
+        let now = Instant::now();
+        client.recovery.on_packet_sent(EncryptionLevel::Application, SentPacket::new(1001, now - 150 * ms, 50, &[Frame::Ping]));
+        client.on_ack_received(EncryptionLevel::Application, &[1001..=1001], 48);
+        let stats = client.stats();
+        assert!(stats.latest_rtt.unwrap() >= 150 * ms);
+        assert!(stats.smoothed_rtt < 102 * ms);
+    }
+
+    #[test]
+    fn test_sending_gated_by_congestion_window() {
+        let (mut client, mut server) = established_pair();
+        let data: Vec<u8> = (0..40_000u32).map(|i| i as u8).collect();
+        let stream_id = client.open_uni().unwrap();
+        client.write(stream_id, &data).unwrap();
+        client.finish(stream_id).unwrap();
+        
+        // The handshake's acknowledgements have already grown the window past its initial size
+        let window = client.stats().congestion_window;
+        assert!(window >= initial_window(client.max_datagram_size));
+        transfer(&mut client, &mut server);
+        let in_flight = client.stats().bytes_in_flight;
+        assert!(in_flight >= window && in_flight < window + client.max_datagram_size);
+        assert!(client.poll_packets().unwrap().is_empty());
+        
+        // Each round of acknowledgements opens the window for more, growing it in slow start
+        for _ in 0..8 {
+            transfer(&mut server, &mut client);
+            transfer(&mut client, &mut server);
+        }
+        assert!(client.stats().congestion_window > window);
+        assert_eq!(read_to_end(&mut server, stream_id), data);
+    }
+
+    #[test]
+    fn test_loss_shrinks_congestion_window() {
+        let (mut client, mut server) = established_pair();
+        let window = client.stats().congestion_window;
+        let stream_id = client.open_uni().unwrap();
+        
+        let mut packets = Vec::new();
+        for chunk in [b"one ", b"two ", b"six ", b"ten "] {
+            client.write(stream_id, chunk).unwrap();
+            packets.extend(client.poll_packets().unwrap());
+        }
+        for packet in &packets[1..] {
+            server.receive_packet(packet).unwrap();
+        }
+        transfer(&mut server, &mut client);
+        assert!(client.stats().congestion_window < window);
+    }
+
+    #[test]
+    fn test_cubic_selected_by_transport_config() {
+        let config = TransportConfig { congestion_control: CongestionControl::Cubic, ..TransportConfig::default() };
+        let (mut client, mut server) = established_pair_with_server_config(config);
+        let window = server.stats().congestion_window;
+        let stream_id = server.open_uni().unwrap();
+        
+        let mut packets = Vec::new();
+        for chunk in [b"one ", b"two ", b"six ", b"ten "] {
+            server.write(stream_id, chunk).unwrap();
+            packets.extend(server.poll_packets().unwrap());
+        }
+        for packet in &packets[1..] {
+            client.receive_packet(packet).unwrap();
+        }
+        transfer(&mut client, &mut server);
+        
+        // CUBIC backs off to 70% of the window where NewReno would halve it
+        let reduced = server.stats().congestion_window;
+        assert!(reduced < window);
+        assert!(reduced * 10 >= window * 7);
+    }
+
+    #[test]
+    fn test_bbr_selected_by_transport_config() {
+        let config = TransportConfig { congestion_control: CongestionControl::Bbr, ..TransportConfig::default() };
+        let (mut client, mut server) = established_pair_with_server_config(config);
+        assert!(format!("{:?}", server.recovery.congestion_controller()).starts_with("Bbr"));
+        
+        let stream_id = server.open_uni().unwrap();
+        server.write(stream_id, b"paced by a model").unwrap();
+        server.finish(stream_id).unwrap();
+        for packet in server.poll_packets().unwrap() {
+            client.receive_packet(&packet).unwrap();
+        }
+        transfer(&mut client, &mut server);
+        let mut buf = [0u8; 64];
+        assert_eq!(client.read(stream_id, &mut buf).unwrap(), 16);
+        assert_eq!(server.stats().bytes_in_flight, 0);
+    }
+
+    #[test]
+    fn test_datagrams_paced_after_burst() {
+        let config = TransportConfig { pacing_burst: 2, ..TransportConfig::default() };
+        let (mut client, mut server) = established_pair_with_server_config(config);
+        let stream_id = server.open_uni().unwrap();
+        server.write(stream_id, &[7; 6000]).unwrap();
+        let packets = server.poll_packets().unwrap();
+        let datagrams = server.coalesce_packets(packets);
+        let count = datagrams.len();
+        assert!(count > 2);
+        
+        let now = Instant::now();
+        server.pace(datagrams);
+        assert_eq!(server.next_send_time(now), Some(now));
+        let mut released = 0;
+        while let Some(datagram) = server.poll_transmit(now) {
+            released += 1;
+            for packet in packet::split_datagram(&datagram) {
+                client.receive_packet(packet.unwrap()).unwrap();
+            }
+        }
+        assert_eq!(released, 2);
+        
+        // The rest leaves at the congestion controller's pacing rate
+        let next = server.next_send_time(now).unwrap();
+        let interval = Duration::from_secs_f64(server.max_datagram_size as f64 / server.pacing_rate() as f64);
+        assert!(next > now && next <= now + interval);
+        let mut later = next;
+        while let Some(next) = server.next_send_time(later) {
+            later = next;
+            while server.poll_transmit(later).is_some() {
+                released += 1;
+            }
+        }
+        assert_eq!(released, count);
+    }
+
+    #[test]
+    fn test_idle_timeout_negotiated_as_smaller_value() {
+        let client_config = TransportConfig { max_idle_timeout: Some(Duration::from_secs(10)), ..TransportConfig::default() };
+        let server_config = TransportConfig { max_idle_timeout: Some(Duration::from_secs(5)), ..TransportConfig::default() };
+        let (client, server) = established_pair_with_configs(client_config, server_config);
+        assert_eq!(client.idle_timeout(), Some(Duration::from_secs(5)));
+        assert_eq!(server.idle_timeout(), Some(Duration::from_secs(5)));
+        
+        // A side that disables the timeout still honours the peer's
+        let client_config = TransportConfig { max_idle_timeout: None, ..TransportConfig::default() };
+        let (client, server) = established_pair_with_configs(client_config.clone(), TransportConfig::default());
+        assert_eq!(client.idle_timeout(), Some(DEFAULT_MAX_IDLE_TIMEOUT));
+        assert_eq!(server.idle_timeout(), Some(DEFAULT_MAX_IDLE_TIMEOUT));
+        let (client, server) = established_pair_with_configs(client_config.clone(), client_config);
+        assert_eq!(client.idle_timeout(), None);
+        assert_eq!(server.idle_timeout(), None);
+    }
+
+    #[test]
+    fn test_idle_timeout_never_below_three_ptos() {
+        let config = TransportConfig { max_idle_timeout: Some(Duration::from_millis(1)), ..TransportConfig::default() };
         let mut conn = Connection::new_client(get_test_addr());
-        conn.remote_conn_id = Some(ConnectionId::new(vec![5, 6, 7, 8]));
+        conn.set_transport_config(config);
+        assert_eq!(conn.idle_timeout(), Some(3 * conn.recovery.probe_timeout()));
+    }
+
+    #[test]
+    fn test_idle_timeout_closes_silently() {
+        let config = TransportConfig { max_idle_timeout: Some(Duration::from_secs(5)), ..TransportConfig::default() };
+        let (mut client, mut server) = established_pair_with_configs(config.clone(), config);
+        transfer(&mut client, &mut server);
+        transfer(&mut server, &mut client);
+        let deadline = server.poll_timeout().unwrap();
+        assert_eq!(deadline, server.idle_timer_start + Duration::from_secs(5));
         
-        let frames = vec![Frame::Ping];
-        let packet = conn.create_handshake_packet(frames).unwrap();
+        server.handle_timeout(deadline - Duration::from_millis(1));
+        assert!(!server.is_closed());
+        server.handle_timeout(deadline);
+        assert!(server.is_closed());
+        assert_eq!(server.poll_timeout(), None);
+        // Nothing tells the peer, which finds out through its own idle timer
+        assert!(server.poll_packets().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_received_packet_restarts_idle_timer() {
+        let config = TransportConfig { max_idle_timeout: Some(Duration::from_secs(5)), ..TransportConfig::default() };
+        let (mut client, mut server) = established_pair_with_configs(config.clone(), config);
+        let before = server.idle_timer_start;
+        std::thread::sleep(Duration::from_millis(2));
         
-        assert!(!packet.is_empty());
-        assert_eq!(conn.packet_number, 1); // Should increment
+        let stream_id = client.open_uni().unwrap();
+        client.write(stream_id, b"still here").unwrap();
+        transfer(&mut client, &mut server);
+        assert!(server.idle_timer_start > before);
+        assert!(server.poll_timeout().unwrap() > before + Duration::from_secs(5));
     }
 
     #[test]
-    fn test_close_connection() {
+    fn test_keep_alive_sends_ping_before_idle_timeout() {
+        let client_config = TransportConfig {
+            max_idle_timeout: Some(Duration::from_secs(5)),
+            keep_alive_interval: Some(Duration::from_secs(2)),
+            ..TransportConfig::default()
+        };
+        let (mut client, mut server) = established_pair_with_configs(client_config, TransportConfig::default());
+        transfer(&mut client, &mut server);
+        transfer(&mut server, &mut client);
+        
+        let keep_alive = client.poll_timeout().unwrap();
+        assert_eq!(keep_alive, client.last_activity + Duration::from_secs(2));
+        client.handle_timeout(keep_alive);
+        assert!(!client.is_closed());
+        let frames = transfer(&mut client, &mut server);
+        assert!(frames.iter().any(|frame| matches!(frame, Frame::Ping)));
+        
+        // The PING's acknowledgement restarts the client's idle timer
+        let idle_start = client.idle_timer_start;
+        std::thread::sleep(Duration::from_millis(2));
+        transfer(&mut server, &mut client);
+        assert!(client.idle_timer_start > idle_start);
+    }
+
+    #[test]
+    fn test_no_keep_alive_before_handshake() {
+        let config = TransportConfig { keep_alive_interval: Some(Duration::from_millis(10)), ..TransportConfig::default() };
         let mut conn = Connection::new_client(get_test_addr());
-        conn.remote_conn_id = Some(ConnectionId::new(vec![1, 2, 3, 4]));
+        conn.set_transport_config(config);
+        assert_eq!(conn.poll_timeout(), Some(conn.idle_timer_start + DEFAULT_MAX_IDLE_TIMEOUT));
+    }
+
+    #[test]
+    fn test_manager_reaps_idle_connections() {
+        let mut manager = ConnectionManager::new();
+        let config = TransportConfig { max_idle_timeout: Some(Duration::from_secs(5)), ..TransportConfig::default() };
+        let (mut client, mut server) = established_pair_with_configs(config.clone(), config);
+        transfer(&mut client, &mut server);
+        transfer(&mut server, &mut client);
+        let deadline = server.poll_timeout().unwrap();
+        manager.add_connection(vec![1], server);
+        manager.add_connection(vec![2], Connection::new_client(get_test_addr()));
         
-        let close_packet = conn.close("Test close".to_string()).unwrap();
+        assert!(manager.handle_timeouts(deadline).datagrams.is_empty());
+        assert_eq!(manager.reap_closed(), vec![vec![1]]);
+        assert!(manager.get_connection(&[1]).is_none());
+        assert!(manager.get_connection(&[2]).is_some());
+        assert!(manager.reap_closed().is_empty());
+    }
+
+    #[test]
+    fn test_failing_connection_does_not_stop_other_timers() {
+        let mut manager = ConnectionManager::new();
+        let mut deadlines = Vec::new();
+        for conn_id in 1..=2u8 {
+            let (mut client, _server) = established_pair();
+            // A lost PING leaves the probe timer armed
+            client.create_short_packet(vec![Frame::Ping]).unwrap();
+            deadlines.push(client.poll_timeout().unwrap());
+            manager.add_connection(vec![conn_id], client);
+        }
+        // The first connection's next packet cannot be encoded
+        let broken = Frame::Ack { largest: 10, delay: 0, ranges: vec![8..=10, 9..=9], ecn: None };
+        manager.get_connection(&[1]).unwrap().requeue(EncryptionLevel::Application, [broken]);
+
+        let now = deadlines.into_iter().max().unwrap();
+        let output = manager.handle_timeouts(now);
+        assert!(matches!(output.errors[..], [(ref conn_id, ConnectionError::FrameEncoding)] if *conn_id == vec![1]));
+        // The failing connection tells its peer and waits out its closing period; the other still probes
+        assert_eq!(output.datagrams.len(), 2);
+        assert_eq!(manager.get_connection(&[1]).unwrap().state, ConnectionState::Closing);
+        assert_eq!(manager.get_connection(&[2]).unwrap().state, ConnectionState::Established);
+    }
+
+    #[test]
+    fn test_close_uses_highest_level_once_confirmed() {
+        let (mut client, mut server) = established_pair();
+        assert!(client.recovery.is_handshake_confirmed());
+        let datagram = client.close("done".to_string()).unwrap();
+        // A single 1-RTT packet, with its short header
+        assert_eq!(packet::split_datagram(&datagram).count(), 1);
+        assert_eq!(datagram[0] & 0x80, 0);
         
-        assert!(!close_packet.is_empty());
-        assert_eq!(conn.state, ConnectionState::Closing);
+        let received = server.receive_packet(&datagram).unwrap();
+        assert!(matches!(&received.frames[0], Frame::ConnectionClose { reason, .. } if reason == "done"));
+        assert_eq!(server.state, ConnectionState::Draining);
+    }
+
+    #[test]
+    fn test_application_close_drains() {
+        let (mut client, mut server) = established_pair();
+        let close = Frame::ApplicationClose { error_code: 0x42, reason: "app done".to_string() };
+        let datagram = client.create_short_packet(vec![close]).unwrap();
+        
+        let received = server.receive_packet(&datagram).unwrap();
+        assert!(matches!(&received.frames[0], Frame::ApplicationClose { error_code: 0x42, .. }));
+        assert_eq!(server.state, ConnectionState::Draining);
+    }
+
+    #[test]
+    fn test_close_before_confirmation_covers_every_level() {
+        let tls_config = Arc::new(TlsConfig::new().unwrap());
+        let mut client = Connection::new_client(get_test_addr());
+        client.setup_tls(tls_config.clone()).unwrap();
+        let client_hello = client.start_tls_handshake().unwrap();
+        let mut server = Connection::accept(get_test_addr(), client.local_conn_id.clone(), client.original_dest_conn_id.clone());
+        server.setup_tls(tls_config).unwrap();
+        deliver_handshake(&mut server, vec![client_hello]);
+        
+        // The client may not have the server's Handshake or 1-RTT keys yet
+        let datagram = server.close("abort".to_string()).unwrap();
+        let levels: Vec<EncryptionLevel> = packet::split_datagram(&datagram)
+            .map(|packet| ProtectedHeader::parse_with_conn_id_len(packet.unwrap(), 8).unwrap().packet_type.encryption_level())
+            .collect();
+        assert_eq!(levels, vec![EncryptionLevel::Initial, EncryptionLevel::Handshake, EncryptionLevel::Application]);
+        let received = client.receive_packet(packet::split_datagram(&datagram).next().unwrap().unwrap()).unwrap();
+        assert!(matches!(received.frames[0], Frame::ConnectionClose { .. }));
+        assert_eq!(client.state, ConnectionState::Draining);
+    }
+
+    #[test]
+    fn test_closing_replies_are_rate_limited() {
+        let (mut client, mut server) = established_pair();
+        let datagram = client.close("bye".to_string()).unwrap();
+        assert_eq!(client.close("again".to_string()).unwrap(), datagram);
+        
+        // The server has not seen the close and keeps sending
+        let stream_id = server.open_uni().unwrap();
+        let now = Instant::now();
+        let mut replies = Vec::new();
+        for _ in 0..8 {
+            server.write(stream_id, b"are you there?").unwrap();
+            for packet in server.poll_packets().unwrap() {
+                client.receive_packet(&packet).unwrap();
+            }
+            while let Some(reply) = client.poll_transmit(now) {
+                replies.push(reply);
+            }
+        }
+        // Replies after the 1st, 2nd, 4th and 8th packets, each a copy of the original close
+        assert_eq!(replies.len(), 4);
+        assert!(replies.iter().all(|reply| *reply == datagram));
+        assert_eq!(client.read(stream_id, &mut [0u8; 64]).ok(), None);
+    }
+
+    #[test]
+    fn test_closing_sends_nothing_else_and_ends_after_three_ptos() {
+        let (mut client, mut server) = established_pair();
+        let stream_id = client.open_uni().unwrap();
+        client.write(stream_id, b"never sent").unwrap();
+        let pto = client.recovery.probe_timeout();
+        let before = Instant::now();
+        client.close("bye".to_string()).unwrap();
+        assert!(client.poll_packets().unwrap().is_empty());
+        
+        let deadline = client.poll_timeout().unwrap();
+        assert!(deadline >= before + 3 * pto && deadline <= Instant::now() + 3 * pto);
+        client.handle_timeout(deadline - Duration::from_millis(1));
+        assert_eq!(client.state, ConnectionState::Closing);
+        client.handle_timeout(deadline);
+        assert!(client.is_closed());
+        assert_eq!(client.poll_timeout(), None);
+        
+        // Packets arriving after the closing period are refused
+        let stream_id = server.open_uni().unwrap();
+        server.write(stream_id, b"late").unwrap();
+        let late = server.poll_packets().unwrap();
+        assert!(matches!(client.receive_packet(&late[0]), Err(ConnectionError::InvalidState)));
+    }
+
+    #[test]
+    fn test_peer_close_while_closing_starts_draining() {
+        let (mut client, mut server) = established_pair();
+        let client_close = client.close("client".to_string()).unwrap();
+        let server_close = server.close("server".to_string()).unwrap();
+        client.receive_packet(&server_close).unwrap();
+        server.receive_packet(&client_close).unwrap();
+        assert_eq!(client.state, ConnectionState::Draining);
+        assert_eq!(server.state, ConnectionState::Draining);
+        
+        // Draining keeps the closing period's deadline and sends nothing
+        let deadline = client.poll_timeout().unwrap();
+        assert!(client.poll_transmit(deadline).is_none());
+        assert!(client.close("again".to_string()).is_err());
+        client.handle_timeout(deadline);
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn test_manager_frees_connection_after_draining() {
+        let mut manager = ConnectionManager::new();
+        let (mut client, mut server) = established_pair();
+        server.receive_packet(&client.close("bye".to_string()).unwrap()).unwrap();
+        let deadline = server.poll_timeout().unwrap();
+        manager.add_connection(vec![1], server);
+        
+        assert!(manager.handle_timeouts(deadline - Duration::from_millis(1)).datagrams.is_empty());
+        assert!(manager.reap_closed().is_empty());
+        assert!(manager.get_connection(&[1]).is_some());
+        manager.handle_timeouts(deadline);
+        assert_eq!(manager.reap_closed(), vec![vec![1]]);
+    }
+
+    /// Controller with a window that never changes
+    #[derive(Debug)]
+    struct FixedWindow(usize);
+
+    impl CongestionController for FixedWindow {
+        fn on_ack(&mut self, _now: Instant, _sent: Instant, _bytes: usize, _rtt: &RttEstimator) {}
+        fn on_congestion_event(&mut self, _now: Instant, _sent: Instant, _persistent_congestion: bool) {}
+        fn window(&self) -> usize {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_custom_congestion_controller() {
+        let (mut client, mut server) = established_pair();
+        client.set_congestion_controller(Box::new(FixedWindow(2000)));
+        let stream_id = client.open_uni().unwrap();
+        client.write(stream_id, &[7u8; 10_000]).unwrap();
+        
+        assert_eq!(client.poll_packets().unwrap().len(), 2);
+        assert_eq!(client.stats().congestion_window, 2000);
+        
+        // Acknowledgements still go out while the window is full
+        server.open_uni().map(|id| server.write(id, b"reply")).unwrap().unwrap();
+        transfer(&mut server, &mut client);
+        let packets = client.poll_packets().unwrap();
+        assert_eq!(packets.len(), 1);
+        let frames = server.receive_packet(&packets[0]).unwrap().frames;
+        assert!(matches!(frames[..], [Frame::Ack { .. }]));
+    }
+
+    #[test]
+    fn test_retry_validates_address_before_handshake() {
+        let tls_config = Arc::new(TlsConfig::new().unwrap());
+        let mut manager = ConnectionManager::new();
+        manager.require_address_validation(AddressValidator::new());
+
+        let mut client = Connection::new_client(get_test_addr());
+        client.setup_tls(tls_config.clone()).unwrap();
+        let client_hello = client.start_tls_handshake().unwrap();
+
+        // No connection is created for the first Initial
+        let IncomingAction::Retry(retry) = manager.handle_incoming(&client_hello, get_test_addr()) else {
+            panic!("Expected a Retry");
+        };
+        let received = client.receive_packet(&retry).unwrap();
+        assert!(matches!(received.header, PacketHeader::Retry(_)));
+
+        // The retried Initial carries the token and is addressed to the Retry's connection ID
+        let client_hello = client.start_tls_handshake().unwrap();
+        let protected = ProtectedHeader::parse(&client_hello).unwrap();
+        assert!(!protected.token.is_empty());
+        assert_eq!(Some(protected.dest_conn_id), client.retry_src_conn_id.clone());
+
+        let IncomingAction::Accept(mut server) = manager.handle_incoming(&client_hello, get_test_addr()) else {
+            panic!("Expected the connection to be accepted");
+        };
+        assert_eq!(server.original_dest_conn_id, client.original_dest_conn_id);
+        server.setup_tls(tls_config).unwrap();
+
+        let server_flight = deliver_handshake(&mut server, vec![client_hello]);
+        let client_finished = deliver_handshake(&mut client, server_flight);
+        assert_eq!(client.state, ConnectionState::Established);
+        assert_eq!(
+            client.peer_transport_parameters.as_ref().unwrap().retry_source_connection_id,
+            client.retry_src_conn_id.clone()
+        );
+
+        deliver_handshake(&mut server, client_finished);
+        assert_eq!(server.state, ConnectionState::Established);
+    }
+
+    #[test]
+    fn test_short_initial_dropped_before_retry_or_accept() {
+        // Only client Initials are padded, so an Initial built by the server side stands in for an unpadded one
+        let client = Connection::new_client(get_test_addr());
+        let mut unpadded = Connection::accept(get_test_addr(), client.local_conn_id.clone(), client.original_dest_conn_id.clone());
+        let initial = unpadded.create_initial_packet(vec![Frame::Ping]).unwrap();
+        assert!(initial.len() < packet::DEFAULT_MAX_DATAGRAM_SIZE);
+
+        assert!(matches!(ConnectionManager::new().handle_incoming(&initial, get_test_addr()), IncomingAction::Drop));
+        let mut manager = ConnectionManager::new();
+        manager.require_address_validation(AddressValidator::new());
+        assert!(matches!(manager.handle_incoming(&initial, get_test_addr()), IncomingAction::Drop));
+    }
+
+    #[test]
+    fn test_retry_token_from_other_address_dropped() {
+        let mut manager = ConnectionManager::new();
+        manager.require_address_validation(AddressValidator::new());
+        let mut client = Connection::new_client(get_test_addr());
+
+        let initial = client.create_initial_packet(vec![Frame::Ping]).unwrap();
+        let IncomingAction::Retry(retry) = manager.handle_incoming(&initial, get_test_addr()) else {
+            panic!("Expected a Retry");
+        };
+        client.receive_packet(&retry).unwrap();
+
+        let initial = client.create_initial_packet(vec![Frame::Ping]).unwrap();
+        let spoofed: SocketAddr = "192.0.2.7:9999".parse().unwrap();
+        assert!(matches!(manager.handle_incoming(&initial, spoofed), IncomingAction::Drop));
+        assert!(matches!(manager.handle_incoming(&initial, get_test_addr()), IncomingAction::Accept(_)));
+    }
+
+    #[test]
+    fn test_client_accepts_one_authentic_retry() {
+        let mut manager = ConnectionManager::new();
+        manager.require_address_validation(AddressValidator::new());
+        let mut client = Connection::new_client(get_test_addr());
+
+        let initial = client.create_initial_packet(vec![Frame::Ping]).unwrap();
+        let IncomingAction::Retry(retry) = manager.handle_incoming(&initial, get_test_addr()) else {
+            panic!("Expected a Retry");
+        };
+
+        // An off-path attacker cannot compute the tag without the original DCID
+        let mut forged = retry.clone();
+        let last = forged.len() - 1;
+        forged[last] ^= 0x01;
+        assert!(matches!(client.receive_packet(&forged), Err(ConnectionError::DecryptionFailed)));
+
+        client.receive_packet(&retry).unwrap();
+        assert!(matches!(client.receive_packet(&retry), Err(ConnectionError::RetryIgnored)));
+    }
+
+    #[test]
+    fn test_handle_incoming_without_validation_accepts() {
+        let manager = ConnectionManager::new();
+        let mut client = Connection::new_client(get_test_addr());
+        let initial = client.create_initial_packet(vec![Frame::Ping]).unwrap();
+
+        let IncomingAction::Accept(mut server) = manager.handle_incoming(&initial, get_test_addr()) else {
+            panic!("Expected the connection to be accepted");
+        };
+        assert!(server.retry_src_conn_id.is_none());
+        assert!(server.receive_packet(&initial).is_ok());
+    }
+
+    #[test]
+    fn test_crypto_stream_reassembly() {
+        let mut stream = CryptoStream::default();
+        
+        assert!(stream.receive(3, Bytes::from_static(b"def")).is_empty());
+        assert_eq!(stream.receive(0, Bytes::from_static(b"abc")), b"abcdef");
+        // Retransmitted and overlapping data is only delivered once
+        assert!(stream.receive(0, Bytes::from_static(b"abc")).is_empty());
+        assert_eq!(stream.receive(4, Bytes::from_static(b"efgh")), b"gh");
     }
 
     #[test]
@@ -502,7 +3371,7 @@ mod tests {
 
     #[test]
     fn test_connection_states_enum() {
-        let states = vec![
+        let states = [
             ConnectionState::Initial,
             ConnectionState::Handshake,
             ConnectionState::Established,
@@ -526,6 +3395,7 @@ mod tests {
             ConnectionError::PacketEncoding,
             ConnectionError::FrameEncoding,
             ConnectionError::InvalidState,
+            ConnectionError::ProtocolViolation,
         ];
         
         for error in errors {
@@ -1,9 +1,65 @@
-use ring::aead::{Aad, Algorithm, LessSafeKey, Nonce, UnboundKey, AES_128_GCM};
-use ring::hkdf::{Prk, HKDF_SHA256};
+use ring::aead::quic::{self, HeaderProtectionKey};
+use ring::aead::{Aad, Algorithm, LessSafeKey, Nonce, UnboundKey, AES_128_GCM, AES_256_GCM, CHACHA20_POLY1305};
+use ring::hkdf::{KeyType, Prk, HKDF_SHA256};
 use bytes::{Bytes, BytesMut};
+use crate::packet::{QUIC_VERSION_1, QUIC_VERSION_DRAFT_29};
 use std::collections::HashMap;
 
-#[derive(Debug)]
+/// Salt used to derive QUIC version 1 Initial secrets (RFC 9001 Section 5.2)
+const INITIAL_SALT_V1: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17,
+    0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad, 0xcc, 0xbb, 0x7f, 0x0a,
+];
+
+/// Fixed key and nonce for the Retry Integrity Tag (RFC 9001 Section 5.8)
+const RETRY_INTEGRITY_KEY: [u8; 16] = [
+    0xbe, 0x0c, 0x69, 0x0b, 0x9f, 0x66, 0x57, 0x5a,
+    0x1d, 0x76, 0x6b, 0x54, 0xe3, 0x68, 0xc8, 0x4e,
+];
+const RETRY_INTEGRITY_NONCE: [u8; 12] = [
+    0x46, 0x15, 0x99, 0xd3, 0x5d, 0x63, 0x2b, 0xf2, 0x23, 0x98, 0x25, 0xbb,
+];
+
+/// Draft 29 counterparts of the version 1 salt, key and nonce (draft-ietf-quic-tls-29 Sections 5.2 and 5.8)
+const INITIAL_SALT_DRAFT_29: [u8; 20] = [
+    0xaf, 0xbf, 0xec, 0x28, 0x99, 0x93, 0xd2, 0x4c, 0x9e, 0x97,
+    0x86, 0xf1, 0x9c, 0x61, 0x11, 0xe0, 0x43, 0x90, 0xa8, 0x99,
+];
+const RETRY_INTEGRITY_KEY_DRAFT_29: [u8; 16] = [
+    0xcc, 0xce, 0x18, 0x7e, 0xd0, 0x9a, 0x09, 0xd0,
+    0x57, 0x28, 0x15, 0x5a, 0x6c, 0xb9, 0x6b, 0xe1,
+];
+const RETRY_INTEGRITY_NONCE_DRAFT_29: [u8; 12] = [
+    0xe5, 0x49, 0x30, 0xf9, 0x7f, 0x21, 0x36, 0xf0, 0x53, 0x0a, 0x8c, 0x1c,
+];
+
+/// Constants that differ between the QUIC versions in `SUPPORTED_VERSIONS`
+struct VersionSecrets {
+    initial_salt: &'static [u8; 20],
+    retry_key: &'static [u8; 16],
+    retry_nonce: &'static [u8; 12],
+}
+
+fn version_secrets(version: u32) -> Result<VersionSecrets, CryptoError> {
+    match version {
+        QUIC_VERSION_1 => Ok(VersionSecrets {
+            initial_salt: &INITIAL_SALT_V1,
+            retry_key: &RETRY_INTEGRITY_KEY,
+            retry_nonce: &RETRY_INTEGRITY_NONCE,
+        }),
+        QUIC_VERSION_DRAFT_29 => Ok(VersionSecrets {
+            initial_salt: &INITIAL_SALT_DRAFT_29,
+            retry_key: &RETRY_INTEGRITY_KEY_DRAFT_29,
+            retry_nonce: &RETRY_INTEGRITY_NONCE_DRAFT_29,
+        }),
+        _ => Err(CryptoError::UnsupportedVersion),
+    }
+}
+
+/// Length of the Retry Integrity Tag that ends every Retry packet
+pub const RETRY_INTEGRITY_TAG_LEN: usize = 16;
+
+#[derive(Debug, Default)]
 pub struct QuicCrypto {
     keys: HashMap<EncryptionLevel, CryptoKeys>,
 }
@@ -15,14 +71,131 @@ pub enum EncryptionLevel {
     Application,
 }
 
+/// Length of the ciphertext sample used for header protection (RFC 9001 Section 5.4.2)
+pub const HEADER_PROTECTION_SAMPLE_LEN: usize = 16;
+
 #[derive(Debug)]
 pub struct CryptoKeys {
-    local_key: LessSafeKey,
-    remote_key: LessSafeKey,
-    local_iv: [u8; 12],
-    remote_iv: [u8; 12],
-    local_pn_key: [u8; 16],
-    remote_pn_key: [u8; 16],
+    local: PacketKeys,
+    remote: PacketKeys,
+}
+
+impl CryptoKeys {
+    fn derive(local_secret: &[u8], remote_secret: &[u8], algorithm: &'static Algorithm) -> Result<Self, CryptoError> {
+        Ok(CryptoKeys {
+            local: PacketKeys::derive(local_secret, algorithm)?,
+            remote: PacketKeys::derive(remote_secret, algorithm)?,
+        })
+    }
+}
+
+/// Packet and header protection keys for a single direction
+enum PacketKeys {
+    /// Keys derived by this module from a traffic secret
+    Derived {
+        key: LessSafeKey,
+        iv: [u8; 12],
+        pn_key: HeaderProtectionKey,
+    },
+    /// Keys handed over by rustls as the handshake progresses
+    Tls(rustls::quic::DirectionalKeys),
+}
+
+impl std::fmt::Debug for PacketKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PacketKeys::Derived { key, .. } => f.debug_struct("Derived").field("key", key).finish_non_exhaustive(),
+            PacketKeys::Tls(_) => f.debug_struct("Tls").finish_non_exhaustive(),
+        }
+    }
+}
+
+impl PacketKeys {
+    fn derive(secret: &[u8], algorithm: &'static Algorithm) -> Result<Self, CryptoError> {
+        let material = derive_key_material(secret, algorithm)?;
+        
+        let unbound_key = UnboundKey::new(algorithm, &material.key)
+            .map_err(|_| CryptoError::KeyDerivationFailed)?;
+        let pn_key = HeaderProtectionKey::new(header_protection_algorithm(algorithm), &material.pn_key)
+            .map_err(|_| CryptoError::KeyDerivationFailed)?;
+        
+        Ok(PacketKeys::Derived {
+            key: LessSafeKey::new(unbound_key),
+            iv: material.iv,
+            pn_key,
+        })
+    }
+    
+    fn seal(&self, packet_number: u64, header: &[u8], payload: &[u8]) -> Result<Bytes, CryptoError> {
+        let mut in_out = BytesMut::new();
+        in_out.extend_from_slice(payload);
+        
+        match self {
+            PacketKeys::Derived { key, iv, .. } => {
+                let nonce = construct_nonce(iv, packet_number);
+                key.seal_in_place_append_tag(
+                    Nonce::assume_unique_for_key(nonce),
+                    Aad::from(header),
+                    &mut in_out
+                ).map_err(|_| CryptoError::EncryptionFailed)?;
+            }
+            PacketKeys::Tls(keys) => {
+                let tag = keys.packet.encrypt_in_place(packet_number, header, &mut in_out)
+                    .map_err(|_| CryptoError::EncryptionFailed)?;
+                in_out.extend_from_slice(tag.as_ref());
+            }
+        }
+        
+        Ok(in_out.freeze())
+    }
+    
+    fn open(&self, packet_number: u64, header: &[u8], ciphertext: &mut [u8]) -> Result<usize, CryptoError> {
+        let plaintext = match self {
+            PacketKeys::Derived { key, iv, .. } => {
+                let nonce = construct_nonce(iv, packet_number);
+                key.open_in_place(Nonce::assume_unique_for_key(nonce), Aad::from(header), ciphertext)
+                    .map_err(|_| CryptoError::DecryptionFailed)?
+            }
+            PacketKeys::Tls(keys) => {
+                keys.packet.decrypt_in_place(packet_number, header, ciphertext)
+                    .map_err(|_| CryptoError::DecryptionFailed)?
+            }
+        };
+        
+        Ok(plaintext.len())
+    }
+    
+    fn mask_header(&self, packet: &mut [u8], pn_offset: usize, unmask: bool) -> Result<usize, CryptoError> {
+        match self {
+            PacketKeys::Derived { pn_key, .. } => apply_header_protection(pn_key, packet, pn_offset, unmask),
+            PacketKeys::Tls(keys) => {
+                let sample_offset = pn_offset + 4;
+                if pn_offset == 0 || packet.len() < sample_offset + HEADER_PROTECTION_SAMPLE_LEN {
+                    return Err(CryptoError::HeaderProtectionFailed);
+                }
+                
+                let (header, rest) = packet.split_at_mut(sample_offset);
+                let sample = &rest[..HEADER_PROTECTION_SAMPLE_LEN];
+                let (first, header_rest) = header.split_first_mut()
+                    .ok_or(CryptoError::HeaderProtectionFailed)?;
+                let packet_number = &mut header_rest[pn_offset - 1..];
+                
+                // The packet number length bits are only readable while the first byte is unprotected
+                let pn_len = if unmask {
+                    keys.header.decrypt_in_place(sample, first, packet_number)
+                        .map_err(|_| CryptoError::HeaderProtectionFailed)?;
+                    (*first & 0x03) as usize + 1
+                } else {
+                    let pn_len = (*first & 0x03) as usize + 1;
+                    keys.header.encrypt_in_place(sample, first, packet_number)
+                        .map_err(|_| CryptoError::HeaderProtectionFailed)?;
+                    pn_len
+                };
+                
+                Ok(pn_len)
+            }
+        }
+    }
 }
 
 impl QuicCrypto {
@@ -41,31 +214,31 @@ impl QuicCrypto {
     }
     
     pub fn setup_initial_keys(&mut self, connection_id: &[u8], is_client: bool) -> Result<(), CryptoError> {
-        // Simplified key setup for now - in a real implementation this would use proper QUIC key derivation
-        let dummy_key_material = vec![0u8; 16];
-        let unbound_key = UnboundKey::new(&AES_128_GCM, &dummy_key_material)
-            .map_err(|_| CryptoError::KeyDerivationFailed)?;
-        let key = LessSafeKey::new(unbound_key);
-        
-        let unbound_key2 = UnboundKey::new(&AES_128_GCM, &dummy_key_material)
-            .map_err(|_| CryptoError::KeyDerivationFailed)?;
-        let key2 = LessSafeKey::new(unbound_key2);
+        self.setup_initial_keys_for_version(QUIC_VERSION_1, connection_id, is_client)
+    }
+    
+    /// Derives the Initial keys with the salt of `version`, which must be one of `SUPPORTED_VERSIONS`
+    pub fn setup_initial_keys_for_version(&mut self, version: u32, connection_id: &[u8], is_client: bool) -> Result<(), CryptoError> {
+        // `connection_id` is the Destination Connection ID of the client's Initial packets: its first
+        // choice, or the Source Connection ID of a Retry (RFC 9001 Section 5.2)
+        let initial_secret = hkdf_extract(version_secrets(version)?.initial_salt, connection_id);
+        let (client_secret, server_secret) = derive_initial_secrets(&initial_secret)?;
         
-        let crypto_keys = CryptoKeys {
-            local_key: key,
-            remote_key: key2,
-            local_iv: [0u8; 12],
-            remote_iv: [0u8; 12],
-            local_pn_key: [0u8; 16],
-            remote_pn_key: [0u8; 16],
+        let (local_secret, remote_secret) = if is_client {
+            (client_secret, server_secret)
+        } else {
+            (server_secret, client_secret)
         };
         
+        let crypto_keys = CryptoKeys::derive(&local_secret, &remote_secret, &AES_128_GCM)?;
+        
         self.keys.insert(EncryptionLevel::Initial, crypto_keys);
         Ok(())
     }
     
-    pub fn setup_handshake_keys(&mut self, handshake_secret: &[u8], is_client: bool) -> Result<(), CryptoError> {
-        let (client_secret, server_secret) = derive_handshake_secrets(handshake_secret)?;
+    /// `transcript_hash` is the hash of ClientHello...ServerHello
+    pub fn setup_handshake_keys(&mut self, handshake_secret: &[u8], transcript_hash: &[u8], is_client: bool) -> Result<(), CryptoError> {
+        let (client_secret, server_secret) = derive_handshake_secrets(handshake_secret, transcript_hash)?;
         
         let (local_secret, remote_secret) = if is_client {
             (client_secret, server_secret)
@@ -73,24 +246,15 @@ impl QuicCrypto {
             (server_secret, client_secret)
         };
         
-        let local_keys = derive_keys(&local_secret, &AES_128_GCM)?;
-        let remote_keys = derive_keys(&remote_secret, &AES_128_GCM)?;
-        
-        let crypto_keys = CryptoKeys {
-            local_key: local_keys.0,
-            remote_key: remote_keys.0,
-            local_iv: local_keys.1,
-            remote_iv: remote_keys.1,
-            local_pn_key: local_keys.2,
-            remote_pn_key: remote_keys.2,
-        };
+        let crypto_keys = CryptoKeys::derive(&local_secret, &remote_secret, &AES_128_GCM)?;
         
         self.keys.insert(EncryptionLevel::Handshake, crypto_keys);
         Ok(())
     }
     
-    pub fn setup_application_keys(&mut self, application_secret: &[u8], is_client: bool) -> Result<(), CryptoError> {
-        let (client_secret, server_secret) = derive_application_secrets(application_secret)?;
+    /// `transcript_hash` is the hash of ClientHello...server Finished
+    pub fn setup_application_keys(&mut self, master_secret: &[u8], transcript_hash: &[u8], is_client: bool) -> Result<(), CryptoError> {
+        let (client_secret, server_secret) = derive_application_secrets(master_secret, transcript_hash)?;
         
         let (local_secret, remote_secret) = if is_client {
             (client_secret, server_secret)
@@ -98,81 +262,143 @@ impl QuicCrypto {
             (server_secret, client_secret)
         };
         
-        let local_keys = derive_keys(&local_secret, &AES_128_GCM)?;
-        let remote_keys = derive_keys(&remote_secret, &AES_128_GCM)?;
+        let crypto_keys = CryptoKeys::derive(&local_secret, &remote_secret, &AES_128_GCM)?;
         
+        self.keys.insert(EncryptionLevel::Application, crypto_keys);
+        Ok(())
+    }
+    
+    /// Installs keys that rustls derived for `level` during the handshake
+    pub fn install_tls_keys(&mut self, level: EncryptionLevel, keys: rustls::quic::Keys) {
         let crypto_keys = CryptoKeys {
-            local_key: local_keys.0,
-            remote_key: remote_keys.0,
-            local_iv: local_keys.1,
-            remote_iv: remote_keys.1,
-            local_pn_key: local_keys.2,
-            remote_pn_key: remote_keys.2,
+            local: PacketKeys::Tls(keys.local),
+            remote: PacketKeys::Tls(keys.remote),
         };
         
-        self.keys.insert(EncryptionLevel::Application, crypto_keys);
-        Ok(())
+        self.keys.insert(level, crypto_keys);
     }
     
     pub fn encrypt_packet(&self, level: EncryptionLevel, packet_number: u64, header: &[u8], payload: &[u8]) -> Result<Bytes, CryptoError> {
         let keys = self.keys.get(&level).ok_or(CryptoError::NoKeys)?;
-        
-        let nonce = construct_nonce(&keys.local_iv, packet_number);
-        let aad = Aad::from(header);
-        
-        let mut in_out = BytesMut::new();
-        in_out.extend_from_slice(payload);
-        
-        keys.local_key.seal_in_place_append_tag(
-            Nonce::assume_unique_for_key(nonce), 
-            aad, 
-            &mut in_out
-        ).map_err(|_| CryptoError::EncryptionFailed)?;
-        
-        Ok(in_out.freeze())
+        keys.local.seal(packet_number, header, payload)
     }
     
     pub fn decrypt_packet(&self, level: EncryptionLevel, packet_number: u64, header: &[u8], ciphertext: &mut [u8]) -> Result<usize, CryptoError> {
         let keys = self.keys.get(&level).ok_or(CryptoError::NoKeys)?;
-        
-        let nonce = construct_nonce(&keys.remote_iv, packet_number);
-        let aad = Aad::from(header);
-        
-        let plaintext = keys.remote_key.open_in_place(
-            Nonce::assume_unique_for_key(nonce),
-            aad,
-            ciphertext
-        ).map_err(|_| CryptoError::DecryptionFailed)?;
-        
-        Ok(plaintext.len())
+        keys.remote.open(packet_number, header, ciphertext)
     }
     
-    pub fn encrypt_packet_number(&self, level: EncryptionLevel, packet_number: u64, sample: &[u8]) -> Result<u64, CryptoError> {
-        let _keys = self.keys.get(&level).ok_or(CryptoError::NoKeys)?;
-        
-        // Simplified packet number encryption
-        // In real QUIC, this uses AES-ECB or ChaCha20 with the sample
-        let encrypted = packet_number ^ u64::from_be_bytes([
-            sample[0], sample[1], sample[2], sample[3],
-            sample[4], sample[5], sample[6], sample[7],
-        ]);
-        
-        Ok(encrypted)
+    /// Applies header protection in place to an encoded packet (RFC 9001 Section 5.4)
+    ///
+    /// `packet` must hold the complete packet with its payload already encrypted, and
+    /// `pn_offset` is the offset of the Packet Number field. The packet number length is
+    /// read from the low two bits of the unprotected first byte.
+    pub fn protect_header(&self, level: EncryptionLevel, packet: &mut [u8], pn_offset: usize) -> Result<(), CryptoError> {
+        let keys = self.keys.get(&level).ok_or(CryptoError::NoKeys)?;
+        keys.local.mask_header(packet, pn_offset, false)?;
+        Ok(())
     }
     
-    pub fn decrypt_packet_number(&self, level: EncryptionLevel, encrypted_pn: u64, sample: &[u8]) -> Result<u64, CryptoError> {
-        // Packet number encryption is symmetric, so decryption is the same as encryption
-        self.encrypt_packet_number(level, encrypted_pn, sample)
+    /// Removes header protection in place and returns the packet number length in bytes
+    pub fn unprotect_header(&self, level: EncryptionLevel, packet: &mut [u8], pn_offset: usize) -> Result<usize, CryptoError> {
+        let keys = self.keys.get(&level).ok_or(CryptoError::NoKeys)?;
+        keys.remote.mask_header(packet, pn_offset, true)
     }
 }
 
+/// Masks or unmasks the first byte and packet number bytes of `packet`, returning the packet number length
+fn apply_header_protection(hp_key: &HeaderProtectionKey, packet: &mut [u8], pn_offset: usize, unmask: bool) -> Result<usize, CryptoError> {
+    // The sample is taken as if the packet number were 4 bytes long
+    let sample_offset = pn_offset + 4;
+    if packet.is_empty() || packet.len() < sample_offset + HEADER_PROTECTION_SAMPLE_LEN {
+        return Err(CryptoError::HeaderProtectionFailed);
+    }
+    
+    let mask = hp_key.new_mask(&packet[sample_offset..sample_offset + HEADER_PROTECTION_SAMPLE_LEN])
+        .map_err(|_| CryptoError::HeaderProtectionFailed)?;
+    
+    // Long headers protect 4 bits of the first byte, short headers 5
+    let first_byte_bits = if packet[0] & 0x80 != 0 { 0x0f } else { 0x1f };
+    
+    if unmask {
+        packet[0] ^= mask[0] & first_byte_bits;
+    }
+    let pn_len = (packet[0] & 0x03) as usize + 1;
+    if !unmask {
+        packet[0] ^= mask[0] & first_byte_bits;
+    }
+    
+    for i in 0..pn_len {
+        packet[pn_offset + i] ^= mask[1 + i];
+    }
+    
+    Ok(pn_len)
+}
+
+/// Computes the Retry Integrity Tag over the Retry pseudo-packet (RFC 9001 Section 5.8)
+///
+/// `retry_without_tag` is the encoded Retry packet up to, but not including, the tag, and
+/// `original_dest_conn_id` is the Destination Connection ID of the Initial it answers.
+pub fn retry_integrity_tag(original_dest_conn_id: &[u8], retry_without_tag: &[u8]) -> Result<[u8; RETRY_INTEGRITY_TAG_LEN], CryptoError> {
+    retry_integrity_tag_for_version(QUIC_VERSION_1, original_dest_conn_id, retry_without_tag)
+}
+
+/// Computes the Retry Integrity Tag with the key and nonce of `version`
+pub fn retry_integrity_tag_for_version(version: u32, original_dest_conn_id: &[u8], retry_without_tag: &[u8]) -> Result<[u8; RETRY_INTEGRITY_TAG_LEN], CryptoError> {
+    let secrets = version_secrets(version)?;
+    let mut pseudo_packet = Vec::with_capacity(1 + original_dest_conn_id.len() + retry_without_tag.len());
+    pseudo_packet.push(original_dest_conn_id.len() as u8);
+    pseudo_packet.extend_from_slice(original_dest_conn_id);
+    pseudo_packet.extend_from_slice(retry_without_tag);
+    
+    let key = UnboundKey::new(&AES_128_GCM, secrets.retry_key)
+        .map_err(|_| CryptoError::KeyDerivationFailed)?;
+    let tag = LessSafeKey::new(key)
+        .seal_in_place_separate_tag(
+            Nonce::assume_unique_for_key(*secrets.retry_nonce),
+            Aad::from(&pseudo_packet[..]),
+            &mut [],
+        )
+        .map_err(|_| CryptoError::EncryptionFailed)?;
+    
+    let mut output = [0u8; RETRY_INTEGRITY_TAG_LEN];
+    output.copy_from_slice(tag.as_ref());
+    Ok(output)
+}
+
+/// Checks the tag at the end of an encoded Retry packet against the client's original DCID
+pub fn verify_retry_integrity(original_dest_conn_id: &[u8], retry_packet: &[u8]) -> bool {
+    verify_retry_integrity_for_version(QUIC_VERSION_1, original_dest_conn_id, retry_packet)
+}
+
+/// Checks a Retry's tag with the key and nonce of `version`
+pub fn verify_retry_integrity_for_version(version: u32, original_dest_conn_id: &[u8], retry_packet: &[u8]) -> bool {
+    let Some(tag_offset) = retry_packet.len().checked_sub(RETRY_INTEGRITY_TAG_LEN) else {
+        return false;
+    };
+    let (packet, tag) = retry_packet.split_at(tag_offset);
+    
+    retry_integrity_tag_for_version(version, original_dest_conn_id, packet)
+        .is_ok_and(|expected| ring::constant_time::verify_slices_are_equal(&expected, tag).is_ok())
+}
+
 fn hkdf_extract(salt: &[u8], ikm: &[u8]) -> Prk {
     ring::hkdf::Salt::new(HKDF_SHA256, salt).extract(ikm)
 }
 
-fn hkdf_expand(prk: &Prk, info: &[u8], length: usize) -> Result<Vec<u8>, CryptoError> {
-    let info_slice = [info];
-    let okm = prk.expand(&info_slice, ring::hkdf::HKDF_SHA256)
+/// HKDF-Expand-Label from RFC 8446 Section 7.1
+///
+/// Every QUIC and TLS 1.3 key schedule step goes through this, so the `HkdfLabel`
+/// structure (output length, "tls13 " prefixed label, context) matches what peers compute.
+fn hkdf_expand_label(secret: &Prk, label: &[u8], context: &[u8], length: usize) -> Result<Vec<u8>, CryptoError> {
+    const LABEL_PREFIX: &[u8] = b"tls13 ";
+    
+    let output_len = (length as u16).to_be_bytes();
+    let label_len = [(LABEL_PREFIX.len() + label.len()) as u8];
+    let context_len = [context.len() as u8];
+    let info = [&output_len[..], &label_len[..], LABEL_PREFIX, label, &context_len[..], context];
+    
+    let okm = secret.expand(&info, OkmLength(length))
         .map_err(|_| CryptoError::HkdfError)?;
     let mut output = vec![0u8; length];
     okm.fill(&mut output)
@@ -180,44 +406,65 @@ fn hkdf_expand(prk: &Prk, info: &[u8], length: usize) -> Result<Vec<u8>, CryptoE
     Ok(output)
 }
 
+/// Output length accepted by ring's HKDF expand
+struct OkmLength(usize);
+
+impl KeyType for OkmLength {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
 fn derive_initial_secrets(initial_secret: &Prk) -> Result<(Vec<u8>, Vec<u8>), CryptoError> {
-    let client_initial_secret = hkdf_expand(initial_secret, b"client in", 32)?;
-    let server_initial_secret = hkdf_expand(initial_secret, b"server in", 32)?;
+    let client_initial_secret = hkdf_expand_label(initial_secret, b"client in", &[], 32)?;
+    let server_initial_secret = hkdf_expand_label(initial_secret, b"server in", &[], 32)?;
     Ok((client_initial_secret, server_initial_secret))
 }
 
-fn derive_handshake_secrets(handshake_secret: &[u8]) -> Result<(Vec<u8>, Vec<u8>), CryptoError> {
-    let prk = hkdf_extract(&[], handshake_secret);
-    let client_handshake_secret = hkdf_expand(&prk, b"c hs traffic", 32)?;
-    let server_handshake_secret = hkdf_expand(&prk, b"s hs traffic", 32)?;
+fn derive_handshake_secrets(handshake_secret: &[u8], transcript_hash: &[u8]) -> Result<(Vec<u8>, Vec<u8>), CryptoError> {
+    let prk = Prk::new_less_safe(HKDF_SHA256, handshake_secret);
+    let client_handshake_secret = hkdf_expand_label(&prk, b"c hs traffic", transcript_hash, 32)?;
+    let server_handshake_secret = hkdf_expand_label(&prk, b"s hs traffic", transcript_hash, 32)?;
     Ok((client_handshake_secret, server_handshake_secret))
 }
 
-fn derive_application_secrets(application_secret: &[u8]) -> Result<(Vec<u8>, Vec<u8>), CryptoError> {
-    let prk = hkdf_extract(&[], application_secret);
-    let client_application_secret = hkdf_expand(&prk, b"c ap traffic", 32)?;
-    let server_application_secret = hkdf_expand(&prk, b"s ap traffic", 32)?;
+fn derive_application_secrets(master_secret: &[u8], transcript_hash: &[u8]) -> Result<(Vec<u8>, Vec<u8>), CryptoError> {
+    let prk = Prk::new_less_safe(HKDF_SHA256, master_secret);
+    let client_application_secret = hkdf_expand_label(&prk, b"c ap traffic", transcript_hash, 32)?;
+    let server_application_secret = hkdf_expand_label(&prk, b"s ap traffic", transcript_hash, 32)?;
     Ok((client_application_secret, server_application_secret))
 }
 
-fn derive_keys(secret: &[u8], algorithm: &'static Algorithm) -> Result<(LessSafeKey, [u8; 12], [u8; 16]), CryptoError> {
-    let prk = hkdf_extract(&[], secret);
-    
-    let key_material = hkdf_expand(&prk, b"quic key", algorithm.key_len())?;
-    let iv_material = hkdf_expand(&prk, b"quic iv", 12)?;
-    let pn_key_material = hkdf_expand(&prk, b"quic hp", 16)?;
+/// Header protection cipher paired with each AEAD (RFC 9001 Section 5.4.1)
+fn header_protection_algorithm(algorithm: &'static Algorithm) -> &'static quic::Algorithm {
+    if algorithm == &AES_256_GCM {
+        &quic::AES_256
+    } else if algorithm == &CHACHA20_POLY1305 {
+        &quic::CHACHA20
+    } else {
+        &quic::AES_128
+    }
+}
+
+/// Raw packet protection material derived from a single traffic secret
+struct KeyMaterial {
+    key: Vec<u8>,
+    iv: [u8; 12],
+    pn_key: Vec<u8>,
+}
+
+/// Derives the packet protection key, IV and header protection key (RFC 9001 Section 5.1)
+fn derive_key_material(secret: &[u8], algorithm: &'static Algorithm) -> Result<KeyMaterial, CryptoError> {
+    let prk = Prk::new_less_safe(HKDF_SHA256, secret);
     
-    let unbound_key = UnboundKey::new(algorithm, &key_material)
-        .map_err(|_| CryptoError::KeyDerivationFailed)?;
-    let key = LessSafeKey::new(unbound_key);
+    let key = hkdf_expand_label(&prk, b"quic key", &[], algorithm.key_len())?;
+    let iv_material = hkdf_expand_label(&prk, b"quic iv", &[], 12)?;
+    let pn_key = hkdf_expand_label(&prk, b"quic hp", &[], header_protection_algorithm(algorithm).key_len())?;
     
     let mut iv = [0u8; 12];
     iv.copy_from_slice(&iv_material);
     
-    let mut pn_key = [0u8; 16];
-    pn_key.copy_from_slice(&pn_key_material);
-    
-    Ok((key, iv, pn_key))
+    Ok(KeyMaterial { key, iv, pn_key })
 }
 
 fn construct_nonce(iv: &[u8; 12], packet_number: u64) -> [u8; 12] {
@@ -239,6 +486,8 @@ pub enum CryptoError {
     DecryptionFailed,
     KeyDerivationFailed,
     HkdfError,
+    HeaderProtectionFailed,
+    UnsupportedVersion,
 }
 
 impl std::fmt::Display for CryptoError {
@@ -249,6 +498,8 @@ impl std::fmt::Display for CryptoError {
             CryptoError::DecryptionFailed => write!(f, "Packet decryption failed"),
             CryptoError::KeyDerivationFailed => write!(f, "Key derivation failed"),
             CryptoError::HkdfError => write!(f, "HKDF operation failed"),
+            CryptoError::HeaderProtectionFailed => write!(f, "Header protection failed"),
+            CryptoError::UnsupportedVersion => write!(f, "No Initial secrets for this QUIC version"),
         }
     }
 }
@@ -278,6 +529,188 @@ mod tests {
         assert!(crypto.keys.contains_key(&EncryptionLevel::Initial));
     }
     
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+    
+    #[test]
+    fn test_initial_secrets_rfc9001() {
+        // RFC 9001 Appendix A.1
+        let dcid = hex("8394c8f03e515708");
+        let initial_secret = hkdf_extract(&INITIAL_SALT_V1, &dcid);
+        let (client_secret, server_secret) = derive_initial_secrets(&initial_secret).unwrap();
+        
+        assert_eq!(client_secret, hex("c00cf151ca5be075ed0ebfb5c80323c42d6b7db67881289af4008f1f6c357aea"));
+        assert_eq!(server_secret, hex("3c199828fd139efd216c155ad844cc81fb82fa8d7446fa7d78be803acdda951b"));
+        
+        let client = derive_key_material(&client_secret, &AES_128_GCM).unwrap();
+        assert_eq!(client.key, hex("1f369613dd76d5467730efcbe3b1a22d"));
+        assert_eq!(client.iv.to_vec(), hex("fa044b2f42a3fd3b46fb255c"));
+        assert_eq!(client.pn_key, hex("9f50449e04a0e810283a1e9933adedd2"));
+        
+        let server = derive_key_material(&server_secret, &AES_128_GCM).unwrap();
+        assert_eq!(server.key, hex("cf3a5331653c364c88f0f379b6067e37"));
+        assert_eq!(server.iv.to_vec(), hex("0ac1493ca1905853b0bba03e"));
+        assert_eq!(server.pn_key, hex("c206b8d9b9f0f37644430b490eeaa314"));
+    }
+    
+    #[test]
+    fn test_hkdf_expand_label_rfc8448() {
+        // RFC 8448 Section 3, "Simple 1-RTT Handshake"
+        let early_secret = hkdf_extract(&[0u8; 32], &[0u8; 32]);
+        let empty_hash = hex("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        let derived = hkdf_expand_label(&early_secret, b"derived", &empty_hash, 32).unwrap();
+        assert_eq!(derived, hex("6f2615a108c702c5678f54fc9dbab69716c076189c48250cebeac3576c3611ba"));
+        
+        let shared_secret = hex("8bd4054fb55b9d63fdfbacf9f04b9f0d35e6d63f537563efd46272900f89492d");
+        let handshake_secret = hkdf_extract(&derived, &shared_secret);
+        let transcript_hash = hex("860c06edc07858ee8e78f0e7428c58edd6b43f2ca3e6e95f02ed063cf0e1cad8");
+        
+        let client = hkdf_expand_label(&handshake_secret, b"c hs traffic", &transcript_hash, 32).unwrap();
+        assert_eq!(client, hex("b3eddb126e067f35a780b3abf45e2d8f3b1a950738f52e9600746a0e27a55a21"));
+        
+        let server = hkdf_expand_label(&handshake_secret, b"s hs traffic", &transcript_hash, 32).unwrap();
+        assert_eq!(server, hex("b67b7d690cc16c4e75e54213cb2d37b4e9c912bcded9105d42befd59d391ad38"));
+        
+        let server_prk = Prk::new_less_safe(HKDF_SHA256, &server);
+        assert_eq!(hkdf_expand_label(&server_prk, b"key", &[], 16).unwrap(), hex("3fce516009c21727d0f2e4e86ee403bc"));
+        assert_eq!(hkdf_expand_label(&server_prk, b"iv", &[], 12).unwrap(), hex("5d313eb2671276ee13000b30"));
+    }
+    
+    #[test]
+    fn test_hkdf_expand_label_rfc9001() {
+        // RFC 9001 Appendix A.1: client_initial_secret and its "quic hp" expansion
+        let initial_secret = hkdf_extract(&INITIAL_SALT_V1, &hex("8394c8f03e515708"));
+        let client_secret = hkdf_expand_label(&initial_secret, b"client in", &[], 32).unwrap();
+        assert_eq!(client_secret, hex("c00cf151ca5be075ed0ebfb5c80323c42d6b7db67881289af4008f1f6c357aea"));
+        
+        let client_prk = Prk::new_less_safe(HKDF_SHA256, &client_secret);
+        assert_eq!(hkdf_expand_label(&client_prk, b"quic hp", &[], 16).unwrap(), hex("9f50449e04a0e810283a1e9933adedd2"));
+    }
+    
+    #[test]
+    fn test_handshake_secrets_from_transcript() {
+        let handshake_secret = hex("1dc826e93606aa6fdc0aadc12f741b01046aa6b99f691ed221a9f0ca043fbeac");
+        let transcript_hash = hex("860c06edc07858ee8e78f0e7428c58edd6b43f2ca3e6e95f02ed063cf0e1cad8");
+        let (client, server) = derive_handshake_secrets(&handshake_secret, &transcript_hash).unwrap();
+        assert_eq!(client, hex("b3eddb126e067f35a780b3abf45e2d8f3b1a950738f52e9600746a0e27a55a21"));
+        assert_eq!(server, hex("b67b7d690cc16c4e75e54213cb2d37b4e9c912bcded9105d42befd59d391ad38"));
+    }
+    
+    #[test]
+    fn test_header_protection_rfc9001_client_initial() {
+        // RFC 9001 Appendix A.2: unprotected header followed by the first 16 ciphertext bytes
+        let mut client = QuicCrypto::new();
+        let mut server = QuicCrypto::new();
+        client.setup_initial_keys(&hex("8394c8f03e515708"), true).unwrap();
+        server.setup_initial_keys(&hex("8394c8f03e515708"), false).unwrap();
+        
+        let header = hex("c300000001088394c8f03e5157080000449e00000002");
+        let sample = hex("d1b1c98dd7689fb8ec11d242b123dc9b");
+        let pn_offset = header.len() - 4;
+        let mut packet = [header.clone(), sample.clone()].concat();
+        
+        client.protect_header(EncryptionLevel::Initial, &mut packet, pn_offset).unwrap();
+        assert_eq!(&packet[..header.len()], &hex("c000000001088394c8f03e5157080000449e7b9aec34")[..]);
+        assert_eq!(&packet[header.len()..], &sample[..]);
+        
+        let pn_len = server.unprotect_header(EncryptionLevel::Initial, &mut packet, pn_offset).unwrap();
+        assert_eq!(pn_len, 4);
+        assert_eq!(&packet[..header.len()], &header[..]);
+    }
+    
+    #[test]
+    fn test_header_protection_rfc9001_server_initial() {
+        // RFC 9001 Appendix A.3: the 2-byte packet number puts the sample 2 bytes into the ciphertext
+        let mut server = QuicCrypto::new();
+        server.setup_initial_keys(&hex("8394c8f03e515708"), false).unwrap();
+        
+        let header = hex("c1000000010008f067a5502a4262b50040750001");
+        let payload = hex("5a482cd0991cd25b0aac406a5816b6394100");
+        let mut packet = [header.clone(), payload].concat();
+        
+        server.protect_header(EncryptionLevel::Initial, &mut packet, header.len() - 2).unwrap();
+        assert_eq!(&packet[..header.len()], &hex("cf000000010008f067a5502a4262b5004075c0d9")[..]);
+    }
+    
+    #[test]
+    fn test_header_protection_chacha20_short_header() {
+        // RFC 9001 Appendix A.5
+        let hp_key = HeaderProtectionKey::new(
+            &quic::CHACHA20,
+            &hex("25a282b9e82f06f21f488917a4fc8f1b73573685608597d0efcb076b0ab7a7a4"),
+        ).unwrap();
+        let sample = hex("5e5cd55c41f69080575d7999c25a5bfb");
+        let mut packet = [hex("4200bff465"), sample.clone()].concat();
+        
+        let pn_len = apply_header_protection(&hp_key, &mut packet, 1, false).unwrap();
+        assert_eq!(pn_len, 3);
+        assert_eq!(packet, [hex("4cfe418965"), sample.clone()].concat());
+        
+        let pn_len = apply_header_protection(&hp_key, &mut packet, 1, true).unwrap();
+        assert_eq!(pn_len, 3);
+        assert_eq!(packet, [hex("4200bff465"), sample].concat());
+    }
+    
+    #[test]
+    fn test_retry_integrity_tag_rfc9001() {
+        // RFC 9001 Appendix A.4
+        let odcid = hex("8394c8f03e515708");
+        let retry = hex("ff000000010008f067a5502a4262b5746f6b656e04a265ba2eff4d829058fb3f0f2496ba");
+        let (packet, tag) = retry.split_at(retry.len() - RETRY_INTEGRITY_TAG_LEN);
+        
+        assert_eq!(&retry_integrity_tag(&odcid, packet).unwrap()[..], tag);
+        assert!(verify_retry_integrity(&odcid, &retry));
+        
+        // The tag covers the original DCID and every byte of the packet
+        assert!(!verify_retry_integrity(&hex("8394c8f03e515709"), &retry));
+        let mut tampered = retry.clone();
+        tampered[20] ^= 0x01;
+        assert!(!verify_retry_integrity(&odcid, &tampered));
+        assert!(!verify_retry_integrity(&odcid, &retry[..10]));
+    }
+
+    #[test]
+    fn test_initial_keys_and_retry_tag_follow_version() {
+        let dcid = hex("8394c8f03e515708");
+        let mut v1 = QuicCrypto::new();
+        let mut draft = QuicCrypto::new();
+        v1.setup_initial_keys_for_version(QUIC_VERSION_1, &dcid, true).unwrap();
+        draft.setup_initial_keys_for_version(QUIC_VERSION_DRAFT_29, &dcid, false).unwrap();
+        
+        // Draft 29's salt gives different keys, so a version 1 packet does not open under them
+        let header = hex("c300000001088394c8f03e5157080000449e00000002");
+        let mut ciphertext = v1.encrypt_packet(EncryptionLevel::Initial, 2, &header, &[0; 32]).unwrap().to_vec();
+        assert!(draft.decrypt_packet(EncryptionLevel::Initial, 2, &header, &mut ciphertext).is_err());
+        
+        let retry = hex("ff000000010008f067a5502a4262b5746f6b656e04a265ba2eff4d829058fb3f0f2496ba");
+        let packet = &retry[..retry.len() - RETRY_INTEGRITY_TAG_LEN];
+        let tag = retry_integrity_tag_for_version(QUIC_VERSION_DRAFT_29, &dcid, packet).unwrap();
+        let draft_retry = [packet, &tag[..]].concat();
+        assert!(verify_retry_integrity_for_version(QUIC_VERSION_DRAFT_29, &dcid, &draft_retry));
+        assert!(!verify_retry_integrity(&dcid, &draft_retry));
+        
+        assert!(matches!(
+            QuicCrypto::new().setup_initial_keys_for_version(0x1a2a_3a4a, &dcid, true),
+            Err(CryptoError::UnsupportedVersion)
+        ));
+    }
+    
+    #[test]
+    fn test_header_protection_short_packet() {
+        let mut crypto = QuicCrypto::new();
+        crypto.setup_initial_keys(b"test_connection_id", true).unwrap();
+        
+        let mut packet = vec![0xc3; 20];
+        assert!(matches!(
+            crypto.protect_header(EncryptionLevel::Initial, &mut packet, 10),
+            Err(CryptoError::HeaderProtectionFailed)
+        ));
+    }
+    
     #[test]
     fn test_nonce_construction() {
         let iv = [0u8; 12];
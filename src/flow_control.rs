@@ -0,0 +1,167 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Receive window a new stream starts with
+pub const DEFAULT_STREAM_WINDOW: u64 = 64 * 1024;
+/// Largest receive window auto-tuning grows a stream to
+pub const MAX_STREAM_WINDOW: u64 = 16 * 1024 * 1024;
+/// Receive window a connection starts with, enough for a few streams at their initial window
+pub const DEFAULT_CONNECTION_WINDOW: u64 = 4 * DEFAULT_STREAM_WINDOW;
+/// Largest receive window auto-tuning grows a connection to
+pub const MAX_CONNECTION_WINDOW: u64 = 24 * 1024 * 1024;
+
+/// Round-trip time assumed until one is measured (RFC 9002 Section 6.2.2)
+pub const INITIAL_RTT: Duration = Duration::from_millis(333);
+
+/// Credit this endpoint extends to its peer for one stream or the whole connection
+///
+/// The limit moves forward as the application consumes data. When the peer uses up
+/// a window in less than two round trips the window doubles, so that it does not
+/// throttle a fast transfer, up to `max_window`.
+#[derive(Debug, Clone)]
+pub struct ReceiveWindow {
+    /// Limit most recently advertised to the peer
+    max_data: u64,
+    window: u64,
+    max_window: u64,
+    /// Bytes the peer has sent, as the highest offset received
+    received: u64,
+    /// Bytes the application has read
+    consumed: u64,
+    last_update: Option<Instant>,
+}
+
+impl ReceiveWindow {
+    pub fn new(window: u64, max_window: u64) -> Self {
+        Self {
+            max_data: window,
+            window,
+            max_window: max_window.max(window),
+            received: 0,
+            consumed: 0,
+            last_update: None,
+        }
+    }
+    
+    pub fn max_data(&self) -> u64 {
+        self.max_data
+    }
+    
+    pub fn window(&self) -> u64 {
+        self.window
+    }
+    
+    /// Records the peer sending up to `received` bytes, failing if that exceeds the advertised limit
+    pub fn receive(&mut self, received: u64) -> Result<(), FlowControlError> {
+        if received > self.max_data {
+            return Err(FlowControlError::LimitExceeded);
+        }
+        self.received = self.received.max(received);
+        Ok(())
+    }
+    
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+    
+    pub fn consume(&mut self, len: u64) {
+        self.consumed += len;
+    }
+    
+    /// New limit to advertise once less than half the window remains, if it is time for one
+    pub fn poll_update(&mut self, now: Instant, rtt: Duration) -> Option<u64> {
+        if self.max_data - self.consumed >= self.window / 2 {
+            return None;
+        }
+        
+        if let Some(last_update) = self.last_update {
+            if now.duration_since(last_update) < 2 * rtt {
+                self.window = (self.window * 2).min(self.max_window);
+            }
+        }
+        self.last_update = Some(now);
+        self.max_data = self.consumed + self.window;
+        Some(self.max_data)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowControlError {
+    /// The peer sent more data than the credit it was given
+    LimitExceeded,
+}
+
+impl fmt::Display for FlowControlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlowControlError::LimitExceeded => write!(f, "Flow control limit exceeded"),
+        }
+    }
+}
+
+impl std::error::Error for FlowControlError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_receive_beyond_limit_rejected() {
+        let mut window = ReceiveWindow::new(100, 1000);
+        assert!(window.receive(100).is_ok());
+        assert_eq!(window.receive(101), Err(FlowControlError::LimitExceeded));
+        assert_eq!(window.received(), 100);
+    }
+
+    #[test]
+    fn test_update_after_half_window_consumed() {
+        let mut window = ReceiveWindow::new(100, 1000);
+        let now = Instant::now();
+        window.receive(50).unwrap();
+        window.consume(50);
+        assert_eq!(window.poll_update(now, INITIAL_RTT), None);
+
+        window.receive(51).unwrap();
+        window.consume(1);
+        assert_eq!(window.poll_update(now, INITIAL_RTT), Some(151));
+        assert_eq!(window.window(), 100);
+    }
+
+    #[test]
+    fn test_window_grows_when_updates_are_frequent() {
+        let mut window = ReceiveWindow::new(100, 300);
+        let start = Instant::now();
+        let rtt = Duration::from_millis(10);
+
+        let mut consumed = 0;
+        let mut consume_window = |window: &mut ReceiveWindow, now| {
+            consumed += window.window();
+            window.receive(consumed).unwrap();
+            window.consume(window.window());
+            window.poll_update(now, rtt).unwrap()
+        };
+
+        assert_eq!(consume_window(&mut window, start), 200);
+        // A second window consumed within two round trips doubles it
+        assert_eq!(consume_window(&mut window, start + rtt), 400);
+        assert_eq!(window.window(), 200);
+        // Growth stops at the maximum
+        assert_eq!(consume_window(&mut window, start + 2 * rtt), 700);
+        assert_eq!(window.window(), 300);
+    }
+
+    #[test]
+    fn test_window_kept_when_updates_are_slow() {
+        let mut window = ReceiveWindow::new(100, 1000);
+        let start = Instant::now();
+        let rtt = Duration::from_millis(10);
+
+        window.receive(100).unwrap();
+        window.consume(100);
+        window.poll_update(start, rtt).unwrap();
+        window.receive(200).unwrap();
+        window.consume(100);
+        assert_eq!(window.poll_update(start + 3 * rtt, rtt), Some(300));
+        assert_eq!(window.window(), 100);
+    }
+}
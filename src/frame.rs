@@ -1,20 +1,79 @@
+use crate::crypto::EncryptionLevel;
+use crate::stream::{Dir, StreamId, MAX_STREAM_COUNT};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::collections::BTreeSet;
 use std::fmt;
+use std::ops::RangeInclusive;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum FrameType {
     Padding = 0x00,
     Ping = 0x01,
+    Ack = 0x02,
+    AckEcn = 0x03,
+    ResetStream = 0x04,
+    StopSending = 0x05,
     Crypto = 0x06,
+    Stream = 0x08,
+    MaxData = 0x10,
+    MaxStreamData = 0x11,
+    MaxStreamsBidi = 0x12,
+    MaxStreamsUni = 0x13,
+    DataBlocked = 0x14,
+    StreamDataBlocked = 0x15,
+    StreamsBlockedBidi = 0x16,
+    StreamsBlockedUni = 0x17,
     ConnectionClose = 0x1c,
+    ApplicationClose = 0x1d,
+    HandshakeDone = 0x1e,
+}
+
+/// Low bits of a STREAM frame type (RFC 9000 Section 19.8)
+const STREAM_FIN_BIT: u8 = 0x01;
+const STREAM_LEN_BIT: u8 = 0x02;
+const STREAM_OFF_BIT: u8 = 0x04;
+
+/// Counts of ECN codepoints seen on received packets, carried by ACK frames of type 0x03
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EcnCounts {
+    pub ect0: u64,
+    pub ect1: u64,
+    pub ce: u64,
 }
 
 #[derive(Debug, Clone)]
 pub enum Frame {
     Padding { length: usize },
     Ping,
+    /// `ranges` are inclusive and in descending order; the first one ends at `largest`
+    Ack {
+        largest: u64,
+        /// Encoded ACK delay, already scaled down by the ack_delay_exponent
+        delay: u64,
+        ranges: Vec<RangeInclusive<u64>>,
+        ecn: Option<EcnCounts>,
+    },
+    /// Abrupt end of the sender's side of a stream; `final_size` is how much it had sent
+    ResetStream { stream_id: StreamId, error_code: u64, final_size: u64 },
+    /// Asks the peer to stop sending on a stream
+    StopSending { stream_id: StreamId, error_code: u64 },
     Crypto { offset: u64, data: Bytes },
+    /// Types 0x08 to 0x0f; `fin` marks `data` as the end of the stream
+    Stream { stream_id: StreamId, offset: u64, data: Bytes, fin: bool },
+    /// Connection-level credit: total bytes the peer may send across all streams
+    MaxData { max_data: u64 },
+    MaxStreamData { stream_id: StreamId, max_data: u64 },
+    /// The sender has data to send but no connection-level credit beyond `limit`
+    DataBlocked { limit: u64 },
+    StreamDataBlocked { stream_id: StreamId, limit: u64 },
+    /// Cumulative number of streams of one direction the peer may open
+    MaxStreams { dir: Dir, max_streams: u64 },
+    StreamsBlocked { dir: Dir, limit: u64 },
     ConnectionClose { error_code: u64, reason: String },
+    /// CONNECTION_CLOSE of type 0x1d: the application closed the connection with its own error code
+    ApplicationClose { error_code: u64, reason: String },
+    /// Sent by the server to confirm the handshake to the client
+    HandshakeDone,
 }
 
 impl Frame {
@@ -28,12 +87,105 @@ impl Frame {
             Frame::Ping => {
                 buf.put_u8(FrameType::Ping as u8);
             }
+            Frame::Ack { largest, delay, ranges, ecn } => {
+                let (first, rest) = ranges.split_first().ok_or(FrameError::InvalidFormat)?;
+                if *first.end() != *largest || first.start() > first.end() {
+                    return Err(FrameError::InvalidFormat);
+                }
+                
+                let frame_type = if ecn.is_some() { FrameType::AckEcn } else { FrameType::Ack };
+                buf.put_u8(frame_type as u8);
+                encode_varint(buf, *largest);
+                encode_varint(buf, *delay);
+                encode_varint(buf, rest.len() as u64);
+                encode_varint(buf, first.end() - first.start());
+                
+                // Each further range is a gap below the previous one and a length (RFC 9000 Section 19.3.1)
+                let mut smallest = *first.start();
+                for range in rest {
+                    if range.start() > range.end() || range.end().saturating_add(2) > smallest {
+                        return Err(FrameError::InvalidFormat);
+                    }
+                    encode_varint(buf, smallest - range.end() - 2);
+                    encode_varint(buf, range.end() - range.start());
+                    smallest = *range.start();
+                }
+                
+                if let Some(ecn) = ecn {
+                    encode_varint(buf, ecn.ect0);
+                    encode_varint(buf, ecn.ect1);
+                    encode_varint(buf, ecn.ce);
+                }
+            }
             Frame::Crypto { offset, data } => {
                 buf.put_u8(FrameType::Crypto as u8);
                 encode_varint(buf, *offset);
                 encode_varint(buf, data.len() as u64);
                 buf.put_slice(data);
             }
+            Frame::Stream { stream_id, offset, data, fin } => {
+                // The length is always written so that more frames may follow in the packet
+                let mut frame_type = FrameType::Stream as u8 | STREAM_LEN_BIT;
+                if *offset != 0 {
+                    frame_type |= STREAM_OFF_BIT;
+                }
+                if *fin {
+                    frame_type |= STREAM_FIN_BIT;
+                }
+                
+                buf.put_u8(frame_type);
+                encode_varint(buf, stream_id.0);
+                if *offset != 0 {
+                    encode_varint(buf, *offset);
+                }
+                encode_varint(buf, data.len() as u64);
+                buf.put_slice(data);
+            }
+            Frame::ResetStream { stream_id, error_code, final_size } => {
+                buf.put_u8(FrameType::ResetStream as u8);
+                encode_varint(buf, stream_id.0);
+                encode_varint(buf, *error_code);
+                encode_varint(buf, *final_size);
+            }
+            Frame::StopSending { stream_id, error_code } => {
+                buf.put_u8(FrameType::StopSending as u8);
+                encode_varint(buf, stream_id.0);
+                encode_varint(buf, *error_code);
+            }
+            Frame::MaxData { max_data } => {
+                buf.put_u8(FrameType::MaxData as u8);
+                encode_varint(buf, *max_data);
+            }
+            Frame::MaxStreamData { stream_id, max_data } => {
+                buf.put_u8(FrameType::MaxStreamData as u8);
+                encode_varint(buf, stream_id.0);
+                encode_varint(buf, *max_data);
+            }
+            Frame::DataBlocked { limit } => {
+                buf.put_u8(FrameType::DataBlocked as u8);
+                encode_varint(buf, *limit);
+            }
+            Frame::StreamDataBlocked { stream_id, limit } => {
+                buf.put_u8(FrameType::StreamDataBlocked as u8);
+                encode_varint(buf, stream_id.0);
+                encode_varint(buf, *limit);
+            }
+            Frame::MaxStreams { dir, max_streams } => {
+                let frame_type = match dir {
+                    Dir::Bi => FrameType::MaxStreamsBidi,
+                    Dir::Uni => FrameType::MaxStreamsUni,
+                };
+                buf.put_u8(frame_type as u8);
+                encode_varint(buf, *max_streams);
+            }
+            Frame::StreamsBlocked { dir, limit } => {
+                let frame_type = match dir {
+                    Dir::Bi => FrameType::StreamsBlockedBidi,
+                    Dir::Uni => FrameType::StreamsBlockedUni,
+                };
+                buf.put_u8(frame_type as u8);
+                encode_varint(buf, *limit);
+            }
             Frame::ConnectionClose { error_code, reason } => {
                 buf.put_u8(FrameType::ConnectionClose as u8);
                 encode_varint(buf, *error_code);
@@ -42,6 +194,16 @@ impl Frame {
                 encode_varint(buf, reason_bytes.len() as u64);
                 buf.put_slice(reason_bytes);
             }
+            Frame::ApplicationClose { error_code, reason } => {
+                buf.put_u8(FrameType::ApplicationClose as u8);
+                encode_varint(buf, *error_code);
+                let reason_bytes = reason.as_bytes();
+                encode_varint(buf, reason_bytes.len() as u64);
+                buf.put_slice(reason_bytes);
+            }
+            Frame::HandshakeDone => {
+                buf.put_u8(FrameType::HandshakeDone as u8);
+            }
         }
         Ok(())
     }
@@ -63,6 +225,49 @@ impl Frame {
                 Ok(Frame::Padding { length })
             }
             0x01 => Ok(Frame::Ping),
+            0x02 | 0x03 => {
+                let largest = decode_varint(buf)?;
+                let delay = decode_varint(buf)?;
+                let range_count = decode_varint(buf)?;
+                let first_range = decode_varint(buf)?;
+                
+                let mut smallest = largest.checked_sub(first_range).ok_or(FrameError::InvalidFormat)?;
+                let mut ranges = vec![smallest..=largest];
+                for _ in 0..range_count {
+                    let gap = decode_varint(buf)?;
+                    let length = decode_varint(buf)?;
+                    
+                    // A range reaching below packet number 0 cannot have been acknowledged
+                    let end = smallest.checked_sub(gap)
+                        .and_then(|end| end.checked_sub(2))
+                        .ok_or(FrameError::InvalidFormat)?;
+                    smallest = end.checked_sub(length).ok_or(FrameError::InvalidFormat)?;
+                    ranges.push(smallest..=end);
+                }
+                
+                let ecn = if frame_type == FrameType::AckEcn as u8 {
+                    Some(EcnCounts {
+                        ect0: decode_varint(buf)?,
+                        ect1: decode_varint(buf)?,
+                        ce: decode_varint(buf)?,
+                    })
+                } else {
+                    None
+                };
+                
+                Ok(Frame::Ack { largest, delay, ranges, ecn })
+            }
+            0x04 => {
+                let stream_id = StreamId(decode_varint(buf)?);
+                let error_code = decode_varint(buf)?;
+                let final_size = decode_varint(buf)?;
+                Ok(Frame::ResetStream { stream_id, error_code, final_size })
+            }
+            0x05 => {
+                let stream_id = StreamId(decode_varint(buf)?);
+                let error_code = decode_varint(buf)?;
+                Ok(Frame::StopSending { stream_id, error_code })
+            }
             0x06 => {
                 let offset = decode_varint(buf)?;
                 let length = decode_varint(buf)? as usize;
@@ -74,9 +279,65 @@ impl Frame {
                 let data = buf.copy_to_bytes(length);
                 Ok(Frame::Crypto { offset, data })
             }
-            0x1c => {
+            0x08..=0x0f => {
+                let stream_id = StreamId(decode_varint(buf)?);
+                let offset = if frame_type & STREAM_OFF_BIT != 0 {
+                    decode_varint(buf)?
+                } else {
+                    0
+                };
+                
+                // Without a length the data runs to the end of the packet
+                let length = if frame_type & STREAM_LEN_BIT != 0 {
+                    decode_varint(buf)? as usize
+                } else {
+                    buf.remaining()
+                };
+                if buf.remaining() < length {
+                    return Err(FrameError::InvalidFormat);
+                }
+                
+                // No stream can extend past the largest varint offset
+                let data = buf.copy_to_bytes(length);
+                if offset.checked_add(length as u64).is_none_or(|end| end > MAX_VARINT) {
+                    return Err(FrameError::InvalidFormat);
+                }
+                
+                let fin = frame_type & STREAM_FIN_BIT != 0;
+                Ok(Frame::Stream { stream_id, offset, data, fin })
+            }
+            0x10 => Ok(Frame::MaxData { max_data: decode_varint(buf)? }),
+            0x11 => {
+                let stream_id = StreamId(decode_varint(buf)?);
+                let max_data = decode_varint(buf)?;
+                Ok(Frame::MaxStreamData { stream_id, max_data })
+            }
+            0x14 => Ok(Frame::DataBlocked { limit: decode_varint(buf)? }),
+            0x15 => {
+                let stream_id = StreamId(decode_varint(buf)?);
+                let limit = decode_varint(buf)?;
+                Ok(Frame::StreamDataBlocked { stream_id, limit })
+            }
+            0x12 | 0x13 | 0x16 | 0x17 => {
+                let dir = if frame_type & 0x01 == 0 { Dir::Bi } else { Dir::Uni };
+                let count = decode_varint(buf)?;
+                // Larger counts would allow stream IDs that cannot be encoded
+                if count > MAX_STREAM_COUNT {
+                    return Err(FrameError::InvalidFormat);
+                }
+                
+                if frame_type <= 0x13 {
+                    Ok(Frame::MaxStreams { dir, max_streams: count })
+                } else {
+                    Ok(Frame::StreamsBlocked { dir, limit: count })
+                }
+            }
+            0x1c | 0x1d => {
                 let error_code = decode_varint(buf)?;
-                let _frame_type = decode_varint(buf)?;
+                // Only the transport variant names the frame type that caused the error
+                if frame_type == 0x1c {
+                    decode_varint(buf)?;
+                }
                 let reason_length = decode_varint(buf)? as usize;
                 
                 if buf.remaining() < reason_length {
@@ -87,14 +348,68 @@ impl Frame {
                 let reason = String::from_utf8(reason_bytes.to_vec())
                     .map_err(|_| FrameError::InvalidFormat)?;
                 
-                Ok(Frame::ConnectionClose { error_code, reason })
+                if frame_type == 0x1c {
+                    Ok(Frame::ConnectionClose { error_code, reason })
+                } else {
+                    Ok(Frame::ApplicationClose { error_code, reason })
+                }
             }
+            0x1e => Ok(Frame::HandshakeDone),
             _ => Err(FrameError::UnknownFrameType(frame_type)),
         }
     }
 }
 
-fn encode_varint(buf: &mut BytesMut, value: u64) {
+impl Frame {
+    /// Whether receiving this frame obliges the peer to send an acknowledgement
+    pub fn is_ack_eliciting(&self) -> bool {
+        !matches!(self, Frame::Padding { .. } | Frame::Ack { .. }) && !self.is_close()
+    }
+    
+    /// Whether this is either type of CONNECTION_CLOSE
+    pub fn is_close(&self) -> bool {
+        matches!(self, Frame::ConnectionClose { .. } | Frame::ApplicationClose { .. })
+    }
+    
+    /// Whether a packet at `level` may carry this frame: Initial and Handshake packets are limited to
+    /// PADDING, PING, ACK, CRYPTO and the transport CONNECTION_CLOSE (RFC 9000 Section 12.4)
+    pub fn is_allowed_at(&self, level: EncryptionLevel) -> bool {
+        level == EncryptionLevel::Application || matches!(
+            self,
+            Frame::Padding { .. } | Frame::Ping | Frame::Ack { .. } | Frame::Crypto { .. } | Frame::ConnectionClose { .. }
+        )
+    }
+}
+
+/// Groups received packet numbers into the descending inclusive ranges an ACK frame carries
+pub fn ack_ranges(packet_numbers: &BTreeSet<u64>) -> Vec<RangeInclusive<u64>> {
+    let mut ranges: Vec<RangeInclusive<u64>> = Vec::new();
+    for &packet_number in packet_numbers.iter().rev() {
+        match ranges.last_mut() {
+            Some(range) if *range.start() == packet_number + 1 => *range = packet_number..=*range.end(),
+            _ => ranges.push(packet_number..=packet_number),
+        }
+    }
+    ranges
+}
+
+/// Largest value a variable-length integer can hold
+pub(crate) const MAX_VARINT: u64 = (1 << 62) - 1;
+
+/// Number of bytes `encode_varint` uses for `value`
+pub(crate) fn varint_len(value: u64) -> usize {
+    if value < 0x40 {
+        1
+    } else if value < 0x4000 {
+        2
+    } else if value < 0x40000000 {
+        4
+    } else {
+        8
+    }
+}
+
+pub(crate) fn encode_varint(buf: &mut BytesMut, value: u64) {
     if value < 0x40 {
         buf.put_u8(value as u8);                           // 00xxxxxx
     } else if value < 0x4000 {
@@ -106,7 +421,7 @@ fn encode_varint(buf: &mut BytesMut, value: u64) {
     }
 }
 
-fn decode_varint(buf: &mut Bytes) -> Result<u64, FrameError> {
+pub(crate) fn decode_varint(buf: &mut Bytes) -> Result<u64, FrameError> {
     if buf.is_empty() {
         return Err(FrameError::InvalidFormat);
     }
@@ -149,6 +464,7 @@ impl std::error::Error for FrameError {}
 mod tests {
     use super::*;
     use bytes::BytesMut;
+    use proptest::prelude::*;
 
     #[test]
     fn test_padding_frame_encode_decode() {
@@ -260,8 +576,22 @@ mod tests {
     fn test_frame_types() {
         assert_eq!(FrameType::Padding as u8, 0x00);
         assert_eq!(FrameType::Ping as u8, 0x01);
+        assert_eq!(FrameType::Ack as u8, 0x02);
+        assert_eq!(FrameType::AckEcn as u8, 0x03);
         assert_eq!(FrameType::Crypto as u8, 0x06);
+        assert_eq!(FrameType::Stream as u8, 0x08);
+        assert_eq!(FrameType::MaxData as u8, 0x10);
+        assert_eq!(FrameType::MaxStreamData as u8, 0x11);
+        assert_eq!(FrameType::DataBlocked as u8, 0x14);
+        assert_eq!(FrameType::StreamDataBlocked as u8, 0x15);
+        assert_eq!(FrameType::ResetStream as u8, 0x04);
+        assert_eq!(FrameType::StopSending as u8, 0x05);
+        assert_eq!(FrameType::MaxStreamsBidi as u8, 0x12);
+        assert_eq!(FrameType::MaxStreamsUni as u8, 0x13);
+        assert_eq!(FrameType::StreamsBlockedBidi as u8, 0x16);
+        assert_eq!(FrameType::StreamsBlockedUni as u8, 0x17);
         assert_eq!(FrameType::ConnectionClose as u8, 0x1c);
+        assert_eq!(FrameType::HandshakeDone as u8, 0x1e);
     }
 
     #[test]
@@ -320,6 +650,28 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_application_close_has_no_frame_type() {
+        let frame = Frame::ApplicationClose {
+            error_code: 0x42,
+            reason: "bye".to_string(),
+        };
+        
+        let mut buf = BytesMut::new();
+        frame.encode(&mut buf).unwrap();
+        assert_eq!(&buf[..], &[0x1d, 0x40, 0x42, 0x03, b'b', b'y', b'e']);
+        
+        let mut bytes = buf.freeze();
+        match Frame::decode(&mut bytes).unwrap() {
+            Frame::ApplicationClose { error_code, reason } => {
+                assert_eq!(error_code, 0x42);
+                assert_eq!(reason, "bye");
+            }
+            _ => panic!("Expected ApplicationClose frame"),
+        }
+        assert!(bytes.is_empty());
+    }
+
     #[test]
     fn test_large_error_code() {
         // Use a large value that fits in the maximum encodable range
@@ -412,4 +764,364 @@ mod tests {
             _ => panic!("Expected Crypto frame"),
         }
     }
-}
\ No newline at end of file
+
+    fn encode_decode(frame: &Frame) -> Frame {
+        let mut buf = BytesMut::new();
+        frame.encode(&mut buf).unwrap();
+        
+        let mut bytes = buf.freeze();
+        let decoded = Frame::decode(&mut bytes).unwrap();
+        assert!(bytes.is_empty());
+        decoded
+    }
+
+    #[test]
+    fn test_ack_frame_encode_decode() {
+        let frame = Frame::Ack {
+            largest: 100,
+            delay: 25,
+            ranges: vec![95..=100, 80..=90, 0..=3],
+            ecn: None,
+        };
+        
+        let mut buf = BytesMut::new();
+        frame.encode(&mut buf).unwrap();
+        
+        // Type, largest, delay, range count, first range, then (gap, length) for each further range
+        assert_eq!(buf.as_ref(), &[0x02, 0x40, 100, 25, 2, 5, 3, 10, 0x40, 75, 3]);
+        
+        match encode_decode(&frame) {
+            Frame::Ack { largest, delay, ranges, ecn } => {
+                assert_eq!(largest, 100);
+                assert_eq!(delay, 25);
+                assert_eq!(ranges, vec![95..=100, 80..=90, 0..=3]);
+                assert_eq!(ecn, None);
+            }
+            _ => panic!("Expected Ack frame"),
+        }
+    }
+
+    #[test]
+    fn test_ack_ecn_frame_encode_decode() {
+        let counts = EcnCounts { ect0: 7, ect1: 0, ce: 2 };
+        let frame = Frame::Ack {
+            largest: 3,
+            delay: 0,
+            ranges: vec![3..=3],
+            ecn: Some(counts),
+        };
+        
+        let mut buf = BytesMut::new();
+        frame.encode(&mut buf).unwrap();
+        assert_eq!(buf[0], 0x03);
+        
+        match encode_decode(&frame) {
+            Frame::Ack { ranges, ecn, .. } => {
+                assert_eq!(ranges, vec![3..=3]);
+                assert_eq!(ecn, Some(counts));
+            }
+            _ => panic!("Expected Ack frame"),
+        }
+    }
+
+    #[test]
+    fn test_ack_frame_invalid_ranges_rejected() {
+        let invalid = [
+            // No ranges at all
+            vec![],
+            // First range does not end at the largest acknowledged
+            vec![1..=5],
+            // Ranges out of order
+            vec![8..=10, 9..=9],
+            // Adjacent ranges must be merged, leaving a gap of at least one
+            vec![8..=10, 2..=7],
+        ];
+        
+        for ranges in invalid {
+            let frame = Frame::Ack { largest: 10, delay: 0, ranges, ecn: None };
+            assert!(matches!(frame.encode(&mut BytesMut::new()), Err(FrameError::InvalidFormat)));
+        }
+    }
+
+    #[test]
+    fn test_ack_frame_below_zero_rejected() {
+        // First range of 6 below largest 5
+        let mut bytes = Bytes::from_static(&[0x02, 5, 0, 0, 6]);
+        assert!(matches!(Frame::decode(&mut bytes), Err(FrameError::InvalidFormat)));
+        
+        // Gap reaching past packet number 0
+        let mut bytes = Bytes::from_static(&[0x02, 5, 0, 1, 0, 4, 0]);
+        assert!(matches!(Frame::decode(&mut bytes), Err(FrameError::InvalidFormat)));
+    }
+
+    #[test]
+    fn test_ack_ranges_from_packet_numbers() {
+        let received: BTreeSet<u64> = [0, 1, 2, 5, 7, 8, 9].into_iter().collect();
+        assert_eq!(ack_ranges(&received), vec![7..=9, 5..=5, 0..=2]);
+        assert!(ack_ranges(&BTreeSet::new()).is_empty());
+    }
+
+    #[test]
+    fn test_ack_eliciting_frames() {
+        assert!(Frame::Ping.is_ack_eliciting());
+        assert!(Frame::Crypto { offset: 0, data: Bytes::new() }.is_ack_eliciting());
+        assert!(!Frame::Padding { length: 1 }.is_ack_eliciting());
+        assert!(!Frame::Ack { largest: 0, delay: 0, ranges: vec![0..=0], ecn: None }.is_ack_eliciting());
+    }
+
+    #[test]
+    fn test_frames_allowed_per_level() {
+        let handshake_frames = [
+            Frame::Padding { length: 1 },
+            Frame::Ping,
+            Frame::Ack { largest: 0, delay: 0, ranges: vec![0..=0], ecn: None },
+            Frame::Crypto { offset: 0, data: Bytes::new() },
+            Frame::ConnectionClose { error_code: 0, reason: String::new() },
+        ];
+        let application_only = [
+            Frame::Stream { stream_id: StreamId(0), offset: 0, data: Bytes::new(), fin: false },
+            Frame::MaxData { max_data: 0 },
+            Frame::MaxStreamData { stream_id: StreamId(0), max_data: 0 },
+            Frame::ResetStream { stream_id: StreamId(0), error_code: 0, final_size: 0 },
+            Frame::HandshakeDone,
+            Frame::ApplicationClose { error_code: 0, reason: String::new() },
+        ];
+        for level in [EncryptionLevel::Initial, EncryptionLevel::Handshake, EncryptionLevel::Application] {
+            assert!(handshake_frames.iter().all(|frame| frame.is_allowed_at(level)));
+            let allowed = level == EncryptionLevel::Application;
+            assert!(application_only.iter().all(|frame| frame.is_allowed_at(level) == allowed));
+        }
+    }
+
+    #[test]
+    fn test_stream_frame_encode_decode() {
+        let frame = Frame::Stream {
+            stream_id: StreamId(4),
+            offset: 0,
+            data: Bytes::from_static(b"hello"),
+            fin: false,
+        };
+        
+        let mut buf = BytesMut::new();
+        frame.encode(&mut buf).unwrap();
+        // LEN bit only, since the offset is zero
+        assert_eq!(buf.as_ref(), &[0x0a, 4, 5, b'h', b'e', b'l', b'l', b'o']);
+        
+        match encode_decode(&frame) {
+            Frame::Stream { stream_id, offset, data, fin } => {
+                assert_eq!(stream_id, StreamId(4));
+                assert_eq!(offset, 0);
+                assert_eq!(data, Bytes::from_static(b"hello"));
+                assert!(!fin);
+            }
+            _ => panic!("Expected Stream frame"),
+        }
+    }
+
+    #[test]
+    fn test_stream_frame_with_offset_and_fin() {
+        let frame = Frame::Stream {
+            stream_id: StreamId(3),
+            offset: 1000,
+            data: Bytes::new(),
+            fin: true,
+        };
+        
+        let mut buf = BytesMut::new();
+        frame.encode(&mut buf).unwrap();
+        assert_eq!(buf[0], 0x0f);
+        
+        match encode_decode(&frame) {
+            Frame::Stream { stream_id, offset, data, fin } => {
+                assert_eq!(stream_id, StreamId(3));
+                assert_eq!(offset, 1000);
+                assert!(data.is_empty());
+                assert!(fin);
+            }
+            _ => panic!("Expected Stream frame"),
+        }
+    }
+
+    #[test]
+    fn test_stream_frame_without_length_runs_to_end() {
+        // OFF and FIN bits set, LEN bit clear
+        let mut bytes = Bytes::from_static(&[0x0d, 0x01, 0x08, b'a', b'b', b'c']);
+        match Frame::decode(&mut bytes).unwrap() {
+            Frame::Stream { stream_id, offset, data, fin } => {
+                assert_eq!(stream_id, StreamId(1));
+                assert_eq!(offset, 8);
+                assert_eq!(data, Bytes::from_static(b"abc"));
+                assert!(fin);
+            }
+            _ => panic!("Expected Stream frame"),
+        }
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn test_stream_frame_past_max_offset_rejected() {
+        let mut buf = BytesMut::new();
+        buf.put_u8(0x0e);
+        encode_varint(&mut buf, 0);
+        encode_varint(&mut buf, MAX_VARINT);
+        encode_varint(&mut buf, 1);
+        buf.put_u8(0);
+        
+        assert!(matches!(Frame::decode(&mut buf.freeze()), Err(FrameError::InvalidFormat)));
+    }
+
+    #[test]
+    fn test_flow_control_frames_encode_decode() {
+        let frames = [
+            Frame::MaxData { max_data: 1 << 20 },
+            Frame::MaxStreamData { stream_id: StreamId(6), max_data: 65536 },
+            Frame::DataBlocked { limit: 0 },
+            Frame::StreamDataBlocked { stream_id: StreamId(1), limit: 300 },
+        ];
+        
+        let mut buf = BytesMut::new();
+        for frame in &frames {
+            frame.encode(&mut buf).unwrap();
+        }
+        
+        let mut bytes = buf.freeze();
+        match Frame::decode(&mut bytes).unwrap() {
+            Frame::MaxData { max_data } => assert_eq!(max_data, 1 << 20),
+            _ => panic!("Expected MaxData frame"),
+        }
+        match Frame::decode(&mut bytes).unwrap() {
+            Frame::MaxStreamData { stream_id, max_data } => {
+                assert_eq!(stream_id, StreamId(6));
+                assert_eq!(max_data, 65536);
+            }
+            _ => panic!("Expected MaxStreamData frame"),
+        }
+        match Frame::decode(&mut bytes).unwrap() {
+            Frame::DataBlocked { limit } => assert_eq!(limit, 0),
+            _ => panic!("Expected DataBlocked frame"),
+        }
+        match Frame::decode(&mut bytes).unwrap() {
+            Frame::StreamDataBlocked { stream_id, limit } => {
+                assert_eq!(stream_id, StreamId(1));
+                assert_eq!(limit, 300);
+            }
+            _ => panic!("Expected StreamDataBlocked frame"),
+        }
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn test_stream_limit_frames_encode_decode() {
+        let frames = [
+            (Frame::MaxStreams { dir: Dir::Bi, max_streams: 100 }, 0x12),
+            (Frame::MaxStreams { dir: Dir::Uni, max_streams: MAX_STREAM_COUNT }, 0x13),
+            (Frame::StreamsBlocked { dir: Dir::Bi, limit: 0 }, 0x16),
+            (Frame::StreamsBlocked { dir: Dir::Uni, limit: 7 }, 0x17),
+        ];
+        
+        for (frame, frame_type) in frames {
+            let mut buf = BytesMut::new();
+            frame.encode(&mut buf).unwrap();
+            assert_eq!(buf[0], frame_type);
+            
+            match (encode_decode(&frame), frame) {
+                (Frame::MaxStreams { dir, max_streams }, Frame::MaxStreams { dir: expected_dir, max_streams: expected }) => {
+                    assert_eq!(dir, expected_dir);
+                    assert_eq!(max_streams, expected);
+                }
+                (Frame::StreamsBlocked { dir, limit }, Frame::StreamsBlocked { dir: expected_dir, limit: expected }) => {
+                    assert_eq!(dir, expected_dir);
+                    assert_eq!(limit, expected);
+                }
+                (decoded, _) => panic!("Unexpected frame {:?}", decoded),
+            }
+        }
+    }
+
+    #[test]
+    fn test_stream_abort_frames_encode_decode() {
+        let frame = Frame::ResetStream { stream_id: StreamId(4), error_code: 0x101, final_size: 1 << 20 };
+        let mut buf = BytesMut::new();
+        frame.encode(&mut buf).unwrap();
+        assert_eq!(buf[0], 0x04);
+        match encode_decode(&frame) {
+            Frame::ResetStream { stream_id, error_code, final_size } => {
+                assert_eq!(stream_id, StreamId(4));
+                assert_eq!(error_code, 0x101);
+                assert_eq!(final_size, 1 << 20);
+            }
+            _ => panic!("Expected ResetStream frame"),
+        }
+        
+        let frame = Frame::StopSending { stream_id: StreamId(3), error_code: 7 };
+        match encode_decode(&frame) {
+            Frame::StopSending { stream_id, error_code } => {
+                assert_eq!(stream_id, StreamId(3));
+                assert_eq!(error_code, 7);
+            }
+            _ => panic!("Expected StopSending frame"),
+        }
+    }
+
+    #[test]
+    fn test_handshake_done_frame_encode_decode() {
+        let mut buf = BytesMut::new();
+        Frame::HandshakeDone.encode(&mut buf).unwrap();
+        assert_eq!(buf.as_ref(), &[0x1e]);
+        assert!(matches!(encode_decode(&Frame::HandshakeDone), Frame::HandshakeDone));
+        assert!(Frame::HandshakeDone.is_ack_eliciting());
+    }
+
+    #[test]
+    fn test_max_streams_above_limit_rejected() {
+        let mut buf = BytesMut::new();
+        buf.put_u8(0x12);
+        encode_varint(&mut buf, MAX_STREAM_COUNT + 1);
+        
+        assert!(matches!(Frame::decode(&mut buf.freeze()), Err(FrameError::InvalidFormat)));
+    }
+
+    fn check_ack_roundtrip(received: BTreeSet<u64>, delay: u64, ecn: Option<EcnCounts>) {
+        let ranges = ack_ranges(&received);
+        let largest = *received.last().unwrap();
+        let frame = Frame::Ack { largest, delay, ranges: ranges.clone(), ecn };
+        
+        match encode_decode(&frame) {
+            Frame::Ack { largest: decoded_largest, delay: decoded_delay, ranges: decoded_ranges, ecn: decoded_ecn } => {
+                assert_eq!(decoded_largest, largest);
+                assert_eq!(decoded_delay, delay);
+                assert_eq!(decoded_ranges, ranges);
+                assert_eq!(decoded_ecn, ecn);
+            }
+            _ => panic!("Expected Ack frame"),
+        }
+        
+        let acked: BTreeSet<u64> = ranges.into_iter().flatten().collect();
+        assert_eq!(acked, received);
+    }
+
+    fn ecn_counts() -> impl Strategy<Value = Option<EcnCounts>> {
+        proptest::option::of((0..=MAX_VARINT, 0..=MAX_VARINT, 0..=MAX_VARINT)
+            .prop_map(|(ect0, ect1, ce)| EcnCounts { ect0, ect1, ce }))
+    }
+
+    proptest! {
+        #[test]
+        fn test_ack_frame_roundtrip_dense(
+            received in proptest::collection::btree_set(0u64..512, 1..256),
+            delay in 0..=MAX_VARINT,
+            ecn in ecn_counts(),
+        ) {
+            check_ack_roundtrip(received, delay, ecn);
+        }
+
+        #[test]
+        fn test_ack_frame_roundtrip_sparse(
+            received in proptest::collection::btree_set(0..=MAX_VARINT, 1..32),
+            delay in 0..=MAX_VARINT,
+            ecn in ecn_counts(),
+        ) {
+            check_ack_roundtrip(received, delay, ecn);
+        }
+    }
+}
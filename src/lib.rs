@@ -2,4 +2,13 @@ pub mod packet;
 pub mod connection;
 pub mod frame;
 pub mod tls;
-pub mod crypto;
\ No newline at end of file
+pub mod crypto;
+pub mod transport_parameters;
+pub mod token;
+pub mod stream;
+pub mod flow_control;
+pub mod config;
+pub mod recovery;
+pub mod rtt;
+pub mod congestion;
+pub mod pacer;
@@ -1,14 +1,8 @@
-mod packet;
-mod connection;
-mod frame;
-mod crypto;
-mod tls;
-
-use crate::connection::{Connection, ConnectionManager, ConnectionState};
-use crate::frame::Frame;
-use crate::packet::PacketHeader;
-use bytes::Bytes;
+use oreno_quic::connection::{ConnectionManager, IncomingAction};
+use oreno_quic::frame::Frame;
+use oreno_quic::packet::{self, ProtectedHeader};
 use tokio::net::UdpSocket;
+use std::time::Instant;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -22,60 +16,132 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut buf = vec![0u8; 1500];
     
     loop {
-        let (len, peer_addr) = socket.recv_from(&mut buf).await?;
+        // Release whatever the connections' pacers allow out now
+        for (peer_addr, datagram) in connection_manager.poll_transmit(Instant::now()) {
+            socket.send_to(&datagram, peer_addr).await?;
+        }
+        
+        // Wait for the next datagram, a connection timer (loss detection, idle or keep-alive), or a pacer with more to send
+        let deadline = connection_manager.poll_timeout().into_iter()
+            .chain(connection_manager.next_send_time(Instant::now()))
+            .min();
+        let sleep = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+        let (len, peer_addr) = tokio::select! {
+            received = socket.recv_from(&mut buf) => received?,
+            _ = sleep => {
+                let timeouts = connection_manager.handle_timeouts(Instant::now());
+                for (conn_id, error) in timeouts.errors {
+                    println!("Connection {:02x?} failed after a timer fired: {}", conn_id, error);
+                }
+                for (peer_addr, datagram) in timeouts.datagrams {
+                    socket.send_to(&datagram, peer_addr).await?;
+                }
+                // Connections that idled out or finished closing or draining are dropped
+                for conn_id in connection_manager.reap_closed() {
+                    println!("Connection {:02x?} closed and was removed", conn_id);
+                }
+                continue;
+            }
+        };
         println!("Received {} bytes from {}", len, peer_addr);
         
-        let mut packet_data = Bytes::copy_from_slice(&buf[..len]);
+        // Packets of a version we do not speak get a Version Negotiation reply instead of a connection
+        if let Some(version) = packet::long_header_version(&buf[..len]) {
+            if !connection_manager.supported_versions().contains(&version) {
+                if let Some(response) = connection_manager.version_negotiation(&buf[..len]) {
+                    println!("Unsupported version {:#x}, sending Version Negotiation", version);
+                    socket.send_to(&response, peer_addr).await?;
+                }
+                continue;
+            }
+        }
         
-        match PacketHeader::decode(&mut packet_data) {
+        match ProtectedHeader::parse(&buf[..len]) {
             Ok(header) => {
-                println!("Decoded packet header: {:?}", header);
+                println!("Parsed packet header: {:?}", header);
                 
-                let conn_id = match &header {
-                    PacketHeader::Long(h) => &h.src_conn_id,
-                    PacketHeader::Short(h) => &h.dest_conn_id,
-                };
+                let conn_id = header.src_conn_id.as_ref().unwrap_or(&header.dest_conn_id);
                 
                 let connection_exists = connection_manager.get_connection(&conn_id.data).is_some();
                 
                 if !connection_exists {
-                    println!("Creating new connection for peer {}", peer_addr);
-                    let new_connection = Connection::new_server(peer_addr, conn_id.clone());
-                    connection_manager.add_connection(conn_id.data.clone(), new_connection);
+                    match connection_manager.handle_incoming(&buf[..len], peer_addr) {
+                        IncomingAction::Accept(new_connection) => {
+                            println!("Creating new connection for peer {}", peer_addr);
+                            connection_manager.add_connection(conn_id.data.clone(), *new_connection);
+                        }
+                        IncomingAction::Retry(retry) => {
+                            println!("Sending Retry to validate {}", peer_addr);
+                            socket.send_to(&retry, peer_addr).await?;
+                            continue;
+                        }
+                        IncomingAction::Drop => {
+                            println!("Dropping packet from unknown peer {}", peer_addr);
+                            continue;
+                        }
+                    }
                 }
                 
                 let connection = connection_manager.get_connection(&conn_id.data).unwrap();
                 
-                while !packet_data.is_empty() {
-                    match Frame::decode(&mut packet_data) {
-                        Ok(frame) => {
-                            println!("Decoded frame: {:?}", frame);
-                            
-                            match frame {
-                                Frame::Ping => {
-                                    println!("Received PING, sending PONG");
-                                    let pong_packet = connection.create_initial_packet(vec![Frame::Ping])?;
-                                    socket.send_to(&pong_packet, peer_addr).await?;
-                                }
-                                Frame::ConnectionClose { .. } => {
-                                    println!("Connection close received");
-                                    connection.handle_state_transition(ConnectionState::Closed);
-                                    connection_manager.remove_connection(&conn_id.data);
-                                    break;
-                                }
-                                Frame::Padding { .. } => {
-                                    // Just padding, ignore
-                                }
-                                Frame::Crypto { .. } => {
-                                    // TLS handshake data, ignore for now
-                                    println!("Received CRYPTO frame (TLS handshake)");
-                                }
+                // A datagram may carry several coalesced packets for the same connection
+                'packets: for packet in packet::split_datagram(&buf[..len]) {
+                    let frames = match packet {
+                        Ok(packet) => match connection.receive_packet(packet) {
+                            Ok(received) => received.frames,
+                            Err(e) => {
+                                println!("Dropping packet: {}", e);
+                                continue;
                             }
-                        }
+                        },
                         Err(e) => {
-                            println!("Frame decode error: {}", e);
+                            println!("Packet decode error: {}", e);
                             break;
                         }
+                    };
+                    
+                    for frame in frames {
+                        println!("Decoded frame: {:?}", frame);
+                        
+                        match frame {
+                            Frame::Ping => {
+                                println!("Received PING, sending PONG");
+                                let pong_packet = connection.create_initial_packet(vec![Frame::Ping])?;
+                                connection.pace(vec![pong_packet]);
+                            }
+                            Frame::ConnectionClose { .. } | Frame::ApplicationClose { .. } => {
+                                // The connection drains for three PTOs and is reaped once its timer fires
+                                println!("Connection close received, draining");
+                                break 'packets;
+                            }
+                            Frame::Padding { .. } => {
+                                // Just padding, ignore
+                            }
+                            Frame::Ack { .. }
+                            | Frame::MaxData { .. }
+                            | Frame::MaxStreamData { .. }
+                            | Frame::DataBlocked { .. }
+                            | Frame::StreamDataBlocked { .. }
+                            | Frame::MaxStreams { .. }
+                            | Frame::StreamsBlocked { .. }
+                            | Frame::ResetStream { .. }
+                            | Frame::StopSending { .. }
+                            | Frame::HandshakeDone => {
+                                // Acknowledgements, flow control, stream aborts and handshake confirmation are handled by the connection
+                            }
+                            Frame::Stream { stream_id, ref data, fin, .. } => {
+                                println!("Received STREAM frame: stream={}, data_len={}, fin={}", stream_id, data.len(), fin);
+                            }
+                            Frame::Crypto { .. } => {
+                                // TLS handshake data, ignore for now
+                                println!("Received CRYPTO frame (TLS handshake)");
+                            }
+                        }
                     }
                 }
             }
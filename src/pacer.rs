@@ -0,0 +1,175 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Datagrams a connection may send back to back after an idle period unless configured otherwise,
+/// matching the initial window (RFC 9002 Section 7.7)
+pub const DEFAULT_PACING_BURST: usize = 10;
+
+/// Token bucket between a connection and its socket: datagrams queue here and leave at the pacing rate,
+/// with up to `burst` bytes allowed out at once once the bucket has refilled
+#[derive(Debug, Clone)]
+pub struct Pacer {
+    /// Bucket capacity in bytes
+    burst: usize,
+    tokens: f64,
+    /// When `tokens` was last brought up to date
+    updated: Option<Instant>,
+    /// Refill rate in bytes per second
+    rate: u64,
+    queue: VecDeque<Vec<u8>>,
+}
+
+impl Pacer {
+    /// Pacer with a full bucket of `burst` bytes, refilled at `rate` bytes per second
+    pub fn new(burst: usize, rate: u64) -> Self {
+        Self {
+            burst,
+            tokens: burst as f64,
+            updated: None,
+            rate,
+            queue: VecDeque::new(),
+        }
+    }
+    
+    pub fn burst(&self) -> usize {
+        self.burst
+    }
+    
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+    
+    /// Changes the refill rate, typically to the congestion controller's latest pacing rate
+    pub fn set_rate(&mut self, rate: u64) {
+        self.rate = rate;
+    }
+    
+    pub fn push(&mut self, datagram: Vec<u8>) {
+        self.queue.push_back(datagram);
+    }
+    
+    /// Datagrams queued and not yet released
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+    
+    /// Releases the next datagram if the bucket holds enough tokens for it at `now`
+    pub fn poll_transmit(&mut self, now: Instant) -> Option<Vec<u8>> {
+        let cost = self.cost(self.queue.front()?);
+        self.tokens = self.tokens_at(now);
+        self.updated = Some(now);
+        if self.tokens < cost {
+            return None;
+        }
+        let datagram = self.queue.pop_front()?;
+        self.tokens -= datagram.len() as f64;
+        Some(datagram)
+    }
+    
+    /// When the next queued datagram may be released, or `None` with nothing queued
+    pub fn next_send_time(&self, now: Instant) -> Option<Instant> {
+        let deficit = self.cost(self.queue.front()?) - self.tokens_at(now);
+        if deficit <= 0.0 {
+            return Some(now);
+        }
+        // Rounded up so the bucket is certain to hold enough by then
+        let nanos = (deficit * 1e9 / self.rate.max(1) as f64).ceil();
+        Some(now + Duration::from_nanos(nanos as u64))
+    }
+    
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let Some(updated) = self.updated else {
+            return self.burst as f64;
+        };
+        let refill = now.saturating_duration_since(updated).as_secs_f64() * self.rate as f64;
+        (self.tokens + refill).min(self.burst as f64)
+    }
+    
+    /// Tokens needed before `datagram` may go; one larger than the bucket waits for a full bucket
+    fn cost(&self, datagram: &[u8]) -> f64 {
+        datagram.len().min(self.burst) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn test_burst_goes_out_at_once() {
+        // 100 bytes per millisecond, with room for three 100-byte datagrams
+        let mut pacer = Pacer::new(300, 100_000);
+        let now = Instant::now();
+        for _ in 0..5 {
+            pacer.push(vec![0; 100]);
+        }
+        assert_eq!(pacer.next_send_time(now), Some(now));
+        for _ in 0..3 {
+            assert!(pacer.poll_transmit(now).is_some());
+        }
+        assert!(pacer.poll_transmit(now).is_none());
+        assert_eq!(pacer.len(), 2);
+        assert_eq!(pacer.next_send_time(now), Some(now + MS));
+    }
+
+    #[test]
+    fn test_spacing_follows_rate() {
+        let mut pacer = Pacer::new(100, 100_000);
+        let start = Instant::now();
+        for _ in 0..4 {
+            pacer.push(vec![0; 100]);
+        }
+        let mut now = start;
+        let mut sent = Vec::new();
+        while let Some(next) = pacer.next_send_time(now) {
+            now = next;
+            while pacer.poll_transmit(now).is_some() {
+                sent.push(now - start);
+            }
+        }
+        assert_eq!(sent, vec![Duration::ZERO, MS, 2 * MS, 3 * MS]);
+    }
+
+    #[test]
+    fn test_idle_refill_capped_at_burst() {
+        let mut pacer = Pacer::new(200, 100_000);
+        let start = Instant::now();
+        pacer.push(vec![0; 200]);
+        assert!(pacer.poll_transmit(start).is_some());
+
+        // A second of silence only refills the bucket to its capacity
+        let later = start + Duration::from_secs(1);
+        for _ in 0..3 {
+            pacer.push(vec![0; 100]);
+        }
+        assert!(pacer.poll_transmit(later).is_some());
+        assert!(pacer.poll_transmit(later).is_some());
+        assert!(pacer.poll_transmit(later).is_none());
+    }
+
+    #[test]
+    fn test_oversized_datagram_waits_for_full_bucket() {
+        let mut pacer = Pacer::new(100, 100_000);
+        let now = Instant::now();
+        pacer.push(vec![0; 150]);
+        pacer.push(vec![0; 50]);
+        assert_eq!(pacer.poll_transmit(now).map(|datagram| datagram.len()), Some(150));
+        // The bucket went 50 bytes into debt, which takes a millisecond to clear
+        assert_eq!(pacer.next_send_time(now), Some(now + MS));
+    }
+
+    #[test]
+    fn test_empty_pacer_has_no_send_time() {
+        let mut pacer = Pacer::new(100, 100_000);
+        let now = Instant::now();
+        assert!(pacer.is_empty());
+        assert_eq!(pacer.next_send_time(now), None);
+        assert!(pacer.poll_transmit(now).is_none());
+    }
+}
@@ -1,3 +1,5 @@
+use crate::crypto::{EncryptionLevel, RETRY_INTEGRITY_TAG_LEN};
+use crate::frame::{decode_varint, encode_varint};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use std::fmt;
 
@@ -10,7 +12,18 @@ pub enum PacketType {
     Short = 0x04,
 }
 
-#[derive(Debug, Clone)]
+impl PacketType {
+    /// Encryption level whose keys protect packets of this type
+    pub fn encryption_level(self) -> EncryptionLevel {
+        match self {
+            PacketType::Handshake => EncryptionLevel::Handshake,
+            PacketType::ZeroRtt | PacketType::Short => EncryptionLevel::Application,
+            PacketType::Initial | PacketType::Retry => EncryptionLevel::Initial,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ConnectionId {
     pub data: Vec<u8>,
 }
@@ -36,32 +49,166 @@ impl ConnectionId {
     }
 }
 
+/// Length of the connection IDs this implementation issues unless configured otherwise
+pub const DEFAULT_CONN_ID_LEN: usize = 8;
+
+/// Smallest datagram every QUIC path must carry, used until a larger path MTU is known
+pub const DEFAULT_MAX_DATAGRAM_SIZE: usize = 1200;
+
+/// QUIC version 1 (RFC 9000)
+pub const QUIC_VERSION_1: u32 = 0x0000_0001;
+
+/// Draft 29, which differs from version 1 only in its Initial salt, Retry keys and transport parameters extension
+pub const QUIC_VERSION_DRAFT_29: u32 = 0xff00_001d;
+
+/// Every version this implementation speaks, in order of preference; endpoints offer and accept
+/// all of them unless configured otherwise
+pub const SUPPORTED_VERSIONS: &[u32] = &[QUIC_VERSION_1, QUIC_VERSION_DRAFT_29];
+
+/// Version field that marks a Version Negotiation packet
+const VERSION_NEGOTIATION: u32 = 0;
+
+/// Fixed bit, set on every packet of QUIC version 1
+const FIXED_BIT: u8 = 0x40;
+/// Bits of the first byte that must be zero once header protection is removed
+const LONG_RESERVED_BITS: u8 = 0x0c;
+const SHORT_RESERVED_BITS: u8 = 0x18;
+
 #[derive(Debug, Clone)]
 pub struct LongHeader {
     pub packet_type: PacketType,
     pub version: u32,
     pub dest_conn_id: ConnectionId,
     pub src_conn_id: ConnectionId,
+    /// Address validation token, only carried by Initial packets
+    pub token: Bytes,
+    /// Length of the packet number and protected payload that follow the header
+    pub length: u64,
+    /// Full packet number when sending; only the low `packet_number_len` bytes go on the wire
     pub packet_number: u64,
+    pub packet_number_len: usize,
 }
 
 #[derive(Debug, Clone)]
 pub struct ShortHeader {
     pub dest_conn_id: ConnectionId,
     pub packet_number: u64,
+    pub packet_number_len: usize,
+}
+
+/// Unprotected reply listing the versions a server supports (RFC 9000 Section 17.2.1)
+#[derive(Debug, Clone)]
+pub struct VersionNegotiationHeader {
+    pub dest_conn_id: ConnectionId,
+    pub src_conn_id: ConnectionId,
+    pub supported_versions: Vec<u32>,
+}
+
+/// Retry packet (RFC 9000 Section 17.2.5); unprotected, but authenticated by its integrity tag
+#[derive(Debug, Clone)]
+pub struct RetryHeader {
+    pub version: u32,
+    pub dest_conn_id: ConnectionId,
+    pub src_conn_id: ConnectionId,
+    /// Address validation token the client must echo in its next Initial
+    pub token: Bytes,
+    pub integrity_tag: [u8; RETRY_INTEGRITY_TAG_LEN],
 }
 
 #[derive(Debug, Clone)]
 pub enum PacketHeader {
     Long(LongHeader),
     Short(ShortHeader),
+    VersionNegotiation(VersionNegotiationHeader),
+    Retry(RetryHeader),
 }
 
 impl PacketHeader {
+    /// Encryption level whose keys protect packets with this header
+    pub fn encryption_level(&self) -> EncryptionLevel {
+        match self {
+            PacketHeader::Long(header) => header.packet_type.encryption_level(),
+            PacketHeader::Short(_) => EncryptionLevel::Application,
+            // Never protected, but only meaningful before the handshake
+            PacketHeader::VersionNegotiation(_) | PacketHeader::Retry(_) => EncryptionLevel::Initial,
+        }
+    }
+    
+    pub fn packet_number(&self) -> u64 {
+        match self {
+            PacketHeader::Long(header) => header.packet_number,
+            PacketHeader::Short(header) => header.packet_number,
+            PacketHeader::VersionNegotiation(_) | PacketHeader::Retry(_) => 0,
+        }
+    }
+    
+    /// Replaces a decoded (truncated) packet number with its reconstructed value
+    pub fn set_packet_number(&mut self, packet_number: u64) {
+        match self {
+            PacketHeader::Long(header) => header.packet_number = packet_number,
+            PacketHeader::Short(header) => header.packet_number = packet_number,
+            PacketHeader::VersionNegotiation(_) | PacketHeader::Retry(_) => {}
+        }
+    }
+    
+    /// Number of bytes the packet number occupies on the wire
+    pub fn packet_number_len(&self) -> usize {
+        match self {
+            PacketHeader::Long(header) => header.packet_number_len,
+            PacketHeader::Short(header) => header.packet_number_len,
+            PacketHeader::VersionNegotiation(_) | PacketHeader::Retry(_) => 0,
+        }
+    }
+    
     pub fn encode(&self, buf: &mut BytesMut) -> Result<(), PacketError> {
+        // Neither packet carries a packet number
+        match self {
+            PacketHeader::VersionNegotiation(header) => {
+                // The low bits are unused; setting the fixed bit keeps the packet indistinguishable from others
+                buf.put_u8(0x80 | FIXED_BIT);
+                buf.put_u32(VERSION_NEGOTIATION);
+                
+                buf.put_u8(header.dest_conn_id.len() as u8);
+                buf.put_slice(&header.dest_conn_id.data);
+                
+                buf.put_u8(header.src_conn_id.len() as u8);
+                buf.put_slice(&header.src_conn_id.data);
+                
+                for version in &header.supported_versions {
+                    buf.put_u32(*version);
+                }
+                return Ok(());
+            }
+            PacketHeader::Retry(header) => {
+                buf.put_u8(0x80 | FIXED_BIT | (PacketType::Retry as u8) << 4);
+                buf.put_u32(header.version);
+                
+                buf.put_u8(header.dest_conn_id.len() as u8);
+                buf.put_slice(&header.dest_conn_id.data);
+                
+                buf.put_u8(header.src_conn_id.len() as u8);
+                buf.put_slice(&header.src_conn_id.data);
+                
+                buf.put_slice(&header.token);
+                buf.put_slice(&header.integrity_tag);
+                return Ok(());
+            }
+            _ => {}
+        }
+        
+        let pn_len = self.packet_number_len();
+        if !(1..=4).contains(&pn_len) {
+            return Err(PacketError::InvalidFormat);
+        }
+        let pn_len_bits = (pn_len - 1) as u8;
+        
         match self {
             PacketHeader::Long(header) => {
-                let first_byte = 0x80 | (header.packet_type as u8) << 4;
+                // A Retry has no Length or packet number and is encoded as `PacketHeader::Retry`
+                if header.packet_type == PacketType::Retry {
+                    return Err(PacketError::InvalidFormat);
+                }
+                let first_byte = 0x80 | FIXED_BIT | (header.packet_type as u8) << 4 | pn_len_bits;
                 buf.put_u8(first_byte);
                 buf.put_u32(header.version);
                 
@@ -71,33 +218,64 @@ impl PacketHeader {
                 buf.put_u8(header.src_conn_id.len() as u8);
                 buf.put_slice(&header.src_conn_id.data);
                 
-                encode_packet_number(buf, header.packet_number);
+                if header.packet_type == PacketType::Initial {
+                    encode_varint(buf, header.token.len() as u64);
+                    buf.put_slice(&header.token);
+                }
+                
+                encode_varint(buf, header.length);
+                write_packet_number(buf, header.packet_number, pn_len);
             }
             PacketHeader::Short(header) => {
-                let first_byte = 0x40;
+                let first_byte = FIXED_BIT | pn_len_bits;
                 buf.put_u8(first_byte);
                 buf.put_slice(&header.dest_conn_id.data);
-                encode_packet_number(buf, header.packet_number);
+                write_packet_number(buf, header.packet_number, pn_len);
             }
+            PacketHeader::VersionNegotiation(_) | PacketHeader::Retry(_) => unreachable!("encoded above"),
         }
         Ok(())
     }
     
+    /// Decodes a header whose protection has already been removed, assuming default-length short-header CIDs
     pub fn decode(buf: &mut Bytes) -> Result<Self, PacketError> {
+        Self::decode_with_conn_id_len(buf, DEFAULT_CONN_ID_LEN)
+    }
+    
+    /// Decodes a header whose short form carries a `conn_id_len`-byte destination connection ID
+    ///
+    /// Short headers do not encode the CID length, so the endpoint supplies the length it issues.
+    pub fn decode_with_conn_id_len(buf: &mut Bytes, conn_id_len: usize) -> Result<Self, PacketError> {
         if buf.is_empty() {
             return Err(PacketError::InvalidFormat);
         }
         
+        if is_version_negotiation(buf) {
+            return decode_version_negotiation(buf);
+        }
+        
         let first_byte = buf[0];
+        if first_byte & FIXED_BIT == 0 {
+            return Err(PacketError::InvalidFormat);
+        }
+        let pn_len = (first_byte & 0x03) as usize + 1;
         
         if first_byte & 0x80 != 0 {
             buf.advance(1);
             
+            // The low bits of a Retry are unused rather than reserved
+            if (first_byte >> 4) & 0x03 == PacketType::Retry as u8 {
+                return decode_retry(buf);
+            }
+            
+            if first_byte & LONG_RESERVED_BITS != 0 {
+                return Err(PacketError::InvalidFormat);
+            }
+            
             let packet_type = match (first_byte >> 4) & 0x03 {
                 0x00 => PacketType::Initial,
                 0x01 => PacketType::ZeroRtt,
                 0x02 => PacketType::Handshake,
-                0x03 => PacketType::Retry,
                 _ => return Err(PacketError::InvalidFormat),
             };
             
@@ -124,77 +302,392 @@ impl PacketHeader {
             }
             let src_conn_id = ConnectionId::new(buf.copy_to_bytes(src_conn_id_len).to_vec());
             
-            let packet_number = decode_packet_number(buf)?;
+            let token = if packet_type == PacketType::Initial {
+                read_token(buf)?
+            } else {
+                Bytes::new()
+            };
+            
+            let length = decode_varint(buf).map_err(|_| PacketError::InvalidFormat)?;
+            let packet_number = read_packet_number(buf, pn_len)?;
             
             Ok(PacketHeader::Long(LongHeader {
                 packet_type,
                 version,
                 dest_conn_id,
                 src_conn_id,
+                token,
+                length,
                 packet_number,
+                packet_number_len: pn_len,
             }))
         } else {
             buf.advance(1);
             
-            // For short header, we need to know the connection ID length
-            // For simplicity, assume 8 bytes (this should be configurable in real implementation)
-            if buf.remaining() < 8 {
+            if first_byte & SHORT_RESERVED_BITS != 0 {
                 return Err(PacketError::InvalidFormat);
             }
-            let dest_conn_id = ConnectionId::new(buf.copy_to_bytes(8).to_vec());
-            let packet_number = decode_packet_number(buf)?;
+            
+            let dest_conn_id = read_short_conn_id(buf, conn_id_len)?;
+            let packet_number = read_packet_number(buf, pn_len)?;
             
             Ok(PacketHeader::Short(ShortHeader {
                 dest_conn_id,
                 packet_number,
+                packet_number_len: pn_len,
             }))
         }
     }
 }
 
-fn encode_packet_number(buf: &mut BytesMut, packet_number: u64) {
-    if packet_number < 0x40 {
-        buf.put_u8(packet_number as u8);                           // 00xxxxxx
-    } else if packet_number < 0x4000 {
-        buf.put_u16(0x4000 | packet_number as u16);               // 01xxxxxx xxxxxxxx
-    } else if packet_number < 0x40000000 {
-        buf.put_u32(0x80000000 | packet_number as u32);           // 10xxxxxx xxxxxxxx xxxxxxxx xxxxxxxx
-    } else {
-        buf.put_u64(0xC000000000000000 | packet_number);          // 11xxxxxx x64 bits
+/// Decodes a Version Negotiation packet, which runs to the end of the datagram
+fn decode_version_negotiation(buf: &mut Bytes) -> Result<PacketHeader, PacketError> {
+    buf.advance(5);
+    let dest_conn_id = read_conn_id(buf)?;
+    let src_conn_id = read_conn_id(buf)?;
+    
+    if buf.is_empty() || !buf.remaining().is_multiple_of(4) {
+        return Err(PacketError::InvalidFormat);
     }
+    let mut supported_versions = Vec::with_capacity(buf.remaining() / 4);
+    while buf.has_remaining() {
+        supported_versions.push(buf.get_u32());
+    }
+    
+    Ok(PacketHeader::VersionNegotiation(VersionNegotiationHeader {
+        dest_conn_id,
+        src_conn_id,
+        supported_versions,
+    }))
 }
 
-fn decode_packet_number(buf: &mut Bytes) -> Result<u64, PacketError> {
-    if buf.is_empty() {
+/// Decodes a Retry packet after its first byte; the token runs up to the integrity tag
+fn decode_retry(buf: &mut Bytes) -> Result<PacketHeader, PacketError> {
+    if buf.remaining() < 4 {
         return Err(PacketError::InvalidFormat);
     }
+    let version = buf.get_u32();
+    let dest_conn_id = read_conn_id(buf)?;
+    let src_conn_id = read_conn_id(buf)?;
     
-    let first_byte = buf[0];
-    let len_bits = first_byte >> 6;
-    let len = 1 << len_bits;
+    let token_len = buf.remaining().checked_sub(RETRY_INTEGRITY_TAG_LEN).ok_or(PacketError::InvalidFormat)?;
+    let token = buf.copy_to_bytes(token_len);
+    let mut integrity_tag = [0u8; RETRY_INTEGRITY_TAG_LEN];
+    buf.copy_to_slice(&mut integrity_tag);
     
+    Ok(PacketHeader::Retry(RetryHeader {
+        version,
+        dest_conn_id,
+        src_conn_id,
+        token,
+        integrity_tag,
+    }))
+}
+
+/// Version field of a long-header packet, or `None` for short headers and truncated input
+pub fn long_header_version(packet: &[u8]) -> Option<u32> {
+    match packet {
+        [first_byte, version @ ..] if first_byte & 0x80 != 0 && version.len() >= 4 => {
+            Some(u32::from_be_bytes([version[0], version[1], version[2], version[3]]))
+        }
+        _ => None,
+    }
+}
+
+/// Whether `packet` is a Version Negotiation packet
+pub fn is_version_negotiation(packet: &[u8]) -> bool {
+    long_header_version(packet) == Some(VERSION_NEGOTIATION)
+}
+
+/// Builds the Version Negotiation packet a server sends when `datagram` uses a version it lacks
+///
+/// Returns `None` when no reply is due: for short headers, supported versions, Version Negotiation
+/// packets themselves, and datagrams too small to carry a client Initial (RFC 9000 Section 6.1).
+pub fn version_negotiation_response(datagram: &[u8], supported_versions: &[u32]) -> Option<Vec<u8>> {
+    let version = long_header_version(datagram)?;
+    if version == VERSION_NEGOTIATION
+        || supported_versions.contains(&version)
+        || datagram.len() < DEFAULT_MAX_DATAGRAM_SIZE
+    {
+        return None;
+    }
+    
+    // Connection IDs are version independent (RFC 8999), so they can be read from any version
+    let mut buf = Bytes::copy_from_slice(&datagram[5..]);
+    let dest_conn_id = read_conn_id(&mut buf).ok()?;
+    let src_conn_id = read_conn_id(&mut buf).ok()?;
+    
+    let header = PacketHeader::VersionNegotiation(VersionNegotiationHeader {
+        dest_conn_id: src_conn_id,
+        src_conn_id: dest_conn_id,
+        supported_versions: supported_versions.to_vec(),
+    });
+    let mut packet = BytesMut::new();
+    header.encode(&mut packet).ok()?;
+    Some(packet.to_vec())
+}
+
+/// Fields that can be read before header protection is removed
+#[derive(Debug, Clone)]
+pub struct ProtectedHeader {
+    pub packet_type: PacketType,
+    pub dest_conn_id: ConnectionId,
+    pub src_conn_id: Option<ConnectionId>,
+    /// Token of an Initial or Retry packet; empty for every other type
+    pub token: Bytes,
+    /// Offset of the (still masked) packet number field
+    pub pn_offset: usize,
+    /// Bytes of the datagram belonging to this packet
+    pub packet_len: usize,
+}
+
+impl ProtectedHeader {
+    pub fn parse(packet: &[u8]) -> Result<Self, PacketError> {
+        Self::parse_with_conn_id_len(packet, DEFAULT_CONN_ID_LEN)
+    }
+    
+    /// Parses a packet whose short form carries a `conn_id_len`-byte destination connection ID
+    pub fn parse_with_conn_id_len(packet: &[u8], conn_id_len: usize) -> Result<Self, PacketError> {
+        let mut buf = Bytes::copy_from_slice(packet);
+        if buf.is_empty() || is_version_negotiation(packet) {
+            return Err(PacketError::InvalidFormat);
+        }
+        
+        let first_byte = buf.get_u8();
+        if first_byte & FIXED_BIT == 0 {
+            return Err(PacketError::InvalidFormat);
+        }
+        
+        if first_byte & 0x80 != 0 {
+            let packet_type = match (first_byte >> 4) & 0x03 {
+                0x00 => PacketType::Initial,
+                0x01 => PacketType::ZeroRtt,
+                0x02 => PacketType::Handshake,
+                _ => PacketType::Retry,
+            };
+            
+            if buf.remaining() < 4 {
+                return Err(PacketError::InvalidFormat);
+            }
+            buf.advance(4);
+            
+            let dest_conn_id = read_conn_id(&mut buf)?;
+            let src_conn_id = read_conn_id(&mut buf)?;
+            
+            // A Retry has no packet number and fills the rest of the datagram
+            if packet_type == PacketType::Retry {
+                let token_len = buf.remaining().checked_sub(RETRY_INTEGRITY_TAG_LEN).ok_or(PacketError::InvalidFormat)?;
+                return Ok(Self {
+                    packet_type,
+                    dest_conn_id,
+                    src_conn_id: Some(src_conn_id),
+                    token: buf.copy_to_bytes(token_len),
+                    pn_offset: packet.len(),
+                    packet_len: packet.len(),
+                });
+            }
+            
+            let token = if packet_type == PacketType::Initial {
+                read_token(&mut buf)?
+            } else {
+                Bytes::new()
+            };
+            
+            let length = decode_varint(&mut buf).map_err(|_| PacketError::InvalidFormat)?;
+            if length > buf.remaining() as u64 {
+                return Err(PacketError::InvalidFormat);
+            }
+            let pn_offset = packet.len() - buf.remaining();
+            
+            Ok(Self {
+                packet_type,
+                dest_conn_id,
+                src_conn_id: Some(src_conn_id),
+                token,
+                pn_offset,
+                packet_len: pn_offset + length as usize,
+            })
+        } else {
+            let dest_conn_id = read_short_conn_id(&mut buf, conn_id_len)?;
+            
+            Ok(Self {
+                packet_type: PacketType::Short,
+                dest_conn_id,
+                src_conn_id: None,
+                token: Bytes::new(),
+                pn_offset: 1 + conn_id_len,
+                packet_len: packet.len(),
+            })
+        }
+    }
+}
+
+fn read_conn_id(buf: &mut Bytes) -> Result<ConnectionId, PacketError> {
+    if buf.is_empty() {
+        return Err(PacketError::InvalidFormat);
+    }
+    let len = buf.get_u8() as usize;
     if buf.remaining() < len {
         return Err(PacketError::InvalidFormat);
     }
+    Ok(ConnectionId::new(buf.copy_to_bytes(len).to_vec()))
+}
+
+/// Reads a short-header destination CID, which must be followed by at least one packet number byte
+fn read_short_conn_id(buf: &mut Bytes, conn_id_len: usize) -> Result<ConnectionId, PacketError> {
+    if buf.remaining() <= conn_id_len {
+        return Err(PacketError::TruncatedConnectionId { expected: conn_id_len });
+    }
+    Ok(ConnectionId::new(buf.copy_to_bytes(conn_id_len).to_vec()))
+}
+
+fn read_token(buf: &mut Bytes) -> Result<Bytes, PacketError> {
+    let len = decode_varint(buf).map_err(|_| PacketError::InvalidFormat)?;
+    if (buf.remaining() as u64) < len {
+        return Err(PacketError::InvalidFormat);
+    }
+    Ok(buf.copy_to_bytes(len as usize))
+}
+
+/// Splits a UDP datagram into the QUIC packets coalesced in it (RFC 9000 Section 12.2)
+pub fn split_datagram(datagram: &[u8]) -> DatagramPackets<'_> {
+    DatagramPackets { remaining: datagram }
+}
+
+/// Iterator over the packets of one datagram; stops after the first packet that cannot be delimited
+#[derive(Debug)]
+pub struct DatagramPackets<'a> {
+    remaining: &'a [u8],
+}
+
+impl<'a> Iterator for DatagramPackets<'a> {
+    type Item = Result<&'a [u8], PacketError>;
+    
+    fn next(&mut self) -> Option<Self::Item> {
+        let first_byte = *self.remaining.first()?;
+        
+        // Short headers and Version Negotiation have no Length field, so they run to the end of the datagram
+        let packet_len = if first_byte & 0x80 == 0 || is_version_negotiation(self.remaining) {
+            self.remaining.len()
+        } else {
+            match ProtectedHeader::parse(self.remaining) {
+                Ok(header) => header.packet_len,
+                Err(e) => {
+                    self.remaining = &[];
+                    return Some(Err(e));
+                }
+            }
+        };
+        
+        let (packet, rest) = self.remaining.split_at(packet_len);
+        self.remaining = rest;
+        Some(Ok(packet))
+    }
+}
+
+/// Packs encoded packets into a single datagram of at most `max_size` bytes
+#[derive(Debug)]
+pub struct DatagramBuilder {
+    max_size: usize,
+    datagram: Vec<u8>,
+    ends_with_short_header: bool,
+}
+
+impl DatagramBuilder {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            datagram: Vec::new(),
+            ends_with_short_header: false,
+        }
+    }
+    
+    /// Appends `packet` if it fits; nothing can follow a short-header packet
+    pub fn push(&mut self, packet: &[u8]) -> bool {
+        if self.ends_with_short_header || self.datagram.len() + packet.len() > self.max_size {
+            return false;
+        }
+        
+        self.ends_with_short_header = packet.first().is_some_and(|first_byte| first_byte & 0x80 == 0);
+        self.datagram.extend_from_slice(packet);
+        true
+    }
+    
+    pub fn len(&self) -> usize {
+        self.datagram.len()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.datagram.is_empty()
+    }
+    
+    pub fn finish(self) -> Vec<u8> {
+        self.datagram
+    }
+}
+
+/// Truncates `full_pn` to as few bytes as let the peer recover it (RFC 9000 Appendix A.2)
+///
+/// Returns the truncated packet number and its length in bytes.
+pub fn encode_packet_number(full_pn: u64, largest_acked: Option<u64>) -> (u64, usize) {
+    let num_unacked = match largest_acked {
+        Some(largest_acked) => full_pn.saturating_sub(largest_acked),
+        None => full_pn + 1,
+    };
+    
+    // Twice the number of packets in flight must fit, so the window is centred on the expected value
+    let min_bits = (u64::BITS - num_unacked.leading_zeros()) as usize + 1;
+    let num_bytes = min_bits.div_ceil(8).clamp(1, 4);
+    
+    (full_pn & packet_number_mask(num_bytes), num_bytes)
+}
+
+/// Recovers a full packet number from its truncated form (RFC 9000 Appendix A.3)
+pub fn decode_packet_number(truncated_pn: u64, pn_len: usize, largest_pn: Option<u64>) -> u64 {
+    let expected_pn = largest_pn.map_or(0, |largest_pn| largest_pn + 1);
+    let pn_win = 1u64 << (pn_len * 8);
+    let pn_hwin = pn_win / 2;
+    let pn_mask = pn_win - 1;
     
-    match len_bits {
-        0 => Ok((buf.get_u8() & 0x3F) as u64),           // 1 byte (00)
-        1 => Ok((buf.get_u16() & 0x3FFF) as u64),        // 2 bytes (01)
-        2 => Ok((buf.get_u32() & 0x3FFFFFFF) as u64),    // 4 bytes (10)
-        3 => Ok(buf.get_u64() & 0x3FFFFFFFFFFFFFFF),     // 8 bytes (11)
-        _ => Err(PacketError::InvalidFormat),
+    let candidate_pn = (expected_pn & !pn_mask) | truncated_pn;
+    if candidate_pn + pn_hwin <= expected_pn && candidate_pn < (1 << 62) - pn_win {
+        candidate_pn + pn_win
+    } else if candidate_pn > expected_pn + pn_hwin && candidate_pn >= pn_win {
+        candidate_pn - pn_win
+    } else {
+        candidate_pn
     }
 }
 
+fn packet_number_mask(len: usize) -> u64 {
+    (1u64 << (len * 8)) - 1
+}
+
+fn write_packet_number(buf: &mut BytesMut, packet_number: u64, len: usize) {
+    buf.put_uint(packet_number & packet_number_mask(len), len);
+}
+
+fn read_packet_number(buf: &mut Bytes, len: usize) -> Result<u64, PacketError> {
+    if buf.remaining() < len {
+        return Err(PacketError::InvalidFormat);
+    }
+    Ok(buf.get_uint(len))
+}
+
 #[derive(Debug)]
 pub enum PacketError {
     InvalidFormat,
+    /// A short header ended before the expected connection ID and packet number
+    TruncatedConnectionId { expected: usize },
 }
 
 impl fmt::Display for PacketError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             PacketError::InvalidFormat => write!(f, "Invalid packet format"),
+            PacketError::TruncatedConnectionId { expected } => {
+                write!(f, "Packet too short for a {}-byte connection ID", expected)
+            }
         }
     }
 }
@@ -238,7 +731,10 @@ mod tests {
             version: 1,
             dest_conn_id: ConnectionId::new(vec![1, 2, 3, 4]),
             src_conn_id: ConnectionId::new(vec![5, 6, 7, 8]),
+            token: Bytes::from_static(b"token"),
+            length: 20,
             packet_number: 42,
+            packet_number_len: 1,
         });
 
         let mut buf = BytesMut::new();
@@ -253,6 +749,8 @@ mod tests {
                 assert_eq!(decoded_header.version, 1);
                 assert_eq!(decoded_header.dest_conn_id.data, vec![1, 2, 3, 4]);
                 assert_eq!(decoded_header.src_conn_id.data, vec![5, 6, 7, 8]);
+                assert_eq!(decoded_header.token, Bytes::from_static(b"token"));
+                assert_eq!(decoded_header.length, 20);
                 assert_eq!(decoded_header.packet_number, 42);
             }
             _ => panic!("Expected Long header"),
@@ -264,6 +762,7 @@ mod tests {
         let header = PacketHeader::Short(ShortHeader {
             dest_conn_id: ConnectionId::new(vec![1, 2, 3, 4, 5, 6, 7, 8]),
             packet_number: 123,
+            packet_number_len: 1,
         });
 
         let mut buf = BytesMut::new();
@@ -282,28 +781,397 @@ mod tests {
     }
 
     #[test]
-    fn test_packet_number_encoding() {
-        let test_cases = vec![
-            (0, 1),                 // 1 byte
-            (63, 1),                // 1 byte max
-            (64, 2),                // 2 bytes
-            (16383, 2),             // 2 bytes max
-            (16384, 4),             // 4 bytes
-            (1073741823, 4),        // 4 bytes max
-            (1073741824, 8),        // 8 bytes
-        ];
+    fn test_packet_number_encoding_rfc9000_examples() {
+        // RFC 9000 Appendix A.2
+        assert_eq!(encode_packet_number(0xac5c02, Some(0xabe8b3)), (0x5c02, 2));
+        assert_eq!(encode_packet_number(0xace8fe, Some(0xabe8b3)), (0xace8fe, 3));
+        
+        // Before anything is acknowledged the whole range from zero is in flight
+        assert_eq!(encode_packet_number(0, None), (0, 1));
+        assert_eq!(encode_packet_number(126, None), (126, 1));
+        assert_eq!(encode_packet_number(127, None), (127, 2));
+    }
+
+    #[test]
+    fn test_packet_number_decoding_rfc9000_example() {
+        // RFC 9000 Appendix A.3
+        assert_eq!(decode_packet_number(0x9b32, 2, Some(0xa82f30ea)), 0xa82f9b32);
+    }
+
+    #[test]
+    fn test_packet_number_decoding_window_edges() {
+        assert_eq!(decode_packet_number(0, 1, None), 0);
+        // Wraps forward past a byte boundary
+        assert_eq!(decode_packet_number(0x01, 1, Some(0xff)), 0x101);
+        // A late packet from just below the boundary
+        assert_eq!(decode_packet_number(0xfe, 1, Some(0x101)), 0xfe);
+    }
 
-        for (packet_num, expected_len) in test_cases {
+    #[test]
+    fn test_packet_number_roundtrip() {
+        for (full_pn, largest_acked) in [(0u64, None), (1000, Some(990)), (0x12345678, Some(0x12340000)), (70000, None)] {
+            let (truncated, len) = encode_packet_number(full_pn, largest_acked);
+            // The receiver has seen at least what the sender knows to be acknowledged
+            assert_eq!(decode_packet_number(truncated, len, largest_acked), full_pn);
+            
+            let header = PacketHeader::Short(ShortHeader {
+                dest_conn_id: ConnectionId::new(vec![0; 8]),
+                packet_number: full_pn,
+                packet_number_len: len,
+            });
             let mut buf = BytesMut::new();
-            encode_packet_number(&mut buf, packet_num);
-            assert_eq!(buf.len(), expected_len, "Packet number {} should encode to {} bytes", packet_num, expected_len);
+            header.encode(&mut buf).unwrap();
+            assert_eq!(buf.len(), 1 + 8 + len);
+            
+            let decoded = PacketHeader::decode(&mut buf.freeze()).unwrap();
+            assert_eq!(decoded.packet_number(), truncated);
+        }
+    }
 
-            let mut bytes = buf.freeze();
-            let decoded = decode_packet_number(&mut bytes).unwrap();
-            assert_eq!(decoded, packet_num, "Packet number {} should decode correctly", packet_num);
+    #[test]
+    fn test_packet_number_behind_largest_acked() {
+        // A packet number below the acknowledged one can only come from a bad ACK, and must not underflow
+        assert_eq!(encode_packet_number(5, Some(10)), (5, 1));
+    }
+
+    #[test]
+    fn test_invalid_packet_number_length_rejected() {
+        for packet_number_len in [0, 5] {
+            let header = PacketHeader::Short(ShortHeader {
+                dest_conn_id: ConnectionId::new(vec![0; 8]),
+                packet_number: 1,
+                packet_number_len,
+            });
+            assert!(header.encode(&mut BytesMut::new()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_first_byte_carries_packet_number_length() {
+        for packet_number_len in 1..=4 {
+            let header = PacketHeader::Short(ShortHeader {
+                dest_conn_id: ConnectionId::new(vec![0; 8]),
+                packet_number: 5,
+                packet_number_len,
+            });
+            
+            let mut buf = BytesMut::new();
+            header.encode(&mut buf).unwrap();
+            
+            assert_eq!((buf[0] & 0x03) as usize, packet_number_len - 1);
+            assert_eq!(buf.len(), 1 + 8 + packet_number_len);
         }
     }
 
+    #[test]
+    fn test_protected_header_parse() {
+        let header = PacketHeader::Long(LongHeader {
+            packet_type: PacketType::Handshake,
+            version: 1,
+            dest_conn_id: ConnectionId::new(vec![1, 2, 3]),
+            src_conn_id: ConnectionId::new(vec![4, 5]),
+            token: Bytes::new(),
+            length: 1,
+            packet_number: 7,
+            packet_number_len: 1,
+        });
+        
+        let mut buf = BytesMut::new();
+        header.encode(&mut buf).unwrap();
+        
+        let protected = ProtectedHeader::parse(&buf).unwrap();
+        assert_eq!(protected.packet_type, PacketType::Handshake);
+        assert_eq!(protected.dest_conn_id.data, vec![1, 2, 3]);
+        assert_eq!(protected.src_conn_id.unwrap().data, vec![4, 5]);
+        assert_eq!(protected.pn_offset, buf.len() - 1);
+        assert_eq!(protected.packet_len, buf.len());
+        
+        // A Length running past the end of the datagram is rejected
+        assert!(ProtectedHeader::parse(&buf[..buf.len() - 1]).is_err());
+        
+        assert!(ProtectedHeader::parse(&[0x40, 1, 2]).is_err());
+    }
+
+    #[test]
+    fn test_long_header_wire_format() {
+        let header = PacketHeader::Long(LongHeader {
+            packet_type: PacketType::Initial,
+            version: 1,
+            dest_conn_id: ConnectionId::new(vec![0xaa]),
+            src_conn_id: ConnectionId::new(vec![]),
+            token: Bytes::from_static(&[0x01, 0x02]),
+            length: 300,
+            packet_number: 0x1234,
+            packet_number_len: 2,
+        });
+        
+        let mut buf = BytesMut::new();
+        header.encode(&mut buf).unwrap();
+        
+        // Long form, fixed bit, Initial type and a 2-byte packet number
+        assert_eq!(
+            buf.as_ref(),
+            &[0xc1, 0x00, 0x00, 0x00, 0x01, 0x01, 0xaa, 0x00, 0x02, 0x01, 0x02, 0x41, 0x2c, 0x12, 0x34]
+        );
+    }
+
+    #[test]
+    fn test_fixed_and_reserved_bits() {
+        let header = PacketHeader::Short(ShortHeader {
+            dest_conn_id: ConnectionId::new(vec![0; 8]),
+            packet_number: 1,
+            packet_number_len: 1,
+        });
+        let mut buf = BytesMut::new();
+        header.encode(&mut buf).unwrap();
+        
+        let mut without_fixed_bit = buf.to_vec();
+        without_fixed_bit[0] &= !FIXED_BIT;
+        assert!(PacketHeader::decode(&mut Bytes::copy_from_slice(&without_fixed_bit)).is_err());
+        assert!(ProtectedHeader::parse(&without_fixed_bit).is_err());
+        
+        let mut reserved = buf.to_vec();
+        reserved[0] |= SHORT_RESERVED_BITS;
+        assert!(PacketHeader::decode(&mut Bytes::from(reserved)).is_err());
+    }
+
+    #[test]
+    fn test_short_header_configured_conn_id_len() {
+        let header = PacketHeader::Short(ShortHeader {
+            dest_conn_id: ConnectionId::new((0..16).collect()),
+            packet_number: 9,
+            packet_number_len: 1,
+        });
+        let mut buf = BytesMut::new();
+        header.encode(&mut buf).unwrap();
+        
+        let decoded = PacketHeader::decode_with_conn_id_len(&mut buf.clone().freeze(), 16).unwrap();
+        match decoded {
+            PacketHeader::Short(decoded_header) => {
+                assert_eq!(decoded_header.dest_conn_id.data, (0..16).collect::<Vec<u8>>());
+                assert_eq!(decoded_header.packet_number, 9);
+            }
+            _ => panic!("Expected Short header"),
+        }
+        
+        let protected = ProtectedHeader::parse_with_conn_id_len(&buf, 16).unwrap();
+        assert_eq!(protected.dest_conn_id.len(), 16);
+        assert_eq!(protected.pn_offset, 17);
+    }
+
+    #[test]
+    fn test_short_header_too_short_for_conn_id() {
+        let packet = [0x40, 1, 2, 3, 4, 5, 6, 7, 8];
+        
+        assert!(matches!(
+            PacketHeader::decode_with_conn_id_len(&mut Bytes::copy_from_slice(&packet), 16),
+            Err(PacketError::TruncatedConnectionId { expected: 16 })
+        ));
+        assert!(matches!(
+            ProtectedHeader::parse(&packet),
+            Err(PacketError::TruncatedConnectionId { expected: 8 })
+        ));
+    }
+
+    fn encoded_long_header(packet_type: PacketType, payload_len: usize) -> Vec<u8> {
+        let header = PacketHeader::Long(LongHeader {
+            packet_type,
+            version: 1,
+            dest_conn_id: ConnectionId::new(vec![1, 2, 3, 4]),
+            src_conn_id: ConnectionId::new(vec![5, 6, 7, 8]),
+            token: Bytes::new(),
+            length: 1 + payload_len as u64,
+            packet_number: 0,
+            packet_number_len: 1,
+        });
+        let mut buf = BytesMut::new();
+        header.encode(&mut buf).unwrap();
+        buf.put_bytes(0xee, payload_len);
+        buf.to_vec()
+    }
+
+    #[test]
+    fn test_split_coalesced_datagram() {
+        let initial = encoded_long_header(PacketType::Initial, 30);
+        let handshake = encoded_long_header(PacketType::Handshake, 50);
+        let short = [0x41, 1, 2, 3, 4, 5, 6, 7, 8, 0, 0xee, 0xee];
+        let datagram = [initial.clone(), handshake.clone(), short.to_vec()].concat();
+        
+        let packets: Vec<&[u8]> = split_datagram(&datagram).collect::<Result<_, _>>().unwrap();
+        
+        assert_eq!(packets, vec![&initial[..], &handshake[..], &short[..]]);
+    }
+
+    #[test]
+    fn test_split_datagram_stops_at_bad_length() {
+        let initial = encoded_long_header(PacketType::Initial, 30);
+        let mut handshake = encoded_long_header(PacketType::Handshake, 50);
+        handshake.truncate(40);
+        let datagram = [initial.clone(), handshake].concat();
+        
+        let mut packets = split_datagram(&datagram);
+        assert_eq!(packets.next().unwrap().unwrap(), &initial[..]);
+        assert!(packets.next().unwrap().is_err());
+        assert!(packets.next().is_none());
+    }
+
+    #[test]
+    fn test_datagram_builder_respects_size_and_short_headers() {
+        let initial = encoded_long_header(PacketType::Initial, 30);
+        let short = [0x41, 1, 2, 3, 4, 5, 6, 7, 8, 0];
+        
+        let mut builder = DatagramBuilder::new(2 * initial.len());
+        assert!(builder.push(&initial));
+        assert!(builder.push(&initial));
+        assert!(!builder.push(&short));
+        assert_eq!(builder.len(), 2 * initial.len());
+        
+        let mut builder = DatagramBuilder::new(DEFAULT_MAX_DATAGRAM_SIZE);
+        assert!(builder.push(&short));
+        assert!(!builder.push(&initial));
+        assert_eq!(builder.finish(), short.to_vec());
+    }
+
+    #[test]
+    fn test_retry_decode_rfc9001() {
+        // RFC 9001 Appendix A.4; the unused low bits of the first byte are all set
+        let retry = [
+            0xff, 0x00, 0x00, 0x00, 0x01, 0x00, 0x08, 0xf0, 0x67, 0xa5, 0x50, 0x2a, 0x42, 0x62, 0xb5,
+            0x74, 0x6f, 0x6b, 0x65, 0x6e, 0x04, 0xa2, 0x65, 0xba, 0x2e, 0xff, 0x4d, 0x82, 0x90, 0x58,
+            0xfb, 0x3f, 0x0f, 0x24, 0x96, 0xba,
+        ];
+
+        match PacketHeader::decode(&mut Bytes::copy_from_slice(&retry)).unwrap() {
+            PacketHeader::Retry(header) => {
+                assert_eq!(header.version, QUIC_VERSION_1);
+                assert!(header.dest_conn_id.is_empty());
+                assert_eq!(header.src_conn_id.data, vec![0xf0, 0x67, 0xa5, 0x50, 0x2a, 0x42, 0x62, 0xb5]);
+                assert_eq!(header.token, Bytes::from_static(b"token"));
+                assert_eq!(header.integrity_tag[..], retry[20..]);
+            }
+            _ => panic!("Expected Retry header"),
+        }
+
+        // It has no Length field, so it fills the datagram
+        let protected = ProtectedHeader::parse(&retry).unwrap();
+        assert_eq!(protected.packet_type, PacketType::Retry);
+        assert_eq!(protected.packet_len, retry.len());
+        assert_eq!(protected.token, Bytes::from_static(b"token"));
+    }
+
+    #[test]
+    fn test_retry_encode_decode() {
+        let header = PacketHeader::Retry(RetryHeader {
+            version: QUIC_VERSION_1,
+            dest_conn_id: ConnectionId::new(vec![1, 2, 3, 4]),
+            src_conn_id: ConnectionId::new(vec![5, 6, 7, 8]),
+            token: Bytes::from_static(b"retry-token"),
+            integrity_tag: [0xaa; RETRY_INTEGRITY_TAG_LEN],
+        });
+
+        let mut buf = BytesMut::new();
+        header.encode(&mut buf).unwrap();
+        assert_eq!(buf[0], 0xf0);
+
+        match PacketHeader::decode(&mut buf.freeze()).unwrap() {
+            PacketHeader::Retry(decoded) => {
+                assert_eq!(decoded.token, Bytes::from_static(b"retry-token"));
+                assert_eq!(decoded.integrity_tag, [0xaa; RETRY_INTEGRITY_TAG_LEN]);
+            }
+            _ => panic!("Expected Retry header"),
+        }
+
+        // Too short to hold the integrity tag
+        let mut truncated = Bytes::from_static(&[0xf0, 0, 0, 0, 1, 0, 0, 0xaa, 0xaa]);
+        assert!(PacketHeader::decode(&mut truncated).is_err());
+    }
+
+    #[test]
+    fn test_long_header_cannot_encode_retry() {
+        let header = PacketHeader::Long(LongHeader {
+            packet_type: PacketType::Retry,
+            version: 1,
+            dest_conn_id: ConnectionId::new(vec![1, 2]),
+            src_conn_id: ConnectionId::new(vec![3, 4]),
+            token: Bytes::new(),
+            length: 0,
+            packet_number: 1,
+            packet_number_len: 1,
+        });
+
+        assert!(header.encode(&mut BytesMut::new()).is_err());
+    }
+
+    #[test]
+    fn test_version_negotiation_encode_decode() {
+        let header = PacketHeader::VersionNegotiation(VersionNegotiationHeader {
+            dest_conn_id: ConnectionId::new(vec![1, 2, 3, 4]),
+            src_conn_id: ConnectionId::new(vec![5, 6]),
+            supported_versions: vec![QUIC_VERSION_1, 0xff00_001d],
+        });
+
+        let mut buf = BytesMut::new();
+        header.encode(&mut buf).unwrap();
+        assert_eq!(&buf[1..5], &[0, 0, 0, 0]);
+        assert!(is_version_negotiation(&buf));
+
+        let mut bytes = buf.freeze();
+        match PacketHeader::decode(&mut bytes).unwrap() {
+            PacketHeader::VersionNegotiation(decoded) => {
+                assert_eq!(decoded.dest_conn_id.data, vec![1, 2, 3, 4]);
+                assert_eq!(decoded.src_conn_id.data, vec![5, 6]);
+                assert_eq!(decoded.supported_versions, vec![QUIC_VERSION_1, 0xff00_001d]);
+            }
+            _ => panic!("Expected Version Negotiation header"),
+        }
+    }
+
+    #[test]
+    fn test_version_negotiation_rejects_partial_version() {
+        let mut bytes = Bytes::from_static(&[0xc0, 0, 0, 0, 0, 1, 0xaa, 1, 0xbb, 0, 0, 0]);
+        assert!(PacketHeader::decode(&mut bytes).is_err());
+    }
+
+    #[test]
+    fn test_version_negotiation_response() {
+        let mut datagram = encoded_long_header(PacketType::Initial, DEFAULT_MAX_DATAGRAM_SIZE);
+        datagram[1..5].copy_from_slice(&0x1a2a_3a4au32.to_be_bytes());
+
+        let response = version_negotiation_response(&datagram, SUPPORTED_VERSIONS).unwrap();
+        match PacketHeader::decode(&mut Bytes::from(response)).unwrap() {
+            PacketHeader::VersionNegotiation(header) => {
+                // Connection IDs are echoed back swapped
+                assert_eq!(header.dest_conn_id.data, vec![5, 6, 7, 8]);
+                assert_eq!(header.src_conn_id.data, vec![1, 2, 3, 4]);
+                assert_eq!(header.supported_versions, SUPPORTED_VERSIONS.to_vec());
+            }
+            _ => panic!("Expected Version Negotiation header"),
+        }
+
+        // Nothing is sent for supported versions or datagrams too small to be a client Initial
+        assert!(version_negotiation_response(&datagram, &[0x1a2a_3a4a]).is_none());
+        assert!(version_negotiation_response(&datagram[..100], SUPPORTED_VERSIONS).is_none());
+        let supported = encoded_long_header(PacketType::Initial, DEFAULT_MAX_DATAGRAM_SIZE);
+        assert!(version_negotiation_response(&supported, SUPPORTED_VERSIONS).is_none());
+    }
+
+    #[test]
+    fn test_version_negotiation_never_answered() {
+        let header = PacketHeader::VersionNegotiation(VersionNegotiationHeader {
+            dest_conn_id: ConnectionId::new(vec![1, 2, 3, 4]),
+            src_conn_id: ConnectionId::new(vec![5, 6, 7, 8]),
+            supported_versions: vec![0x1a2a_3a4a; DEFAULT_MAX_DATAGRAM_SIZE / 4],
+        });
+        let mut buf = BytesMut::new();
+        header.encode(&mut buf).unwrap();
+
+        assert!(version_negotiation_response(&buf, SUPPORTED_VERSIONS).is_none());
+
+        // It has no Length field, so it fills the rest of the datagram
+        let packets: Vec<&[u8]> = split_datagram(&buf).collect::<Result<_, _>>().unwrap();
+        assert_eq!(packets, vec![&buf[..]]);
+    }
+
     #[test]
     fn test_invalid_packet_decode() {
         let mut empty_bytes = Bytes::new();
@@ -334,7 +1202,6 @@ mod tests {
             PacketType::Initial,
             PacketType::ZeroRtt,
             PacketType::Handshake,
-            PacketType::Retry,
         ];
 
         for packet_type in packet_types {
@@ -343,7 +1210,10 @@ mod tests {
                 version: 1,
                 dest_conn_id: ConnectionId::new(vec![1, 2]),
                 src_conn_id: ConnectionId::new(vec![3, 4]),
+                token: Bytes::new(),
+                length: 0,
                 packet_number: 1,
+                packet_number_len: 1,
             });
 
             let mut buf = BytesMut::new();
@@ -0,0 +1,571 @@
+use crate::congestion::{CongestionController, NewReno};
+use crate::crypto::EncryptionLevel;
+use crate::frame::Frame;
+use crate::rtt::RttEstimator;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
+
+/// Packets this far below the largest acknowledged one are lost (RFC 9002 Section 6.1.1)
+pub const PACKET_THRESHOLD: u64 = 3;
+/// Timer granularity, the smallest loss delay or PTO variance allowed (RFC 9002 Section 6.1.2)
+pub const GRANULARITY: Duration = Duration::from_millis(1);
+/// Longest the peer delays its acknowledgements unless it says otherwise, the default max_ack_delay (RFC 9000 Section 18.2)
+pub const MAX_ACK_DELAY: Duration = Duration::from_millis(25);
+/// Most probe packets sent when the PTO fires (RFC 9002 Section 6.2.4)
+const MAX_PROBES: usize = 2;
+/// PTOs' worth of consecutive losses that count as persistent congestion (RFC 9002 Section 7.6.1)
+const PERSISTENT_CONGESTION_THRESHOLD: u32 = 3;
+
+/// A packet awaiting acknowledgement, with the frames to requeue if it is lost
+#[derive(Debug, Clone)]
+pub struct SentPacket {
+    pub packet_number: u64,
+    pub time_sent: Instant,
+    pub size: usize,
+    pub ack_eliciting: bool,
+    /// Frames worth sending again; ACK, PADDING and PING are left out
+    pub frames: Vec<Frame>,
+    /// Largest packet number of the ACK frame this packet carried, which the peer has seen once this packet is acknowledged
+    pub ack_largest: Option<u64>,
+}
+
+impl SentPacket {
+    pub fn new(packet_number: u64, time_sent: Instant, size: usize, frames: &[Frame]) -> Self {
+        Self {
+            packet_number,
+            time_sent,
+            size,
+            ack_eliciting: frames.iter().any(Frame::is_ack_eliciting),
+            frames: frames.iter().filter(|frame| is_retransmittable(frame)).cloned().collect(),
+            ack_largest: frames.iter().find_map(|frame| match frame {
+                Frame::Ack { largest, .. } => Some(*largest),
+                _ => None,
+            }),
+        }
+    }
+}
+
+/// Whether a lost frame carries information the peer still needs
+fn is_retransmittable(frame: &Frame) -> bool {
+    !matches!(frame, Frame::Padding { .. } | Frame::Ping | Frame::Ack { .. }) && !frame.is_close()
+}
+
+/// Packets sent and not yet acknowledged or lost in one packet number space
+#[derive(Debug, Default)]
+struct SentPackets {
+    packets: BTreeMap<u64, SentPacket>,
+    largest_acked: Option<u64>,
+    /// When the earliest packet not yet lost by the packet threshold becomes lost by time
+    loss_time: Option<Instant>,
+    time_of_last_ack_eliciting: Option<Instant>,
+}
+
+impl SentPackets {
+    fn has_ack_eliciting_in_flight(&self) -> bool {
+        self.packets.values().any(|packet| packet.ack_eliciting)
+    }
+}
+
+/// Packets the peer acknowledged and packets declared lost by an ACK frame
+#[derive(Debug, Default)]
+pub struct AckOutcome {
+    pub acked: Vec<SentPacket>,
+    pub lost: Vec<SentPacket>,
+}
+
+/// What the loss detection timer did when it fired
+#[derive(Debug)]
+pub enum Timeout {
+    /// Packets that went unacknowledged past the time threshold
+    Lost(EncryptionLevel, Vec<SentPacket>),
+    /// The PTO expired; the frames of the oldest unacknowledged packets should be sent again,
+    /// or a PING if there are none
+    Probe(EncryptionLevel, Vec<Frame>),
+}
+
+/// Loss detection and the probe timeout for one connection (RFC 9002 Section 6)
+#[derive(Debug)]
+pub struct Recovery {
+    spaces: HashMap<EncryptionLevel, SentPackets>,
+    /// Consecutive PTOs without an acknowledgement, doubling the timeout each time
+    pto_count: u32,
+    /// Application data is only probed once the handshake is confirmed (RFC 9002 Section 6.2.1)
+    handshake_confirmed: bool,
+    rtt: RttEstimator,
+    /// When the first RTT sample was taken; losses before it cannot be persistent congestion
+    first_rtt_sample: Option<Instant>,
+    /// Longest the peer says it delays acknowledgements, from its transport parameters
+    max_ack_delay: Duration,
+    congestion: Box<dyn CongestionController>,
+    /// Probe packets that may still be sent regardless of the congestion window
+    probes: usize,
+}
+
+impl Default for Recovery {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Recovery {
+    pub fn new() -> Self {
+        Self::with_congestion_controller(Box::new(NewReno::default()))
+    }
+    
+    pub fn with_congestion_controller(congestion: Box<dyn CongestionController>) -> Self {
+        Self {
+            spaces: HashMap::new(),
+            pto_count: 0,
+            handshake_confirmed: false,
+            rtt: RttEstimator::new(),
+            first_rtt_sample: None,
+            max_ack_delay: MAX_ACK_DELAY,
+            congestion,
+            probes: 0,
+        }
+    }
+    
+    pub fn set_congestion_controller(&mut self, congestion: Box<dyn CongestionController>) {
+        self.congestion = congestion;
+    }
+    
+    pub fn congestion_controller(&self) -> &dyn CongestionController {
+        self.congestion.as_ref()
+    }
+    
+    /// Forgets every packet sent, keeping the congestion controller, for a handshake started over
+    pub fn reset(&mut self) {
+        self.spaces.clear();
+        self.pto_count = 0;
+        self.probes = 0;
+    }
+    
+    /// Whether another ack-eliciting packet fits in the congestion window, or is owed as a probe
+    pub fn can_send(&self) -> bool {
+        self.probes > 0 || self.bytes_in_flight() < self.congestion.window()
+    }
+    
+    pub fn rtt(&self) -> &RttEstimator {
+        &self.rtt
+    }
+    
+    pub fn set_max_ack_delay(&mut self, max_ack_delay: Duration) {
+        self.max_ack_delay = max_ack_delay;
+    }
+    
+    pub fn pto_count(&self) -> u32 {
+        self.pto_count
+    }
+    
+    pub fn confirm_handshake(&mut self) {
+        self.handshake_confirmed = true;
+    }
+    
+    pub fn is_handshake_confirmed(&self) -> bool {
+        self.handshake_confirmed
+    }
+    
+    pub fn on_packet_sent(&mut self, level: EncryptionLevel, packet: SentPacket) {
+        let space = self.spaces.entry(level).or_default();
+        if packet.ack_eliciting {
+            space.time_of_last_ack_eliciting = Some(packet.time_sent);
+            self.probes = self.probes.saturating_sub(1);
+            self.congestion.on_packet_sent(packet.time_sent, packet.size);
+        }
+        space.packets.insert(packet.packet_number, packet);
+    }
+    
+    /// Removes the packets covered by an ACK frame's `ranges`, samples the RTT from the largest
+    /// of them, then detects any lost packets
+    pub fn on_ack_received(&mut self, level: EncryptionLevel, ranges: &[RangeInclusive<u64>], ack_delay: Duration, now: Instant) -> AckOutcome {
+        let space = self.spaces.entry(level).or_default();
+        let mut outcome = AckOutcome::default();
+        for range in ranges {
+            let acked: Vec<u64> = space.packets.range(range.clone()).map(|(pn, _)| *pn).collect();
+            outcome.acked.extend(acked.into_iter().filter_map(|pn| space.packets.remove(&pn)));
+        }
+        if outcome.acked.is_empty() {
+            return outcome;
+        }
+        
+        // Loss detection counts back from packets really acknowledged, not from whatever the frame claims
+        let largest = ranges.iter().map(|range| *range.end()).max();
+        let largest_newly_acked = outcome.acked.iter().map(|packet| packet.packet_number).max();
+        space.largest_acked = space.largest_acked.max(largest_newly_acked);
+        
+        // Only a newly acknowledged largest packet gives an RTT sample (RFC 9002 Section 5.1)
+        let newest = outcome.acked.iter().find(|packet| Some(packet.packet_number) == largest);
+        if let Some(newest) = newest.filter(|_| outcome.acked.iter().any(|packet| packet.ack_eliciting)) {
+            // Initial packets are acknowledged without delay, and max_ack_delay is only trusted once confirmed
+            let ack_delay = if level == EncryptionLevel::Initial { Duration::ZERO } else { ack_delay };
+            let max_ack_delay = self.handshake_confirmed.then_some(self.max_ack_delay);
+            self.rtt.update(now.saturating_duration_since(newest.time_sent), ack_delay, max_ack_delay);
+            self.first_rtt_sample.get_or_insert(now);
+        }
+        self.pto_count = 0;
+        // Ranges arrive largest first; controllers see packets in the order they were sent
+        outcome.acked.sort_by_key(|packet| packet.packet_number);
+        for packet in &outcome.acked {
+            self.congestion.on_ack(now, packet.time_sent, packet.size, &self.rtt);
+        }
+        outcome.lost = self.detect_lost_packets(level, now);
+        self.on_packets_lost(&outcome.lost, &outcome.acked, now);
+        outcome
+    }
+    
+    /// Reports lost packets to the congestion controller as a single congestion event
+    fn on_packets_lost(&mut self, lost: &[SentPacket], acked: &[SentPacket], now: Instant) {
+        let Some(newest) = lost.iter().max_by_key(|packet| packet.time_sent) else {
+            return;
+        };
+        let persistent_congestion = self.is_persistent_congestion(lost, acked);
+        self.congestion.on_congestion_event(now, newest.time_sent, persistent_congestion);
+    }
+    
+    /// Whether the lost packets span longer than the persistent congestion duration with nothing
+    /// in between acknowledged, counting only packets sent after the first RTT sample (RFC 9002 Section 7.6.2)
+    fn is_persistent_congestion(&self, lost: &[SentPacket], acked: &[SentPacket]) -> bool {
+        let Some(first_rtt_sample) = self.first_rtt_sample else {
+            return false;
+        };
+        let mut lost = lost.iter().filter(|packet| packet.ack_eliciting && packet.time_sent > first_rtt_sample);
+        let Some(first) = lost.next() else {
+            return false;
+        };
+        let last = lost.next_back().unwrap_or(first);
+        if acked.iter().any(|packet| (first.packet_number..=last.packet_number).contains(&packet.packet_number)) {
+            return false;
+        }
+        
+        let duration = (self.rtt.smoothed_rtt() + (4 * self.rtt.rttvar()).max(GRANULARITY) + self.max_ack_delay)
+            * PERSISTENT_CONGESTION_THRESHOLD;
+        last.time_sent.saturating_duration_since(first.time_sent) > duration
+    }
+    
+    /// Declares packets lost by the packet or time threshold (RFC 9002 Section 6.1)
+    fn detect_lost_packets(&mut self, level: EncryptionLevel, now: Instant) -> Vec<SentPacket> {
+        let latest_rtt = self.rtt.latest_rtt().unwrap_or_default();
+        let loss_delay = (self.rtt.smoothed_rtt().max(latest_rtt) * 9 / 8).max(GRANULARITY);
+        let Some(space) = self.spaces.get_mut(&level) else {
+            return Vec::new();
+        };
+        space.loss_time = None;
+        let Some(largest_acked) = space.largest_acked else {
+            return Vec::new();
+        };
+        
+        let mut lost = Vec::new();
+        for (pn, packet) in space.packets.range(..largest_acked) {
+            if largest_acked - pn >= PACKET_THRESHOLD || packet.time_sent + loss_delay <= now {
+                lost.push(*pn);
+            } else {
+                let loss_time = packet.time_sent + loss_delay;
+                space.loss_time = Some(space.loss_time.map_or(loss_time, |time| time.min(loss_time)));
+            }
+        }
+        lost.into_iter().filter_map(|pn| space.packets.remove(&pn)).collect()
+    }
+    
+    /// Current probe timeout for application data, including backoff
+    pub fn probe_timeout(&self) -> Duration {
+        self.pto(EncryptionLevel::Application)
+    }
+    
+    /// Probe timeout for `level` before backoff (RFC 9002 Section 6.2.1)
+    fn pto(&self, level: EncryptionLevel) -> Duration {
+        let mut pto = self.rtt.smoothed_rtt() + (4 * self.rtt.rttvar()).max(GRANULARITY);
+        if level == EncryptionLevel::Application {
+            pto += self.max_ack_delay;
+        }
+        pto * 2u32.saturating_pow(self.pto_count)
+    }
+    
+    /// Earliest time-threshold loss, or else earliest PTO, across the spaces
+    fn next_timeout(&self) -> Option<(Instant, EncryptionLevel, bool)> {
+        let levels = [EncryptionLevel::Initial, EncryptionLevel::Handshake, EncryptionLevel::Application];
+        let loss = levels.iter()
+            .filter_map(|level| Some((self.spaces.get(level)?.loss_time?, *level, true)))
+            .min_by_key(|(time, _, _)| *time);
+        if loss.is_some() {
+            return loss;
+        }
+        
+        levels.iter()
+            .filter(|level| **level != EncryptionLevel::Application || self.handshake_confirmed)
+            .filter_map(|level| {
+                let space = self.spaces.get(level)?;
+                if !space.has_ack_eliciting_in_flight() {
+                    return None;
+                }
+                Some((space.time_of_last_ack_eliciting? + self.pto(*level), *level, false))
+            })
+            .min_by_key(|(time, _, _)| *time)
+    }
+    
+    /// When the loss detection timer fires, if it is armed
+    pub fn timeout(&self) -> Option<Instant> {
+        self.next_timeout().map(|(time, _, _)| time)
+    }
+    
+    /// Runs the loss detection timer if it has expired at `now`
+    pub fn on_timeout(&mut self, now: Instant) -> Option<Timeout> {
+        let (time, level, is_loss) = self.next_timeout()?;
+        if time > now {
+            return None;
+        }
+        if is_loss {
+            let lost = self.detect_lost_packets(level, now);
+            self.on_packets_lost(&lost, &[], now);
+            return Some(Timeout::Lost(level, lost));
+        }
+        
+        self.pto_count += 1;
+        self.probes = MAX_PROBES;
+        let frames = self.spaces.get(&level)
+            .map(|space| {
+                space.packets.values()
+                    .filter(|packet| packet.ack_eliciting)
+                    .take(MAX_PROBES)
+                    .flat_map(|packet| packet.frames.iter().cloned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        // The probe restarts the timer, as sending any ack-eliciting packet would
+        if let Some(space) = self.spaces.get_mut(&level) {
+            space.time_of_last_ack_eliciting = Some(now);
+        }
+        Some(Timeout::Probe(level, frames))
+    }
+    
+    /// Forgets everything sent at `level` once its keys are no longer used (RFC 9002 Section 6.4)
+    pub fn discard_space(&mut self, level: EncryptionLevel) {
+        self.spaces.remove(&level);
+        self.pto_count = 0;
+    }
+    
+    /// Bytes sent in packets not yet acknowledged or declared lost
+    pub fn bytes_in_flight(&self) -> usize {
+        self.spaces.values().flat_map(|space| space.packets.values()).map(|packet| packet.size).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::congestion::minimum_window;
+    use crate::flow_control::INITIAL_RTT;
+    use bytes::Bytes;
+
+    fn crypto_frame(offset: u64) -> Frame {
+        Frame::Crypto { offset, data: Bytes::from_static(b"hello") }
+    }
+
+    fn send(recovery: &mut Recovery, level: EncryptionLevel, packet_number: u64, time_sent: Instant) {
+        recovery.on_packet_sent(level, SentPacket::new(packet_number, time_sent, 100, &[crypto_frame(packet_number)]));
+    }
+
+    #[test]
+    fn test_only_retransmittable_frames_kept() {
+        let frames = [
+            Frame::Ack { largest: 0, delay: 0, ranges: vec![0..=0], ecn: None },
+            Frame::Ping,
+            Frame::Padding { length: 3 },
+            crypto_frame(0),
+        ];
+        let packet = SentPacket::new(0, Instant::now(), 50, &frames);
+        assert!(packet.ack_eliciting);
+        assert!(matches!(packet.frames[..], [Frame::Crypto { .. }]));
+
+        let ack_only = SentPacket::new(1, Instant::now(), 50, &frames[..1]);
+        assert!(!ack_only.ack_eliciting);
+    }
+
+    #[test]
+    fn test_packet_threshold_loss() {
+        let mut recovery = Recovery::new();
+        let now = Instant::now();
+        for pn in 0..5 {
+            send(&mut recovery, EncryptionLevel::Application, pn, now);
+        }
+
+        let rtt = Duration::from_millis(80);
+        let outcome = recovery.on_ack_received(EncryptionLevel::Application, &[4..=4], Duration::ZERO, now + rtt);
+        assert_eq!(outcome.acked.len(), 1);
+        let lost: Vec<u64> = outcome.lost.iter().map(|packet| packet.packet_number).collect();
+        assert_eq!(lost, vec![0, 1]);
+
+        // Packets 2 and 3 are too close to the largest acknowledged, so they wait for the time threshold
+        let loss_time = recovery.timeout().unwrap();
+        assert_eq!(loss_time, now + rtt * 9 / 8);
+        assert!(recovery.on_timeout(loss_time - GRANULARITY).is_none());
+        match recovery.on_timeout(loss_time) {
+            Some(Timeout::Lost(EncryptionLevel::Application, lost)) => assert_eq!(lost.len(), 2),
+            other => panic!("Expected time-threshold loss, got {:?}", other),
+        }
+        assert_eq!(recovery.bytes_in_flight(), 0);
+    }
+
+    #[test]
+    fn test_ack_range_beyond_sent_ignored_for_loss() {
+        let mut recovery = Recovery::new();
+        let now = Instant::now();
+        for pn in 0..2 {
+            send(&mut recovery, EncryptionLevel::Application, pn, now);
+        }
+
+        // The range claims packets never sent; only packet 1 is really acknowledged, too close to packet 0 to lose it
+        let outcome = recovery.on_ack_received(EncryptionLevel::Application, &[1..=1000], Duration::ZERO, now);
+        assert_eq!(outcome.acked.len(), 1);
+        assert!(outcome.lost.is_empty());
+    }
+
+    #[test]
+    fn test_time_threshold_loss_on_ack() {
+        let mut recovery = Recovery::new();
+        let start = Instant::now();
+        send(&mut recovery, EncryptionLevel::Initial, 0, start);
+        send(&mut recovery, EncryptionLevel::Initial, 1, start + Duration::from_secs(1));
+
+        let outcome = recovery.on_ack_received(EncryptionLevel::Initial, &[1..=1], Duration::ZERO, start + Duration::from_secs(1));
+        assert_eq!(outcome.lost.len(), 1);
+        assert_eq!(outcome.lost[0].packet_number, 0);
+    }
+
+    #[test]
+    fn test_pto_backs_off_exponentially() {
+        let mut recovery = Recovery::new();
+        let start = Instant::now();
+        send(&mut recovery, EncryptionLevel::Initial, 0, start);
+        send(&mut recovery, EncryptionLevel::Initial, 1, start);
+        send(&mut recovery, EncryptionLevel::Initial, 2, start);
+
+        let pto = INITIAL_RTT + 4 * (INITIAL_RTT / 2);
+        assert_eq!(recovery.timeout(), Some(start + pto));
+        match recovery.on_timeout(start + pto) {
+            // Up to two packets' worth of frames are probed, oldest first
+            Some(Timeout::Probe(EncryptionLevel::Initial, frames)) => {
+                assert!(matches!(frames[..], [Frame::Crypto { offset: 0, .. }, Frame::Crypto { offset: 1, .. }]));
+            }
+            other => panic!("Expected probe, got {:?}", other),
+        }
+        assert_eq!(recovery.pto_count(), 1);
+        assert_eq!(recovery.timeout(), Some(start + pto + 2 * pto));
+
+        // An acknowledgement resets the backoff
+        recovery.on_ack_received(EncryptionLevel::Initial, &[0..=0], Duration::ZERO, start + pto);
+        assert_eq!(recovery.pto_count(), 0);
+    }
+
+    #[test]
+    fn test_application_pto_waits_for_confirmed_handshake() {
+        let mut recovery = Recovery::new();
+        let start = Instant::now();
+        send(&mut recovery, EncryptionLevel::Application, 0, start);
+        assert_eq!(recovery.timeout(), None);
+
+        recovery.confirm_handshake();
+        let pto = INITIAL_RTT + 4 * (INITIAL_RTT / 2) + MAX_ACK_DELAY;
+        assert_eq!(recovery.timeout(), Some(start + pto));
+    }
+
+    #[test]
+    fn test_discarded_space_stops_timer() {
+        let mut recovery = Recovery::new();
+        send(&mut recovery, EncryptionLevel::Initial, 0, Instant::now());
+        assert!(recovery.timeout().is_some());
+
+        recovery.discard_space(EncryptionLevel::Initial);
+        assert_eq!(recovery.timeout(), None);
+        assert_eq!(recovery.bytes_in_flight(), 0);
+    }
+
+    #[test]
+    fn test_rtt_sampled_from_largest_newly_acked() {
+        let mut recovery = Recovery::new();
+        let start = Instant::now();
+        let ms = Duration::from_millis(1);
+        send(&mut recovery, EncryptionLevel::Handshake, 0, start);
+        send(&mut recovery, EncryptionLevel::Handshake, 1, start + 10 * ms);
+
+        recovery.on_ack_received(EncryptionLevel::Handshake, &[0..=1], Duration::ZERO, start + 110 * ms);
+        assert_eq!(recovery.rtt().latest_rtt(), Some(100 * ms));
+        assert_eq!(recovery.rtt().smoothed_rtt(), 100 * ms);
+
+        // Acknowledging nothing new gives no sample
+        recovery.on_ack_received(EncryptionLevel::Handshake, &[0..=1], Duration::ZERO, start + 500 * ms);
+        assert_eq!(recovery.rtt().latest_rtt(), Some(100 * ms));
+
+        // Before confirmation the peer's full ack delay is taken off, even past max_ack_delay
+        send(&mut recovery, EncryptionLevel::Handshake, 2, start + 200 * ms);
+        recovery.on_ack_received(EncryptionLevel::Handshake, &[2..=2], 40 * ms, start + 340 * ms);
+        assert_eq!(recovery.rtt().latest_rtt(), Some(140 * ms));
+        assert_eq!(recovery.rtt().smoothed_rtt(), 100 * ms);
+    }
+
+    #[test]
+    fn test_peer_max_ack_delay_in_pto() {
+        let mut recovery = Recovery::new();
+        let start = Instant::now();
+        recovery.confirm_handshake();
+        recovery.set_max_ack_delay(Duration::from_millis(5));
+        send(&mut recovery, EncryptionLevel::Application, 0, start);
+
+        let pto = INITIAL_RTT + 4 * (INITIAL_RTT / 2) + Duration::from_millis(5);
+        assert_eq!(recovery.timeout(), Some(start + pto));
+    }
+
+    #[test]
+    fn test_probes_ignore_full_congestion_window() {
+        let mut recovery = Recovery::new();
+        let start = Instant::now();
+        let window = recovery.congestion_controller().window();
+        for pn in 0..(window / 1200) as u64 {
+            recovery.on_packet_sent(EncryptionLevel::Initial, SentPacket::new(pn, start, 1200, &[crypto_frame(pn)]));
+        }
+        assert!(!recovery.can_send());
+
+        let timeout = recovery.timeout().unwrap();
+        assert!(matches!(recovery.on_timeout(timeout), Some(Timeout::Probe(..))));
+        for pn in 100..102 {
+            assert!(recovery.can_send());
+            recovery.on_packet_sent(EncryptionLevel::Initial, SentPacket::new(pn, timeout, 1200, &[Frame::Ping]));
+        }
+        assert!(!recovery.can_send());
+    }
+
+    #[test]
+    fn test_persistent_congestion_collapses_window() {
+        let mut recovery = Recovery::new();
+        let start = Instant::now();
+        let ms = Duration::from_millis(1);
+        send(&mut recovery, EncryptionLevel::Application, 0, start);
+        recovery.on_ack_received(EncryptionLevel::Application, &[0..=0], Duration::ZERO, start + 100 * ms);
+
+        // Packets 1 and 2 are lost more than three PTOs apart, with nothing between them acknowledged
+        send(&mut recovery, EncryptionLevel::Application, 1, start + 200 * ms);
+        for pn in 2..6 {
+            send(&mut recovery, EncryptionLevel::Application, pn, start + 1300 * ms);
+        }
+        let outcome = recovery.on_ack_received(EncryptionLevel::Application, &[5..=5], Duration::ZERO, start + 1400 * ms);
+        assert_eq!(outcome.lost.len(), 2);
+        assert_eq!(recovery.congestion_controller().window(), minimum_window(1200));
+    }
+
+    #[test]
+    fn test_loss_without_persistent_congestion_halves_window() {
+        let mut recovery = Recovery::new();
+        let start = Instant::now();
+        let ms = Duration::from_millis(1);
+        send(&mut recovery, EncryptionLevel::Application, 0, start);
+        recovery.on_ack_received(EncryptionLevel::Application, &[0..=0], Duration::ZERO, start + 100 * ms);
+        let window = recovery.congestion_controller().window();
+
+        for pn in 1..5 {
+            send(&mut recovery, EncryptionLevel::Application, pn, start + 200 * ms);
+        }
+        let outcome = recovery.on_ack_received(EncryptionLevel::Application, &[4..=4], Duration::ZERO, start + 300 * ms);
+        assert_eq!(outcome.lost.len(), 1);
+        assert_eq!(recovery.congestion_controller().window(), (window + 100) / 2);
+    }
+}
@@ -0,0 +1,138 @@
+use crate::flow_control::INITIAL_RTT;
+use std::time::Duration;
+
+/// Round-trip time estimates for one connection (RFC 9002 Section 5)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RttEstimator {
+    /// Most recent sample, before any ack delay correction
+    latest_rtt: Option<Duration>,
+    /// Smallest sample seen, never corrected for ack delay
+    min_rtt: Option<Duration>,
+    smoothed_rtt: Duration,
+    rttvar: Duration,
+}
+
+impl Default for RttEstimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RttEstimator {
+    /// Estimator holding the initial RTT until the first sample arrives (RFC 9002 Section 5.3)
+    pub fn new() -> Self {
+        Self {
+            latest_rtt: None,
+            min_rtt: None,
+            smoothed_rtt: INITIAL_RTT,
+            rttvar: INITIAL_RTT / 2,
+        }
+    }
+    
+    pub fn latest_rtt(&self) -> Option<Duration> {
+        self.latest_rtt
+    }
+    
+    pub fn min_rtt(&self) -> Option<Duration> {
+        self.min_rtt
+    }
+    
+    pub fn smoothed_rtt(&self) -> Duration {
+        self.smoothed_rtt
+    }
+    
+    pub fn rttvar(&self) -> Duration {
+        self.rttvar
+    }
+    
+    /// Folds in a sample, removing the peer's reported `ack_delay` when that leaves it above min_rtt.
+    /// `max_ack_delay` caps the delay once the handshake is confirmed, and is `None` before.
+    pub fn update(&mut self, latest_rtt: Duration, ack_delay: Duration, max_ack_delay: Option<Duration>) {
+        self.latest_rtt = Some(latest_rtt);
+        let Some(min_rtt) = self.min_rtt else {
+            self.min_rtt = Some(latest_rtt);
+            self.smoothed_rtt = latest_rtt;
+            self.rttvar = latest_rtt / 2;
+            return;
+        };
+        let min_rtt = min_rtt.min(latest_rtt);
+        self.min_rtt = Some(min_rtt);
+        
+        let ack_delay = max_ack_delay.map_or(ack_delay, |max| ack_delay.min(max));
+        let adjusted_rtt = if latest_rtt >= min_rtt + ack_delay {
+            latest_rtt - ack_delay
+        } else {
+            latest_rtt
+        };
+        
+        let deviation = self.smoothed_rtt.abs_diff(adjusted_rtt);
+        self.rttvar = (self.rttvar * 3 + deviation) / 4;
+        self.smoothed_rtt = (self.smoothed_rtt * 7 + adjusted_rtt) / 8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn test_initial_estimate() {
+        let rtt = RttEstimator::new();
+        assert_eq!(rtt.smoothed_rtt(), INITIAL_RTT);
+        assert_eq!(rtt.rttvar(), INITIAL_RTT / 2);
+        assert_eq!(rtt.latest_rtt(), None);
+        assert_eq!(rtt.min_rtt(), None);
+    }
+
+    #[test]
+    fn test_first_sample_replaces_initial_rtt() {
+        let mut rtt = RttEstimator::new();
+        // The first sample ignores ack delay entirely
+        rtt.update(100 * MS, 20 * MS, Some(25 * MS));
+        assert_eq!(rtt.latest_rtt(), Some(100 * MS));
+        assert_eq!(rtt.min_rtt(), Some(100 * MS));
+        assert_eq!(rtt.smoothed_rtt(), 100 * MS);
+        assert_eq!(rtt.rttvar(), 50 * MS);
+    }
+
+    #[test]
+    fn test_smoothing() {
+        let mut rtt = RttEstimator::new();
+        rtt.update(100 * MS, Duration::ZERO, None);
+        rtt.update(180 * MS, Duration::ZERO, None);
+        assert_eq!(rtt.smoothed_rtt(), 110 * MS);
+        assert_eq!(rtt.rttvar(), (150 * MS + 80 * MS) / 4);
+        assert_eq!(rtt.min_rtt(), Some(100 * MS));
+
+        rtt.update(60 * MS, Duration::ZERO, None);
+        assert_eq!(rtt.min_rtt(), Some(60 * MS));
+        assert_eq!(rtt.latest_rtt(), Some(60 * MS));
+    }
+
+    #[test]
+    fn test_ack_delay_subtracted() {
+        let mut rtt = RttEstimator::new();
+        rtt.update(100 * MS, Duration::ZERO, None);
+        rtt.update(140 * MS, 40 * MS, None);
+        assert_eq!(rtt.smoothed_rtt(), 100 * MS);
+    }
+
+    #[test]
+    fn test_ack_delay_capped_by_max_ack_delay() {
+        let mut rtt = RttEstimator::new();
+        rtt.update(100 * MS, Duration::ZERO, None);
+        rtt.update(140 * MS, 40 * MS, Some(25 * MS));
+        assert_eq!(rtt.smoothed_rtt(), (700 * MS + 115 * MS) / 8);
+    }
+
+    #[test]
+    fn test_ack_delay_never_takes_sample_below_min_rtt() {
+        let mut rtt = RttEstimator::new();
+        rtt.update(100 * MS, Duration::ZERO, None);
+        rtt.update(110 * MS, 30 * MS, None);
+        assert_eq!(rtt.smoothed_rtt(), (700 * MS + 110 * MS) / 8);
+        assert_eq!(rtt.latest_rtt(), Some(110 * MS));
+    }
+}
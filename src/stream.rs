@@ -0,0 +1,1198 @@
+use crate::config::TransportConfig;
+use crate::flow_control::ReceiveWindow;
+use crate::frame::{self, Frame};
+use crate::transport_parameters::TransportParameters;
+use bytes::{Bytes, BytesMut};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Largest number of streams of one type, since stream IDs are 62-bit (RFC 9000 Section 4.6)
+pub const MAX_STREAM_COUNT: u64 = 1 << 60;
+
+/// Endpoint that opened a stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+/// Whether both endpoints or only the initiator send on a stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dir {
+    Bi = 0,
+    Uni = 1,
+}
+
+/// Stream identifier whose two low bits encode the initiator and direction (RFC 9000 Section 2.1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StreamId(pub u64);
+
+impl StreamId {
+    pub fn new(initiator: Side, dir: Dir, index: u64) -> Self {
+        let mut id = index << 2;
+        if initiator == Side::Server {
+            id |= 0x01;
+        }
+        if dir == Dir::Uni {
+            id |= 0x02;
+        }
+        StreamId(id)
+    }
+    
+    pub fn initiator(self) -> Side {
+        if self.0 & 0x01 == 0 { Side::Client } else { Side::Server }
+    }
+    
+    pub fn dir(self) -> Dir {
+        if self.0 & 0x02 == 0 { Dir::Bi } else { Dir::Uni }
+    }
+    
+    /// Position of this stream among those with the same initiator and direction
+    pub fn index(self) -> u64 {
+        self.0 >> 2
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Credit from the peer that a sender has run out of, reported once per limit with a *_BLOCKED frame
+#[derive(Debug, Default)]
+struct Blocked {
+    limit: Option<u64>,
+    reported: bool,
+}
+
+impl Blocked {
+    fn block(&mut self, limit: u64) {
+        if self.limit != Some(limit) {
+            self.limit = Some(limit);
+            self.reported = false;
+        }
+    }
+    
+    /// Limit to report, if it is still the current one and has not been reported yet
+    fn poll(&mut self, current_limit: u64) -> Option<u64> {
+        if self.reported || self.limit != Some(current_limit) {
+            return None;
+        }
+        self.reported = true;
+        self.limit
+    }
+}
+
+/// States of the sending half of a stream (RFC 9000 Section 3.1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendState {
+    /// Opened, but nothing has been sent yet
+    Ready,
+    Send,
+    /// Everything up to and including the FIN has been sent
+    DataSent,
+    /// The peer acknowledged all data and the FIN
+    DataRecvd,
+    ResetSent,
+    /// The peer acknowledged the RESET_STREAM
+    ResetRecvd,
+}
+
+/// States of the receiving half of a stream (RFC 9000 Section 3.2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvState {
+    Recv,
+    /// The FIN arrived, so the final size is known
+    SizeKnown,
+    /// Every byte up to the final size has arrived
+    DataRecvd,
+    /// The application read to the end of the stream
+    DataRead,
+    ResetRecvd,
+    /// The application has been told of the reset
+    ResetRead,
+}
+
+/// Sending half of a stream
+#[derive(Debug)]
+struct SendStream {
+    state: SendState,
+    /// Data written by the application and not yet sent, starting at `offset`
+    pending: BytesMut,
+    offset: u64,
+    /// Limit the peer set with its transport parameters or MAX_STREAM_DATA
+    max_data: u64,
+    blocked: Blocked,
+    finished: bool,
+    /// Error code of a RESET_STREAM waiting to be sent
+    reset: Option<u64>,
+    /// Error code from the peer's STOP_SENDING, and whether the application has seen it
+    stopped: Option<u64>,
+    stop_reported: bool,
+    /// Acknowledged byte ranges, keyed by start offset
+    acked: BTreeMap<u64, u64>,
+    fin_acked: bool,
+}
+
+impl SendStream {
+    fn new(max_data: u64) -> Self {
+        Self {
+            state: SendState::Ready,
+            pending: BytesMut::new(),
+            offset: 0,
+            max_data,
+            blocked: Blocked::default(),
+            finished: false,
+            reset: None,
+            stopped: None,
+            stop_reported: false,
+            acked: BTreeMap::new(),
+            fin_acked: false,
+        }
+    }
+    
+    fn is_writable(&self) -> bool {
+        matches!(self.state, SendState::Ready | SendState::Send) && !self.finished && self.reset.is_none()
+    }
+    
+    /// Fails with the peer's error code once it has asked us to stop
+    fn check_stopped(&mut self) -> Result<(), StreamError> {
+        match self.stopped {
+            Some(error_code) => {
+                self.stop_reported = true;
+                Err(StreamError::Stopped(error_code))
+            }
+            None => Ok(()),
+        }
+    }
+    
+    fn has_pending(&self) -> bool {
+        matches!(self.state, SendState::Ready | SendState::Send)
+            && self.reset.is_none()
+            && (!self.pending.is_empty() || self.finished)
+    }
+    
+    /// Whether nothing more will be sent, and any STOP_SENDING has reached the application
+    fn is_closed(&self) -> bool {
+        match self.state {
+            SendState::DataSent | SendState::DataRecvd => true,
+            SendState::ResetSent | SendState::ResetRecvd => self.stopped.is_none() || self.stop_reported,
+            SendState::Ready | SendState::Send => false,
+        }
+    }
+    
+    /// Abandons unsent data, returning how many bytes were dropped
+    fn reset(&mut self, error_code: u64) -> u64 {
+        let discarded = self.pending.len() as u64;
+        self.pending.clear();
+        self.reset = Some(error_code);
+        discarded
+    }
+    
+    fn ack(&mut self, offset: u64, len: u64, fin: bool) {
+        let mut start = offset;
+        let mut end = offset + len;
+        let overlapping: Vec<u64> = self.acked.range(..=end)
+            .filter(|(_, acked_end)| **acked_end >= start)
+            .map(|(acked_start, _)| *acked_start)
+            .collect();
+        for acked_start in overlapping {
+            let acked_end = self.acked.remove(&acked_start).unwrap_or(acked_start);
+            start = start.min(acked_start);
+            end = end.max(acked_end);
+        }
+        self.acked.insert(start, end);
+        self.fin_acked |= fin;
+        
+        if self.state == SendState::DataSent && self.fin_acked && self.acked.get(&0) == Some(&self.offset) {
+            self.state = SendState::DataRecvd;
+        }
+    }
+    
+    /// Total bytes the application has written
+    fn written(&self) -> u64 {
+        self.offset + self.pending.len() as u64
+    }
+}
+
+/// Receiving half of a stream, reassembling data by offset
+#[derive(Debug)]
+struct RecvStream {
+    state: RecvState,
+    pending: BTreeMap<u64, Bytes>,
+    read_offset: u64,
+    /// Largest offset the peer has sent data up to
+    end: u64,
+    final_size: Option<u64>,
+    window: ReceiveWindow,
+    /// Error code of the peer's RESET_STREAM
+    reset_code: Option<u64>,
+    /// Whether the application stopped reading, and the STOP_SENDING error code waiting to be sent
+    stopped: bool,
+    stop_sending: Option<u64>,
+}
+
+impl RecvStream {
+    fn new(config: &TransportConfig) -> Self {
+        Self {
+            state: RecvState::Recv,
+            pending: BTreeMap::new(),
+            read_offset: 0,
+            end: 0,
+            final_size: None,
+            window: ReceiveWindow::new(config.stream_receive_window, config.max_stream_receive_window),
+            reset_code: None,
+            stopped: false,
+            stop_sending: None,
+        }
+    }
+    
+    /// Whether the application is done with the stream, or has stopped and the peer sent all it will
+    fn is_closed(&self) -> bool {
+        match self.state {
+            RecvState::DataRead | RecvState::ResetRead => true,
+            RecvState::SizeKnown | RecvState::DataRecvd | RecvState::ResetRecvd => self.stopped,
+            RecvState::Recv => false,
+        }
+    }
+    
+    fn receive(&mut self, offset: u64, data: Bytes, fin: bool) -> Result<(), StreamError> {
+        let end = offset + data.len() as u64;
+        
+        // Once known, the final size may not change (RFC 9000 Section 4.5)
+        if let Some(final_size) = self.final_size {
+            if end > final_size || (fin && end != final_size) {
+                return Err(StreamError::FinalSize);
+            }
+        }
+        if fin {
+            if end < self.end {
+                return Err(StreamError::FinalSize);
+            }
+            self.final_size = Some(end);
+            if self.state == RecvState::Recv {
+                self.state = RecvState::SizeKnown;
+            }
+        }
+        self.window.receive(end).map_err(|_| StreamError::FlowControl)?;
+        self.end = self.end.max(end);
+        
+        let wanted = end > self.read_offset && !data.is_empty() && !self.stopped && self.reset_code.is_none();
+        // Keep the longer chunk if the peer retransmitted from the same offset
+        if wanted && self.pending.get(&offset).is_none_or(|existing| existing.len() < data.len()) {
+            self.pending.insert(offset, data);
+        }
+        if self.state == RecvState::SizeKnown && self.is_complete() {
+            self.state = RecvState::DataRecvd;
+        }
+        Ok(())
+    }
+    
+    /// Whether every byte up to the final size has arrived
+    fn is_complete(&self) -> bool {
+        let Some(final_size) = self.final_size else {
+            return false;
+        };
+        let mut contiguous = self.read_offset;
+        for (offset, data) in &self.pending {
+            if *offset > contiguous {
+                break;
+            }
+            contiguous = contiguous.max(offset + data.len() as u64);
+        }
+        contiguous >= final_size
+    }
+    
+    /// Applies a RESET_STREAM; data that has already fully arrived is still delivered (RFC 9000 Section 3.2)
+    fn reset(&mut self, error_code: u64, final_size: u64) -> Result<(), StreamError> {
+        if self.final_size.is_some_and(|known| known != final_size) || final_size < self.end {
+            return Err(StreamError::FinalSize);
+        }
+        self.window.receive(final_size).map_err(|_| StreamError::FlowControl)?;
+        self.end = final_size;
+        self.final_size = Some(final_size);
+        
+        if matches!(self.state, RecvState::Recv | RecvState::SizeKnown) {
+            self.state = RecvState::ResetRecvd;
+            self.reset_code = Some(error_code);
+        }
+        Ok(())
+    }
+    
+    /// Drops buffered data up to `offset`, returning how many unread bytes that skipped
+    fn discard(&mut self, offset: u64) -> u64 {
+        self.pending.clear();
+        let skipped = offset.saturating_sub(self.read_offset);
+        self.read_offset += skipped;
+        skipped
+    }
+    
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, StreamError> {
+        if let Some(error_code) = self.reset_code {
+            self.state = RecvState::ResetRead;
+            return Err(StreamError::Reset(error_code));
+        }
+        if self.stopped {
+            return Err(StreamError::UnknownStream);
+        }
+        
+        let mut read = 0;
+        while read < buf.len() {
+            let Some(entry) = self.pending.first_entry() else {
+                break;
+            };
+            let chunk_offset = *entry.key();
+            if chunk_offset > self.read_offset {
+                break;
+            }
+            
+            let chunk_end = chunk_offset + entry.get().len() as u64;
+            if chunk_end > self.read_offset {
+                let skip = (self.read_offset - chunk_offset) as usize;
+                let len = (entry.get().len() - skip).min(buf.len() - read);
+                buf[read..read + len].copy_from_slice(&entry.get()[skip..skip + len]);
+                read += len;
+                self.read_offset += len as u64;
+            }
+            if self.read_offset >= chunk_end {
+                entry.remove();
+            }
+        }
+        self.window.consume(read as u64);
+        
+        if read > 0 || buf.is_empty() {
+            Ok(read)
+        } else if self.final_size == Some(self.read_offset) {
+            // End of stream, as with std::io::Read
+            self.state = RecvState::DataRead;
+            Ok(0)
+        } else {
+            Err(StreamError::Blocked)
+        }
+    }
+}
+
+/// Streams of one connection, with IDs allocated by initiator and direction
+#[derive(Debug)]
+pub struct Streams {
+    side: Side,
+    config: TransportConfig,
+    send: BTreeMap<StreamId, SendStream>,
+    recv: BTreeMap<StreamId, RecvStream>,
+    /// Index of the next stream this endpoint opens, per direction
+    next_local: [u64; 2],
+    /// Number of streams the peer allows this endpoint to open, per direction
+    max_local: [u64; 2],
+    streams_blocked: [Blocked; 2],
+    /// Number of streams the peer has opened, per direction
+    next_remote: [u64; 2],
+    /// Number of streams the peer may open, as last advertised, per direction
+    max_remote: [u64; 2],
+    /// Peer streams that have been closed and forgotten, each of which frees credit for a new one
+    reaped_remote: [u64; 2],
+    /// Streams the peer opened that the application has not accepted yet
+    incoming: VecDeque<StreamId>,
+    /// Stream that sent last, so that the others take turns after it
+    last_sent: Option<StreamId>,
+    /// Connection-level limit from the peer and the bytes written against it
+    max_data: u64,
+    data_written: u64,
+    data_blocked: Blocked,
+    /// Connection-level credit extended to the peer
+    window: ReceiveWindow,
+    /// The peer's initial_max_stream_data_* transport parameters
+    peer_stream_limits: TransportParameters,
+}
+
+impl Streams {
+    pub fn new(side: Side) -> Self {
+        Self::with_config(side, TransportConfig::default())
+    }
+    
+    pub fn with_config(side: Side, config: TransportConfig) -> Self {
+        let max_remote = [config.max_concurrent_bidi_streams, config.max_concurrent_uni_streams];
+        let window = ReceiveWindow::new(config.receive_window, config.max_receive_window);
+        Self {
+            side,
+            config,
+            send: BTreeMap::new(),
+            recv: BTreeMap::new(),
+            next_local: [0; 2],
+            max_local: [0; 2],
+            streams_blocked: Default::default(),
+            next_remote: [0; 2],
+            max_remote,
+            reaped_remote: [0; 2],
+            incoming: VecDeque::new(),
+            last_sent: None,
+            max_data: 0,
+            data_written: 0,
+            data_blocked: Blocked::default(),
+            window,
+            peer_stream_limits: TransportParameters::default(),
+        }
+    }
+    
+    /// Fills in the flow control and stream limits this endpoint advertises
+    pub fn local_transport_parameters(&self, params: &mut TransportParameters) {
+        params.initial_max_data = Some(self.window.max_data());
+        params.initial_max_stream_data_bidi_local = Some(self.config.stream_receive_window);
+        params.initial_max_stream_data_bidi_remote = Some(self.config.stream_receive_window);
+        params.initial_max_stream_data_uni = Some(self.config.stream_receive_window);
+        params.initial_max_streams_bidi = Some(self.max_remote[Dir::Bi as usize]);
+        params.initial_max_streams_uni = Some(self.max_remote[Dir::Uni as usize]);
+    }
+    
+    /// Applies the flow control and stream limits from the peer's transport parameters
+    pub fn set_peer_transport_parameters(&mut self, params: &TransportParameters) {
+        self.peer_stream_limits = params.clone();
+        self.max_data = self.max_data.max(params.initial_max_data.unwrap_or(0));
+        self.receive_max_streams(Dir::Bi, params.initial_max_streams_bidi.unwrap_or(0));
+        self.receive_max_streams(Dir::Uni, params.initial_max_streams_uni.unwrap_or(0));
+        
+        // Streams opened before the parameters arrived start with no credit
+        let ids: Vec<StreamId> = self.send.keys().copied().collect();
+        for id in ids {
+            let limit = self.initial_send_limit(id);
+            if let Some(stream) = self.send.get_mut(&id) {
+                stream.max_data = stream.max_data.max(limit);
+            }
+        }
+    }
+    
+    /// Credit the peer's transport parameters give a stream we send on
+    fn initial_send_limit(&self, id: StreamId) -> u64 {
+        let params = &self.peer_stream_limits;
+        let limit = match (id.dir(), id.initiator() == self.side) {
+            (Dir::Uni, _) => params.initial_max_stream_data_uni,
+            (Dir::Bi, true) => params.initial_max_stream_data_bidi_remote,
+            (Dir::Bi, false) => params.initial_max_stream_data_bidi_local,
+        };
+        limit.unwrap_or(0)
+    }
+    
+    /// Opens a stream, or fails with `Blocked` once the peer's stream limit is reached
+    pub fn open(&mut self, dir: Dir) -> Result<StreamId, StreamError> {
+        let index = self.next_local[dir as usize];
+        if index >= self.max_local[dir as usize] {
+            self.streams_blocked[dir as usize].block(self.max_local[dir as usize]);
+            return Err(StreamError::Blocked);
+        }
+        let id = StreamId::new(self.side, dir, index);
+        self.next_local[dir as usize] += 1;
+        
+        self.send.insert(id, SendStream::new(self.initial_send_limit(id)));
+        if dir == Dir::Bi {
+            self.recv.insert(id, RecvStream::new(&self.config));
+        }
+        Ok(id)
+    }
+    
+    /// Next stream opened by the peer
+    pub fn accept(&mut self) -> Option<StreamId> {
+        self.incoming.pop_front()
+    }
+    
+    /// Queues as much of `data` as flow control allows and returns how much was accepted
+    pub fn write(&mut self, id: StreamId, data: &[u8]) -> Result<usize, StreamError> {
+        let connection_credit = self.max_data - self.data_written;
+        let stream = self.send.get_mut(&id).ok_or(StreamError::UnknownStream)?;
+        if let Err(e) = stream.check_stopped() {
+            self.reap_if_closed(id);
+            return Err(e);
+        }
+        if !stream.is_writable() {
+            return Err(StreamError::Finished);
+        }
+        
+        let stream_credit = stream.max_data - stream.written();
+        let len = (data.len() as u64).min(stream_credit).min(connection_credit) as usize;
+        if len < data.len() {
+            if len as u64 == stream_credit {
+                stream.blocked.block(stream.max_data);
+            }
+            if len as u64 == connection_credit {
+                self.data_blocked.block(self.max_data);
+            }
+        }
+        if len == 0 && !data.is_empty() {
+            return Err(StreamError::Blocked);
+        }
+        
+        stream.pending.extend_from_slice(&data[..len]);
+        self.data_written += len as u64;
+        Ok(len)
+    }
+    
+    /// Marks the end of the data written on the stream
+    pub fn finish(&mut self, id: StreamId) -> Result<(), StreamError> {
+        let stream = self.send.get_mut(&id).ok_or(StreamError::UnknownStream)?;
+        if let Err(e) = stream.check_stopped() {
+            self.reap_if_closed(id);
+            return Err(e);
+        }
+        if !stream.is_writable() {
+            return Err(StreamError::Finished);
+        }
+        stream.finished = true;
+        Ok(())
+    }
+    
+    /// Abandons sending on the stream, telling the peer with a RESET_STREAM carrying `error_code`
+    pub fn reset(&mut self, id: StreamId, error_code: u64) -> Result<(), StreamError> {
+        let stream = self.send.get_mut(&id).ok_or(StreamError::UnknownStream)?;
+        if !matches!(stream.state, SendState::Ready | SendState::Send) || stream.reset.is_some() {
+            return Err(StreamError::Finished);
+        }
+        // Data never sent no longer counts against the connection's credit
+        self.data_written -= stream.reset(error_code);
+        Ok(())
+    }
+    
+    /// Asks the peer to stop sending on the stream, discarding anything not yet read
+    pub fn stop_sending(&mut self, id: StreamId, error_code: u64) -> Result<(), StreamError> {
+        let stream = self.recv.get_mut(&id).ok_or(StreamError::UnknownStream)?;
+        if stream.stopped {
+            return Ok(());
+        }
+        stream.stopped = true;
+        if matches!(stream.state, RecvState::Recv | RecvState::SizeKnown) {
+            stream.stop_sending = Some(error_code);
+        }
+        let discarded = stream.discard(stream.end);
+        self.window.consume(discarded);
+        self.reap_if_closed(id);
+        Ok(())
+    }
+    
+    /// Reads contiguous data into `buf`, returning 0 once the stream has ended
+    ///
+    /// Fails with `Reset` carrying the peer's error code if it reset the stream.
+    pub fn read(&mut self, id: StreamId, buf: &mut [u8]) -> Result<usize, StreamError> {
+        let stream = self.recv.get_mut(&id).ok_or(StreamError::UnknownStream)?;
+        let result = stream.read(buf);
+        if let Ok(read) = result {
+            self.window.consume(read as u64);
+        }
+        if stream.is_closed() {
+            self.reap_if_closed(id);
+        }
+        result
+    }
+    
+    /// Whether the application or the peer reset the stream, so its data is not retransmitted
+    pub fn is_reset(&self, id: StreamId) -> bool {
+        self.send.get(&id).is_some_and(|stream| {
+            stream.reset.is_some() || matches!(stream.state, SendState::ResetSent | SendState::ResetRecvd)
+        })
+    }
+    
+    pub fn send_state(&self, id: StreamId) -> Option<SendState> {
+        self.send.get(&id).map(|stream| stream.state)
+    }
+    
+    pub fn recv_state(&self, id: StreamId) -> Option<RecvState> {
+        self.recv.get(&id).map(|stream| stream.state)
+    }
+    
+    pub fn has_pending(&self) -> bool {
+        self.send.values().any(SendStream::has_pending)
+    }
+    
+    /// Takes the next STREAM frame of at most `max_len` encoded bytes, serving streams in turn
+    pub fn poll_frame(&mut self, max_len: usize) -> Option<Frame> {
+        let after = self.last_sent.map_or(0, |id| id.0 + 1);
+        let id = self.send.range(StreamId(after)..)
+            .chain(self.send.range(..StreamId(after)))
+            .find(|(_, stream)| stream.has_pending())
+            .map(|(id, _)| *id)?;
+        let stream = self.send.get_mut(&id)?;
+        
+        let mut header_len = 1 + frame::varint_len(id.0) + frame::varint_len(max_len as u64);
+        if stream.offset != 0 {
+            header_len += frame::varint_len(stream.offset);
+        }
+        let room = max_len.checked_sub(header_len)?;
+        if room == 0 && !stream.pending.is_empty() {
+            return None;
+        }
+        
+        let offset = stream.offset;
+        let data = stream.pending.split_to(room.min(stream.pending.len())).freeze();
+        stream.offset += data.len() as u64;
+        let fin = stream.finished && stream.pending.is_empty();
+        stream.state = if fin { SendState::DataSent } else { SendState::Send };
+        self.last_sent = Some(id);
+        if fin {
+            self.reap_if_closed(id);
+        }
+        
+        Some(Frame::Stream { stream_id: id, offset, data, fin })
+    }
+    
+    /// Records the peer acknowledging a STREAM frame
+    pub fn stream_frame_acked(&mut self, id: StreamId, offset: u64, len: u64, fin: bool) {
+        if let Some(stream) = self.send.get_mut(&id) {
+            stream.ack(offset, len, fin);
+        }
+    }
+    
+    /// Records the peer acknowledging a RESET_STREAM frame
+    pub fn reset_stream_acked(&mut self, id: StreamId) {
+        if let Some(stream) = self.send.get_mut(&id) {
+            if stream.state == SendState::ResetSent {
+                stream.state = SendState::ResetRecvd;
+            }
+        }
+    }
+    
+    /// Forgets a stream once both of its halves are done, freeing credit if the peer opened it
+    ///
+    /// Lost data is retransmitted from the frames kept by loss recovery, so a sending half is done
+    /// once it has sent everything.
+    fn reap_if_closed(&mut self, id: StreamId) {
+        let send_closed = self.send.get(&id).is_none_or(SendStream::is_closed);
+        let recv_closed = self.recv.get(&id).is_none_or(RecvStream::is_closed);
+        if !send_closed || !recv_closed {
+            return;
+        }
+        
+        let removed = self.send.remove(&id).is_some() | self.recv.remove(&id).is_some();
+        if removed && id.initiator() != self.side {
+            self.reaped_remote[id.dir() as usize] += 1;
+        }
+    }
+    
+    /// Credit updates for the peer and reports of our own exhausted credit
+    ///
+    /// Receive windows move forward as the application reads, so this is polled before
+    /// stream data each time packets are built.
+    pub fn poll_control_frames(&mut self, now: Instant, rtt: Duration) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        for dir in [Dir::Bi, Dir::Uni] {
+            let concurrent = match dir {
+                Dir::Bi => self.config.max_concurrent_bidi_streams,
+                Dir::Uni => self.config.max_concurrent_uni_streams,
+            };
+            let max_streams = (self.reaped_remote[dir as usize] + concurrent).min(MAX_STREAM_COUNT);
+            if max_streams > self.max_remote[dir as usize] {
+                self.max_remote[dir as usize] = max_streams;
+                frames.push(Frame::MaxStreams { dir, max_streams });
+            }
+            if let Some(limit) = self.streams_blocked[dir as usize].poll(self.max_local[dir as usize]) {
+                frames.push(Frame::StreamsBlocked { dir, limit });
+            }
+        }
+        let mut reset = Vec::new();
+        for (id, stream) in &mut self.send {
+            if let Some(error_code) = stream.reset.take() {
+                stream.state = SendState::ResetSent;
+                frames.push(Frame::ResetStream { stream_id: *id, error_code, final_size: stream.offset });
+                reset.push(*id);
+            }
+        }
+        for id in reset {
+            self.reap_if_closed(id);
+        }
+        
+        if let Some(max_data) = self.window.poll_update(now, rtt) {
+            frames.push(Frame::MaxData { max_data });
+        }
+        for (id, stream) in &mut self.recv {
+            if let Some(error_code) = stream.stop_sending.take() {
+                frames.push(Frame::StopSending { stream_id: *id, error_code });
+            }
+            // Once the final size is known, or nothing more will be read, the peer needs no more credit
+            if stream.final_size.is_some() || stream.stopped {
+                continue;
+            }
+            if let Some(max_data) = stream.window.poll_update(now, rtt) {
+                frames.push(Frame::MaxStreamData { stream_id: *id, max_data });
+            }
+        }
+        
+        if let Some(limit) = self.data_blocked.poll(self.max_data) {
+            frames.push(Frame::DataBlocked { limit });
+        }
+        for (id, stream) in &mut self.send {
+            if let Some(limit) = stream.blocked.poll(stream.max_data) {
+                frames.push(Frame::StreamDataBlocked { stream_id: *id, limit });
+            }
+        }
+        frames
+    }
+    
+    /// Delivers the contents of a STREAM frame, opening any peer streams it implies
+    pub fn receive(&mut self, id: StreamId, offset: u64, data: Bytes, fin: bool) -> Result<(), StreamError> {
+        self.open_for_peer(id, true)?;
+        let Some(stream) = self.recv.get_mut(&id) else {
+            return Ok(());
+        };
+        
+        // New bytes on any stream count against the connection-level limit too
+        let previous_end = stream.end;
+        stream.receive(offset, data, fin)?;
+        let newly_received = stream.end - previous_end;
+        self.window.receive(self.window.received() + newly_received)
+            .map_err(|_| StreamError::FlowControl)?;
+        
+        // Data the application will never read must not hold back the peer's credit
+        if stream.stopped {
+            let discarded = stream.discard(stream.end);
+            self.window.consume(discarded);
+            self.reap_if_closed(id);
+        }
+        Ok(())
+    }
+    
+    /// Applies the peer's RESET_STREAM, which counts the whole final size against flow control
+    pub fn receive_reset_stream(&mut self, id: StreamId, error_code: u64, final_size: u64) -> Result<(), StreamError> {
+        self.open_for_peer(id, true)?;
+        let Some(stream) = self.recv.get_mut(&id) else {
+            return Ok(());
+        };
+        
+        let previous_end = stream.end;
+        stream.reset(error_code, final_size)?;
+        let newly_received = stream.end - previous_end;
+        self.window.receive(self.window.received() + newly_received)
+            .map_err(|_| StreamError::FlowControl)?;
+        
+        if stream.reset_code.is_some() || stream.stopped {
+            let discarded = stream.discard(final_size);
+            self.window.consume(discarded);
+        }
+        if stream.is_closed() {
+            self.reap_if_closed(id);
+        }
+        Ok(())
+    }
+    
+    /// Applies the peer's STOP_SENDING, resetting the stream with its error code if still sending (RFC 9000 Section 3.5)
+    pub fn receive_stop_sending(&mut self, id: StreamId, error_code: u64) -> Result<(), StreamError> {
+        self.open_for_peer(id, false)?;
+        let Some(stream) = self.send.get_mut(&id) else {
+            return Ok(());
+        };
+        
+        stream.stopped.get_or_insert(error_code);
+        if matches!(stream.state, SendState::Ready | SendState::Send) && stream.reset.is_none() {
+            self.data_written -= stream.reset(error_code);
+        }
+        Ok(())
+    }
+    
+    pub fn receive_max_streams(&mut self, dir: Dir, max_streams: u64) {
+        let max_local = &mut self.max_local[dir as usize];
+        *max_local = (*max_local).max(max_streams);
+    }
+    
+    pub fn receive_max_data(&mut self, max_data: u64) {
+        self.max_data = self.max_data.max(max_data);
+    }
+    
+    pub fn receive_max_stream_data(&mut self, id: StreamId, max_data: u64) -> Result<(), StreamError> {
+        self.open_for_peer(id, false)?;
+        if let Some(stream) = self.send.get_mut(&id) {
+            stream.max_data = stream.max_data.max(max_data);
+        }
+        Ok(())
+    }
+    
+    /// Checks a STREAM_DATA_BLOCKED frame, which only tells us the peer is waiting for credit
+    pub fn receive_stream_data_blocked(&mut self, id: StreamId) -> Result<(), StreamError> {
+        self.open_for_peer(id, true)
+    }
+    
+    /// Checks that the peer may refer to `id` in a frame about the direction it sends in
+    /// (`peer_sends`) or receives in, and opens any peer streams the reference implies
+    fn open_for_peer(&mut self, id: StreamId, peer_sends: bool) -> Result<(), StreamError> {
+        let dir = id.dir() as usize;
+        if id.initiator() == self.side {
+            // The peer cannot send on our unidirectional streams or refer to streams we have not opened
+            if id.index() >= self.next_local[dir] || (peer_sends && id.dir() == Dir::Uni) {
+                return Err(StreamError::StreamState);
+            }
+            return Ok(());
+        }
+        
+        // Nor can it receive on its own unidirectional streams
+        if !peer_sends && id.dir() == Dir::Uni {
+            return Err(StreamError::StreamState);
+        }
+        if id.index() >= self.max_remote[dir] {
+            return Err(StreamError::StreamLimit);
+        }
+        
+        // Opening a stream implicitly opens every lower-numbered one of the same type
+        while self.next_remote[dir] <= id.index() {
+            let opened = StreamId::new(id.initiator(), id.dir(), self.next_remote[dir]);
+            self.next_remote[dir] += 1;
+            
+            self.recv.insert(opened, RecvStream::new(&self.config));
+            if opened.dir() == Dir::Bi {
+                self.send.insert(opened, SendStream::new(self.initial_send_limit(opened)));
+            }
+            self.incoming.push_back(opened);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamError {
+    /// The stream does not exist or cannot be used in this direction
+    UnknownStream,
+    /// No data is available to read yet, or no credit to write
+    Blocked,
+    /// The application already finished or reset the stream
+    Finished,
+    /// The peer sent on a stream it may not send on
+    StreamState,
+    /// The peer changed the final size of a stream
+    FinalSize,
+    /// The peer sent more than the credit it was given
+    FlowControl,
+    /// The peer opened more streams than it was allowed
+    StreamLimit,
+    /// The peer reset the stream with this application error code
+    Reset(u64),
+    /// The peer asked us to stop sending with this application error code
+    Stopped(u64),
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamError::UnknownStream => write!(f, "Unknown stream"),
+            StreamError::Blocked => write!(f, "Stream blocked"),
+            StreamError::Finished => write!(f, "Stream already finished"),
+            StreamError::StreamState => write!(f, "Frame received for a stream in the wrong state"),
+            StreamError::FinalSize => write!(f, "Stream final size changed"),
+            StreamError::FlowControl => write!(f, "Flow control limit exceeded"),
+            StreamError::StreamLimit => write!(f, "Stream limit exceeded"),
+            StreamError::Reset(error_code) => write!(f, "Stream reset by peer with error code {}", error_code),
+            StreamError::Stopped(error_code) => write!(f, "Peer stopped the stream with error code {}", error_code),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Streams whose peer advertised the default limits
+    fn with_peer_limits(side: Side) -> Streams {
+        let mut params = TransportParameters::default();
+        Streams::new(side).local_transport_parameters(&mut params);
+
+        let mut streams = Streams::new(side);
+        streams.set_peer_transport_parameters(&params);
+        streams
+    }
+
+    fn read_all(streams: &mut Streams, id: StreamId) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut buf = [0u8; 7];
+        loop {
+            match streams.read(id, &mut buf) {
+                Ok(0) | Err(StreamError::Blocked) => return data,
+                Ok(len) => data.extend_from_slice(&buf[..len]),
+                Err(e) => panic!("read failed: {}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_stream_id_allocation() {
+        let mut client = with_peer_limits(Side::Client);
+        let mut server = with_peer_limits(Side::Server);
+
+        assert_eq!(client.open(Dir::Bi).unwrap(), StreamId(0));
+        assert_eq!(client.open(Dir::Bi).unwrap(), StreamId(4));
+        assert_eq!(client.open(Dir::Uni).unwrap(), StreamId(2));
+        assert_eq!(server.open(Dir::Bi).unwrap(), StreamId(1));
+        assert_eq!(server.open(Dir::Uni).unwrap(), StreamId(3));
+        assert_eq!(server.open(Dir::Uni).unwrap(), StreamId(7));
+
+        let id = StreamId(7);
+        assert_eq!(id.initiator(), Side::Server);
+        assert_eq!(id.dir(), Dir::Uni);
+        assert_eq!(id.index(), 1);
+        assert_eq!(StreamId::new(Side::Server, Dir::Uni, 1), id);
+    }
+
+    #[test]
+    fn test_out_of_order_reassembly() {
+        let mut streams = Streams::new(Side::Server);
+        let id = StreamId(0);
+
+        streams.receive(id, 6, Bytes::from_static(b"world"), true).unwrap();
+        assert_eq!(streams.read(id, &mut [0u8; 16]), Err(StreamError::Blocked));
+
+        streams.receive(id, 0, Bytes::from_static(b"hello "), false).unwrap();
+        // A retransmission overlapping data already read is ignored
+        streams.receive(id, 0, Bytes::from_static(b"hel"), false).unwrap();
+
+        assert_eq!(read_all(&mut streams, id), b"hello world");
+        assert_eq!(streams.read(id, &mut [0u8; 16]), Ok(0));
+    }
+
+    #[test]
+    fn test_peer_streams_opened_in_order() {
+        let mut server = Streams::new(Side::Server);
+        server.receive(StreamId(8), 0, Bytes::from_static(b"x"), false).unwrap();
+        server.receive(StreamId(2), 0, Bytes::from_static(b"y"), false).unwrap();
+
+        assert_eq!(server.accept(), Some(StreamId(0)));
+        assert_eq!(server.accept(), Some(StreamId(4)));
+        assert_eq!(server.accept(), Some(StreamId(8)));
+        assert_eq!(server.accept(), Some(StreamId(2)));
+        assert_eq!(server.accept(), None);
+
+        // The server cannot write on the client's unidirectional stream
+        assert_eq!(server.write(StreamId(2), b"no"), Err(StreamError::UnknownStream));
+    }
+
+    #[test]
+    fn test_peer_cannot_send_on_local_streams() {
+        let mut client = with_peer_limits(Side::Client);
+        let uni = client.open(Dir::Uni).unwrap();
+
+        assert_eq!(client.receive(uni, 0, Bytes::new(), true), Err(StreamError::StreamState));
+        assert_eq!(client.receive(StreamId(0), 0, Bytes::new(), true), Err(StreamError::StreamState));
+    }
+
+    #[test]
+    fn test_final_size_cannot_change() {
+        let mut streams = Streams::new(Side::Server);
+        let id = StreamId(0);
+        streams.receive(id, 0, Bytes::from_static(b"abcdef"), false).unwrap();
+
+        assert_eq!(streams.receive(id, 0, Bytes::from_static(b"abc"), true), Err(StreamError::FinalSize));
+        streams.receive(id, 4, Bytes::from_static(b"ef"), true).unwrap();
+        assert_eq!(streams.receive(id, 6, Bytes::from_static(b"g"), false), Err(StreamError::FinalSize));
+    }
+
+    #[test]
+    fn test_poll_frame_splits_and_finishes() {
+        let mut client = with_peer_limits(Side::Client);
+        let id = client.open(Dir::Uni).unwrap();
+        client.write(id, &[7u8; 100]).unwrap();
+        client.finish(id).unwrap();
+        assert_eq!(client.write(id, b"late"), Err(StreamError::Finished));
+
+        let mut server = Streams::new(Side::Server);
+        let mut frames = 0;
+        while let Some(frame) = client.poll_frame(40) {
+            let mut buf = BytesMut::new();
+            frame.encode(&mut buf).unwrap();
+            assert!(buf.len() <= 40);
+
+            let Frame::Stream { stream_id, offset, data, fin } = frame else {
+                panic!("Expected Stream frame");
+            };
+            server.receive(stream_id, offset, data, fin).unwrap();
+            frames += 1;
+        }
+
+        assert!(frames > 1);
+        assert!(!client.has_pending());
+        assert_eq!(read_all(&mut server, id), vec![7u8; 100]);
+        // Both halves of the stream are done, so each side has forgotten it
+        assert_eq!(server.read(id, &mut [0u8; 1]), Err(StreamError::UnknownStream));
+        assert_eq!(client.write(id, b"x"), Err(StreamError::UnknownStream));
+    }
+
+    #[test]
+    fn test_poll_frame_round_robin() {
+        let mut client = with_peer_limits(Side::Client);
+        let first = client.open(Dir::Bi).unwrap();
+        let second = client.open(Dir::Bi).unwrap();
+        client.write(first, &[1u8; 50]).unwrap();
+        client.write(second, &[2u8; 50]).unwrap();
+
+        let ids: Vec<StreamId> = std::iter::from_fn(|| client.poll_frame(30))
+            .map(|frame| match frame {
+                Frame::Stream { stream_id, .. } => stream_id,
+                _ => panic!("Expected Stream frame"),
+            })
+            .collect();
+        assert_eq!(&ids[..4], &[first, second, first, second]);
+    }
+
+    #[test]
+    fn test_open_blocked_by_peer_limit() {
+        let mut params = TransportParameters::default();
+        Streams::with_config(Side::Server, TransportConfig { max_concurrent_uni_streams: 1, ..TransportConfig::default() })
+            .local_transport_parameters(&mut params);
+        assert_eq!(params.initial_max_streams_uni, Some(1));
+
+        let mut client = Streams::new(Side::Client);
+        client.set_peer_transport_parameters(&params);
+        client.open(Dir::Uni).unwrap();
+        assert_eq!(client.open(Dir::Uni), Err(StreamError::Blocked));
+
+        // The limit is reported once until the peer raises it
+        let frames = client.poll_control_frames(Instant::now(), Duration::from_millis(10));
+        assert!(matches!(frames[..], [Frame::StreamsBlocked { dir: Dir::Uni, limit: 1 }]));
+        assert!(client.poll_control_frames(Instant::now(), Duration::from_millis(10)).is_empty());
+
+        client.receive_max_streams(Dir::Uni, 2);
+        assert_eq!(client.open(Dir::Uni), Ok(StreamId(6)));
+    }
+
+    #[test]
+    fn test_peer_stream_limit_enforced() {
+        let config = TransportConfig { max_concurrent_bidi_streams: 2, ..TransportConfig::default() };
+        let mut server = Streams::with_config(Side::Server, config);
+
+        server.receive(StreamId(4), 0, Bytes::new(), false).unwrap();
+        assert_eq!(server.receive(StreamId(8), 0, Bytes::new(), false), Err(StreamError::StreamLimit));
+        // Unidirectional streams have their own limit
+        server.receive(StreamId(2), 0, Bytes::new(), true).unwrap();
+    }
+
+    #[test]
+    fn test_stream_credit_reissued_when_reaped() {
+        let config = TransportConfig { max_concurrent_uni_streams: 1, ..TransportConfig::default() };
+        let mut server = Streams::with_config(Side::Server, config);
+        let now = Instant::now();
+        let rtt = Duration::from_millis(10);
+
+        server.receive(StreamId(2), 0, Bytes::from_static(b"data"), true).unwrap();
+        assert_eq!(server.receive(StreamId(6), 0, Bytes::new(), true), Err(StreamError::StreamLimit));
+        assert!(server.poll_control_frames(now, rtt).is_empty());
+
+        // Reading to the end closes the stream, which lets the client open another
+        assert_eq!(read_all(&mut server, StreamId(2)), b"data");
+        let frames = server.poll_control_frames(now, rtt);
+        assert!(matches!(frames[..], [Frame::MaxStreams { dir: Dir::Uni, max_streams: 2 }]));
+        server.receive(StreamId(6), 0, Bytes::new(), true).unwrap();
+
+        // A late retransmission for the reaped stream is ignored
+        server.receive(StreamId(2), 0, Bytes::from_static(b"data"), true).unwrap();
+        assert_eq!(server.accept(), Some(StreamId(2)));
+        assert_eq!(server.accept(), Some(StreamId(6)));
+        assert_eq!(server.accept(), None);
+    }
+
+    /// Delivers every STREAM frame `from` has pending to `to`
+    fn deliver(from: &mut Streams, to: &mut Streams) {
+        while let Some(frame) = from.poll_frame(1200) {
+            let Frame::Stream { stream_id, offset, data, fin } = frame else {
+                panic!("Expected Stream frame");
+            };
+            to.receive(stream_id, offset, data, fin).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_send_and_receive_states() {
+        let mut client = with_peer_limits(Side::Client);
+        let mut server = with_peer_limits(Side::Server);
+        let id = client.open(Dir::Bi).unwrap();
+        assert_eq!(client.send_state(id), Some(SendState::Ready));
+
+        client.write(id, b"abc").unwrap();
+        deliver(&mut client, &mut server);
+        assert_eq!(client.send_state(id), Some(SendState::Send));
+        assert_eq!(server.recv_state(id), Some(RecvState::Recv));
+
+        client.finish(id).unwrap();
+        deliver(&mut client, &mut server);
+        assert_eq!(client.send_state(id), Some(SendState::DataSent));
+        assert_eq!(server.recv_state(id), Some(RecvState::DataRecvd));
+
+        assert_eq!(read_all(&mut server, id), b"abc");
+        assert_eq!(server.recv_state(id), Some(RecvState::DataRead));
+
+        // Every byte and the FIN must be acknowledged, in any order
+        client.stream_frame_acked(id, 3, 0, true);
+        assert_eq!(client.send_state(id), Some(SendState::DataSent));
+        client.stream_frame_acked(id, 0, 3, false);
+        assert_eq!(client.send_state(id), Some(SendState::DataRecvd));
+    }
+
+    #[test]
+    fn test_size_known_before_all_data() {
+        let mut server = Streams::new(Side::Server);
+        let id = StreamId(0);
+        server.receive(id, 4, Bytes::from_static(b"end"), true).unwrap();
+        assert_eq!(server.recv_state(id), Some(RecvState::SizeKnown));
+
+        server.receive(id, 0, Bytes::from_static(b"the "), false).unwrap();
+        assert_eq!(server.recv_state(id), Some(RecvState::DataRecvd));
+    }
+
+    #[test]
+    fn test_reset_reaches_reader() {
+        let mut client = with_peer_limits(Side::Client);
+        let mut server = with_peer_limits(Side::Server);
+        let id = client.open(Dir::Bi).unwrap();
+        client.write(id, &[1u8; 10]).unwrap();
+        deliver(&mut client, &mut server);
+
+        // Unsent data is dropped and the final size covers only what was sent
+        client.write(id, &[2u8; 10]).unwrap();
+        client.reset(id, 42).unwrap();
+        assert_eq!(client.write(id, b"more"), Err(StreamError::Finished));
+        assert!(!client.has_pending());
+        assert_eq!(client.data_written, 10);
+
+        let frames = client.poll_control_frames(Instant::now(), Duration::from_millis(10));
+        let [Frame::ResetStream { stream_id, error_code: 42, final_size: 10 }] = frames[..] else {
+            panic!("Expected ResetStream frame, got {:?}", frames);
+        };
+        assert_eq!(client.send_state(id), Some(SendState::ResetSent));
+
+        server.receive_reset_stream(stream_id, 42, 10).unwrap();
+        assert_eq!(server.recv_state(id), Some(RecvState::ResetRecvd));
+        assert_eq!(server.read(id, &mut [0u8; 16]), Err(StreamError::Reset(42)));
+        assert_eq!(server.recv_state(id), Some(RecvState::ResetRead));
+        assert_eq!(server.window.received(), 10);
+
+        client.reset_stream_acked(id);
+        assert_eq!(client.send_state(id), Some(SendState::ResetRecvd));
+    }
+
+    #[test]
+    fn test_reset_final_size_checked() {
+        let mut server = Streams::new(Side::Server);
+        let id = StreamId(0);
+        server.receive(id, 0, Bytes::from_static(b"0123456789"), false).unwrap();
+
+        assert_eq!(server.receive_reset_stream(id, 1, 5), Err(StreamError::FinalSize));
+        server.receive(id, 10, Bytes::new(), true).unwrap();
+        assert_eq!(server.receive_reset_stream(id, 1, 11), Err(StreamError::FinalSize));
+    }
+
+    #[test]
+    fn test_stop_sending_reaches_writer() {
+        let mut client = with_peer_limits(Side::Client);
+        let mut server = with_peer_limits(Side::Server);
+        let id = client.open(Dir::Uni).unwrap();
+        client.write(id, b"unwanted").unwrap();
+        deliver(&mut client, &mut server);
+
+        server.stop_sending(id, 7).unwrap();
+        let frames = server.poll_control_frames(Instant::now(), Duration::from_millis(10));
+        assert!(matches!(frames[..], [Frame::StopSending { error_code: 7, .. }]));
+
+        // The writer answers with a RESET_STREAM carrying the same code
+        client.write(id, b"queued").unwrap();
+        client.receive_stop_sending(id, 7).unwrap();
+        let frames = client.poll_control_frames(Instant::now(), Duration::from_millis(10));
+        assert!(matches!(frames[..], [Frame::ResetStream { error_code: 7, final_size: 8, .. }]));
+        assert_eq!(client.write(id, b"more"), Err(StreamError::Stopped(7)));
+        // Once the application has seen the error the stream is forgotten
+        assert_eq!(client.send_state(id), None);
+
+        server.receive_reset_stream(id, 7, 8).unwrap();
+        assert_eq!(server.recv_state(id), None);
+    }
+}
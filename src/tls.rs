@@ -1,6 +1,8 @@
-use rustls::{ClientConfig, ServerConfig, ClientConnection, ServerConnection, Certificate, PrivateKey};
+use crate::crypto::EncryptionLevel;
+use crate::packet::{QUIC_VERSION_1, QUIC_VERSION_DRAFT_29};
+use rustls::quic::{self, KeyChange, Keys, Version};
+use rustls::{ClientConfig, ServerConfig, Certificate, PrivateKey, RootCertStore};
 use std::sync::Arc;
-use std::io;
 
 #[derive(Debug)]
 pub struct TlsConfig {
@@ -10,8 +12,9 @@ pub struct TlsConfig {
 
 impl TlsConfig {
     pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
-        let client_config = Self::create_client_config()?;
-        let server_config = Self::create_server_config()?;
+        let (cert_chain, private_key) = Self::generate_self_signed_cert()?;
+        let client_config = Self::create_client_config(&cert_chain)?;
+        let server_config = Self::create_server_config(cert_chain, private_key)?;
         
         Ok(TlsConfig {
             client_config: Arc::new(client_config),
@@ -19,10 +22,18 @@ impl TlsConfig {
         })
     }
     
-    fn create_client_config() -> Result<ClientConfig, Box<dyn std::error::Error>> {
+    fn create_client_config(trusted_certs: &[Certificate]) -> Result<ClientConfig, Box<dyn std::error::Error>> {
+        // Trust the self-signed certificate generated alongside this configuration
+        let mut root_store = RootCertStore::empty();
+        for cert in trusted_certs {
+            root_store.add(cert)?;
+        }
+        
         let mut config = ClientConfig::builder()
-            .with_safe_defaults()
-            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_root_certificates(root_store)
             .with_no_client_auth();
         
         config.alpn_protocols = vec![b"h3".to_vec()];
@@ -30,12 +41,11 @@ impl TlsConfig {
         Ok(config)
     }
     
-    fn create_server_config() -> Result<ServerConfig, Box<dyn std::error::Error>> {
-        let cert_chain = Self::generate_self_signed_cert()?;
-        let private_key = Self::generate_private_key()?;
-        
+    fn create_server_config(cert_chain: Vec<Certificate>, private_key: PrivateKey) -> Result<ServerConfig, Box<dyn std::error::Error>> {
         let mut config = ServerConfig::builder()
-            .with_safe_defaults()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])?
             .with_no_client_auth()
             .with_single_cert(cert_chain, private_key)?;
         
@@ -44,94 +54,170 @@ impl TlsConfig {
         Ok(config)
     }
     
-    fn generate_self_signed_cert() -> Result<Vec<Certificate>, Box<dyn std::error::Error>> {
+    fn generate_self_signed_cert() -> Result<(Vec<Certificate>, PrivateKey), Box<dyn std::error::Error>> {
         let subject_alt_names = vec!["localhost".to_string(), "127.0.0.1".to_string()];
         let cert = rcgen::generate_simple_self_signed(subject_alt_names)?;
         let cert_der = cert.serialize_der()?;
+        let key_der = cert.serialize_private_key_der();
         
-        Ok(vec![Certificate(cert_der)])
+        Ok((vec![Certificate(cert_der)], PrivateKey(key_der)))
     }
+}
+
+/// Result of driving the TLS handshake forward
+#[derive(Default)]
+pub struct HandshakeOutput {
+    /// Handshake messages to carry in CRYPTO frames, tagged with the level they must be sent at
+    pub messages: Vec<(EncryptionLevel, Vec<u8>)>,
+    /// Packet protection keys that became available, in the order rustls derived them
+    pub keys: Vec<(EncryptionLevel, Keys)>,
+}
+
+/// Collects pending handshake messages and key changes from rustls
+///
+/// Must run after every `read_hs` call so data is attributed to the level it was produced at.
+fn drain_handshake(connection: &mut quic::Connection, write_level: &mut EncryptionLevel) -> HandshakeOutput {
+    let mut output = HandshakeOutput::default();
     
-    fn generate_private_key() -> Result<PrivateKey, Box<dyn std::error::Error>> {
-        let subject_alt_names = vec!["localhost".to_string(), "127.0.0.1".to_string()];
-        let cert = rcgen::generate_simple_self_signed(subject_alt_names)?;
-        let key_der = cert.serialize_private_key_der();
+    loop {
+        let mut buf = Vec::new();
+        let key_change = connection.write_hs(&mut buf);
         
-        Ok(PrivateKey(key_der))
+        // Bytes written alongside a key change still belong to the previous level
+        if !buf.is_empty() {
+            output.messages.push((*write_level, buf));
+        }
+        
+        match key_change {
+            Some(KeyChange::Handshake { keys }) => {
+                output.keys.push((EncryptionLevel::Handshake, keys));
+                *write_level = EncryptionLevel::Handshake;
+            }
+            Some(KeyChange::OneRtt { keys, .. }) => {
+                output.keys.push((EncryptionLevel::Application, keys));
+                *write_level = EncryptionLevel::Application;
+            }
+            None => break,
+        }
+    }
+    
+    output
+}
+
+/// The rustls flavour of QUIC that matches `version`: draft 29 uses its own transport parameters extension
+fn rustls_version(version: u32) -> Result<Version, rustls::Error> {
+    match version {
+        QUIC_VERSION_1 => Ok(Version::V1),
+        QUIC_VERSION_DRAFT_29 => Ok(Version::V1Draft),
+        _ => Err(rustls::Error::General(format!("unsupported QUIC version {:#x}", version))),
     }
 }
 
 #[derive(Debug)]
 pub struct QuicClientTls {
-    connection: ClientConnection,
+    connection: quic::Connection,
+    write_level: EncryptionLevel,
 }
 
 impl QuicClientTls {
     pub fn new(config: Arc<ClientConfig>, server_name: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::with_transport_parameters(config, server_name, Vec::new())
+    }
+    
+    /// `transport_parameters` is sent to the server in the quic_transport_parameters extension
+    pub fn with_transport_parameters(config: Arc<ClientConfig>, server_name: &str, transport_parameters: Vec<u8>) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::for_version(config, server_name, QUIC_VERSION_1, transport_parameters)
+    }
+    
+    /// Handshake for QUIC `version`, which must be one of `SUPPORTED_VERSIONS`
+    pub fn for_version(config: Arc<ClientConfig>, server_name: &str, version: u32, transport_parameters: Vec<u8>) -> Result<Self, Box<dyn std::error::Error>> {
         let server_name = server_name.try_into()?;
-        let connection = ClientConnection::new(config, server_name)?;
+        let connection = quic::ClientConnection::new(config, rustls_version(version)?, server_name, transport_parameters)?;
         
-        Ok(QuicClientTls { connection })
+        Ok(QuicClientTls {
+            connection: connection.into(),
+            write_level: EncryptionLevel::Initial,
+        })
     }
     
-    pub fn get_handshake_data(&mut self) -> Result<Vec<u8>, io::Error> {
-        let mut buf = Vec::new();
-        self.connection.write_tls(&mut buf)?;
-        Ok(buf)
+    pub fn get_handshake_data(&mut self) -> HandshakeOutput {
+        drain_handshake(&mut self.connection, &mut self.write_level)
     }
     
-    pub fn process_handshake_data(&mut self, data: &[u8]) -> Result<(), io::Error> {
-        self.connection.read_tls(&mut io::Cursor::new(data))?;
-        self.connection.process_new_packets().map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-        Ok(())
+    /// Feeds handshake bytes received at a single encryption level, in order
+    pub fn process_handshake_data(&mut self, data: &[u8]) -> Result<(), rustls::Error> {
+        self.connection.read_hs(data)
     }
     
     pub fn is_handshake_complete(&self) -> bool {
         !self.connection.is_handshaking()
     }
     
+    /// Encoded transport parameters received from the server, once available
+    pub fn peer_transport_parameters(&self) -> Option<&[u8]> {
+        self.connection.quic_transport_parameters()
+    }
+    
+    /// Fills `out` from the TLS exporter (RFC 8446 Section 7.5); fails until the handshake completes
     pub fn export_keying_material(&self, out: &mut [u8], label: &[u8], context: Option<&[u8]>) -> Result<(), rustls::Error> {
-        // For now, simplified implementation - in real QUIC this would export proper keys
-        for (i, byte) in out.iter_mut().enumerate() {
-            *byte = (label[i % label.len()] ^ (i as u8)) as u8;
+        if self.connection.is_handshaking() {
+            return Err(rustls::Error::HandshakeNotComplete);
         }
+        self.connection.export_keying_material(out, label, context)?;
         Ok(())
     }
 }
 
 #[derive(Debug)]
 pub struct QuicServerTls {
-    connection: ServerConnection,
+    connection: quic::Connection,
+    write_level: EncryptionLevel,
 }
 
 impl QuicServerTls {
     pub fn new(config: Arc<ServerConfig>) -> Result<Self, Box<dyn std::error::Error>> {
-        let connection = ServerConnection::new(config)?;
+        Self::with_transport_parameters(config, Vec::new())
+    }
+    
+    /// `transport_parameters` is sent to the client in the quic_transport_parameters extension
+    pub fn with_transport_parameters(config: Arc<ServerConfig>, transport_parameters: Vec<u8>) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::for_version(config, QUIC_VERSION_1, transport_parameters)
+    }
+    
+    /// Handshake for QUIC `version`, which must be one of `SUPPORTED_VERSIONS`
+    pub fn for_version(config: Arc<ServerConfig>, version: u32, transport_parameters: Vec<u8>) -> Result<Self, Box<dyn std::error::Error>> {
+        let connection = quic::ServerConnection::new(config, rustls_version(version)?, transport_parameters)?;
         
-        Ok(QuicServerTls { connection })
+        Ok(QuicServerTls {
+            connection: connection.into(),
+            write_level: EncryptionLevel::Initial,
+        })
     }
     
-    pub fn get_handshake_data(&mut self) -> Result<Vec<u8>, io::Error> {
-        let mut buf = Vec::new();
-        self.connection.write_tls(&mut buf)?;
-        Ok(buf)
+    pub fn get_handshake_data(&mut self) -> HandshakeOutput {
+        drain_handshake(&mut self.connection, &mut self.write_level)
     }
     
-    pub fn process_handshake_data(&mut self, data: &[u8]) -> Result<(), io::Error> {
-        self.connection.read_tls(&mut io::Cursor::new(data))?;
-        self.connection.process_new_packets().map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-        Ok(())
+    /// Feeds handshake bytes received at a single encryption level, in order
+    pub fn process_handshake_data(&mut self, data: &[u8]) -> Result<(), rustls::Error> {
+        self.connection.read_hs(data)
     }
     
     pub fn is_handshake_complete(&self) -> bool {
         !self.connection.is_handshaking()
     }
     
+    /// Encoded transport parameters received from the client, once available
+    pub fn peer_transport_parameters(&self) -> Option<&[u8]> {
+        self.connection.quic_transport_parameters()
+    }
+    
+    /// Fills `out` from the TLS exporter (RFC 8446 Section 7.5); fails until the handshake completes
     pub fn export_keying_material(&self, out: &mut [u8], label: &[u8], context: Option<&[u8]>) -> Result<(), rustls::Error> {
-        // For now, simplified implementation - in real QUIC this would export proper keys
-        for (i, byte) in out.iter_mut().enumerate() {
-            *byte = (label[i % label.len()] ^ (i as u8)) as u8;
+        if self.connection.is_handshaking() {
+            return Err(rustls::Error::HandshakeNotComplete);
         }
+        self.connection.export_keying_material(out, label, context)?;
         Ok(())
     }
 }
@@ -160,4 +246,89 @@ mod tests {
         let server_tls = QuicServerTls::new(config.server_config);
         assert!(server_tls.is_ok());
     }
+    
+    fn complete_handshake(client: &mut QuicClientTls, server: &mut QuicServerTls) {
+        // Shuttle raw handshake messages (no TLS records), draining after every read
+        let mut to_server = client.get_handshake_data().messages;
+        while !to_server.is_empty() {
+            let mut to_client = Vec::new();
+            for (_, data) in to_server {
+                server.process_handshake_data(&data).unwrap();
+                to_client.extend(server.get_handshake_data().messages);
+            }
+            to_server = Vec::new();
+            for (_, data) in to_client {
+                client.process_handshake_data(&data).unwrap();
+                to_server.extend(client.get_handshake_data().messages);
+            }
+        }
+        assert!(client.is_handshake_complete());
+        assert!(server.is_handshake_complete());
+    }
+    
+    #[test]
+    fn test_export_keying_material() {
+        let config = TlsConfig::new().expect("Failed to create TLS config");
+        let mut client = QuicClientTls::new(config.client_config.clone(), "localhost").unwrap();
+        let mut server = QuicServerTls::new(config.server_config.clone()).unwrap();
+        
+        let mut early = [0u8; 32];
+        assert!(matches!(
+            client.export_keying_material(&mut early, b"EXPORTER-test", None),
+            Err(rustls::Error::HandshakeNotComplete)
+        ));
+        
+        complete_handshake(&mut client, &mut server);
+        
+        let mut client_out = [0u8; 32];
+        let mut server_out = [0u8; 32];
+        client.export_keying_material(&mut client_out, b"EXPORTER-test", Some(b"ctx")).unwrap();
+        server.export_keying_material(&mut server_out, b"EXPORTER-test", Some(b"ctx")).unwrap();
+        assert_eq!(client_out, server_out);
+        assert_ne!(client_out, [0u8; 32]);
+        
+        // The context participates in the derivation
+        let mut other = [0u8; 32];
+        client.export_keying_material(&mut other, b"EXPORTER-test", Some(b"other")).unwrap();
+        assert_ne!(client_out, other);
+    }
+    
+    #[test]
+    fn test_handshake_levels_and_keys() {
+        let config = TlsConfig::new().expect("Failed to create TLS config");
+        let mut client = QuicClientTls::with_transport_parameters(config.client_config.clone(), "localhost", vec![0x0f, 0x00]).unwrap();
+        let mut server = QuicServerTls::with_transport_parameters(config.server_config.clone(), vec![0x0f, 0x01, 0xaa]).unwrap();
+        
+        // ClientHello goes out at the Initial level without any key change
+        let output = client.get_handshake_data();
+        assert_eq!(output.messages.len(), 1);
+        assert_eq!(output.messages[0].0, EncryptionLevel::Initial);
+        assert!(output.keys.is_empty());
+        server.process_handshake_data(&output.messages[0].1).unwrap();
+        
+        // ServerHello at Initial, the rest of the flight at Handshake, then 1-RTT keys
+        let output = server.get_handshake_data();
+        let levels: Vec<_> = output.messages.iter().map(|(level, _)| *level).collect();
+        assert_eq!(levels, vec![EncryptionLevel::Initial, EncryptionLevel::Handshake]);
+        let key_levels: Vec<_> = output.keys.iter().map(|(level, _)| *level).collect();
+        assert_eq!(key_levels, vec![EncryptionLevel::Handshake, EncryptionLevel::Application]);
+        
+        // Reading ServerHello only yields the client's Handshake keys
+        client.process_handshake_data(&output.messages[0].1).unwrap();
+        let output_after_hello = client.get_handshake_data();
+        assert!(output_after_hello.messages.is_empty());
+        assert_eq!(output_after_hello.keys[0].0, EncryptionLevel::Handshake);
+        
+        client.process_handshake_data(&output.messages[1].1).unwrap();
+        assert!(client.is_handshake_complete());
+        assert_eq!(client.peer_transport_parameters(), Some(&[0x0f, 0x01, 0xaa][..]));
+        
+        // Client Finished is sent at the Handshake level
+        let output = client.get_handshake_data();
+        assert_eq!(output.messages.len(), 1);
+        assert_eq!(output.messages[0].0, EncryptionLevel::Handshake);
+        server.process_handshake_data(&output.messages[0].1).unwrap();
+        assert!(server.is_handshake_complete());
+        assert_eq!(server.peer_transport_parameters(), Some(&[0x0f, 0x00][..]));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,207 @@
+use crate::packet::ConnectionId;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use ring::hmac;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long a Retry token stays valid unless configured otherwise
+pub const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(10);
+
+const TAG_LEN: usize = 32;
+
+/// Issues and checks the address validation tokens a server sends in Retry packets (RFC 9000 Section 8.1)
+///
+/// Tokens are authenticated with a key only the server knows and bind the client address,
+/// the time of issue and the connection IDs the server must later echo in its transport parameters.
+#[derive(Debug)]
+pub struct AddressValidator {
+    key: hmac::Key,
+    lifetime: Duration,
+}
+
+/// Connection IDs recovered from a valid token
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidatedToken {
+    /// Destination Connection ID of the client's first Initial
+    pub original_dest_conn_id: ConnectionId,
+    /// Source Connection ID of the Retry packet that carried the token
+    pub retry_src_conn_id: ConnectionId,
+}
+
+impl AddressValidator {
+    pub fn new() -> Self {
+        Self::with_lifetime(DEFAULT_TOKEN_LIFETIME)
+    }
+    
+    pub fn with_lifetime(lifetime: Duration) -> Self {
+        let secret: [u8; 32] = rand::random();
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, &secret),
+            lifetime,
+        }
+    }
+    
+    pub fn issue(&self, remote_addr: SocketAddr, original_dest_conn_id: &ConnectionId, retry_src_conn_id: &ConnectionId) -> Bytes {
+        self.issue_at(remote_addr, original_dest_conn_id, retry_src_conn_id, SystemTime::now())
+    }
+    
+    pub fn validate(&self, token: &[u8], remote_addr: SocketAddr) -> Result<ValidatedToken, TokenError> {
+        self.validate_at(token, remote_addr, SystemTime::now())
+    }
+    
+    fn issue_at(&self, remote_addr: SocketAddr, original_dest_conn_id: &ConnectionId, retry_src_conn_id: &ConnectionId, now: SystemTime) -> Bytes {
+        let issued_at = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        
+        let mut token = BytesMut::new();
+        token.put_u64(issued_at);
+        token.put_u8(original_dest_conn_id.len() as u8);
+        token.put_slice(&original_dest_conn_id.data);
+        token.put_u8(retry_src_conn_id.len() as u8);
+        token.put_slice(&retry_src_conn_id.data);
+        
+        let tag = hmac::sign(&self.key, &signed_data(remote_addr, &token));
+        token.put_slice(tag.as_ref());
+        token.freeze()
+    }
+    
+    fn validate_at(&self, token: &[u8], remote_addr: SocketAddr, now: SystemTime) -> Result<ValidatedToken, TokenError> {
+        let body_len = token.len().checked_sub(TAG_LEN).ok_or(TokenError::Malformed)?;
+        let (body, tag) = token.split_at(body_len);
+        
+        // A token presented from another address was not issued to this client
+        hmac::verify(&self.key, &signed_data(remote_addr, body), tag)
+            .map_err(|_| TokenError::Invalid)?;
+        
+        let mut buf = Bytes::copy_from_slice(body);
+        if buf.remaining() < 8 {
+            return Err(TokenError::Malformed);
+        }
+        let issued_at = UNIX_EPOCH + Duration::from_secs(buf.get_u64());
+        let original_dest_conn_id = read_conn_id(&mut buf)?;
+        let retry_src_conn_id = read_conn_id(&mut buf)?;
+        
+        let age = now.duration_since(issued_at).unwrap_or_default();
+        if age > self.lifetime {
+            return Err(TokenError::Expired);
+        }
+        
+        Ok(ValidatedToken {
+            original_dest_conn_id,
+            retry_src_conn_id,
+        })
+    }
+}
+
+impl Default for AddressValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Token body prefixed with the client address it is bound to
+fn signed_data(remote_addr: SocketAddr, body: &[u8]) -> Vec<u8> {
+    let mut data = match remote_addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    data.extend_from_slice(&remote_addr.port().to_be_bytes());
+    data.extend_from_slice(body);
+    data
+}
+
+fn read_conn_id(buf: &mut Bytes) -> Result<ConnectionId, TokenError> {
+    if buf.is_empty() {
+        return Err(TokenError::Malformed);
+    }
+    let len = buf.get_u8() as usize;
+    if buf.remaining() < len {
+        return Err(TokenError::Malformed);
+    }
+    Ok(ConnectionId::new(buf.copy_to_bytes(len).to_vec()))
+}
+
+#[derive(Debug)]
+pub enum TokenError {
+    Malformed,
+    /// The token was not issued by this server for this address
+    Invalid,
+    Expired,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::Malformed => write!(f, "Malformed address validation token"),
+            TokenError::Invalid => write!(f, "Invalid address validation token"),
+            TokenError::Expired => write!(f, "Expired address validation token"),
+        }
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_addr() -> SocketAddr {
+        "192.0.2.1:4433".parse().unwrap()
+    }
+
+    #[test]
+    fn test_token_roundtrip() {
+        let validator = AddressValidator::new();
+        let original = ConnectionId::new(vec![1, 2, 3, 4]);
+        let retry = ConnectionId::new(vec![5, 6, 7, 8, 9]);
+
+        let token = validator.issue(client_addr(), &original, &retry);
+        let validated = validator.validate(&token, client_addr()).unwrap();
+
+        assert_eq!(validated.original_dest_conn_id, original);
+        assert_eq!(validated.retry_src_conn_id, retry);
+    }
+
+    #[test]
+    fn test_token_bound_to_address() {
+        let validator = AddressValidator::new();
+        let conn_id = ConnectionId::new(vec![1, 2, 3, 4]);
+        let token = validator.issue(client_addr(), &conn_id, &conn_id);
+
+        let spoofed: SocketAddr = "198.51.100.7:4433".parse().unwrap();
+        assert!(matches!(validator.validate(&token, spoofed), Err(TokenError::Invalid)));
+    }
+
+    #[test]
+    fn test_token_from_other_server_rejected() {
+        let conn_id = ConnectionId::new(vec![1, 2, 3, 4]);
+        let token = AddressValidator::new().issue(client_addr(), &conn_id, &conn_id);
+
+        assert!(matches!(AddressValidator::new().validate(&token, client_addr()), Err(TokenError::Invalid)));
+    }
+
+    #[test]
+    fn test_tampered_token_rejected() {
+        let validator = AddressValidator::new();
+        let conn_id = ConnectionId::new(vec![1, 2, 3, 4]);
+        let mut token = validator.issue(client_addr(), &conn_id, &conn_id).to_vec();
+        token[9] ^= 0x01;
+
+        assert!(matches!(validator.validate(&token, client_addr()), Err(TokenError::Invalid)));
+        assert!(matches!(validator.validate(&token[..10], client_addr()), Err(TokenError::Malformed)));
+    }
+
+    #[test]
+    fn test_token_expires() {
+        let validator = AddressValidator::with_lifetime(Duration::from_secs(10));
+        let conn_id = ConnectionId::new(vec![1, 2, 3, 4]);
+        let issued = SystemTime::now();
+        let token = validator.issue_at(client_addr(), &conn_id, &conn_id, issued);
+
+        assert!(validator.validate_at(&token, client_addr(), issued + Duration::from_secs(5)).is_ok());
+        assert!(matches!(
+            validator.validate_at(&token, client_addr(), issued + Duration::from_secs(11)),
+            Err(TokenError::Expired)
+        ));
+    }
+}
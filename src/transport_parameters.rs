@@ -0,0 +1,292 @@
+use crate::frame::{decode_varint, encode_varint};
+use crate::packet::ConnectionId;
+use crate::stream::MAX_STREAM_COUNT;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::fmt;
+
+const ORIGINAL_DESTINATION_CONNECTION_ID: u64 = 0x00;
+const MAX_IDLE_TIMEOUT: u64 = 0x01;
+const INITIAL_MAX_DATA: u64 = 0x04;
+const INITIAL_MAX_STREAM_DATA_BIDI_LOCAL: u64 = 0x05;
+const INITIAL_MAX_STREAM_DATA_BIDI_REMOTE: u64 = 0x06;
+const INITIAL_MAX_STREAM_DATA_UNI: u64 = 0x07;
+const INITIAL_MAX_STREAMS_BIDI: u64 = 0x08;
+const INITIAL_MAX_STREAMS_UNI: u64 = 0x09;
+const ACK_DELAY_EXPONENT: u64 = 0x0a;
+const MAX_ACK_DELAY: u64 = 0x0b;
+const INITIAL_SOURCE_CONNECTION_ID: u64 = 0x0f;
+const RETRY_SOURCE_CONNECTION_ID: u64 = 0x10;
+
+/// Largest ack_delay_exponent a peer may send (RFC 9000 Section 18.2)
+pub const MAX_ACK_DELAY_EXPONENT: u64 = 20;
+/// Largest max_ack_delay a peer may send, in milliseconds
+pub const MAX_MAX_ACK_DELAY: u64 = (1 << 14) - 1;
+
+/// QUIC transport parameters carried in the TLS quic_transport_parameters extension (RFC 9000 Section 18)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransportParameters {
+    pub original_destination_connection_id: Option<ConnectionId>,
+    pub initial_source_connection_id: Option<ConnectionId>,
+    /// Source Connection ID of the Retry the server sent, if any
+    pub retry_source_connection_id: Option<ConnectionId>,
+    /// Milliseconds without activity after which the sender closes the connection; absent or zero disables it
+    pub max_idle_timeout: Option<u64>,
+    /// Connection-level flow control limit; absent means zero
+    pub initial_max_data: Option<u64>,
+    /// Limit for bidirectional streams the sender of these parameters opens
+    pub initial_max_stream_data_bidi_local: Option<u64>,
+    /// Limit for bidirectional streams the receiver of these parameters opens
+    pub initial_max_stream_data_bidi_remote: Option<u64>,
+    pub initial_max_stream_data_uni: Option<u64>,
+    /// Number of bidirectional streams the receiver of these parameters may open
+    pub initial_max_streams_bidi: Option<u64>,
+    pub initial_max_streams_uni: Option<u64>,
+    /// Scale of the ACK Delay field in the sender's ACK frames; absent means 3
+    pub ack_delay_exponent: Option<u64>,
+    /// Longest the sender delays acknowledgements, in milliseconds; absent means 25
+    pub max_ack_delay: Option<u64>,
+}
+
+impl TransportParameters {
+    pub fn encode(&self, buf: &mut BytesMut) {
+        if let Some(conn_id) = &self.original_destination_connection_id {
+            encode_parameter(buf, ORIGINAL_DESTINATION_CONNECTION_ID, &conn_id.data);
+        }
+        if let Some(conn_id) = &self.initial_source_connection_id {
+            encode_parameter(buf, INITIAL_SOURCE_CONNECTION_ID, &conn_id.data);
+        }
+        if let Some(conn_id) = &self.retry_source_connection_id {
+            encode_parameter(buf, RETRY_SOURCE_CONNECTION_ID, &conn_id.data);
+        }
+        
+        let limits = [
+            (MAX_IDLE_TIMEOUT, self.max_idle_timeout),
+            (INITIAL_MAX_DATA, self.initial_max_data),
+            (INITIAL_MAX_STREAM_DATA_BIDI_LOCAL, self.initial_max_stream_data_bidi_local),
+            (INITIAL_MAX_STREAM_DATA_BIDI_REMOTE, self.initial_max_stream_data_bidi_remote),
+            (INITIAL_MAX_STREAM_DATA_UNI, self.initial_max_stream_data_uni),
+            (INITIAL_MAX_STREAMS_BIDI, self.initial_max_streams_bidi),
+            (INITIAL_MAX_STREAMS_UNI, self.initial_max_streams_uni),
+            (ACK_DELAY_EXPONENT, self.ack_delay_exponent),
+            (MAX_ACK_DELAY, self.max_ack_delay),
+        ];
+        for (id, value) in limits {
+            if let Some(value) = value {
+                let mut encoded = BytesMut::new();
+                encode_varint(&mut encoded, value);
+                encode_parameter(buf, id, &encoded);
+            }
+        }
+    }
+    
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf);
+        buf.to_vec()
+    }
+    
+    pub fn decode(buf: &mut Bytes) -> Result<Self, TransportParameterError> {
+        let mut params = TransportParameters::default();
+        
+        while buf.has_remaining() {
+            let id = decode_varint(buf).map_err(|_| TransportParameterError::InvalidFormat)?;
+            let length = decode_varint(buf).map_err(|_| TransportParameterError::InvalidFormat)? as usize;
+            if buf.remaining() < length {
+                return Err(TransportParameterError::InvalidFormat);
+            }
+            let value = buf.copy_to_bytes(length);
+            
+            match id {
+                ORIGINAL_DESTINATION_CONNECTION_ID => {
+                    set_once(&mut params.original_destination_connection_id, decode_connection_id(value)?, id)?;
+                }
+                INITIAL_SOURCE_CONNECTION_ID => {
+                    set_once(&mut params.initial_source_connection_id, decode_connection_id(value)?, id)?;
+                }
+                RETRY_SOURCE_CONNECTION_ID => {
+                    set_once(&mut params.retry_source_connection_id, decode_connection_id(value)?, id)?;
+                }
+                MAX_IDLE_TIMEOUT => {
+                    set_once(&mut params.max_idle_timeout, decode_integer(value)?, id)?;
+                }
+                INITIAL_MAX_DATA => {
+                    set_once(&mut params.initial_max_data, decode_integer(value)?, id)?;
+                }
+                INITIAL_MAX_STREAM_DATA_BIDI_LOCAL => {
+                    set_once(&mut params.initial_max_stream_data_bidi_local, decode_integer(value)?, id)?;
+                }
+                INITIAL_MAX_STREAM_DATA_BIDI_REMOTE => {
+                    set_once(&mut params.initial_max_stream_data_bidi_remote, decode_integer(value)?, id)?;
+                }
+                INITIAL_MAX_STREAM_DATA_UNI => {
+                    set_once(&mut params.initial_max_stream_data_uni, decode_integer(value)?, id)?;
+                }
+                INITIAL_MAX_STREAMS_BIDI => {
+                    set_once(&mut params.initial_max_streams_bidi, decode_stream_count(value)?, id)?;
+                }
+                INITIAL_MAX_STREAMS_UNI => {
+                    set_once(&mut params.initial_max_streams_uni, decode_stream_count(value)?, id)?;
+                }
+                ACK_DELAY_EXPONENT => {
+                    set_once(&mut params.ack_delay_exponent, decode_bounded(value, MAX_ACK_DELAY_EXPONENT)?, id)?;
+                }
+                MAX_ACK_DELAY => {
+                    set_once(&mut params.max_ack_delay, decode_bounded(value, MAX_MAX_ACK_DELAY)?, id)?;
+                }
+                // Unknown parameters must be ignored
+                _ => {}
+            }
+        }
+        
+        Ok(params)
+    }
+}
+
+fn encode_parameter(buf: &mut BytesMut, id: u64, value: &[u8]) {
+    encode_varint(buf, id);
+    encode_varint(buf, value.len() as u64);
+    buf.put_slice(value);
+}
+
+fn decode_connection_id(value: Bytes) -> Result<ConnectionId, TransportParameterError> {
+    if value.len() > 20 {
+        return Err(TransportParameterError::InvalidFormat);
+    }
+    Ok(ConnectionId::new(value.to_vec()))
+}
+
+/// Integer parameters are a single varint filling the whole value
+fn decode_integer(mut value: Bytes) -> Result<u64, TransportParameterError> {
+    let integer = decode_varint(&mut value).map_err(|_| TransportParameterError::InvalidFormat)?;
+    if value.has_remaining() {
+        return Err(TransportParameterError::InvalidFormat);
+    }
+    Ok(integer)
+}
+
+fn decode_stream_count(value: Bytes) -> Result<u64, TransportParameterError> {
+    decode_bounded(value, MAX_STREAM_COUNT)
+}
+
+/// Integer parameters with an upper bound, above which the parameters are invalid
+fn decode_bounded(value: Bytes, max: u64) -> Result<u64, TransportParameterError> {
+    let integer = decode_integer(value)?;
+    if integer > max {
+        return Err(TransportParameterError::InvalidFormat);
+    }
+    Ok(integer)
+}
+
+fn set_once<T>(slot: &mut Option<T>, value: T, id: u64) -> Result<(), TransportParameterError> {
+    if slot.is_some() {
+        return Err(TransportParameterError::DuplicateParameter(id));
+    }
+    *slot = Some(value);
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum TransportParameterError {
+    InvalidFormat,
+    DuplicateParameter(u64),
+}
+
+impl fmt::Display for TransportParameterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportParameterError::InvalidFormat => write!(f, "Invalid transport parameters"),
+            TransportParameterError::DuplicateParameter(id) => write!(f, "Duplicate transport parameter: {:#x}", id),
+        }
+    }
+}
+
+impl std::error::Error for TransportParameterError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transport_parameters_roundtrip() {
+        let params = TransportParameters {
+            original_destination_connection_id: Some(ConnectionId::new(vec![1, 2, 3, 4])),
+            initial_source_connection_id: Some(ConnectionId::new(vec![5, 6, 7, 8, 9])),
+            retry_source_connection_id: Some(ConnectionId::new(vec![10, 11])),
+            max_idle_timeout: Some(30_000),
+            initial_max_data: Some(1 << 20),
+            initial_max_stream_data_bidi_local: Some(0),
+            initial_max_stream_data_bidi_remote: Some(65536),
+            initial_max_stream_data_uni: Some(63),
+            initial_max_streams_bidi: Some(100),
+            initial_max_streams_uni: Some(MAX_STREAM_COUNT),
+            ack_delay_exponent: Some(MAX_ACK_DELAY_EXPONENT),
+            max_ack_delay: Some(10),
+        };
+        
+        let mut bytes = Bytes::from(params.to_bytes());
+        let decoded = TransportParameters::decode(&mut bytes).unwrap();
+        assert_eq!(decoded, params);
+    }
+
+    #[test]
+    fn test_transport_parameters_skip_unknown() {
+        let mut buf = BytesMut::new();
+        encode_parameter(&mut buf, 0x2ab2, &[0xff; 3]);
+        encode_parameter(&mut buf, INITIAL_SOURCE_CONNECTION_ID, &[7, 7]);
+        
+        let decoded = TransportParameters::decode(&mut buf.freeze()).unwrap();
+        assert_eq!(decoded.initial_source_connection_id, Some(ConnectionId::new(vec![7, 7])));
+        assert!(decoded.original_destination_connection_id.is_none());
+    }
+
+    #[test]
+    fn test_transport_parameters_duplicate() {
+        let mut buf = BytesMut::new();
+        encode_parameter(&mut buf, INITIAL_SOURCE_CONNECTION_ID, &[1]);
+        encode_parameter(&mut buf, INITIAL_SOURCE_CONNECTION_ID, &[2]);
+        
+        assert!(matches!(
+            TransportParameters::decode(&mut buf.freeze()),
+            Err(TransportParameterError::DuplicateParameter(INITIAL_SOURCE_CONNECTION_ID))
+        ));
+    }
+
+    #[test]
+    fn test_integer_parameter_with_trailing_bytes() {
+        let mut buf = BytesMut::new();
+        encode_parameter(&mut buf, INITIAL_MAX_DATA, &[0x05, 0x00]);
+        
+        assert!(matches!(
+            TransportParameters::decode(&mut buf.freeze()),
+            Err(TransportParameterError::InvalidFormat)
+        ));
+    }
+
+    #[test]
+    fn test_stream_count_above_limit_rejected() {
+        let mut buf = BytesMut::new();
+        let mut value = BytesMut::new();
+        encode_varint(&mut value, MAX_STREAM_COUNT + 1);
+        encode_parameter(&mut buf, INITIAL_MAX_STREAMS_UNI, &value);
+        
+        assert!(TransportParameters::decode(&mut buf.freeze()).is_err());
+    }
+
+    #[test]
+    fn test_ack_delay_parameters_above_limit_rejected() {
+        for (id, value) in [(ACK_DELAY_EXPONENT, MAX_ACK_DELAY_EXPONENT + 1), (MAX_ACK_DELAY, 1 << 14)] {
+            let mut buf = BytesMut::new();
+            let mut encoded = BytesMut::new();
+            encode_varint(&mut encoded, value);
+            encode_parameter(&mut buf, id, &encoded);
+
+            assert!(TransportParameters::decode(&mut buf.freeze()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_transport_parameters_truncated() {
+        let mut bytes = Bytes::from_static(&[0x0f, 0x05, 0x01]);
+        assert!(TransportParameters::decode(&mut bytes).is_err());
+    }
+}
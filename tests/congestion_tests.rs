@@ -0,0 +1,279 @@
+use oreno_quic::congestion::{initial_window, minimum_window, Bbr, BbrState, CongestionControl, CongestionController, Cubic, NewReno};
+use oreno_quic::rtt::RttEstimator;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+const MDS: usize = 1200;
+
+#[test]
+fn test_new_reno_slow_start_then_avoidance() {
+    let mut reno = NewReno::new(MDS);
+    let rtt = RttEstimator::new();
+    let start = Instant::now();
+    assert_eq!(reno.window(), initial_window(MDS));
+    
+    // A full window acknowledged in slow start doubles it
+    for _ in 0..10 {
+        reno.on_ack(start, start, MDS, &rtt);
+    }
+    assert_eq!(reno.window(), 2 * initial_window(MDS));
+    
+    let loss = start + Duration::from_millis(50);
+    reno.on_congestion_event(loss, start, false);
+    assert_eq!(reno.window(), initial_window(MDS));
+    assert!(!reno.in_slow_start());
+    
+    // After recovery, a window's worth of acknowledgements adds one datagram
+    let sent = loss + Duration::from_millis(1);
+    for _ in 0..10 {
+        reno.on_ack(sent, sent, MDS, &rtt);
+    }
+    assert_eq!(reno.window(), initial_window(MDS) + MDS);
+}
+
+#[test]
+fn test_new_reno_window_floor() {
+    let mut reno = NewReno::new(MDS);
+    let mut now = Instant::now();
+    for _ in 0..10 {
+        now += Duration::from_millis(100);
+        reno.on_congestion_event(now, now, false);
+    }
+    assert_eq!(reno.window(), minimum_window(MDS));
+}
+
+#[test]
+fn test_controller_as_trait_object() {
+    let mut controller: Box<dyn CongestionController> = Box::new(NewReno::new(MDS));
+    let now = Instant::now();
+    controller.on_packet_sent(now, MDS);
+    controller.on_congestion_event(now, now, true);
+    assert_eq!(controller.window(), minimum_window(MDS));
+    
+    let rtt = RttEstimator::new();
+    assert!(controller.pacing_rate(&rtt) > 0);
+}
+
+#[test]
+fn test_cubic_backs_off_less_than_new_reno() {
+    let start = Instant::now();
+    let mut cubic = Cubic::new(MDS);
+    let mut reno = NewReno::new(MDS);
+    cubic.on_congestion_event(start, start, false);
+    reno.on_congestion_event(start, start, false);
+    assert_eq!(cubic.window(), initial_window(MDS) * 7 / 10);
+    assert_eq!(reno.window(), initial_window(MDS) / 2);
+    assert_eq!(cubic.w_max(), initial_window(MDS));
+}
+
+#[test]
+fn test_cubic_returns_to_w_max_and_probes_beyond() {
+    let start = Instant::now();
+    let mut cubic = Cubic::new(MDS);
+    let mut rtt = RttEstimator::new();
+    rtt.update(Duration::from_millis(50), Duration::ZERO, None);
+    cubic.on_ack(start, start, 90 * MDS, &rtt);
+    cubic.on_congestion_event(start, start, false);
+    let w_max = cubic.w_max();
+    
+    // A steady stream of acknowledgements, one window per RTT, for ten seconds
+    let mut now = start;
+    let mut trajectory = Vec::new();
+    for _ in 0..200 {
+        now += Duration::from_millis(50);
+        for _ in 0..cubic.window() / MDS {
+            cubic.on_ack(now, now - Duration::from_millis(25), MDS, &rtt);
+        }
+        trajectory.push(cubic.window());
+    }
+    assert!(trajectory.windows(2).all(|pair| pair[0] <= pair[1]));
+    assert!(trajectory.iter().any(|window| window.abs_diff(w_max) < MDS));
+    assert!(trajectory[199] > w_max + 10 * MDS);
+}
+
+#[test]
+fn test_congestion_control_builds_selected_controller() {
+    let controller = CongestionControl::Cubic.build(MDS);
+    assert!(format!("{:?}", controller).starts_with("Cubic"));
+    let controller = CongestionControl::default().build(MDS);
+    assert!(format!("{:?}", controller).starts_with("NewReno"));
+    let controller = CongestionControl::Bbr.build(MDS);
+    assert!(format!("{:?}", controller).starts_with("Bbr"));
+}
+
+/// A sender behind a single bottleneck link, driven by a simulated clock: packets queue at the link,
+/// leave it at `bandwidth` bytes per second, and are acknowledged `propagation` later
+struct Link {
+    bandwidth: f64,
+    propagation: Duration,
+    start: Instant,
+    now: Instant,
+    /// When the link finishes sending everything queued so far
+    link_free: Instant,
+    next_send: Instant,
+    /// Packets in flight as (acknowledged at, sent at), in order since the link is FIFO
+    in_flight: VecDeque<(Instant, Instant)>,
+    rtt: RttEstimator,
+    /// Acknowledgements as (time, RTT sample, window, state)
+    log: Vec<(Instant, Duration, usize, BbrState)>,
+}
+
+impl Link {
+    fn new(bandwidth: f64, propagation: Duration) -> Self {
+        let start = Instant::now();
+        Self {
+            bandwidth,
+            propagation,
+            start,
+            now: start,
+            link_free: start,
+            next_send: start,
+            in_flight: VecDeque::new(),
+            rtt: RttEstimator::new(),
+            log: Vec::new(),
+        }
+    }
+
+    fn bdp(&self) -> f64 {
+        self.bandwidth * self.propagation.as_secs_f64()
+    }
+
+    /// Sends whenever both the window and the pacing rate allow, until `duration` has passed
+    fn run(&mut self, bbr: &mut Bbr, duration: Duration) {
+        let end = self.now + duration;
+        let serialization = Duration::from_secs_f64(MDS as f64 / self.bandwidth);
+        while self.now < end {
+            while self.in_flight.len() * MDS < bbr.window() && self.next_send <= self.now {
+                bbr.on_packet_sent(self.now, MDS);
+                self.link_free = self.link_free.max(self.now) + serialization;
+                self.in_flight.push_back((self.link_free + self.propagation, self.now));
+                let interval = MDS as f64 / bbr.pacing_rate(&self.rtt) as f64;
+                self.next_send = self.now + Duration::from_secs_f64(interval);
+            }
+            let next_ack = self.in_flight.front().map(|&(acked, _)| acked);
+            let can_send = self.in_flight.len() * MDS < bbr.window();
+            self.now = match next_ack {
+                Some(acked) if can_send => acked.min(self.next_send),
+                Some(acked) => acked,
+                None => self.next_send,
+            };
+            while let Some(&(acked, sent)) = self.in_flight.front().filter(|&&(acked, _)| acked <= self.now) {
+                self.in_flight.pop_front();
+                self.rtt.update(acked - sent, Duration::ZERO, None);
+                bbr.on_ack(acked, sent, MDS, &self.rtt);
+                self.log.push((acked, acked - sent, bbr.window(), bbr.state()));
+            }
+        }
+    }
+
+    /// Acknowledgements logged between `from` and `to`, measured from the start of the simulation
+    fn between(&self, from: Duration, to: Duration) -> impl Iterator<Item = &(Instant, Duration, usize, BbrState)> {
+        let (from, to) = (self.start + from, self.start + to);
+        self.log.iter().filter(move |&&(time, ..)| time >= from && time < to)
+    }
+
+    /// Bytes per second acknowledged between `from` and `to`
+    fn throughput(&self, from: Duration, to: Duration) -> f64 {
+        (self.between(from, to).count() * MDS) as f64 / (to - from).as_secs_f64()
+    }
+}
+
+const SECOND: Duration = Duration::from_secs(1);
+
+#[test]
+fn test_bbr_converges_on_fixed_bandwidth_link() {
+    // 1.2 MB/s (about 10 Mbit/s) with a 50ms round trip: a bandwidth-delay product of 50 datagrams
+    let mut link = Link::new(1_200_000.0, Duration::from_millis(50));
+    let mut bbr = Bbr::new(MDS);
+    link.run(&mut bbr, 5 * SECOND);
+    
+    assert_eq!(bbr.state(), BbrState::ProbeBw);
+    let bandwidth = bbr.bottleneck_bandwidth() as f64;
+    assert!(bandwidth > link.bandwidth * 0.95 && bandwidth < link.bandwidth * 1.05, "estimated {}", bandwidth);
+    let min_rtt = bbr.min_rtt().unwrap();
+    assert!(min_rtt >= link.propagation && min_rtt < link.propagation + Duration::from_millis(2), "min RTT {:?}", min_rtt);
+    
+    // The link stays busy without the queue growing past about one bandwidth-delay product
+    assert!(link.throughput(2 * SECOND, 5 * SECOND) > link.bandwidth * 0.95);
+    let worst_rtt = link.between(2 * SECOND, 5 * SECOND).map(|&(_, rtt, ..)| rtt).max().unwrap();
+    assert!(worst_rtt < link.propagation * 2, "RTT reached {:?}", worst_rtt);
+    let window = bbr.window() as f64;
+    assert!(window > link.bdp() && window < 2.5 * link.bdp(), "window {}", window);
+}
+
+#[test]
+fn test_bbr_startup_exits_within_a_few_rounds_of_filling_the_pipe() {
+    let mut link = Link::new(1_200_000.0, Duration::from_millis(50));
+    let mut bbr = Bbr::new(MDS);
+    link.run(&mut bbr, SECOND);
+    
+    // Growing from ten datagrams to fifty takes a few rounds, then three more confirm the plateau
+    let left_startup = link.log.iter().find(|&&(.., state)| state != BbrState::Startup).unwrap().0;
+    assert!(left_startup - link.start < 20 * link.propagation, "left Startup after {:?}", left_startup - link.start);
+    assert!(link.between(Duration::ZERO, SECOND).any(|&(.., state)| state == BbrState::Drain));
+    assert_eq!(bbr.state(), BbrState::ProbeBw);
+}
+
+#[test]
+fn test_bbr_cycles_pacing_gain_in_probe_bw() {
+    let mut link = Link::new(1_200_000.0, Duration::from_millis(50));
+    let mut bbr = Bbr::new(MDS);
+    link.run(&mut bbr, 2 * SECOND);
+    
+    // Eight phases of one min RTT each visit every gain within half a second
+    let mut gains = Vec::new();
+    for _ in 0..20 {
+        link.run(&mut bbr, Duration::from_millis(25));
+        gains.push(bbr.pacing_gain());
+    }
+    assert!(gains.contains(&1.25));
+    assert!(gains.contains(&0.75));
+    assert!(gains.contains(&1.0));
+}
+
+#[test]
+fn test_bbr_probe_rtt_refreshes_min_rtt() {
+    let mut link = Link::new(1_200_000.0, Duration::from_millis(50));
+    let mut bbr = Bbr::new(MDS);
+    link.run(&mut bbr, 12 * SECOND);
+    
+    // After ten seconds without a lower sample the flight drains to four datagrams to measure it again
+    let probe_rtt: Vec<_> = link.log.iter().filter(|&&(.., state)| state == BbrState::ProbeRtt).collect();
+    assert!(!probe_rtt.is_empty());
+    assert!(probe_rtt[0].0 - link.start > 10 * SECOND);
+    assert!(probe_rtt.iter().all(|&&(_, _, window, _)| window <= 4 * MDS));
+    let span = probe_rtt[probe_rtt.len() - 1].0 - probe_rtt[0].0;
+    assert!(span >= Duration::from_millis(200) && span < SECOND, "ProbeRTT lasted {:?}", span);
+    
+    // It returns to ProbeBW with the same estimates
+    assert_eq!(bbr.state(), BbrState::ProbeBw);
+    assert!(bbr.min_rtt().unwrap() < link.propagation + Duration::from_millis(2));
+    assert!(link.throughput(11 * SECOND, 12 * SECOND) > link.bandwidth * 0.9);
+}
+
+#[test]
+fn test_bbr_tracks_link_bandwidth() {
+    for bandwidth in [300_000.0, 6_000_000.0] {
+        let mut link = Link::new(bandwidth, Duration::from_millis(30));
+        let mut bbr = Bbr::new(MDS);
+        link.run(&mut bbr, 4 * SECOND);
+        let estimate = bbr.bottleneck_bandwidth() as f64;
+        assert!(estimate > bandwidth * 0.95 && estimate < bandwidth * 1.05, "estimated {} for {}", estimate, bandwidth);
+        assert!(link.throughput(2 * SECOND, 4 * SECOND) > bandwidth * 0.9);
+    }
+}
+
+#[test]
+fn test_bbr_loss_caps_flight() {
+    let start = Instant::now();
+    let mut bbr = Bbr::new(MDS);
+    let window = bbr.window();
+    bbr.on_packet_sent(start, MDS);
+    bbr.on_congestion_event(start + Duration::from_millis(100), start, false);
+    assert_eq!(bbr.window(), window * 7 / 10);
+    // A loss in Startup means the pipe is full
+    assert_eq!(bbr.state(), BbrState::Drain);
+    
+    bbr.on_congestion_event(start + SECOND, start + SECOND, true);
+    assert_eq!(bbr.window(), 4 * MDS);
+}
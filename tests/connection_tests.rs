@@ -1,7 +1,10 @@
 use oreno_quic::connection::{Connection, ConnectionManager, ConnectionState, ConnectionError};
+use oreno_quic::config::{TransportConfig, DEFAULT_MAX_IDLE_TIMEOUT};
+use oreno_quic::flow_control::INITIAL_RTT;
 use oreno_quic::frame::Frame;
 use oreno_quic::packet::ConnectionId;
 use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
 fn get_test_addr() -> SocketAddr {
     "127.0.0.1:8080".parse().unwrap()
@@ -44,6 +47,21 @@ fn test_packet_number_increment() {
     assert_eq!(conn.packet_number, 3);
 }
 
+#[test]
+fn test_stats_before_any_ack() {
+    let mut conn = Connection::new_client(get_test_addr());
+    let stats = conn.stats();
+    assert_eq!(stats.latest_rtt, None);
+    assert_eq!(stats.min_rtt, None);
+    assert_eq!(stats.smoothed_rtt, INITIAL_RTT);
+    assert_eq!(stats.rttvar, INITIAL_RTT / 2);
+    assert_eq!(stats.bytes_in_flight, 0);
+    
+    let packet = conn.create_initial_packet(vec![Frame::Ping]).unwrap();
+    assert_eq!(conn.stats().bytes_in_flight, packet.len());
+    assert!(conn.poll_timeout().is_some());
+}
+
 #[test]
 fn test_connection_state_transitions() {
     let mut conn = Connection::new_client(get_test_addr());
@@ -84,6 +102,7 @@ fn test_create_initial_packet() {
 fn test_create_handshake_packet() {
     let mut conn = Connection::new_client(get_test_addr());
     conn.remote_conn_id = Some(ConnectionId::new(vec![5, 6, 7, 8]));
+    conn.crypto.setup_handshake_keys(&[0x42; 32], &[0x24; 32], true).unwrap();
     
     let frames = vec![Frame::Ping];
     let packet = conn.create_handshake_packet(frames).unwrap();
@@ -101,6 +120,15 @@ fn test_close_connection() {
     
     assert!(!close_packet.is_empty());
     assert_eq!(conn.state, ConnectionState::Closing);
+    assert!(conn.is_closing());
+    assert!(!conn.is_closed());
+    
+    // Closing again repeats the same packet rather than building another
+    assert_eq!(conn.close("Second close".to_string()).unwrap(), close_packet);
+    let deadline = conn.poll_timeout().unwrap();
+    conn.handle_timeout(deadline);
+    assert!(conn.is_closed());
+    assert!(matches!(conn.close("Too late".to_string()), Err(ConnectionError::InvalidState)));
 }
 
 #[test]
@@ -184,11 +212,12 @@ fn test_connection_without_remote_conn_id() {
 
 #[test]
 fn test_connection_states_enum() {
-    let states = vec![
+    let states = [
         ConnectionState::Initial,
         ConnectionState::Handshake,
         ConnectionState::Established,
         ConnectionState::Closing,
+        ConnectionState::Draining,
         ConnectionState::Closed,
     ];
     
@@ -206,15 +235,81 @@ fn test_connection_states_enum() {
 fn test_connection_error_display() {
     let errors = vec![
         ConnectionError::PacketEncoding,
+        ConnectionError::PacketDecoding,
         ConnectionError::FrameEncoding,
+        ConnectionError::FrameDecoding,
         ConnectionError::InvalidState,
         ConnectionError::TlsSetupFailed,
         ConnectionError::TlsHandshakeFailed,
         ConnectionError::TlsNotSetup,
+        ConnectionError::HandshakeNotComplete,
+        ConnectionError::TransportParameters,
+        ConnectionError::KeysUnavailable,
+        ConnectionError::DecryptionFailed,
+        ConnectionError::DuplicatePacket,
+        ConnectionError::VersionNegotiationIgnored,
+        ConnectionError::NoCommonVersion,
+        ConnectionError::RetryIgnored,
+        ConnectionError::StreamState,
+        ConnectionError::FinalSize,
+        ConnectionError::FlowControl,
+        ConnectionError::StreamLimit,
+        ConnectionError::ProtocolViolation,
     ];
     
     for error in errors {
         let error_string = error.to_string();
         assert!(!error_string.is_empty());
     }
-}
\ No newline at end of file
+}
+#[test]
+fn test_pacing_releases_first_datagrams_immediately() {
+    let mut conn = Connection::new_client(get_test_addr());
+    let now = Instant::now();
+    assert_eq!(conn.next_send_time(now), None);
+    assert!(conn.pacing_rate() > 0);
+    
+    conn.pace(vec![vec![1; 1200], vec![2; 1200]]);
+    assert_eq!(conn.next_send_time(now), Some(now));
+    assert_eq!(conn.poll_transmit(now), Some(vec![1; 1200]));
+    assert_eq!(conn.poll_transmit(now), Some(vec![2; 1200]));
+    assert_eq!(conn.poll_transmit(now), None);
+}
+
+#[test]
+fn test_connection_manager_pacing() {
+    let mut manager = ConnectionManager::new();
+    let now = Instant::now();
+    assert_eq!(manager.next_send_time(now), None);
+    
+    let mut conn = Connection::new_client(get_test_addr());
+    conn.pace(vec![vec![0; 100]]);
+    manager.add_connection(vec![1, 2, 3, 4], conn);
+    assert_eq!(manager.next_send_time(now), Some(now));
+    assert_eq!(manager.poll_transmit(now), vec![(get_test_addr(), vec![0; 100])]);
+    assert_eq!(manager.next_send_time(now), None);
+}
+
+#[test]
+fn test_idle_connection_reaped_by_manager() {
+    let mut manager = ConnectionManager::new();
+    let conn = Connection::new_client(get_test_addr());
+    let deadline = conn.poll_timeout().unwrap();
+    assert!(deadline <= Instant::now() + DEFAULT_MAX_IDLE_TIMEOUT);
+    manager.add_connection(vec![1, 2, 3, 4], conn);
+    
+    manager.handle_timeouts(deadline - Duration::from_millis(1));
+    assert!(manager.reap_closed().is_empty());
+    manager.handle_timeouts(deadline);
+    assert_eq!(manager.reap_closed(), vec![vec![1, 2, 3, 4]]);
+    assert!(manager.get_connection(&[1, 2, 3, 4]).is_none());
+    assert_eq!(manager.poll_timeout(), None);
+}
+
+#[test]
+fn test_idle_timeout_disabled() {
+    let mut conn = Connection::new_client(get_test_addr());
+    conn.set_transport_config(TransportConfig { max_idle_timeout: None, ..TransportConfig::default() });
+    assert_eq!(conn.idle_timeout(), None);
+    assert_eq!(conn.poll_timeout(), None);
+}
@@ -1,4 +1,4 @@
-use oreno_quic::crypto::{QuicCrypto, EncryptionLevel};
+use oreno_quic::crypto::{QuicCrypto, EncryptionLevel, retry_integrity_tag, verify_retry_integrity};
 
 #[test]
 fn test_crypto_creation() {
@@ -60,4 +60,82 @@ fn test_encryption_levels() {
     // Verify other levels are not present (they haven't been set up)
     assert!(!crypto.has_keys(&EncryptionLevel::Handshake));
     assert!(!crypto.has_keys(&EncryptionLevel::Application));
-}
\ No newline at end of file
+}
+#[test]
+fn test_initial_keys_client_server_agree() {
+    let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+    let mut client = QuicCrypto::new();
+    let mut server = QuicCrypto::new();
+    client.setup_initial_keys(&dcid, true).unwrap();
+    server.setup_initial_keys(&dcid, false).unwrap();
+    
+    let header = b"initial header";
+    let sealed = client.encrypt_packet(EncryptionLevel::Initial, 2, header, b"ClientHello").unwrap();
+    
+    let mut ciphertext = sealed.to_vec();
+    let len = server.decrypt_packet(EncryptionLevel::Initial, 2, header, &mut ciphertext).unwrap();
+    assert_eq!(&ciphertext[..len], b"ClientHello");
+    
+    // A different Destination Connection ID yields different keys
+    let mut other = QuicCrypto::new();
+    other.setup_initial_keys(b"other_dcid", false).unwrap();
+    let mut ciphertext = sealed.to_vec();
+    assert!(other.decrypt_packet(EncryptionLevel::Initial, 2, header, &mut ciphertext).is_err());
+}
+
+#[test]
+fn test_handshake_keys_client_server_agree() {
+    let handshake_secret = [0x1d; 32];
+    let transcript_hash = [0x86; 32];
+    let mut client = QuicCrypto::new();
+    let mut server = QuicCrypto::new();
+    client.setup_handshake_keys(&handshake_secret, &transcript_hash, true).unwrap();
+    server.setup_handshake_keys(&handshake_secret, &transcript_hash, false).unwrap();
+    
+    let sealed = server.encrypt_packet(EncryptionLevel::Handshake, 0, b"hdr", b"EncryptedExtensions").unwrap();
+    let mut ciphertext = sealed.to_vec();
+    let len = client.decrypt_packet(EncryptionLevel::Handshake, 0, b"hdr", &mut ciphertext).unwrap();
+    assert_eq!(&ciphertext[..len], b"EncryptedExtensions");
+    
+    // Keys bound to a different transcript must not interoperate
+    let mut other = QuicCrypto::new();
+    other.setup_handshake_keys(&handshake_secret, &[0x87; 32], true).unwrap();
+    let mut ciphertext = sealed.to_vec();
+    assert!(other.decrypt_packet(EncryptionLevel::Handshake, 0, b"hdr", &mut ciphertext).is_err());
+}
+
+#[test]
+fn test_header_protection_roundtrip() {
+    let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+    let mut client = QuicCrypto::new();
+    let mut server = QuicCrypto::new();
+    client.setup_initial_keys(&dcid, true).unwrap();
+    server.setup_initial_keys(&dcid, false).unwrap();
+    
+    // Short header: first byte, 8-byte DCID, 2-byte packet number, then ciphertext
+    let mut packet = vec![0x41, 1, 2, 3, 4, 5, 6, 7, 8, 0x12, 0x34];
+    packet.extend_from_slice(&[0xaa; 20]);
+    let original = packet.clone();
+    
+    client.protect_header(EncryptionLevel::Initial, &mut packet, 9).unwrap();
+    assert_ne!(packet, original);
+    assert_eq!(&packet[11..], &original[11..]);
+    
+    let pn_len = server.unprotect_header(EncryptionLevel::Initial, &mut packet, 9).unwrap();
+    assert_eq!(pn_len, 2);
+    assert_eq!(packet, original);
+}
+
+#[test]
+fn test_retry_integrity_tag() {
+    let odcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+    let mut retry = vec![0xf0, 0x00, 0x00, 0x00, 0x01, 0x00, 0x04, 9, 9, 9, 9];
+    retry.extend_from_slice(b"token");
+    
+    let tag = retry_integrity_tag(&odcid, &retry).unwrap();
+    retry.extend_from_slice(&tag);
+    assert!(verify_retry_integrity(&odcid, &retry));
+    
+    // A Retry answering a different Initial does not verify
+    assert!(!verify_retry_integrity(&[0x01; 8], &retry));
+}
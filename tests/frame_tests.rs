@@ -1,4 +1,6 @@
-use oreno_quic::frame::{Frame, FrameType, FrameError};
+use oreno_quic::frame::{self, EcnCounts, Frame, FrameType, FrameError};
+use oreno_quic::stream::{Dir, StreamId};
+use std::collections::BTreeSet;
 use bytes::{Bytes, BytesMut};
 
 #[test]
@@ -123,8 +125,22 @@ fn test_varint_encoding_through_connection_close() {
 fn test_frame_types() {
     assert_eq!(FrameType::Padding as u8, 0x00);
     assert_eq!(FrameType::Ping as u8, 0x01);
+    assert_eq!(FrameType::Ack as u8, 0x02);
+    assert_eq!(FrameType::AckEcn as u8, 0x03);
+    assert_eq!(FrameType::ResetStream as u8, 0x04);
+    assert_eq!(FrameType::StopSending as u8, 0x05);
     assert_eq!(FrameType::Crypto as u8, 0x06);
+    assert_eq!(FrameType::Stream as u8, 0x08);
+    assert_eq!(FrameType::MaxData as u8, 0x10);
+    assert_eq!(FrameType::MaxStreamData as u8, 0x11);
+    assert_eq!(FrameType::MaxStreamsBidi as u8, 0x12);
+    assert_eq!(FrameType::MaxStreamsUni as u8, 0x13);
+    assert_eq!(FrameType::DataBlocked as u8, 0x14);
+    assert_eq!(FrameType::StreamDataBlocked as u8, 0x15);
+    assert_eq!(FrameType::StreamsBlockedBidi as u8, 0x16);
+    assert_eq!(FrameType::StreamsBlockedUni as u8, 0x17);
     assert_eq!(FrameType::ConnectionClose as u8, 0x1c);
+    assert_eq!(FrameType::HandshakeDone as u8, 0x1e);
 }
 
 #[test]
@@ -274,4 +290,149 @@ fn test_crypto_frame_large_offset() {
         }
         _ => panic!("Expected Crypto frame"),
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_ack_frame_encode_decode() {
+    let received: BTreeSet<u64> = [1, 2, 3, 10, 11, 20].into_iter().collect();
+    let ranges = frame::ack_ranges(&received);
+    assert_eq!(ranges, vec![20..=20, 10..=11, 1..=3]);
+    
+    let frame = Frame::Ack {
+        largest: 20,
+        delay: 1000,
+        ranges: ranges.clone(),
+        ecn: Some(EcnCounts { ect0: 6, ect1: 0, ce: 0 }),
+    };
+    
+    let mut buf = BytesMut::new();
+    frame.encode(&mut buf).unwrap();
+    assert_eq!(buf[0], 0x03);
+    
+    let mut bytes = buf.freeze();
+    match Frame::decode(&mut bytes).unwrap() {
+        Frame::Ack { largest, delay, ranges: decoded_ranges, ecn } => {
+            assert_eq!(largest, 20);
+            assert_eq!(delay, 1000);
+            assert_eq!(decoded_ranges, ranges);
+            assert_eq!(ecn, Some(EcnCounts { ect0: 6, ect1: 0, ce: 0 }));
+        }
+        _ => panic!("Expected Ack frame"),
+    }
+}
+
+#[test]
+fn test_ack_frame_overlapping_ranges_rejected() {
+    let frame = Frame::Ack {
+        largest: 10,
+        delay: 0,
+        ranges: vec![5..=10, 4..=4],
+        ecn: None,
+    };
+    assert!(matches!(frame.encode(&mut BytesMut::new()), Err(FrameError::InvalidFormat)));
+}
+
+#[test]
+fn test_truncated_ack_frame() {
+    // ACK with one additional range whose gap and length are missing
+    let mut truncated = Bytes::from_static(&[0x02, 0x0a, 0x00, 0x01, 0x00]);
+    assert!(Frame::decode(&mut truncated).is_err());
+}
+
+#[test]
+fn test_stream_frame_encode_decode() {
+    let frame = Frame::Stream {
+        stream_id: StreamId(8),
+        offset: 64,
+        data: Bytes::from_static(b"stream data"),
+        fin: true,
+    };
+    
+    let mut buf = BytesMut::new();
+    frame.encode(&mut buf).unwrap();
+    assert_eq!(buf[0], 0x0f);
+    
+    let mut bytes = buf.freeze();
+    match Frame::decode(&mut bytes).unwrap() {
+        Frame::Stream { stream_id, offset, data, fin } => {
+            assert_eq!(stream_id, StreamId(8));
+            assert_eq!(offset, 64);
+            assert_eq!(data, Bytes::from_static(b"stream data"));
+            assert!(fin);
+        }
+        _ => panic!("Expected Stream frame"),
+    }
+}
+
+#[test]
+fn test_truncated_stream_frame() {
+    // STREAM with LEN bit claiming 5 bytes but carrying 2
+    let mut truncated = Bytes::from_static(&[0x0a, 0x00, 0x05, 0x01, 0x02]);
+    assert!(Frame::decode(&mut truncated).is_err());
+}
+
+#[test]
+fn test_max_stream_data_frame_encode_decode() {
+    let frame = Frame::MaxStreamData { stream_id: StreamId(5), max_data: 1 << 30 };
+    
+    let mut buf = BytesMut::new();
+    frame.encode(&mut buf).unwrap();
+    assert_eq!(buf[0], 0x11);
+    
+    let mut bytes = buf.freeze();
+    match Frame::decode(&mut bytes).unwrap() {
+        Frame::MaxStreamData { stream_id, max_data } => {
+            assert_eq!(stream_id, StreamId(5));
+            assert_eq!(max_data, 1 << 30);
+        }
+        _ => panic!("Expected MaxStreamData frame"),
+    }
+}
+
+#[test]
+fn test_streams_blocked_frame_encode_decode() {
+    let frame = Frame::StreamsBlocked { dir: Dir::Uni, limit: 100 };
+    
+    let mut buf = BytesMut::new();
+    frame.encode(&mut buf).unwrap();
+    assert_eq!(buf[0], 0x17);
+    
+    let mut bytes = buf.freeze();
+    match Frame::decode(&mut bytes).unwrap() {
+        Frame::StreamsBlocked { dir, limit } => {
+            assert_eq!(dir, Dir::Uni);
+            assert_eq!(limit, 100);
+        }
+        _ => panic!("Expected StreamsBlocked frame"),
+    }
+}
+
+#[test]
+fn test_reset_stream_frame_encode_decode() {
+    let frame = Frame::ResetStream { stream_id: StreamId(2), error_code: 3, final_size: 1000 };
+    
+    let mut buf = BytesMut::new();
+    frame.encode(&mut buf).unwrap();
+    assert_eq!(buf[0], 0x04);
+    
+    let mut bytes = buf.freeze();
+    match Frame::decode(&mut bytes).unwrap() {
+        Frame::ResetStream { stream_id, error_code, final_size } => {
+            assert_eq!(stream_id, StreamId(2));
+            assert_eq!(error_code, 3);
+            assert_eq!(final_size, 1000);
+        }
+        _ => panic!("Expected ResetStream frame"),
+    }
+}
+
+#[test]
+fn test_handshake_done_frame_encode_decode() {
+    let mut buf = BytesMut::new();
+    Frame::HandshakeDone.encode(&mut buf).unwrap();
+    assert_eq!(&buf[..], &[0x1e]);
+    assert!(Frame::HandshakeDone.is_ack_eliciting());
+    
+    let mut bytes = buf.freeze();
+    assert!(matches!(Frame::decode(&mut bytes).unwrap(), Frame::HandshakeDone));
+}
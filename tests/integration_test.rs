@@ -1,6 +1,6 @@
-use oreno_quic::connection::{Connection, ConnectionState};
+use oreno_quic::connection::{Connection, ConnectionError, ConnectionState};
 use oreno_quic::frame::Frame;
-use oreno_quic::packet::{ConnectionId, PacketHeader};
+use oreno_quic::packet::{ConnectionId, PacketHeader, ProtectedHeader, DEFAULT_MAX_DATAGRAM_SIZE};
 use bytes::Bytes;
 use std::net::SocketAddr;
 use tokio::net::UdpSocket;
@@ -31,21 +31,13 @@ async fn test_client_server_ping_pong() {
     
     assert_eq!(peer_addr, client_addr);
     
-    let mut packet_data = Bytes::copy_from_slice(&buf[..len]);
-    let header = PacketHeader::decode(&mut packet_data).unwrap();
-    
-    // Extract connection ID for server
-    let src_conn_id = match &header {
-        PacketHeader::Long(h) => h.src_conn_id.clone(),
-        _ => panic!("Expected long header"),
-    };
+    // Create server connection from the unprotected header fields
+    let header = ProtectedHeader::parse(&buf[..len]).unwrap();
+    let mut server_conn = Connection::accept(peer_addr, header.src_conn_id.unwrap(), header.dest_conn_id);
     
-    // Create server connection
-    let mut server_conn = Connection::new_server(peer_addr, src_conn_id);
-    
-    // Decode frame
-    let frame = Frame::decode(&mut packet_data).unwrap();
-    assert!(matches!(frame, Frame::Ping));
+    // Decrypt packet
+    let packet = server_conn.receive_packet(&buf[..len]).unwrap();
+    assert!(matches!(packet.frames[0], Frame::Ping));
     
     // Server sends PING response
     let pong_packet = server_conn.create_initial_packet(vec![Frame::Ping]).unwrap();
@@ -57,11 +49,8 @@ async fn test_client_server_ping_pong() {
         .unwrap()
         .unwrap();
     
-    let mut response_data = Bytes::copy_from_slice(&buf[..len]);
-    let _response_header = PacketHeader::decode(&mut response_data).unwrap();
-    let response_frame = Frame::decode(&mut response_data).unwrap();
-    
-    assert!(matches!(response_frame, Frame::Ping));
+    let response = client_conn.receive_packet(&buf[..len]).unwrap();
+    assert!(matches!(response.frames[0], Frame::Ping));
 }
 
 #[tokio::test]
@@ -89,23 +78,15 @@ async fn test_connection_close() {
     
     assert_eq!(peer_addr, client_addr);
     
-    let mut packet_data = Bytes::copy_from_slice(&buf[..len]);
-    let header = PacketHeader::decode(&mut packet_data).unwrap();
-    
-    // Extract connection ID for server
-    let src_conn_id = match &header {
-        PacketHeader::Long(h) => h.src_conn_id.clone(),
-        _ => panic!("Expected long header"),
-    };
+    // Create server connection from the unprotected header fields
+    let header = ProtectedHeader::parse(&buf[..len]).unwrap();
+    let mut server_conn = Connection::accept(peer_addr, header.src_conn_id.unwrap(), header.dest_conn_id);
     
-    // Create server connection
-    let mut server_conn = Connection::new_server(peer_addr, src_conn_id);
-    
-    // Decode frame
-    let frame = Frame::decode(&mut packet_data).unwrap();
-    match frame {
+    // Decrypt packet
+    let packet = server_conn.receive_packet(&buf[..len]).unwrap();
+    match &packet.frames[0] {
         Frame::ConnectionClose { error_code, reason } => {
-            assert_eq!(error_code, 0);
+            assert_eq!(*error_code, 0);
             assert_eq!(reason, "Client disconnect");
         }
         _ => panic!("Expected ConnectionClose frame"),
@@ -114,10 +95,12 @@ async fn test_connection_close() {
     // Verify client is in closing state
     assert_eq!(client_conn.state, ConnectionState::Closing);
     
-    // Server responds with its own CONNECTION_CLOSE
-    server_conn.handle_state_transition(ConnectionState::Closed);
-    let server_close = server_conn.close("Server acknowledged".to_string()).unwrap();
-    server_socket.send_to(&server_close, peer_addr).await.unwrap();
+    // The server drains instead of answering, and is closed once the draining period ends
+    assert_eq!(server_conn.state, ConnectionState::Draining);
+    assert!(server_conn.close("Server acknowledged".to_string()).is_err());
+    let drained = server_conn.poll_timeout().unwrap();
+    server_conn.handle_timeout(drained);
+    assert!(server_conn.is_closed());
 }
 
 #[tokio::test]
@@ -147,16 +130,16 @@ async fn test_packet_with_multiple_frames() {
         .unwrap()
         .unwrap();
     
-    let mut packet_data = Bytes::copy_from_slice(&buf[..len]);
-    let _header = PacketHeader::decode(&mut packet_data).unwrap();
+    let header = ProtectedHeader::parse(&buf[..len]).unwrap();
+    let mut server_conn = Connection::accept(actual_server_addr, header.src_conn_id.unwrap(), header.dest_conn_id);
+    let packet = server_conn.receive_packet(&buf[..len]).unwrap();
     
     // Decode all frames
     let mut frame_count = 0;
     let mut found_ping = false;
     let mut total_padding = 0;
     
-    while !packet_data.is_empty() {
-        let frame = Frame::decode(&mut packet_data).unwrap();
+    for frame in packet.frames {
         frame_count += 1;
         
         match frame {
@@ -166,9 +149,11 @@ async fn test_packet_with_multiple_frames() {
         }
     }
     
+    // The trailing padding runs on to fill the client's Initial out to a full datagram
+    assert_eq!(len, DEFAULT_MAX_DATAGRAM_SIZE);
     assert_eq!(frame_count, 3);
     assert!(found_ping);
-    assert_eq!(total_padding, 8); // 5 + 3
+    assert!(total_padding > 8); // 5 + 3 and the datagram padding
 }
 
 #[tokio::test]
@@ -217,16 +202,36 @@ async fn test_large_connection_id() {
         .unwrap()
         .unwrap();
     
-    let mut packet_data = Bytes::copy_from_slice(&buf[..len]);
-    let header = PacketHeader::decode(&mut packet_data).unwrap();
+    let header = ProtectedHeader::parse(&buf[..len]).unwrap();
+    assert_eq!(header.dest_conn_id.len(), 20);
+    assert_eq!(header.src_conn_id.as_ref().unwrap().len(), 8); // Client's local conn ID
+}
+
+#[tokio::test]
+async fn test_tampered_packet_dropped() {
+    let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let actual_server_addr = server_socket.local_addr().unwrap();
+
+    let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    let mut client_conn = Connection::new_client(actual_server_addr);
+    let mut packet = client_conn.create_initial_packet(vec![Frame::Ping]).unwrap();
     
-    match header {
-        PacketHeader::Long(h) => {
-            assert_eq!(h.dest_conn_id.len(), 20);
-            assert_eq!(h.src_conn_id.len(), 8); // Client's local conn ID
-        }
-        _ => panic!("Expected long header"),
-    }
+    // Flip a bit in the protected payload
+    let last = packet.len() - 1;
+    packet[last] ^= 0x80;
+    client_socket.send_to(&packet, actual_server_addr).await.unwrap();
+
+    let mut buf = vec![0u8; 1500];
+    let (len, peer_addr) = timeout(Duration::from_millis(100), server_socket.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    
+    let header = ProtectedHeader::parse(&buf[..len]).unwrap();
+    let mut server_conn = Connection::accept(peer_addr, header.src_conn_id.unwrap(), header.dest_conn_id);
+    
+    assert!(matches!(server_conn.receive_packet(&buf[..len]), Err(ConnectionError::DecryptionFailed)));
 }
 
 #[test]
@@ -0,0 +1,69 @@
+use oreno_quic::pacer::Pacer;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Sends everything queued the way an event loop would, sleeping until each next send time,
+/// and returns when each datagram left relative to the start
+async fn drain(pacer: &mut Pacer) -> Vec<Duration> {
+    let start = Instant::now();
+    let mut sent = Vec::new();
+    while let Some(next) = pacer.next_send_time(Instant::now().into_std()) {
+        tokio::time::sleep_until(Instant::from_std(next)).await;
+        while pacer.poll_transmit(Instant::now().into_std()).is_some() {
+            sent.push(start.elapsed());
+        }
+    }
+    sent
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_paced_sends_are_spread_over_time() {
+    // 1200-byte datagrams at 120 KB/s leave 10ms apart once the two-datagram burst is spent
+    let mut pacer = Pacer::new(2400, 120_000);
+    for _ in 0..10 {
+        pacer.push(vec![0; 1200]);
+    }
+    let sent = drain(&mut pacer).await;
+    assert_eq!(sent.len(), 10);
+    assert_eq!(sent[0], Duration::ZERO);
+    assert_eq!(sent[1], Duration::ZERO);
+    for pair in sent[1..].windows(2) {
+        let gap = pair[1] - pair[0];
+        assert!(gap >= Duration::from_millis(10) && gap < Duration::from_micros(10_100), "gap {:?}", gap);
+    }
+    assert!(sent[9] >= Duration::from_millis(80));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_burst_allowance_refills_while_idle() {
+    let mut pacer = Pacer::new(3600, 120_000);
+    for _ in 0..3 {
+        pacer.push(vec![0; 1200]);
+    }
+    assert_eq!(drain(&mut pacer).await, vec![Duration::ZERO; 3]);
+    
+    // After a quiet second the whole burst is available again, but no more
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    for _ in 0..4 {
+        pacer.push(vec![0; 1200]);
+    }
+    let sent = drain(&mut pacer).await;
+    assert_eq!(&sent[..3], &[Duration::ZERO; 3]);
+    assert!(sent[3] >= Duration::from_millis(10));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_rate_change_takes_effect() {
+    let mut pacer = Pacer::new(1200, 120_000);
+    for _ in 0..3 {
+        pacer.push(vec![0; 1200]);
+    }
+    let start = Instant::now();
+    assert!(pacer.poll_transmit(start.into_std()).is_some());
+    
+    // Halving the rate doubles the spacing
+    pacer.set_rate(60_000);
+    let sent = drain(&mut pacer).await;
+    assert!(sent[0] >= Duration::from_millis(20) && sent[0] < Duration::from_millis(21));
+    assert!(sent[1] - sent[0] >= Duration::from_millis(20));
+}
@@ -1,4 +1,4 @@
-use oreno_quic::packet::{ConnectionId, PacketHeader, LongHeader, ShortHeader, PacketType};
+use oreno_quic::packet::{ConnectionId, PacketHeader, LongHeader, ShortHeader, PacketType, PacketError, VersionNegotiationHeader, RetryHeader, encode_packet_number, decode_packet_number, split_datagram, version_negotiation_response, SUPPORTED_VERSIONS};
 use bytes::{Bytes, BytesMut};
 
 #[test]
@@ -33,7 +33,10 @@ fn test_long_header_encode_decode() {
         version: 1,
         dest_conn_id: ConnectionId::new(vec![1, 2, 3, 4]),
         src_conn_id: ConnectionId::new(vec![5, 6, 7, 8]),
+        token: Bytes::new(),
+        length: 0,
         packet_number: 42,
+        packet_number_len: 1,
     });
 
     let mut buf = BytesMut::new();
@@ -59,6 +62,7 @@ fn test_short_header_encode_decode() {
     let header = PacketHeader::Short(ShortHeader {
         dest_conn_id: ConnectionId::new(vec![1, 2, 3, 4, 5, 6, 7, 8]),
         packet_number: 123,
+        packet_number_len: 1,
     });
 
     let mut buf = BytesMut::new();
@@ -96,7 +100,10 @@ fn test_packet_number_encoding() {
             version: 1,
             dest_conn_id: ConnectionId::new(vec![1, 2, 3, 4]),
             src_conn_id: ConnectionId::new(vec![5, 6, 7, 8]),
+            token: Bytes::new(),
+            length: 0,
             packet_number: packet_num,
+            packet_number_len: 4,
         });
 
         let mut buf = BytesMut::new();
@@ -144,7 +151,6 @@ fn test_long_header_different_packet_types() {
         PacketType::Initial,
         PacketType::ZeroRtt,
         PacketType::Handshake,
-        PacketType::Retry,
     ];
 
     for packet_type in packet_types {
@@ -153,7 +159,10 @@ fn test_long_header_different_packet_types() {
             version: 1,
             dest_conn_id: ConnectionId::new(vec![1, 2]),
             src_conn_id: ConnectionId::new(vec![3, 4]),
+            token: Bytes::new(),
+            length: 0,
             packet_number: 1,
+            packet_number_len: 1,
         });
 
         let mut buf = BytesMut::new();
@@ -169,4 +178,170 @@ fn test_long_header_different_packet_types() {
             _ => panic!("Expected Long header"),
         }
     }
-}
\ No newline at end of file
+}
+#[test]
+fn test_initial_header_token_roundtrip() {
+    let header = PacketHeader::Long(LongHeader {
+        packet_type: PacketType::Initial,
+        version: 1,
+        dest_conn_id: ConnectionId::new(vec![1, 2, 3, 4]),
+        src_conn_id: ConnectionId::new(vec![5, 6, 7, 8]),
+        token: Bytes::from_static(b"retry-token"),
+        length: 1200,
+        packet_number: 70000,
+        packet_number_len: 3,
+    });
+
+    let mut buf = BytesMut::new();
+    header.encode(&mut buf).unwrap();
+    
+    // Fixed bit set, 3-byte packet number
+    assert_eq!(buf[0], 0xc2);
+
+    let mut bytes = buf.freeze();
+    let decoded = PacketHeader::decode(&mut bytes).unwrap();
+    assert!(bytes.is_empty());
+
+    match decoded {
+        PacketHeader::Long(decoded_header) => {
+            assert_eq!(decoded_header.token, Bytes::from_static(b"retry-token"));
+            assert_eq!(decoded_header.length, 1200);
+            assert_eq!(decoded_header.packet_number, 70000);
+        }
+        _ => panic!("Expected Long header"),
+    }
+}
+
+#[test]
+fn test_packet_number_truncation_rfc9000() {
+    // RFC 9000 Appendix A.2 and A.3
+    assert_eq!(encode_packet_number(0xac5c02, Some(0xabe8b3)), (0x5c02, 2));
+    assert_eq!(encode_packet_number(0xace8fe, Some(0xabe8b3)), (0xace8fe, 3));
+    assert_eq!(decode_packet_number(0x9b32, 2, Some(0xa82f30ea)), 0xa82f9b32);
+}
+
+#[test]
+fn test_short_header_sixteen_byte_conn_id() {
+    let header = PacketHeader::Short(ShortHeader {
+        dest_conn_id: ConnectionId::new(vec![0xab; 16]),
+        packet_number: 77,
+        packet_number_len: 1,
+    });
+
+    let mut buf = BytesMut::new();
+    header.encode(&mut buf).unwrap();
+
+    let mut bytes = buf.freeze();
+    let decoded = PacketHeader::decode_with_conn_id_len(&mut bytes, 16).unwrap();
+
+    match decoded {
+        PacketHeader::Short(decoded_header) => {
+            assert_eq!(decoded_header.dest_conn_id.data, vec![0xab; 16]);
+            assert_eq!(decoded_header.packet_number, 77);
+        }
+        _ => panic!("Expected Short header"),
+    }
+    
+    let mut truncated = Bytes::from_static(&[0x40, 0xab, 0xab]);
+    assert!(matches!(
+        PacketHeader::decode_with_conn_id_len(&mut truncated, 16),
+        Err(PacketError::TruncatedConnectionId { expected: 16 })
+    ));
+}
+
+#[test]
+fn test_split_datagram_yields_each_packet() {
+    let mut datagram = BytesMut::new();
+    for (packet_type, payload) in [(PacketType::Initial, 20usize), (PacketType::Handshake, 40)] {
+        let header = PacketHeader::Long(LongHeader {
+            packet_type,
+            version: 1,
+            dest_conn_id: ConnectionId::new(vec![1, 2, 3, 4]),
+            src_conn_id: ConnectionId::new(vec![5, 6, 7, 8]),
+            token: Bytes::new(),
+            length: 1 + payload as u64,
+            packet_number: 0,
+            packet_number_len: 1,
+        });
+        header.encode(&mut datagram).unwrap();
+        datagram.extend_from_slice(&vec![0xee; payload]);
+    }
+    
+    let packets: Vec<&[u8]> = split_datagram(&datagram).map(|packet| packet.unwrap()).collect();
+    
+    assert_eq!(packets.len(), 2);
+    assert_eq!(packets[0].len() + packets[1].len(), datagram.len());
+    assert_eq!(packets[1][0] & 0x30, 0x20); // Handshake
+}
+
+#[test]
+fn test_version_negotiation_roundtrip() {
+    let header = PacketHeader::VersionNegotiation(VersionNegotiationHeader {
+        dest_conn_id: ConnectionId::new(vec![1, 2, 3, 4]),
+        src_conn_id: ConnectionId::new(vec![5, 6, 7, 8]),
+        supported_versions: vec![1, 0xff00_001d],
+    });
+
+    let mut buf = BytesMut::new();
+    header.encode(&mut buf).unwrap();
+
+    let mut bytes = buf.freeze();
+    match PacketHeader::decode(&mut bytes).unwrap() {
+        PacketHeader::VersionNegotiation(decoded_header) => {
+            assert_eq!(decoded_header.dest_conn_id.data, vec![1, 2, 3, 4]);
+            assert_eq!(decoded_header.src_conn_id.data, vec![5, 6, 7, 8]);
+            assert_eq!(decoded_header.supported_versions, vec![1, 0xff00_001d]);
+        }
+        _ => panic!("Expected Version Negotiation header"),
+    }
+}
+
+#[test]
+fn test_unsupported_version_gets_version_negotiation() {
+    let header = PacketHeader::Long(LongHeader {
+        packet_type: PacketType::Initial,
+        version: 0x1a2a_3a4a,
+        dest_conn_id: ConnectionId::new(vec![1, 2, 3, 4]),
+        src_conn_id: ConnectionId::new(vec![5, 6, 7, 8]),
+        token: Bytes::new(),
+        length: 1200,
+        packet_number: 0,
+        packet_number_len: 1,
+    });
+    let mut datagram = BytesMut::new();
+    header.encode(&mut datagram).unwrap();
+    datagram.resize(1200, 0);
+
+    let response = version_negotiation_response(&datagram, SUPPORTED_VERSIONS).unwrap();
+    match PacketHeader::decode(&mut Bytes::from(response)).unwrap() {
+        PacketHeader::VersionNegotiation(decoded_header) => {
+            assert_eq!(decoded_header.dest_conn_id.data, vec![5, 6, 7, 8]);
+            assert_eq!(decoded_header.supported_versions, SUPPORTED_VERSIONS.to_vec());
+        }
+        _ => panic!("Expected Version Negotiation header"),
+    }
+}
+
+#[test]
+fn test_retry_roundtrip() {
+    let header = PacketHeader::Retry(RetryHeader {
+        version: 1,
+        dest_conn_id: ConnectionId::new(vec![1, 2, 3, 4]),
+        src_conn_id: ConnectionId::new(vec![5, 6, 7, 8]),
+        token: Bytes::from_static(b"address-token"),
+        integrity_tag: [0x5a; 16],
+    });
+
+    let mut buf = BytesMut::new();
+    header.encode(&mut buf).unwrap();
+
+    let mut bytes = buf.freeze();
+    match PacketHeader::decode(&mut bytes).unwrap() {
+        PacketHeader::Retry(decoded_header) => {
+            assert_eq!(decoded_header.src_conn_id.data, vec![5, 6, 7, 8]);
+            assert_eq!(decoded_header.token, Bytes::from_static(b"address-token"));
+            assert_eq!(decoded_header.integrity_tag, [0x5a; 16]);
+        }
+        _ => panic!("Expected Retry header"),
+    }
+}
@@ -0,0 +1,118 @@
+use oreno_quic::crypto::EncryptionLevel;
+use oreno_quic::flow_control::INITIAL_RTT;
+use oreno_quic::frame::Frame;
+use oreno_quic::recovery::{Recovery, SentPacket, Timeout, GRANULARITY, MAX_ACK_DELAY};
+use oreno_quic::stream::StreamId;
+use bytes::Bytes;
+use std::time::{Duration, Instant};
+
+fn stream_frame(offset: u64) -> Frame {
+    Frame::Stream {
+        stream_id: StreamId(0),
+        offset,
+        data: Bytes::from_static(b"data"),
+        fin: false,
+    }
+}
+
+fn send(recovery: &mut Recovery, level: EncryptionLevel, packet_number: u64, time_sent: Instant) {
+    recovery.on_packet_sent(level, SentPacket::new(packet_number, time_sent, 1200, &[stream_frame(packet_number * 4)]));
+}
+
+#[test]
+fn test_ack_only_packets_not_ack_eliciting() {
+    let ack = Frame::Ack { largest: 3, delay: 0, ranges: vec![0..=3], ecn: None };
+    let packet = SentPacket::new(0, Instant::now(), 40, &[ack, Frame::Padding { length: 10 }]);
+    assert!(!packet.ack_eliciting);
+    assert!(packet.frames.is_empty());
+    
+    // A PING needs an acknowledgement but has nothing worth sending again
+    let ping = SentPacket::new(1, Instant::now(), 40, &[Frame::Ping]);
+    assert!(ping.ack_eliciting);
+    assert!(ping.frames.is_empty());
+}
+
+#[test]
+fn test_acked_packets_leave_flight() {
+    let mut recovery = Recovery::new();
+    let now = Instant::now();
+    recovery.confirm_handshake();
+    for pn in 0..3 {
+        send(&mut recovery, EncryptionLevel::Application, pn, now);
+    }
+    assert_eq!(recovery.bytes_in_flight(), 3600);
+    
+    let outcome = recovery.on_ack_received(EncryptionLevel::Application, &[0..=2], Duration::ZERO, now);
+    let acked: Vec<u64> = outcome.acked.iter().map(|packet| packet.packet_number).collect();
+    assert_eq!(acked, vec![0, 1, 2]);
+    assert!(outcome.lost.is_empty());
+    assert_eq!(recovery.bytes_in_flight(), 0);
+    assert_eq!(recovery.timeout(), None);
+}
+
+#[test]
+fn test_packet_threshold_declares_loss() {
+    let mut recovery = Recovery::new();
+    let now = Instant::now();
+    for pn in 0..4 {
+        send(&mut recovery, EncryptionLevel::Handshake, pn, now);
+    }
+    
+    // Packet 0 is three behind the largest acknowledged, and its frames come back for retransmission
+    let outcome = recovery.on_ack_received(EncryptionLevel::Handshake, &[1..=3], Duration::ZERO, now);
+    assert_eq!(outcome.lost.len(), 1);
+    assert!(matches!(outcome.lost[0].frames[..], [Frame::Stream { offset: 0, .. }]));
+}
+
+#[test]
+fn test_time_threshold_timer() {
+    let mut recovery = Recovery::new();
+    let now = Instant::now();
+    send(&mut recovery, EncryptionLevel::Initial, 0, now);
+    send(&mut recovery, EncryptionLevel::Initial, 1, now);
+    
+    let rtt = Duration::from_millis(100);
+    let outcome = recovery.on_ack_received(EncryptionLevel::Initial, &[1..=1], Duration::ZERO, now + rtt);
+    assert!(outcome.lost.is_empty());
+    assert_eq!(recovery.rtt().smoothed_rtt(), rtt);
+    let loss_time = recovery.timeout().unwrap();
+    assert_eq!(loss_time, now + rtt * 9 / 8);
+    assert!(recovery.on_timeout(loss_time - GRANULARITY).is_none());
+    assert!(matches!(recovery.on_timeout(loss_time), Some(Timeout::Lost(EncryptionLevel::Initial, lost)) if lost.len() == 1));
+}
+
+#[test]
+fn test_pto_probes_with_backoff() {
+    let mut recovery = Recovery::new();
+    let start = Instant::now();
+    recovery.confirm_handshake();
+    send(&mut recovery, EncryptionLevel::Application, 0, start);
+    
+    let pto = INITIAL_RTT + 4 * (INITIAL_RTT / 2) + MAX_ACK_DELAY;
+    let mut fired = start;
+    for count in 1..=3u32 {
+        let timeout = recovery.timeout().unwrap();
+        assert_eq!(timeout, fired + pto * 2u32.pow(count - 1));
+        match recovery.on_timeout(timeout) {
+            Some(Timeout::Probe(EncryptionLevel::Application, frames)) => assert_eq!(frames.len(), 1),
+            other => panic!("Expected probe, got {:?}", other),
+        }
+        assert_eq!(recovery.pto_count(), count);
+        fired = timeout;
+    }
+    
+    recovery.on_ack_received(EncryptionLevel::Application, &[0..=0], Duration::ZERO, fired + Duration::from_millis(1));
+    assert_eq!(recovery.pto_count(), 0);
+}
+
+#[test]
+fn test_handshake_space_discarded() {
+    let mut recovery = Recovery::new();
+    let now = Instant::now();
+    send(&mut recovery, EncryptionLevel::Handshake, 0, now);
+    assert!(recovery.timeout().is_some());
+    
+    recovery.discard_space(EncryptionLevel::Handshake);
+    assert_eq!(recovery.timeout(), None);
+    assert_eq!(recovery.bytes_in_flight(), 0);
+}
@@ -0,0 +1,34 @@
+use oreno_quic::flow_control::INITIAL_RTT;
+use oreno_quic::rtt::RttEstimator;
+use std::time::Duration;
+
+const MS: Duration = Duration::from_millis(1);
+
+#[test]
+fn test_estimator_converges_on_steady_rtt() {
+    let mut rtt = RttEstimator::new();
+    assert_eq!(rtt.smoothed_rtt(), INITIAL_RTT);
+    
+    for _ in 0..50 {
+        rtt.update(40 * MS, Duration::ZERO, Some(25 * MS));
+    }
+    assert_eq!(rtt.min_rtt(), Some(40 * MS));
+    assert!(rtt.smoothed_rtt().abs_diff(40 * MS) < MS);
+    assert!(rtt.rttvar() < MS);
+}
+
+#[test]
+fn test_ack_delay_correction() {
+    let mut rtt = RttEstimator::new();
+    rtt.update(50 * MS, Duration::ZERO, None);
+    
+    // Only max_ack_delay of the reported 30ms is taken off once the handshake is confirmed
+    rtt.update(80 * MS, 30 * MS, Some(10 * MS));
+    assert_eq!(rtt.latest_rtt(), Some(80 * MS));
+    assert_eq!(rtt.smoothed_rtt(), (350 * MS + 70 * MS) / 8);
+    
+    // A delay that would push the sample under min_rtt is ignored
+    rtt.update(55 * MS, 30 * MS, None);
+    assert_eq!(rtt.min_rtt(), Some(50 * MS));
+    assert_eq!(rtt.latest_rtt(), Some(55 * MS));
+}
@@ -0,0 +1,153 @@
+use oreno_quic::config::TransportConfig;
+use oreno_quic::stream::{Dir, RecvState, SendState, Side, StreamError, StreamId, Streams};
+use oreno_quic::frame::Frame;
+use oreno_quic::transport_parameters::TransportParameters;
+use bytes::Bytes;
+
+/// Client and server stream sets that have exchanged their flow control limits
+fn stream_pair() -> (Streams, Streams) {
+    let mut client = Streams::new(Side::Client);
+    let mut server = Streams::new(Side::Server);
+    
+    let mut client_params = TransportParameters::default();
+    let mut server_params = TransportParameters::default();
+    client.local_transport_parameters(&mut client_params);
+    server.local_transport_parameters(&mut server_params);
+    client.set_peer_transport_parameters(&server_params);
+    server.set_peer_transport_parameters(&client_params);
+    (client, server)
+}
+
+#[test]
+fn test_stream_id_bits() {
+    assert_eq!(StreamId::new(Side::Client, Dir::Bi, 0), StreamId(0));
+    assert_eq!(StreamId::new(Side::Server, Dir::Bi, 0), StreamId(1));
+    assert_eq!(StreamId::new(Side::Client, Dir::Uni, 0), StreamId(2));
+    assert_eq!(StreamId::new(Side::Server, Dir::Uni, 2), StreamId(11));
+    
+    let id = StreamId(11);
+    assert_eq!(id.initiator(), Side::Server);
+    assert_eq!(id.dir(), Dir::Uni);
+    assert_eq!(id.index(), 2);
+}
+
+#[test]
+fn test_streams_exchange_data() {
+    let (mut client, mut server) = stream_pair();
+    
+    let id = client.open(Dir::Bi).unwrap();
+    assert_eq!(client.write(id, b"hello").unwrap(), 5);
+    client.finish(id).unwrap();
+    
+    while let Some(frame) = client.poll_frame(1200) {
+        match frame {
+            Frame::Stream { stream_id, offset, data, fin } => {
+                server.receive(stream_id, offset, data, fin).unwrap();
+            }
+            _ => panic!("Expected Stream frame"),
+        }
+    }
+    
+    assert_eq!(server.accept(), Some(id));
+    let mut buf = [0u8; 16];
+    assert_eq!(server.read(id, &mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"hello");
+    assert_eq!(server.read(id, &mut buf).unwrap(), 0);
+}
+
+#[test]
+fn test_read_before_data_is_blocked() {
+    let mut server = Streams::new(Side::Server);
+    server.receive(StreamId(0), 3, Bytes::from_static(b"later"), false).unwrap();
+    
+    let mut buf = [0u8; 16];
+    assert_eq!(server.read(StreamId(0), &mut buf), Err(StreamError::Blocked));
+    assert_eq!(server.read(StreamId(4), &mut buf), Err(StreamError::UnknownStream));
+}
+
+#[test]
+fn test_stream_error_display() {
+    let errors = vec![
+        StreamError::UnknownStream,
+        StreamError::Blocked,
+        StreamError::Finished,
+        StreamError::StreamState,
+        StreamError::FinalSize,
+        StreamError::FlowControl,
+        StreamError::StreamLimit,
+        StreamError::Reset(1),
+        StreamError::Stopped(2),
+    ];
+    
+    for error in errors {
+        assert!(!error.to_string().is_empty());
+    }
+}
+
+#[test]
+fn test_write_limited_by_peer_credit() {
+    let mut client = Streams::new(Side::Client);
+    let params = TransportParameters {
+        initial_max_data: Some(100),
+        initial_max_stream_data_uni: Some(60),
+        initial_max_streams_uni: Some(2),
+        ..TransportParameters::default()
+    };
+    client.set_peer_transport_parameters(&params);
+    
+    let first = client.open(Dir::Uni).unwrap();
+    let second = client.open(Dir::Uni).unwrap();
+    assert_eq!(client.write(first, &[0u8; 80]).unwrap(), 60);
+    assert_eq!(client.write(second, &[0u8; 80]).unwrap(), 40);
+    assert_eq!(client.write(second, &[0u8; 1]), Err(StreamError::Blocked));
+}
+
+#[test]
+fn test_stream_limit_follows_config() {
+    let config = TransportConfig { max_concurrent_bidi_streams: 2, ..TransportConfig::default() };
+    let mut server = Streams::with_config(Side::Server, config);
+    let mut params = TransportParameters::default();
+    server.local_transport_parameters(&mut params);
+    assert_eq!(params.initial_max_streams_bidi, Some(2));
+    
+    let mut client = Streams::new(Side::Client);
+    client.set_peer_transport_parameters(&params);
+    assert_eq!(client.open(Dir::Bi), Ok(StreamId(0)));
+    assert_eq!(client.open(Dir::Bi), Ok(StreamId(4)));
+    assert_eq!(client.open(Dir::Bi), Err(StreamError::Blocked));
+    assert_eq!(server.receive(StreamId(8), 0, Bytes::new(), true), Err(StreamError::StreamLimit));
+}
+
+#[test]
+fn test_reset_stream_error_reaches_reader() {
+    let (mut client, mut server) = stream_pair();
+    
+    let id = client.open(Dir::Bi).unwrap();
+    client.write(id, b"partial").unwrap();
+    let Some(Frame::Stream { stream_id, offset, data, fin }) = client.poll_frame(1200) else {
+        panic!("Expected Stream frame");
+    };
+    server.receive(stream_id, offset, data, fin).unwrap();
+    
+    client.reset(id, 99).unwrap();
+    assert_eq!(client.finish(id), Err(StreamError::Finished));
+    server.receive_reset_stream(id, 99, 7).unwrap();
+    assert_eq!(server.recv_state(id), Some(RecvState::ResetRecvd));
+    
+    let mut buf = [0u8; 16];
+    assert_eq!(server.read(id, &mut buf), Err(StreamError::Reset(99)));
+    assert_eq!(server.recv_state(id), Some(RecvState::ResetRead));
+    assert_eq!(server.send_state(id), Some(SendState::Ready));
+}
+
+#[test]
+fn test_stop_sending_error_reaches_writer() {
+    let (mut client, mut server) = stream_pair();
+    
+    let id = client.open(Dir::Bi).unwrap();
+    server.receive(id, 0, Bytes::new(), false).unwrap();
+    server.receive_stop_sending(id, 5).unwrap();
+    
+    assert_eq!(server.write(id, b"reply"), Err(StreamError::Stopped(5)));
+    assert_eq!(server.finish(id), Err(StreamError::Stopped(5)));
+}
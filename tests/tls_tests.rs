@@ -65,4 +65,4 @@ fn test_different_server_names() {
     
     assert!(localhost_client.is_ok(), "Localhost client should be created successfully");
     assert!(ip_client.is_ok(), "IP address client should be created successfully");
-}
\ No newline at end of file
+}